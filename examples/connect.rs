@@ -1,20 +1,27 @@
 pub mod shared;
 
+use std::time::Duration;
 use structopt::StructOpt;
 
-use wifi_captive::{credentials_from_data, NetworkBackend, Security};
+use wifi_captive::{credentials_from_data, ConnectCancel, ConnectOptions, NetworkBackend, Security};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config: shared::Config = shared::Config::from_args();
 
-    let manager = NetworkBackend::new(&config.interface).await?;
+    let manager = NetworkBackend::new(&config.interface, Duration::from_secs(0), Duration::from_secs(0), None).await?;
     let state = manager
         .connect_to(
-            config.ssid,
-            credentials_from_data(config.passphrase, None, Security::WPA2)?,
-            None,
-            true,
+            config.ssid.into_bytes(),
+            credentials_from_data(Some(config.passphrase), None, None, None, None, None, Security::WPA2)?,
+            ConnectOptions {
+                hw: None,
+                overwrite_same_ssid_connection: true,
+                lock_bssid: false,
+                persist_connection: true,
+                activation_timeout: Duration::from_secs(30),
+            },
+            ConnectCancel::default().start(),
         )
         .await?;
 
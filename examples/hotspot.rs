@@ -2,17 +2,24 @@
 pub mod shared;
 
 use std::net::Ipv4Addr;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use wifi_captive::NetworkBackend;
+use wifi_captive::{NetworkBackend, WifiBand};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config: shared::Config = shared::Config::from_args();
 
-    let manager = NetworkBackend::new(&config.interface).await?;
+    let manager = NetworkBackend::new(&config.interface, Duration::from_secs(0), Duration::from_secs(0), None).await?;
     manager
-        .hotspot_start(config.ssid, config.passphrase, Some(Ipv4Addr::new(10, 0, 0, 1)))
+        .hotspot_start(
+            config.ssid,
+            config.passphrase,
+            Some(Ipv4Addr::new(10, 0, 0, 1)),
+            24,
+            WifiBand::Bg,
+        )
         .await?;
 
     Ok(())
@@ -8,7 +8,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::builder().filter_level(LevelFilter::Info).init();
 
     info!("Starting");
-    let manager = NetworkBackend::new(&None).await?;
+    let manager = NetworkBackend::new(&None, Duration::from_secs(0), Duration::from_secs(0), None).await?;
 
     manager.wait_for_connectivity(true, Duration::from_secs(20)).await?;
     info!("Connected");
@@ -1,15 +1,54 @@
 //! # The command line configuration is defined in this module.
 
-use std::net::Ipv4Addr;
+use crate::dhcp_server::vendor::parse_vendor_option;
+use crate::dhcp_server::DEFAULT_LEASE_GRACE_SECS;
+use crate::dns_server::domain_policy::{parse_domain_policy, parse_host_address, DomainPolicy};
+use crate::dns_server::upstream::parse_dns_server;
+use crate::log_buffer::LogBuffer;
+use crate::network_interface::{ConnectCancel, ConnectProgressChannel, CredentialStore, FileCredentialStore, WifiBand};
+use crate::portal::PortalSessionSummary;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::sync::Arc;
 use structopt::StructOpt;
 
+/// Default of [`Config::hotspot_settle_delay`] in milliseconds
+const DEFAULT_HOTSPOT_SETTLE_DELAY: &str = "500";
+
+/// Default of [`Config::noise_paths`], separated by commas
+const DEFAULT_NOISE_PATHS: &str = "/favicon.ico,/apple-touch-icon*,/robots.txt";
+
 #[derive(StructOpt, Debug, Clone)] //
 pub struct Config {
     /// Wireless network interface to be used by WiFi Connect
     #[structopt(short, long = "interface", env = "PORTAL_INTERFACE")]
     pub interface: Option<String>,
 
+    /// The uuid of the hotspot connection. Multiple instances on the same machine, for example
+    /// one per wifi device, require distinct uuids. A built-in uuid is used if not set.
+    #[structopt(long = "hotspot-uuid", env = "HOTSPOT_UUID")]
+    pub hotspot_uuid: Option<String>,
+
+    /// Set the wireless network interface managed if it is unmanaged by the network backend.
+    /// The program quits with an error on an unmanaged interface otherwise.
+    #[structopt(long = "manage-device")]
+    pub manage_device: bool,
+
+    /// Time in seconds to wait for the wireless network interface to appear, for example on a cold boot.
+    /// The program quits immediately if no wifi device can be found and this is 0.
+    #[structopt(long = "wait-for-device", default_value = "0", env = "WAIT_FOR_DEVICE")]
+    pub wait_for_device: u64,
+
+    /// Time in seconds to wait for network manager to finish starting up, for example on a cold boot.
+    /// Network manager is not waited for if this is 0.
+    #[structopt(long = "wait-for-backend", default_value = "0", env = "WAIT_FOR_BACKEND")]
+    pub wait_for_backend: u64,
+
+    /// Number of attempts to bind the server ports on startup, if the gateway address is not assigned yet or a port
+    /// is held by another service. The delay between two attempts doubles up to 30 seconds. 0 retries forever.
+    #[structopt(long = "bind-attempts", default_value = "10", env = "BIND_ATTEMPTS")]
+    pub bind_attempts: u32,
+
     /// ssid of the captive portal WiFi network
     #[structopt(short, long = "portal-ssid", default_value = "WiFi Connect", env = "PORTAL_SSID")]
     pub ssid: String,
@@ -47,6 +86,54 @@ pub struct Config {
     )]
     pub gateway: Ipv4Addr,
 
+    /// Subnet prefix length of the captive portal WiFi network. 24 equals a subnet mask of 255.255.255.0.
+    /// The DHCP server hands out addresses of this subnet. Must be in the range of 8 to 30.
+    #[structopt(long = "portal-subnet-prefix", default_value = "24", env = "PORTAL_SUBNET_PREFIX")]
+    pub subnet_prefix: u8,
+
+    /// Refuse to start the hotspot if the gateway subnet overlaps with the subnet of another active connection.
+    /// Only a warning is logged otherwise.
+    #[structopt(long = "strict-address-check")]
+    pub strict_address_check: bool,
+
+    /// Time in milliseconds to wait after stopping a running hotspot, before scanning or checking connectivity.
+    /// Some radios need a moment to switch from access point to station mode.
+    #[structopt(long = "hotspot-settle-delay", default_value = DEFAULT_HOTSPOT_SETTLE_DELAY, env = "HOTSPOT_SETTLE_DELAY")]
+    pub hotspot_settle_delay: u64,
+
+    /// Time in seconds to wait for the hotspot to come up.
+    #[structopt(long = "hotspot-timeout", default_value = "25", env = "HOTSPOT_TIMEOUT")]
+    pub hotspot_timeout: u64,
+
+    /// Retry creating the hotspot once, if it failed or timed out, before trying to reconnect instead.
+    #[structopt(long = "hotspot-retry")]
+    pub hotspot_retry: bool,
+
+    /// Keep the hotspot and the portal up while connecting to the selected network and only stop them on success,
+    /// so that the user stays connected to the portal if the attempt fails.
+    /// Requires a second wifi device for the connection. The hotspot is stopped for the attempt otherwise.
+    #[structopt(long = "keep-hotspot-during-connect")]
+    pub keep_hotspot_during_connect: bool,
+
+    /// Save a successfully established connection to disk, so that it is known after a reboot.
+    /// If false, the connection is only kept in memory, eg for kiosk or guest deployments.
+    #[structopt(
+        long = "persist-connection",
+        parse(try_from_str),
+        default_value = "true",
+        env = "PERSIST_CONNECTION"
+    )]
+    pub persist_connection: bool,
+
+    /// The wifi band of the captive portal WiFi network. Either "bg" (2.4 GHz) or "a" (5 GHz).
+    #[structopt(long = "hotspot-band", default_value = "bg", env = "HOTSPOT_BAND")]
+    pub hotspot_band: WifiBand,
+
+    /// Connect to the strongest 5 GHz access point of the selected network, if it is also broadcast on 5 GHz.
+    /// Otherwise network manager chooses the access point, which is often on 2.4 GHz.
+    #[structopt(long = "prefer-5ghz")]
+    pub prefer_5ghz: bool,
+
     /// Listening port of the captive portal web server
     #[structopt(
         short,
@@ -56,6 +143,20 @@ pub struct Config {
     )]
     pub listening_port: u16,
 
+    /// Serve the captive portal on this unix domain socket instead of the listening port, eg to front it with
+    /// another web server or for integration tests.
+    #[structopt(long = "portal-unix-socket", env = "PORTAL_UNIX_SOCKET", parse(from_os_str))]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Path prefix of the captive portal web server, eg "/portal", if it is served behind a reverse proxy.
+    #[structopt(long = "base-path", default_value = "", env = "PORTAL_BASE_PATH")]
+    pub base_path: String,
+
+    /// Host name in urls that point to the portal, eg "portal.local". The gateway address is used if not set.
+    /// The name must resolve to the gateway, which is the case for all names while dns hijacking is enabled.
+    #[structopt(long = "portal-host", env = "PORTAL_HOST")]
+    pub portal_host: Option<String>,
+
     /// DNS server port
     #[structopt(default_value = "53", long = "dns-port")]
     pub dns_port: u16,
@@ -77,14 +178,239 @@ pub struct Config {
     #[structopt(short, long, default_value = "360", env = "RETRY_IN")]
     pub retry_in: u64,
 
+    /// Time in seconds to wait for a connection to an open or a WPA/WEP protected network to be established
+    #[structopt(long = "connect-timeout", default_value = "30", env = "CONNECT_TIMEOUT")]
+    pub connect_timeout: u64,
+
+    /// Time in seconds to wait for a connection to an enterprise (802.1X) network to be established.
+    /// The authentication server round trips take considerably longer than a pre-shared key handshake.
+    #[structopt(
+        long = "enterprise-connect-timeout",
+        default_value = "90",
+        env = "ENTERPRISE_CONNECT_TIMEOUT"
+    )]
+    pub enterprise_connect_timeout: u64,
+
+    /// Maximum time in seconds the portal stays open, even if clients are connected.
+    /// A connection attempt to a configured WiFi SSID is made afterwards. 0 keeps the portal open.
+    #[structopt(long = "max-portal-secs", default_value = "0", env = "MAX_PORTAL_SECS")]
+    pub max_portal_secs: u64,
+
+    /// Minimum time in seconds the portal stays open. A connection request that arrives earlier is
+    /// accepted, but the portal only closes after this time. 0 applies requests immediately.
+    #[structopt(long = "min-portal-secs", default_value = "0", env = "MIN_PORTAL_SECS")]
+    pub min_portal_secs: u64,
+
+    /// Time in seconds between two wifi scans while the portal is open. New networks are pushed to the UI.
+    /// Scans are skipped if the wifi device does not allow them in access point mode. 0 disables periodic scans.
+    #[structopt(long = "scan-interval", default_value = "0", env = "SCAN_INTERVAL")]
+    pub scan_interval: u64,
+
+    /// Maximum time in seconds for listing the access points before the portal is opened. The listing includes
+    /// a wait of up to 7 seconds for scan results. The access points found so far are shown if the time is up.
+    /// 0 disables the limit.
+    #[structopt(long = "scan-budget", default_value = "15", env = "SCAN_BUDGET")]
+    pub scan_budget: u64,
+
     /// Exit after a connection has been established.
     #[structopt(short, long)]
     pub quit_after_connected: bool,
 
+    /// Never open the portal. Known networks are retried endlessly instead, with an increasing delay
+    /// between attempts. For headless devices that rely on auto connect only.
+    #[structopt(long = "never-open-portal")]
+    pub never_open_portal: bool,
+
+    /// Stop the hotspot on exit
+    #[structopt(
+        long = "exit-stop-hotspot",
+        parse(try_from_str),
+        default_value = "true",
+        env = "EXIT_STOP_HOTSPOT"
+    )]
+    pub exit_stop_hotspot: bool,
+
+    /// Enable autoconnect of the wifi device on exit, so that network manager reconnects to known networks
+    #[structopt(
+        long = "exit-enable-autoconnect",
+        parse(try_from_str),
+        default_value = "true",
+        env = "EXIT_ENABLE_AUTOCONNECT"
+    )]
+    pub exit_enable_autoconnect: bool,
+
+    /// Enable networking and wifi on exit, in case it has been disabled while the service was running
+    #[structopt(long = "exit-enable-wifi")]
+    pub exit_enable_wifi: bool,
+
+    /// Minimum signal strength in percent of access points that are listed in the portal.
+    /// Weaker access points are hidden. 0 lists all access points.
+    #[structopt(long = "min-strength", default_value = "0", env = "MIN_STRENGTH")]
+    pub min_strength: u8,
+
+    /// Time in seconds after which access points that have not been reported again are removed from the list
+    /// of the portal. Some drivers do not report vanished access points. 0 keeps access points until they are removed.
+    #[structopt(long = "ap-ttl", default_value = "0", env = "AP_TTL")]
+    pub ap_ttl: u64,
+
+    /// The uri that is used to check for internet connectivity, eg "http://example.com/check".
+    /// Network manager is configured to use this uri instead of its built-in default.
+    /// Useful if the default check endpoint is not reachable, for example behind a proxy.
+    /// For network manager a configuration snippet is written, which is removed again on exit.
+    #[structopt(long = "connectivity-check-uri", env = "CONNECTIVITY_CHECK_URI")]
+    pub connectivity_check_uri: Option<String>,
+
+    /// Upstream dns servers, eg "1.1.1.1,9.9.9.9:53". The servers are tried in order.
+    /// Used by the iwd backend to resolve the connectivity check host.
+    #[structopt(
+        long = "upstream-dns",
+        use_delimiter = true,
+        parse(try_from_str = parse_dns_server),
+        env = "UPSTREAM_DNS"
+    )]
+    pub upstream_dns: Vec<SocketAddr>,
+
+    /// The captive portal uri that is announced to dhcp clients via option 114 (RFC 8910),
+    /// eg "http://192.168.42.1/index.html". Not announced if not set, see also `announce_captive_portal_api`.
+    #[structopt(long = "captive-portal-uri", env = "CAPTIVE_PORTAL_URI")]
+    pub captive_portal_uri: Option<String>,
+
+    /// Announce the "/captive-portal-api" endpoint (RFC 8908) of the portal via option 114,
+    /// if no captive portal uri is set. The endpoint is served via http, RFC 8908 requires https.
+    /// Clients that follow the RFC strictly ignore the announcement.
+    #[structopt(long = "announce-captive-portal-api")]
+    pub announce_captive_portal_api: bool,
+
+    /// Domains whose TXT queries are answered with the announced captive portal uri, eg "_captive-portal.local".
+    /// Some captive portal detection tools discover the captive portal api this way.
+    #[structopt(long = "dns-txt-record", use_delimiter = true, env = "DNS_TXT_RECORD")]
+    pub dns_txt_records: Vec<String>,
+
+    /// Time in seconds that an expired dhcp lease is reserved for its client. The client gets the same address
+    /// again within this time, for example after waking up from sleep. Longer expired leases are handed out to
+    /// other clients.
+    #[structopt(long = "dhcp-lease-grace", default_value = "3600", env = "DHCP_LEASE_GRACE")]
+    pub dhcp_lease_grace: u64,
+
+    /// Vendor specific sub-options like "1=http://192.168.42.1/provision" or "2=0x0a0b", handed out via dhcp
+    /// option 43 to clients that request it. Some provisioning clients locate their management server this way.
+    /// Sub-options are separated by commas, text values that contain a comma must be given hex encoded.
+    #[structopt(
+        long = "dhcp-vendor-option",
+        use_delimiter = true,
+        parse(try_from_str = parse_vendor_option),
+        env = "DHCP_VENDOR_OPTION"
+    )]
+    pub dhcp_vendor_options: Vec<(u8, Vec<u8>)>,
+
+    /// IANA enterprise number. If set, the vendor sub-options are also handed out via dhcp option 125.
+    #[structopt(long = "dhcp-vendor-enterprise-number", env = "DHCP_VENDOR_ENTERPRISE_NUMBER")]
+    pub dhcp_vendor_enterprise_number: Option<u32>,
+
+    /// Dns servers that are handed out via dhcp option 6, in the given order. The gateway address is handed out
+    /// if not set. Add the gateway first to keep the portal detection working.
+    #[structopt(long = "dhcp-dns-server", use_delimiter = true, env = "DHCP_DNS_SERVER")]
+    pub dhcp_dns_servers: Vec<Ipv4Addr>,
+
+    /// Do not answer all dns queries with the gateway address. Queries are forwarded to the upstream dns servers
+    /// instead, or refused if none are configured. Clients are directed to the portal via option 114 (RFC 8910) only.
+    #[structopt(long = "no-dns-hijack")]
+    pub no_dns_hijack: bool,
+
+    /// Dns policies of single domains, eg "captive.apple.com=pass,example.com=hijack:60". A policy also applies to
+    /// subdomains. "pass" forwards queries to the upstream dns servers, "hijack" answers with the gateway address
+    /// and a short ttl. The connectivity check domains of common operating systems are hijacked with a short ttl
+    /// by default, unless dns hijacking is disabled.
+    #[structopt(
+        long = "dns-domain-policy",
+        use_delimiter = true,
+        parse(try_from_str = parse_domain_policy),
+        env = "DNS_DOMAIN_POLICY"
+    )]
+    pub dns_domain_policies: Vec<(String, DomainPolicy)>,
+
+    /// Host names that are answered with their own address, eg "api.portal=192.168.42.1", instead of the gateway
+    /// address. Only exact matches count. Mapped hosts are answered even if dns hijacking is disabled.
+    #[structopt(
+        long = "dns-host",
+        use_delimiter = true,
+        parse(try_from_str = parse_host_address),
+        env = "DNS_HOST"
+    )]
+    pub dns_hosts: Vec<(String, Ipv4Addr)>,
+
+    /// Hijacked dns queries are answered with this address instead of the gateway address, eg if the portal page
+    /// is served by another host.
+    #[structopt(long = "dns-answer-ip", env = "DNS_ANSWER_IP")]
+    pub dns_answer_ip: Option<Ipv4Addr>,
+
+    /// Request paths that are answered with "204 No Content" instead of being redirected to the portal page,
+    /// eg browser favicon requests. A trailing "*" matches any path with that prefix.
+    #[structopt(
+        long = "noise-paths",
+        use_delimiter = true,
+        default_value = DEFAULT_NOISE_PATHS,
+        env = "PORTAL_NOISE_PATHS"
+    )]
+    pub noise_paths: Vec<String>,
+
+    /// Token that guards the "/reset" http endpoint, which forgets all wifi connections.
+    /// Requests must send it as "Authorization: Bearer <token>" header. The endpoint is disabled if not set.
+    #[structopt(long = "reset-token", env = "PORTAL_RESET_TOKEN")]
+    pub reset_token: Option<String>,
+
+    /// Number of log lines that are kept in memory and served at the "/logs" http endpoint.
+    /// The endpoint is disabled if this is 0.
+    #[structopt(long = "log-buffer-size", default_value = "0", env = "LOG_BUFFER_SIZE")]
+    pub log_buffer_size: usize,
+
+    /// Token that guards the "/logs" http endpoint. Requests must send it as "Authorization: Bearer <token>" header.
+    /// The endpoint is disabled if not set, even if log lines are kept.
+    #[structopt(long = "logs-token", env = "PORTAL_LOGS_TOKEN")]
+    pub logs_token: Option<String>,
+
+    /// Directory of credential profiles, eg "corp.json". Connection requests may reference a profile by name
+    /// instead of sending the identity and passphrase. See [`FileCredentialStore`].
+    #[structopt(long = "credential-profiles", env = "CREDENTIAL_PROFILES", parse(from_os_str))]
+    pub credential_profiles: Option<PathBuf>,
+
+    /// Resolves credential profiles. Takes precedence over "credential_profiles" if set.
+    #[structopt(skip)]
+    pub credential_store: Option<Arc<dyn CredentialStore>>,
+
+    /// The log buffer that is installed at startup if "log_buffer_size" is not 0.
+    #[structopt(skip)]
+    pub log_buffer: Option<LogBuffer>,
+
+    /// The progress of connection attempts. The last progress is reported by the http server at "/status".
+    #[structopt(skip)]
+    pub connect_progress: ConnectProgressChannel,
+
+    /// Cancels the running connection attempt. The http server cancels it at "/cancel-connect".
+    #[structopt(skip)]
+    pub connect_cancel: ConnectCancel,
+
+    /// Receives a summary of each portal session when the portal is torn down
+    #[structopt(skip)]
+    pub portal_summaries: Option<tokio::sync::mpsc::UnboundedSender<PortalSessionSummary>>,
+
     /// Require internet connectivity to deem a connection successful. Usually it is sufficient if a connection to the local network can be established.
     #[structopt(long)]
     pub internet_connectivity: bool,
 
+    /// Number of connectivity checks that must fail in a row before a connection is deemed lost.
+    /// Higher values prevent short connectivity dips from reopening the portal.
+    #[structopt(long = "connectivity-failures", default_value = "1", env = "CONNECTIVITY_FAILURES")]
+    pub connectivity_failures: u32,
+
+    /// Time in seconds between two connectivity checks, if a check failed.
+    #[structopt(
+        long = "connectivity-check-delay",
+        default_value = "2",
+        env = "CONNECTIVITY_CHECK_DELAY"
+    )]
+    pub connectivity_check_delay: u64,
+
     /// The directory where the html files reside.
     #[structopt(parse(from_os_str), short, long, env = "UI_DIRECTORY")]
     #[cfg(all(not(feature = "includeui"), debug_assertions))]
@@ -95,18 +421,72 @@ impl Config {
     pub fn new() -> Config {
         Config {
             interface: None,
+            wait_for_device: 0,
+            wait_for_backend: 0,
+            bind_attempts: 10,
+            manage_device: false,
+            hotspot_uuid: None,
             ssid: "".to_string(),
             passphrase: "".to_string(),
             passphrase_file: None,
             identity: None,
             gateway: Ipv4Addr::new(0, 0, 0, 0),
+            subnet_prefix: 24,
+            strict_address_check: false,
+            hotspot_settle_delay: DEFAULT_HOTSPOT_SETTLE_DELAY.parse().expect("Valid settle delay"),
+            hotspot_timeout: 25,
+            hotspot_retry: false,
+            keep_hotspot_during_connect: false,
+            hotspot_band: WifiBand::Bg,
+            prefer_5ghz: false,
             listening_port: 0,
+            base_path: String::new(),
+            portal_host: None,
+            unix_socket: None,
             dns_port: 0,
             dhcp_port: 0,
             wait_before_reconfigure: 0,
             retry_in: 0,
+            connect_timeout: 30,
+            enterprise_connect_timeout: 90,
+            max_portal_secs: 0,
+            min_portal_secs: 0,
+            scan_interval: 0,
+            scan_budget: 15,
             quit_after_connected: false,
+            never_open_portal: false,
+            exit_stop_hotspot: true,
+            exit_enable_autoconnect: true,
+            exit_enable_wifi: false,
+            persist_connection: true,
             internet_connectivity: false,
+            connectivity_failures: 1,
+            connectivity_check_delay: 2,
+            min_strength: 0,
+            ap_ttl: 0,
+            connectivity_check_uri: None,
+            upstream_dns: Vec::new(),
+            captive_portal_uri: None,
+            announce_captive_portal_api: false,
+            dhcp_lease_grace: DEFAULT_LEASE_GRACE_SECS,
+            dhcp_vendor_options: Vec::new(),
+            dhcp_vendor_enterprise_number: None,
+            dhcp_dns_servers: Vec::new(),
+            dns_txt_records: Vec::new(),
+            no_dns_hijack: false,
+            dns_domain_policies: Vec::new(),
+            dns_hosts: Vec::new(),
+            dns_answer_ip: None,
+            noise_paths: DEFAULT_NOISE_PATHS.split(',').map(str::to_owned).collect(),
+            reset_token: None,
+            log_buffer_size: 0,
+            logs_token: None,
+            log_buffer: None,
+            connect_progress: ConnectProgressChannel::default(),
+            connect_cancel: ConnectCancel::default(),
+            portal_summaries: None,
+            credential_profiles: None,
+            credential_store: None,
             #[cfg(all(not(feature = "includeui"), debug_assertions))]
             ui_directory: None,
         }
@@ -120,4 +500,13 @@ impl Config {
     pub fn get_ui_directory(&self) -> PathBuf {
         PathBuf::new()
     }
+
+    /// The store that resolves credential profiles of connection requests, if any
+    pub fn credential_store(&self) -> Option<Arc<dyn CredentialStore>> {
+        match (&self.credential_store, &self.credential_profiles) {
+            (Some(store), _) => Some(store.clone()),
+            (None, Some(directory)) => Some(Arc::new(FileCredentialStore::new(directory.clone()))),
+            (None, None) => None,
+        }
+    }
 }
@@ -1,8 +1,12 @@
 //! An async dhcp server implementation for a given gateway address. This is a very
-//! rudimentary implementation (no timeouts or lease refreshes), with a fixed /24 subnet.
+//! rudimentary implementation (no timeouts or lease refreshes). The subnet is given by a prefix length
+//! (usually /24) and addresses are leased from the range after the gateway address.
 //! Client request IP addresses are considered.
+//! If a captive portal uri is set, it is announced via option 114 (RFC 8910) to clients that request it.
+//! Configured vendor specific information is announced via option 43 and 125 (RFC 3925) likewise.
 pub mod options;
 pub mod packet;
+pub mod vendor;
 
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 
@@ -29,19 +33,68 @@ macro_rules! bytes_u32 {
 }
 
 // Server configuration constants
-const SUBNET_MASK: [u8; 4] = [255, 255, 255, 0];
 const LEASE_DURATION_SECS: u32 = 7200;
-const LEASE_NUM: u8 = 100;
+const LEASE_NUM: u32 = 100;
+/// An expired lease is reserved for its client for this time, see [`DHCPServer::lease_grace`]
+pub const DEFAULT_LEASE_GRACE_SECS: u64 = 3600;
+/// Relay agents listen on the dhcp server port (RFC 2131, 4.1)
+const DHCP_SERVER_PORT: u16 = 67;
 const LEASE_DURATION_BYTES: [u8; 4] = u32_bytes!(LEASE_DURATION_SECS);
 
+/// Computes the subnet mask octets for the given prefix length, eg 24 -> 255.255.255.0.
+/// Prefix lengths above 32 are treated as 32.
+pub fn subnet_mask(prefix: u8) -> [u8; 4] {
+    let mask: u32 = match prefix {
+        0 => 0,
+        p if p >= 32 => !0,
+        p => !0u32 << (32 - p as u32),
+    };
+    u32_bytes!(mask)
+}
+
+/// An address has been leased to a client. See [`DHCPServer::lease_events`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseEvent {
+    /// The client hardware address
+    pub chaddr: [u8; 6],
+    pub ip: Ipv4Addr,
+    /// The user classes (option 77) of the client, eg to tell managed devices apart
+    pub user_classes: Vec<String>,
+}
+
+impl LeaseEvent {
+    fn new(in_packet: &Packet, ip: [u8; 4]) -> Self {
+        LeaseEvent {
+            chaddr: in_packet.chaddr,
+            ip: Ipv4Addr::from(ip),
+            user_classes: in_packet.user_classes(),
+        }
+    }
+}
+
 pub struct DHCPServer {
     leases: HashMap<u32, ([u8; 6], Instant)>,
-    last_lease: u8,
+    last_lease: u32,
+    /// Number of addresses that are considered for leasing. At most LEASE_NUM.
+    lease_num: u32,
     lease_duration: Duration,
+    /// An expired lease is re-offered to its client and not handed out to other clients within this time.
+    /// Sleeping devices keep their address this way. Longer expired leases are treated like released ones.
+    pub lease_grace: Duration,
     exit_receiver: tokio::sync::oneshot::Receiver<()>,
     server_addr: SocketAddrV4,
     server_ip_octets: [u8; 4],
-    dns_ips: [u8; 8],
+    subnet_mask: [u8; 4],
+    /// Encoded data of option 6. See [`DHCPServer::set_dns_servers`].
+    dns_ips: Vec<u8>,
+    /// The captive portal uri (RFC 8910) that is handed out as option 114, eg "http://192.168.42.1/index.html".
+    /// Must not exceed 255 bytes.
+    pub captive_portal_uri: Option<String>,
+    /// Encoded data of option 43 and 125. See [`DHCPServer::set_vendor_options`].
+    vendor_specific: Option<Vec<u8>>,
+    vendor_identifying: Option<Vec<u8>>,
+    /// Receives an event for each acknowledged lease, if set
+    pub lease_events: Option<tokio::sync::mpsc::UnboundedSender<LeaseEvent>>,
     pub only_once: bool,
 }
 
@@ -52,32 +105,73 @@ struct Sender {
 }
 
 impl DHCPServer {
-    /// The default port is 67
-    pub fn new(server_addr: SocketAddrV4) -> (Self, tokio::sync::oneshot::Sender<()>) {
-        // Construct the dns dhcp option. Requires two dns addresses (2*IPv4 ala 4 octets).
-        // We have only one dns (the router IP itself), so copying that two times is sufficient
-        let mut dns_ips: [u8; 8] = [0; 8];
+    /// The default port is 67. The subnet prefix length determines the subnet mask and the lease pool.
+    pub fn new(server_addr: SocketAddrV4, subnet_prefix: u8) -> (Self, tokio::sync::oneshot::Sender<()>) {
         let octets = &server_addr.ip().octets();
-        dns_ips[0..4].copy_from_slice(octets);
-        dns_ips[4..8].copy_from_slice(octets);
 
         let (exit_handler, exit_receiver) = tokio::sync::oneshot::channel::<()>();
 
+        // Addresses between the server ip and the subnet broadcast address can be leased
+        let subnet_mask = subnet_mask(subnet_prefix);
+        let server_ip = bytes_u32!(octets);
+        let broadcast = server_ip | !bytes_u32!(subnet_mask);
+        let lease_num = LEASE_NUM.min(broadcast.saturating_sub(server_ip).saturating_sub(1));
+
         (
             DHCPServer {
                 server_addr,
                 server_ip_octets: server_addr.ip().octets(),
+                subnet_mask,
                 exit_receiver,
                 leases: HashMap::new(),
                 last_lease: 0,
+                lease_num,
                 lease_duration: Duration::new(LEASE_DURATION_SECS as u64, 0),
-                dns_ips,
+                lease_grace: Duration::from_secs(DEFAULT_LEASE_GRACE_SECS),
+                dns_ips: default_dns_ips(octets),
+                captive_portal_uri: None,
+                vendor_specific: None,
+                vendor_identifying: None,
+                lease_events: None,
                 only_once: false,
             },
             exit_handler,
         )
     }
 
+    /// Hands out the given vendor sub-options as option 43 to clients that request it.
+    /// If an enterprise number is given, they are also handed out as option 125 (RFC 3925).
+    /// Fails if the encoded sub-options exceed the size of a dhcp option.
+    pub fn set_vendor_options(
+        &mut self,
+        sub_options: &[(u8, Vec<u8>)],
+        enterprise_number: Option<u32>,
+    ) -> Result<(), super::CaptivePortalError> {
+        if sub_options.is_empty() {
+            self.vendor_specific = None;
+            self.vendor_identifying = None;
+            return Ok(());
+        }
+        self.vendor_specific = Some(vendor::encode_vendor_specific(sub_options)?);
+        self.vendor_identifying = match enterprise_number {
+            Some(enterprise_number) => Some(vendor::encode_vendor_identifying(enterprise_number, sub_options)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Hands out the given dns servers as option 6, in the given order.
+    /// Without dns servers the server address itself is handed out, which is the default.
+    /// Fails if the addresses exceed the size of a dhcp option.
+    pub fn set_dns_servers(&mut self, dns_servers: &[Ipv4Addr]) -> Result<(), super::CaptivePortalError> {
+        self.dns_ips = if dns_servers.is_empty() {
+            default_dns_ips(&self.server_ip_octets)
+        } else {
+            encode_dns_servers(dns_servers)?
+        };
+        Ok(())
+    }
+
     pub async fn run(&mut self) -> Result<(), super::CaptivePortalError> {
         let socket = self.bind().await?;
         Ok(self.receive_loop(socket).await?)
@@ -148,20 +242,20 @@ impl DHCPServer {
         }
     }
 
-    // DHCP lease address range is server_ip[3]+1..255
+    // DHCP lease address range is server_ip+1..broadcast address of the subnet
     fn available(&self, chaddr: &[u8; 6], ip: &[u8; 4]) -> bool {
-        // The last ip octet is a wrapped number 0..LEASE_NUM (we are only on subset 255.255.255.0)
-        let pos = ip[3];
-        let in_range = pos > self.server_ip_octets[3] && pos < 255;
+        let ip_u32: u32 = bytes_u32!(ip);
+        let server_ip: u32 = bytes_u32!(self.server_ip_octets);
+        let mask: u32 = bytes_u32!(self.subnet_mask);
+        let in_range = ip_u32 & mask == server_ip & mask && ip_u32 > server_ip && ip_u32 < server_ip | !mask;
         if !in_range {
             return false;
         }
 
-        let ip_u32: u32 = bytes_u32!(ip);
-
-        // Check if in lease table and if address has been taken by another client
+        // Check if in lease table and if address has been taken by another client.
+        // An expired lease is still reserved for its client within the grace period.
         if let Some(x) = self.leases.get(&ip_u32) {
-            if x.0 != *chaddr && !Instant::now().gt(&x.1) {
+            if x.0 != *chaddr && !Instant::now().gt(&x.1.add(self.lease_grace)) {
                 return false;
             }
         }
@@ -169,28 +263,28 @@ impl DHCPServer {
         return true;
     }
 
+    /// Returns the lease of the given client, if it has not been expired for longer than the grace period
     fn current_lease(&self, chaddr: &[u8; 6]) -> Option<u32> {
+        let now = Instant::now();
         for (i, v) in self.leases.iter() {
-            if &v.0 == chaddr {
+            if &v.0 == chaddr && !now.gt(&v.1.add(self.lease_grace)) {
                 return Some(*i);
             }
         }
         return None;
     }
 
-    async fn handle_discover(
-        &mut self,
-        in_packet: packet::Packet<'_>,
-        sender: &mut Sender,
-        socket: &mut tokio::net::UdpSocket,
-    ) -> Result<usize, std::io::Error> {
+    /// Chooses the address to offer to the given client. The address requested by the client is preferred,
+    /// then the current lease of the client. The expiry of a current lease is refreshed.
+    /// Otherwise a free address is chosen. Returns None if the lease pool is exhausted.
+    fn offer_ip(&mut self, chaddr: &[u8; 6], requested_ip: Option<&[u8]>) -> Option<[u8; 4]> {
         // Prefer client's choice if available
-        let ip = in_packet.option(options::REQUESTED_IP_ADDRESS).and_then(|r| {
+        let ip = requested_ip.and_then(|r| {
             if r.len() == 4 {
                 let mut client_preferred_ip: [u8; 4] = Default::default();
                 client_preferred_ip.copy_from_slice(&r[0..4]);
 
-                if self.available(&in_packet.chaddr, &client_preferred_ip) {
+                if self.available(chaddr, &client_preferred_ip) {
                     Some(client_preferred_ip)
                 } else {
                     None
@@ -200,35 +294,57 @@ impl DHCPServer {
             }
         });
 
-        // Otherwise prefer existing (including expired if available)
+        // Otherwise prefer existing (including expired within the grace period)
         let ip = ip.or_else(|| {
-            self.current_lease(&in_packet.chaddr)
-                .and_then(|ip| Some(u32_bytes!(ip)))
+            let ip = self.current_lease(chaddr)?;
+            self.leases
+                .insert(ip, (*chaddr, Instant::now().add(self.lease_duration)));
+            Some(u32_bytes!(ip))
         });
 
         // Otherwise choose free ip if available
-        let ip = ip.or_else(|| {
-            let mut result = None;
-            for _ in 0..LEASE_NUM {
-                let mut ip_offer = self.server_ip_octets.clone();
+        ip.or_else(|| {
+            let server_ip: u32 = bytes_u32!(self.server_ip_octets);
+            for _ in 0..self.lease_num {
                 // Start with one number higher than server ip + lease offset
-                self.last_lease = (self.last_lease + 1) % LEASE_NUM;
-                ip_offer[3] = ip_offer[3] + self.last_lease;
+                self.last_lease = self.last_lease % self.lease_num + 1;
+                let ip_offer = u32_bytes!(server_ip + self.last_lease);
 
-                if self.available(&in_packet.chaddr, &ip_offer) {
-                    result = Some(ip_offer);
-                    break;
+                if self.available(chaddr, &ip_offer) {
+                    return Some(ip_offer);
                 }
             }
-            result
-        });
+            None
+        })
+    }
+
+    /// The lease options of an offer or acknowledgement for the given parameter request list
+    fn lease_options<'a>(&'a self, request_options: &[u8]) -> Vec<DhcpOption<'a>> {
+        lease_options(
+            &self.server_ip_octets,
+            &self.subnet_mask,
+            &self.dns_ips,
+            self.captive_portal_uri.as_ref().map(|uri| uri.as_bytes()),
+            self.vendor_specific.as_deref(),
+            self.vendor_identifying.as_deref(),
+            request_options,
+        )
+    }
+
+    async fn handle_discover(
+        &mut self,
+        in_packet: packet::Packet<'_>,
+        sender: &mut Sender,
+        socket: &mut tokio::net::UdpSocket,
+    ) -> Result<usize, std::io::Error> {
+        let ip = self.offer_ip(&in_packet.chaddr, in_packet.option(options::REQUESTED_IP_ADDRESS));
 
         // Return reply if ip could be found
         if let Some(ip) = ip {
             let request_options = in_packet.option(options::PARAMETER_REQUEST_LIST).unwrap_or(&[]);
             return reply(
                 options::MessageType::Offer,
-                lease_options(&self.server_ip_octets, &self.dns_ips, request_options),
+                self.lease_options(request_options),
                 in_packet,
                 ip,
                 sender,
@@ -277,10 +393,14 @@ impl DHCPServer {
                 (in_packet.chaddr, Instant::now().add(self.lease_duration)),
             );
         }
+        if let Some(lease_events) = self.lease_events.as_ref() {
+            // The receiver might be gone already. Leasing continues regardless.
+            let _ = lease_events.send(LeaseEvent::new(&in_packet, req_ip));
+        }
         let request_options = in_packet.option(options::PARAMETER_REQUEST_LIST).unwrap_or(&[]);
         reply(
             options::MessageType::Ack,
-            lease_options(&self.server_ip_octets, &self.dns_ips, request_options),
+            self.lease_options(request_options),
             in_packet,
             req_ip,
             sender,
@@ -300,30 +420,64 @@ impl DHCPServer {
     }
 }
 
-fn lease_options<'a>(router_ip: &'a [u8; 4], dns_ips: &'a [u8; 8], options: &[u8]) -> Vec<DhcpOption<'a>> {
-    let mut vec = Vec::new();
+/// Encodes the dns servers contiguously as data of option 6 (4 octets per address).
+fn encode_dns_servers(dns_servers: &[Ipv4Addr]) -> Result<Vec<u8>, super::CaptivePortalError> {
+    if dns_servers.len() * 4 > 255 {
+        return Err(super::CaptivePortalError::DhcpError("Dns servers exceed 255 bytes"));
+    }
+    Ok(dns_servers.iter().flat_map(|ip| ip.octets().to_vec()).collect())
+}
+
+/// Clients usually expect two dns addresses. We have only one dns (the router IP itself),
+/// so copying that two times is sufficient.
+fn default_dns_ips(server_ip: &[u8; 4]) -> Vec<u8> {
+    let mut dns_ips = Vec::with_capacity(8);
+    dns_ips.extend_from_slice(server_ip);
+    dns_ips.extend_from_slice(server_ip);
+    dns_ips
+}
+
+/// Returns the lease options in the order of the clients parameter request list (option 55).
+/// Unsupported options are skipped. The lease time is mandatory and always the first option.
+fn lease_options<'a>(
+    router_ip: &'a [u8; 4],
+    subnet_mask: &'a [u8; 4],
+    dns_ips: &'a [u8],
+    captive_portal_uri: Option<&'a [u8]>,
+    vendor_specific: Option<&'a [u8]>,
+    vendor_identifying: Option<&'a [u8]>,
+    options: &[u8],
+) -> Vec<DhcpOption<'a>> {
+    let mut vec = Vec::with_capacity(options.len() + 1);
 
     vec.push(options::DhcpOption {
         code: options::IP_ADDRESS_LEASE_TIME,
         data: &LEASE_DURATION_BYTES,
     });
-    if options.contains(&options::SUBNET_MASK) {
-        vec.push(options::DhcpOption {
-            code: options::SUBNET_MASK,
-            data: &SUBNET_MASK,
-        });
-    }
-    if options.contains(&options::ROUTER) {
-        vec.push(options::DhcpOption {
-            code: options::ROUTER,
-            data: router_ip,
-        });
-    }
-    if options.contains(&options::DOMAIN_NAME_SERVER) {
-        vec.push(options::DhcpOption {
-            code: options::DOMAIN_NAME_SERVER,
-            data: dns_ips,
-        });
+    for code in options {
+        let data: &'a [u8] = match *code {
+            options::SUBNET_MASK => subnet_mask,
+            options::ROUTER => router_ip,
+            options::DOMAIN_NAME_SERVER => dns_ips,
+            options::CAPTIVE_PORTAL => match captive_portal_uri {
+                Some(uri) => uri,
+                None => continue,
+            },
+            options::VENDOR_SPECIFIC_INFORMATION => match vendor_specific {
+                Some(data) => data,
+                None => continue,
+            },
+            options::VENDOR_IDENTIFYING_VENDOR_SPECIFIC_INFORMATION => match vendor_identifying {
+                Some(data) => data,
+                None => continue,
+            },
+            _ => continue,
+        };
+        // Clients may request an option more than once
+        if vec.iter().any(|o| o.code == *code) {
+            continue;
+        }
+        vec.push(options::DhcpOption { code: *code, data });
     }
     vec
 }
@@ -354,6 +508,15 @@ async fn reply(
         _ => req_packet.ciaddr,
     };
 
+    // The flags field is copied from the request. A NAK that is forwarded by a relay agent must be
+    // broadcast by the relay, because the client can no longer use its address (RFC 2131 4.1).
+    let broadcast = match msg_type {
+        MessageType::Nak => req_packet.broadcast || req_packet.giaddr != [0, 0, 0, 0],
+        _ => req_packet.broadcast,
+    };
+
+    let addr = reply_destination(sender.src, &msg_type, req_packet.ciaddr, broadcast, req_packet.giaddr);
+
     let mt = &[msg_type as u8];
 
     let mut opts: Vec<DhcpOption> = Vec::with_capacity(additional_options.len() + 2);
@@ -373,7 +536,7 @@ async fn reply(
         hops: 0,
         xid: req_packet.xid,
         secs: 0,
-        broadcast: req_packet.broadcast,
+        broadcast,
         ciaddr,
         yiaddr: offer_ip,
         siaddr: [0, 0, 0, 0],
@@ -381,25 +544,58 @@ async fn reply(
         chaddr: req_packet.chaddr,
         options: opts,
     };
-    let mut addr = sender.src;
-    if p.broadcast || addr.ip() == IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)) {
-        addr.set_ip(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255)));
-    }
     socket.send_to(p.encode(sender.out_buf.as_mut()), &addr).await
 }
 
+/// Determines where a reply is sent to, following RFC 2131 section 4.1:
+///
+/// * If the request was forwarded by a relay agent (giaddr is set), the reply is unicast to the
+///   relay agent on the server port.
+/// * A NAK is always broadcast, because the client must not use its address anymore.
+/// * A client that already has an address (ciaddr is set, e.g. when renewing) gets a unicast to ciaddr.
+/// * A client that set the broadcast flag gets a broadcast.
+/// * Otherwise the reply is unicast to the source address of the request. The RFC wants a unicast
+///   to yiaddr and chaddr here, which would require injecting an ARP entry. A client without
+///   an address (source 0.0.0.0) is therefore answered with a broadcast instead.
+///
+/// The client port is always taken from the request.
+fn reply_destination(
+    src: SocketAddr,
+    msg_type: &MessageType,
+    ciaddr: [u8; 4],
+    broadcast: bool,
+    giaddr: [u8; 4],
+) -> SocketAddr {
+    if giaddr != [0, 0, 0, 0] {
+        return SocketAddr::new(IpAddr::V4(Ipv4Addr::from(giaddr)), DHCP_SERVER_PORT);
+    }
+    let broadcast_addr = IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255));
+    let mut addr = src;
+    if let MessageType::Nak = msg_type {
+        addr.set_ip(broadcast_addr);
+    } else if ciaddr != [0, 0, 0, 0] {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::from(ciaddr)));
+    } else if broadcast || addr.ip() == IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)) {
+        addr.set_ip(broadcast_addr);
+    }
+    addr
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::CaptivePortalError;
-    use super::{options::*, packet::decode, DHCPServer, DhcpOption, Packet};
+    use super::{
+        lease_options, options::*, packet::decode, reply_destination, subnet_mask, DHCPServer, DhcpOption, LeaseEvent,
+        Packet,
+    };
     use futures_util::future::select;
     use futures_util::future::Either;
     use futures_util::future::try_join;
     use pin_utils::pin_mut;
     use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-    use std::time::Duration;
-    use tokio::time::delay_for;
+    use std::time::{Duration, Instant};
     use tokio::net::UdpSocket;
+    use tokio::time::delay_for;
 
     fn new_dhcp_discover(request_ip: [u8; 4]) -> Vec<u8> {
         let mut vec = Vec::with_capacity(1000);
@@ -513,7 +709,7 @@ mod tests {
 
     async fn test_domain_async() {
         let socket_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0);
-        let (mut dhcp_server, exit_handler) = DHCPServer::new(socket_addr);
+        let (mut dhcp_server, exit_handler) = DHCPServer::new(socket_addr, 24);
         dhcp_server.only_once = true;
 
         let socket = dhcp_server.bind().await.expect("Socket bind");
@@ -524,7 +720,7 @@ mod tests {
 
         let server = dhcp_server.receive_loop(socket);
         let query = async move {
-            let request_ip: [u8; 4] = [192, 168, 0, 10];
+            let request_ip: [u8; 4] = [127, 0, 0, 10];
             let mut res_buffer: [u8; 300] = [0; 300];
             let r = query(&mut res_buffer, request_ip, socket_addr).await?;
             assert_eq!(&r.yiaddr, &request_ip);
@@ -550,4 +746,282 @@ mod tests {
             _ => {},
         };
     }
+
+    #[test]
+    fn test_lease_options_order() {
+        let router_ip = [192, 168, 42, 1];
+        let mask = subnet_mask(24);
+        let dns_ips = [192, 168, 42, 1, 192, 168, 42, 1];
+        // Domain name server, unsupported domain name, router, subnet mask and a duplicated router
+        let request_list = [DOMAIN_NAME_SERVER, DOMAIN_NAME, ROUTER, SUBNET_MASK, ROUTER];
+        let options = lease_options(&router_ip, &mask, &dns_ips, None, None, None, &request_list);
+        let codes: Vec<u8> = options.iter().map(|o| o.code).collect();
+        assert_eq!(
+            codes,
+            vec![IP_ADDRESS_LEASE_TIME, DOMAIN_NAME_SERVER, ROUTER, SUBNET_MASK]
+        );
+        assert_eq!(options[3].data, &[255, 255, 255, 0]);
+    }
+
+    #[test]
+    fn test_captive_portal_option() {
+        let router_ip = [192, 168, 42, 1];
+        let mask = subnet_mask(24);
+        let dns_ips = [192, 168, 42, 1, 192, 168, 42, 1];
+        let uri = b"http://192.168.42.1/index.html";
+        let request_list = [SUBNET_MASK, CAPTIVE_PORTAL];
+
+        let options = lease_options(&router_ip, &mask, &dns_ips, Some(uri), None, None, &request_list);
+        let option = options
+            .iter()
+            .find(|o| o.code == CAPTIVE_PORTAL)
+            .expect("captive portal option");
+        assert_eq!(option.data, uri);
+
+        // Not configured
+        let options = lease_options(&router_ip, &mask, &dns_ips, None, None, None, &request_list);
+        assert!(options.iter().all(|o| o.code != CAPTIVE_PORTAL));
+
+        // Only announced if set, the captive portal api of the portal is opt-in
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 67);
+        let (mut server, _exit) = DHCPServer::new(server_addr, 24);
+        assert!(server
+            .lease_options(&request_list)
+            .iter()
+            .all(|o| o.code != CAPTIVE_PORTAL));
+        let api_uri = crate::http_server::captive_portal_api_uri(&SocketAddrV4::new(router_ip.into(), 80), None, "");
+        server.captive_portal_uri = Some(api_uri.clone());
+        let options = server.lease_options(&request_list);
+        let option = options
+            .iter()
+            .find(|o| o.code == CAPTIVE_PORTAL)
+            .expect("captive portal option");
+        assert_eq!(option.data, api_uri.as_bytes());
+    }
+
+    #[test]
+    fn test_dns_servers() {
+        let (mut server, _exit) = DHCPServer::new(SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 67), 24);
+        // Defaults to the server address, given twice
+        assert_eq!(server.dns_ips, vec![192, 168, 42, 1, 192, 168, 42, 1]);
+
+        let dns_servers = [Ipv4Addr::new(192, 168, 42, 1), Ipv4Addr::new(9, 9, 9, 9)];
+        server.set_dns_servers(&dns_servers).unwrap();
+        let options = lease_options(
+            &server.server_ip_octets,
+            &server.subnet_mask,
+            &server.dns_ips,
+            None,
+            None,
+            None,
+            &[DOMAIN_NAME_SERVER],
+        );
+        let option = options.iter().find(|o| o.code == DOMAIN_NAME_SERVER).expect("option 6");
+        assert_eq!(option.data, &[192, 168, 42, 1, 9, 9, 9, 9]);
+
+        // Back to the default
+        server.set_dns_servers(&[]).unwrap();
+        assert_eq!(server.dns_ips, vec![192, 168, 42, 1, 192, 168, 42, 1]);
+
+        // Exceeds a single option
+        assert!(server.set_dns_servers(&vec![Ipv4Addr::new(9, 9, 9, 9); 64]).is_err());
+    }
+
+    #[test]
+    fn test_vendor_options() {
+        let (mut server, _exit) = DHCPServer::new(SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 67), 24);
+        let sub_options = vec![(1, b"http://acs".to_vec()), (2, vec![0x0a, 0x0b])];
+        server.set_vendor_options(&sub_options, Some(3561)).unwrap();
+        let request_list = [
+            VENDOR_SPECIFIC_INFORMATION,
+            VENDOR_IDENTIFYING_VENDOR_SPECIFIC_INFORMATION,
+        ];
+        let options = lease_options(
+            &server.server_ip_octets,
+            &server.subnet_mask,
+            &server.dns_ips,
+            None,
+            server.vendor_specific.as_deref(),
+            server.vendor_identifying.as_deref(),
+            &request_list,
+        );
+        let option = options
+            .iter()
+            .find(|o| o.code == VENDOR_SPECIFIC_INFORMATION)
+            .expect("option 43");
+        assert_eq!(&option.data[..2], &[1, 10]);
+        assert_eq!(&option.data[12..], &[2, 2, 10, 11]);
+        let option = options
+            .iter()
+            .find(|o| o.code == VENDOR_IDENTIFYING_VENDOR_SPECIFIC_INFORMATION)
+            .expect("option 125");
+        assert_eq!(&option.data[..5], &[0, 0, 0x0d, 0xe9, 16]);
+
+        // Without enterprise number only option 43 is handed out
+        server.set_vendor_options(&sub_options, None).unwrap();
+        assert!(server.vendor_specific.is_some());
+        assert!(server.vendor_identifying.is_none());
+
+        // Exceeds a single option
+        assert!(server.set_vendor_options(&[(1, vec![0; 300])], None).is_err());
+    }
+
+    #[test]
+    fn test_subnet_mask() {
+        assert_eq!(subnet_mask(24), [255, 255, 255, 0]);
+        assert_eq!(subnet_mask(16), [255, 255, 0, 0]);
+        assert_eq!(subnet_mask(20), [255, 255, 240, 0]);
+        assert_eq!(subnet_mask(30), [255, 255, 255, 252]);
+        assert_eq!(subnet_mask(8), [255, 0, 0, 0]);
+        assert_eq!(subnet_mask(0), [0, 0, 0, 0]);
+        assert_eq!(subnet_mask(32), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn test_lease_pool() {
+        // A /30 subnet only leaves room for a single client after the gateway
+        let (dhcp_server, _) = DHCPServer::new(SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 0), 30);
+        assert_eq!(dhcp_server.lease_num, 1);
+        assert!(dhcp_server.available(&[0; 6], &[192, 168, 42, 2]));
+        assert!(!dhcp_server.available(&[0; 6], &[192, 168, 42, 3]));
+
+        // Addresses beyond the last octet are leasable for a /16 subnet, but not outside the subnet
+        let (dhcp_server, _) = DHCPServer::new(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 0), 16);
+        assert_eq!(dhcp_server.lease_num, 100);
+        assert!(dhcp_server.available(&[0; 6], &[10, 0, 3, 7]));
+        assert!(!dhcp_server.available(&[0; 6], &[10, 0, 255, 255]));
+        assert!(!dhcp_server.available(&[0; 6], &[10, 1, 0, 2]));
+        assert!(!dhcp_server.available(&[0; 6], &[10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_lease_grace() {
+        let (mut dhcp_server, _) = DHCPServer::new(SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 67), 24);
+        dhcp_server.lease_grace = Duration::from_secs(600);
+        let sleeper = [1, 2, 3, 4, 5, 6];
+        let other = [6, 5, 4, 3, 2, 1];
+        let ip = u32::from(Ipv4Addr::new(192, 168, 42, 7));
+
+        // Expired a minute ago, within the grace period
+        let expired = Instant::now() - Duration::from_secs(60);
+        dhcp_server.leases.insert(ip, (sleeper, expired));
+        assert!(!dhcp_server.available(&other, &[192, 168, 42, 7]));
+        assert_ne!(
+            dhcp_server.offer_ip(&other, Some(&[192, 168, 42, 7])),
+            Some([192, 168, 42, 7])
+        );
+        assert_eq!(dhcp_server.offer_ip(&sleeper, None), Some([192, 168, 42, 7]));
+        // The expiry has been refreshed
+        assert!(dhcp_server.leases[&ip].1 > Instant::now());
+
+        // Expired longer than the grace period: Treated like a new client
+        let expired = Instant::now() - Duration::from_secs(900);
+        dhcp_server.leases.insert(ip, (sleeper, expired));
+        assert!(dhcp_server.available(&other, &[192, 168, 42, 7]));
+        assert_eq!(dhcp_server.current_lease(&sleeper), None);
+        assert_eq!(
+            dhcp_server.offer_ip(&other, Some(&[192, 168, 42, 7])),
+            Some([192, 168, 42, 7])
+        );
+    }
+
+    #[test]
+    fn test_user_class() {
+        let request = new_dhcp_request([192, 168, 42, 2], [192, 168, 42, 1]);
+        let mut packet = decode(&request).expect("Decode request");
+        assert!(LeaseEvent::new(&packet, [192, 168, 42, 2]).user_classes.is_empty());
+
+        // RFC 3004 encoding: Two classes with a length prefix each
+        let user_class = b"\x05kiosk\x07managed";
+        packet.options.push(DhcpOption {
+            code: USER_CLASS,
+            data: user_class,
+        });
+        let mut buffer = [0u8; 1000];
+        let request = packet.encode(&mut buffer).to_vec();
+        let packet = decode(&request).expect("Decode request with user class");
+        let event = LeaseEvent::new(&packet, [192, 168, 42, 2]);
+        assert_eq!(event.user_classes, vec!["kiosk", "managed"]);
+        assert_eq!(event.ip, Ipv4Addr::new(192, 168, 42, 2));
+
+        // Plain string
+        let packet = Packet {
+            options: vec![DhcpOption {
+                code: USER_CLASS,
+                data: b"MSFT 5.0",
+            }],
+            ..decode(&request).expect("Decode request")
+        };
+        assert_eq!(packet.user_classes(), vec!["MSFT 5.0"]);
+    }
+
+    #[test]
+    fn test_reply_destination() {
+        let client: SocketAddr = "0.0.0.0:68".parse().unwrap();
+        let relay: SocketAddr = "10.0.0.1:67".parse().unwrap();
+        let none = [0, 0, 0, 0];
+        // Requests of a relay agent are answered to the relay agent
+        assert_eq!(
+            reply_destination(relay, &MessageType::Offer, none, true, [10, 0, 0, 1]),
+            relay
+        );
+        assert_eq!(
+            reply_destination(client, &MessageType::Offer, none, false, [10, 0, 0, 2]),
+            "10.0.0.2:67".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            reply_destination(relay, &MessageType::Nak, none, true, [10, 0, 0, 1]),
+            relay
+        );
+        // Directly attached clients without an address get a broadcast
+        assert_eq!(
+            reply_destination(client, &MessageType::Offer, none, false, none),
+            "255.255.255.255:68".parse::<SocketAddr>().unwrap()
+        );
+        let configured: SocketAddr = "192.168.42.2:68".parse().unwrap();
+        assert_eq!(
+            reply_destination(configured, &MessageType::Ack, none, false, none),
+            configured
+        );
+        // A NAK is broadcast, even if the client has an address
+        assert_eq!(
+            reply_destination(configured, &MessageType::Nak, [192, 168, 42, 2], false, none),
+            "255.255.255.255:68".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reply_destination_broadcast_flag() {
+        // A discover with the broadcast flag, e.g. sent by a client that cannot receive unicasts
+        // before it is configured. Also if the source address is already set.
+        let mut discover = new_dhcp_discover([0, 0, 0, 0]);
+        discover[10] = 0x80;
+        let packet = decode(&discover).unwrap();
+        assert!(packet.broadcast);
+        let src: SocketAddr = "192.168.42.7:68".parse().unwrap();
+        assert_eq!(
+            reply_destination(src, &MessageType::Offer, packet.ciaddr, packet.broadcast, packet.giaddr),
+            "255.255.255.255:68".parse::<SocketAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_reply_destination_renewing() {
+        // A renewing client fills in ciaddr and is answered by unicast, even if the request
+        // was broadcast (rebinding) or has the broadcast flag set
+        let mut request = new_dhcp_request([0, 0, 0, 0], [192, 168, 42, 1]);
+        request[12..16].copy_from_slice(&[192, 168, 42, 5]);
+        let packet = decode(&request).unwrap();
+        assert_eq!(packet.ciaddr, [192, 168, 42, 5]);
+        let src: SocketAddr = "0.0.0.0:68".parse().unwrap();
+        let renewing: SocketAddr = "192.168.42.5:68".parse().unwrap();
+        assert_eq!(
+            reply_destination(src, &MessageType::Ack, packet.ciaddr, packet.broadcast, packet.giaddr),
+            renewing
+        );
+        assert_eq!(
+            reply_destination(src, &MessageType::Ack, packet.ciaddr, true, packet.giaddr),
+            renewing
+        );
+    }
 }
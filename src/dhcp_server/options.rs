@@ -101,8 +101,12 @@ pub const CLIENT_ARCHITECTURE: u8 = 93;
 pub const TZ_POSIX_STRING: u8 = 100;
 pub const TZ_DATABASE_STRING: u8 = 101;
 
+pub const CAPTIVE_PORTAL: u8 = 114;
+
 pub const CLASSLESS_ROUTE_FORMAT: u8 = 121;
 
+pub const VENDOR_IDENTIFYING_VENDOR_SPECIFIC_INFORMATION: u8 = 125;
+
 use crate::CaptivePortalError;
 use core::fmt::Debug;
 use std::fmt::Formatter;
@@ -218,7 +222,9 @@ pub fn title(code: u8) -> Option<&'static str> {
 
         TZ_POSIX_STRING => "TZ-POSIX String",
         TZ_DATABASE_STRING => "TZ-Database String",
+        CAPTIVE_PORTAL => "Captive-Portal",
         CLASSLESS_ROUTE_FORMAT => "Classless Route Format",
+        VENDOR_IDENTIFYING_VENDOR_SPECIFIC_INFORMATION => "Vendor-Identifying Vendor-Specific Information",
 
         _ => return None,
     })
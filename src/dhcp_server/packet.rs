@@ -90,6 +90,27 @@ impl<'a> Packet<'a> {
         None
     }
 
+    /// Extracts the user classes (option 77) of a client. RFC 3004 encodes each class with a length prefix.
+    /// Some clients send a plain string instead, which is returned as single class.
+    pub fn user_classes(&self) -> Vec<String> {
+        let data = match self.option(USER_CLASS) {
+            Some(data) if !data.is_empty() => data,
+            _ => return Vec::new(),
+        };
+        let mut classes = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let end = i + 1 + data[i] as usize;
+            if data[i] == 0 || end > data.len() {
+                // Not RFC 3004 encoded
+                return vec![String::from_utf8_lossy(data).into_owned()];
+            }
+            classes.push(String::from_utf8_lossy(&data[i + 1..end]).into_owned());
+            i = end;
+        }
+        classes
+    }
+
     /// Convenience function for extracting packet's message type.
     pub fn message_type(&self) -> Result<MessageType, CaptivePortalError> {
         if let Some(x) = self.option(DHCP_MESSAGE_TYPE) {
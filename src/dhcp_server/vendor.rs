@@ -0,0 +1,109 @@
+//! Vendor specific information for provisioning clients, handed out as option 43 (RFC 2132)
+//! and option 125 (RFC 3925). Both carry encapsulated sub-options in the usual code, length, data format.
+
+use crate::{parse_hex, CaptivePortalError};
+
+/// Parses a vendor sub-option like "1=http://192.168.42.1/provision" or "2=0x0a0b0c".
+/// Values starting with "0x" are hex encoded, all other values are taken as text.
+pub fn parse_vendor_option(value: &str) -> Result<(u8, Vec<u8>), String> {
+    let mut parts = value.splitn(2, '=');
+    let code = parts.next().unwrap_or_default().trim();
+    let data = parts
+        .next()
+        .ok_or_else(|| format!("Expected <code>=<value> in vendor option {}", value))?;
+    let code = code
+        .parse::<u8>()
+        .map_err(|_| format!("Invalid sub-option code in vendor option {}", value))?;
+    // Code 0 and 255 are the pad and end markers
+    if code == 0 || code == 255 {
+        return Err(format!(
+            "Sub-option code must be between 1 and 254 in vendor option {}",
+            value
+        ));
+    }
+    let data = match data {
+        hex if hex.starts_with("0x") => {
+            parse_hex(&hex[2..]).ok_or_else(|| format!("Invalid hex value in vendor option {}", value))?
+        },
+        _ => data.as_bytes().to_vec(),
+    };
+    Ok((code, data))
+}
+
+/// Encodes the sub-options as data of option 43. The result must fit into a single dhcp option.
+pub fn encode_vendor_specific(sub_options: &[(u8, Vec<u8>)]) -> Result<Vec<u8>, CaptivePortalError> {
+    let mut data = Vec::new();
+    for (code, value) in sub_options {
+        if value.len() > 255 {
+            return Err(CaptivePortalError::DhcpError("Vendor sub-option exceeds 255 bytes"));
+        }
+        data.push(*code);
+        data.push(value.len() as u8);
+        data.extend_from_slice(value);
+    }
+    if data.len() > 255 {
+        return Err(CaptivePortalError::DhcpError(
+            "Vendor specific information exceeds 255 bytes",
+        ));
+    }
+    Ok(data)
+}
+
+/// Encodes the sub-options as data of option 125: The enterprise number, the length of the sub-options and
+/// the sub-options themselves.
+pub fn encode_vendor_identifying(
+    enterprise_number: u32,
+    sub_options: &[(u8, Vec<u8>)],
+) -> Result<Vec<u8>, CaptivePortalError> {
+    let sub_options = encode_vendor_specific(sub_options)?;
+    if sub_options.len() + 5 > 255 {
+        return Err(CaptivePortalError::DhcpError(
+            "Vendor identifying information exceeds 255 bytes",
+        ));
+    }
+    let mut data = Vec::with_capacity(sub_options.len() + 5);
+    data.extend_from_slice(&enterprise_number.to_be_bytes());
+    data.push(sub_options.len() as u8);
+    data.extend(sub_options);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendor_specific() {
+        let sub_options = vec![(1, b"http://acs".to_vec()), (2, vec![0x0a, 0x0b])];
+        let data = encode_vendor_specific(&sub_options).unwrap();
+        let mut expected = vec![1, 10];
+        expected.extend_from_slice(b"http://acs");
+        expected.extend_from_slice(&[2, 2, 0x0a, 0x0b]);
+        assert_eq!(data, expected);
+
+        // With enterprise number 3561 (Broadband Forum)
+        let data = encode_vendor_identifying(3561, &sub_options).unwrap();
+        assert_eq!(&data[..5], &[0, 0, 0x0d, 0xe9, expected.len() as u8]);
+        assert_eq!(&data[5..], &expected[..]);
+
+        // Too long
+        assert!(encode_vendor_specific(&[(1, vec![0; 256])]).is_err());
+        assert!(encode_vendor_specific(&[(1, vec![0; 200]), (2, vec![0; 100])]).is_err());
+        assert!(encode_vendor_identifying(1, &[(1, vec![0; 250])]).is_err());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_vendor_option("1=http://acs").unwrap(),
+            (1, b"http://acs".to_vec())
+        );
+        assert_eq!(parse_vendor_option("2=0x0a0B").unwrap(), (2, vec![0x0a, 0x0b]));
+        assert_eq!(parse_vendor_option("3=").unwrap(), (3, vec![]));
+        assert!(parse_vendor_option("1").is_err());
+        assert!(parse_vendor_option("0=a").is_err());
+        assert!(parse_vendor_option("256=a").is_err());
+        assert!(parse_vendor_option("1=0xabc").is_err());
+        assert!(parse_vendor_option("1=0xzz").is_err());
+    }
+}
@@ -1,12 +1,24 @@
 //! A byte buffer that deals with big/little endianess for us.
 //!
 //! The buffer is developer with safely in mind, not efficiency.
-//! Each read checks towards the actual buffer size.
+//! Each access and position change is checked against the buffer size.
+//! An out of range access returns an error and never panics.
+//!
+//! The buffer is reused for multiple requests. Call [`BytePacketBuffer::set_size`] after
+//! receiving a packet and [`BytePacketBuffer::reset_for_write`] before writing a response.
 // https://github.com/EmilHernvall/dnsguide/blob/master/samples/sample4.rs
 
 use std::io::Result;
 use std::io::{Error, ErrorKind};
 
+/// Name compression pointers may point to other pointers. Limit the amount of jumps
+/// to not loop forever on malicious packets.
+const MAX_QNAME_JUMPS: usize = 5;
+
+fn end_of_buffer() -> Error {
+    Error::new(ErrorKind::InvalidInput, "End of buffer")
+}
+
 pub struct BytePacketBuffer {
     pub buf: [u8; 512],
     pub pos: usize,
@@ -27,11 +39,13 @@ impl BytePacketBuffer {
         self.pos = 0;
     }
 
+    /// Sets the size of the received packet and resets the position for reading
     pub fn set_size(&mut self, size: usize) -> Result<()> {
-        if size >= self.buf.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        if size > self.buf.len() {
+            return Err(end_of_buffer());
         }
         self.size = size;
+        self.pos = 0;
         Ok(())
     }
 
@@ -40,12 +54,14 @@ impl BytePacketBuffer {
     }
 
     pub fn step(&mut self, steps: usize) -> Result<()> {
-        self.pos += steps;
-
-        Ok(())
+        let pos = self.pos.checked_add(steps).ok_or_else(end_of_buffer)?;
+        self.seek(pos)
     }
 
     pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.size {
+            return Err(end_of_buffer());
+        }
         self.pos = pos;
 
         Ok(())
@@ -53,7 +69,7 @@ impl BytePacketBuffer {
 
     pub fn read(&mut self) -> Result<u8> {
         if self.pos >= self.size {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(end_of_buffer());
         }
         let res = self.buf[self.pos];
         self.pos += 1;
@@ -63,16 +79,17 @@ impl BytePacketBuffer {
 
     pub fn get(&mut self, pos: usize) -> Result<u8> {
         if pos >= self.size {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(end_of_buffer());
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= self.size {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+        let end = start.checked_add(len).ok_or_else(end_of_buffer)?;
+        if end > self.size {
+            return Err(end_of_buffer());
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..end])
     }
 
     pub fn read_u16(&mut self) -> Result<u16> {
@@ -93,6 +110,7 @@ impl BytePacketBuffer {
     pub fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
         let mut pos = self.pos();
         let mut jumped = false;
+        let mut jumps = 0;
 
         let mut delim = "";
         loop {
@@ -103,13 +121,19 @@ impl BytePacketBuffer {
             // handle this by jumping to the offset, setting a flag to indicate
             // that we shouldn't update the shared buffer position once done.
             if (len & 0xC0) == 0xC0 {
+                if jumps >= MAX_QNAME_JUMPS {
+                    return Err(Error::new(ErrorKind::InvalidData, "Too many jumps in qname"));
+                }
+                jumps += 1;
+
+                let b2 = self.get(pos + 1)? as u16;
+
                 // When a jump is performed, we only modify the shared buffer
                 // position once, and avoid making the change later on.
                 if !jumped {
                     self.seek(pos + 2)?;
                 }
 
-                let b2 = self.get(pos + 1)? as u16;
                 let offset = (((len as u16) ^ 0xC0) << 8) | b2;
                 pos = offset as usize;
                 jumped = true;
@@ -142,7 +166,7 @@ impl BytePacketBuffer {
 
     pub fn write(&mut self, val: u8) -> Result<()> {
         if self.pos >= self.size {
-            return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            return Err(end_of_buffer());
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -176,7 +200,7 @@ impl BytePacketBuffer {
 
         for label in split_str {
             let len = label.len();
-            if len > 0x34 {
+            if len > 0x3f {
                 return Err(Error::new(
                     ErrorKind::InvalidInput,
                     "Single label exceeds 63 characters of length",
@@ -195,6 +219,9 @@ impl BytePacketBuffer {
     }
 
     pub fn set(&mut self, pos: usize, val: u8) -> Result<()> {
+        if pos >= self.size {
+            return Err(end_of_buffer());
+        }
         self.buf[pos] = val;
 
         Ok(())
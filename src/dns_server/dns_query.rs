@@ -5,6 +5,7 @@ use std::io::Result;
 use super::byte_buffer::BytePacketBuffer;
 
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
@@ -15,6 +16,8 @@ pub enum QueryType {
     // 5
     MX,
     // 15
+    TXT,
+    // 16
     AAAA, // 28
 }
 
@@ -32,6 +35,7 @@ impl QueryType {
             QueryType::NS => 2,
             QueryType::CNAME => 5,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
         }
     }
@@ -42,6 +46,7 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::CNAME,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
             _ => QueryType::UNKNOWN(num),
         }
@@ -1,6 +1,6 @@
 //! A DNS Record structure and its encoding and decoding
 
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::net::{Ipv4Addr, Ipv6Addr};
 
 use log::info;
@@ -9,7 +9,7 @@ use super::byte_buffer::BytePacketBuffer;
 use super::dns_query::QueryType;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
@@ -43,6 +43,14 @@ pub enum DnsRecord {
         ttl: u32,
     },
     // 15
+    /// The text is encoded as a sequence of character-strings of at most 255 bytes each.
+    /// All character-strings of a record are concatenated on reading.
+    TXT {
+        domain: String,
+        text: String,
+        ttl: u32,
+    },
+    // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
@@ -123,6 +131,28 @@ impl DnsRecord {
                     ttl,
                 })
             },
+            QueryType::TXT => {
+                let mut text = Vec::new();
+                let mut remaining = data_len as usize;
+                while remaining > 0 {
+                    let len = buffer.read()? as usize;
+                    if len >= remaining {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "TXT character-string exceeds record",
+                        ));
+                    }
+                    text.extend_from_slice(buffer.get_range(buffer.pos(), len)?);
+                    buffer.step(len)?;
+                    remaining -= len + 1;
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    text: String::from_utf8_lossy(&text).into_owned(),
+                    ttl,
+                })
+            },
             QueryType::UNKNOWN(_) => {
                 buffer.step(data_len as usize)?;
 
@@ -213,6 +243,34 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             },
+            DnsRecord::TXT {
+                ref domain,
+                ref text,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                // A character-string is length prefixed with a single byte
+                let bytes = text.as_bytes();
+                if bytes.is_empty() {
+                    buffer.write_u8(0)?;
+                }
+                for chunk in bytes.chunks(255) {
+                    buffer.write_u8(chunk.len() as u8)?;
+                    for b in chunk {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
@@ -0,0 +1,205 @@
+//! Per domain answer policies of the dns server. Operating systems detect a captive portal by resolving
+//! and requesting well-known connectivity check domains. Those are hijacked with a short ttl by default,
+//! so that clients recover quickly after the portal has been closed. Each domain can be configured to be
+//! hijacked or passed through to the upstream dns servers instead.
+
+use std::net::Ipv4Addr;
+
+/// The ttl in seconds of hijacked answers for domains without a policy
+pub const DEFAULT_TTL: u32 = 360;
+
+/// The ttl in seconds of hijacked answers for connectivity check domains
+pub const CHECK_DOMAIN_TTL: u32 = 5;
+
+/// The well-known connectivity check domains of Apple, Android, Windows and Firefox
+const CHECK_DOMAINS: &[&str] = &[
+    // Apple
+    "captive.apple.com",
+    "www.appleiphonecell.com",
+    // Android
+    "connectivitycheck.gstatic.com",
+    "connectivitycheck.android.com",
+    "clients3.google.com",
+    // Windows
+    "www.msftconnecttest.com",
+    "ipv6.msftconnecttest.com",
+    "www.msftncsi.com",
+    "dns.msftncsi.com",
+    // Firefox
+    "detectportal.firefox.com",
+];
+
+/// How the dns server answers queries for a domain
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DomainPolicy {
+    /// Answer with the gateway address and the given ttl in seconds
+    Hijack { ttl: u32 },
+    /// Forward the query to the upstream dns servers. Refused if there are none.
+    PassThrough,
+}
+
+/// Parses a policy like "captive.apple.com=pass", "captive.apple.com=hijack" or "captive.apple.com=hijack:30".
+/// A hijack policy without ttl uses [`CHECK_DOMAIN_TTL`].
+pub fn parse_domain_policy(value: &str) -> Result<(String, DomainPolicy), String> {
+    let mut parts = value.splitn(2, '=');
+    let domain = normalize(parts.next().unwrap_or_default());
+    let policy = parts.next().unwrap_or_default();
+    if domain.is_empty() {
+        return Err(format!("No domain given in dns policy {}", value));
+    }
+    let policy = match policy {
+        "pass" => DomainPolicy::PassThrough,
+        "hijack" => DomainPolicy::Hijack { ttl: CHECK_DOMAIN_TTL },
+        _ if policy.starts_with("hijack:") => DomainPolicy::Hijack {
+            ttl: policy["hijack:".len()..]
+                .parse()
+                .map_err(|_| format!("Invalid ttl in dns policy {}", value))?,
+        },
+        _ => return Err(format!("Expected pass, hijack or hijack:<ttl> in dns policy {}", value)),
+    };
+    Ok((domain, policy))
+}
+
+/// Parses a host mapping like "api.portal=192.168.42.1"
+pub fn parse_host_address(value: &str) -> Result<(String, Ipv4Addr), String> {
+    let mut parts = value.splitn(2, '=');
+    let host = normalize(parts.next().unwrap_or_default());
+    if host.is_empty() {
+        return Err(format!("No host given in dns host mapping {}", value));
+    }
+    let addr = parts
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid address in dns host mapping {}", value))?;
+    Ok((host, addr))
+}
+
+/// Dns names are case insensitive and may be given fully qualified with a trailing dot
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+/// A table of domain policies. A policy also applies to all subdomains. The longest matching domain wins.
+#[derive(Clone, Debug, Default)]
+pub struct DomainPolicies {
+    entries: Vec<(String, DomainPolicy)>,
+}
+
+impl DomainPolicies {
+    /// A table of only the given policies
+    pub fn new(policies: Vec<(String, DomainPolicy)>) -> DomainPolicies {
+        let mut table = DomainPolicies::default();
+        for (domain, policy) in policies {
+            table.set(domain, policy);
+        }
+        table
+    }
+
+    /// The connectivity check domains are hijacked with a short ttl. The given policies override those.
+    pub fn with_defaults(overrides: Vec<(String, DomainPolicy)>) -> DomainPolicies {
+        let mut table = DomainPolicies::new(
+            CHECK_DOMAINS
+                .iter()
+                .map(|domain| (domain.to_string(), DomainPolicy::Hijack { ttl: CHECK_DOMAIN_TTL }))
+                .collect(),
+        );
+        for (domain, policy) in overrides {
+            table.set(domain, policy);
+        }
+        table
+    }
+
+    /// Sets the policy of the given domain and replaces an existing one
+    pub fn set(&mut self, domain: String, policy: DomainPolicy) {
+        let domain = normalize(&domain);
+        match self.entries.iter_mut().find(|(d, _)| d == &domain) {
+            Some(entry) => entry.1 = policy,
+            None => self.entries.push((domain, policy)),
+        }
+    }
+
+    /// Returns the policy of the given domain or of its closest parent domain, if any
+    pub fn get(&self, domain: &str) -> Option<DomainPolicy> {
+        let domain = normalize(domain);
+        self.entries
+            .iter()
+            .filter(|(d, _)| domain == *d || domain.ends_with(&format!(".{}", d)))
+            .max_by_key(|(d, _)| d.len())
+            .map(|(_, policy)| *policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_domain_policy, parse_host_address, DomainPolicies, DomainPolicy, CHECK_DOMAIN_TTL};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn default_policies() {
+        let policies = DomainPolicies::with_defaults(Vec::new());
+        let short = Some(DomainPolicy::Hijack { ttl: CHECK_DOMAIN_TTL });
+        assert_eq!(policies.get("captive.apple.com"), short);
+        assert_eq!(policies.get("connectivitycheck.gstatic.com"), short);
+        assert_eq!(policies.get("www.msftconnecttest.com"), short);
+        assert_eq!(policies.get("dns.msftncsi.com"), short);
+        // Case insensitive and fully qualified
+        assert_eq!(policies.get("Captive.Apple.com."), short);
+        // Other domains have no policy
+        assert_eq!(policies.get("www.apple.com"), None);
+        assert_eq!(policies.get("apple.com"), None);
+        assert_eq!(policies.get("notcaptive.apple.com"), None);
+    }
+
+    #[test]
+    fn override_policies() {
+        let policies = DomainPolicies::with_defaults(vec![
+            ("captive.apple.com".to_owned(), DomainPolicy::PassThrough),
+            ("Example.com.".to_owned(), DomainPolicy::Hijack { ttl: 60 }),
+            ("www.example.com".to_owned(), DomainPolicy::PassThrough),
+        ]);
+        assert_eq!(policies.get("captive.apple.com"), Some(DomainPolicy::PassThrough));
+        assert_eq!(policies.get("example.com"), Some(DomainPolicy::Hijack { ttl: 60 }));
+        assert_eq!(policies.get("mail.example.com"), Some(DomainPolicy::Hijack { ttl: 60 }));
+        // The longest match wins
+        assert_eq!(policies.get("www.example.com"), Some(DomainPolicy::PassThrough));
+        assert_eq!(policies.get("static.www.example.com"), Some(DomainPolicy::PassThrough));
+
+        // Without defaults
+        let policies = DomainPolicies::new(vec![("example.com".to_owned(), DomainPolicy::PassThrough)]);
+        assert_eq!(policies.get("captive.apple.com"), None);
+        assert_eq!(policies.get("example.com"), Some(DomainPolicy::PassThrough));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            parse_domain_policy("captive.apple.com=pass").unwrap(),
+            ("captive.apple.com".to_owned(), DomainPolicy::PassThrough)
+        );
+        assert_eq!(
+            parse_domain_policy("captive.apple.com=hijack").unwrap(),
+            (
+                "captive.apple.com".to_owned(),
+                DomainPolicy::Hijack { ttl: CHECK_DOMAIN_TTL }
+            )
+        );
+        assert_eq!(
+            parse_domain_policy("captive.apple.com=hijack:30").unwrap(),
+            ("captive.apple.com".to_owned(), DomainPolicy::Hijack { ttl: 30 })
+        );
+        assert!(parse_domain_policy("captive.apple.com").is_err());
+        assert!(parse_domain_policy("=pass").is_err());
+        assert!(parse_domain_policy("captive.apple.com=hijack:soon").is_err());
+        assert!(parse_domain_policy("captive.apple.com=block").is_err());
+
+        assert_eq!(
+            parse_host_address("API.portal.=192.168.42.2").unwrap(),
+            ("api.portal".to_owned(), Ipv4Addr::new(192, 168, 42, 2))
+        );
+        assert!(parse_host_address("api.portal").is_err());
+        assert!(parse_host_address("=192.168.42.2").is_err());
+        assert!(parse_host_address("api.portal=portal").is_err());
+    }
+}
@@ -1,28 +1,58 @@
 //! This is a DNS server implementation that returns the given gateway address for
 //! every request. This will be recognised by most mobile phones and browsers as
 //! a captive portal.
+//!
+//! The hijacking can be disabled for clients that are directed to the portal via dhcp option 114.
+//! Queries are then forwarded to the upstream dns servers, or refused if there are none.
+//! Single domains can be hijacked or passed through independently, see [`domain_policy`].
+//! Configured host names are answered with their own address, regardless of the policies.
 
 mod byte_buffer;
 mod dns_header;
 mod dns_packet;
 mod dns_query;
 mod dns_record;
+pub mod domain_policy;
+pub mod upstream;
 
 use byte_buffer::BytePacketBuffer;
 use dns_header::ResultCode;
 use dns_packet::DnsPacket;
+use dns_query::{DnsQuery, QueryType};
 use dns_record::DnsRecord;
+use domain_policy::{DomainPolicies, DomainPolicy, CHECK_DOMAIN_TTL, DEFAULT_TTL};
 
 use super::CaptivePortalError;
 
 use std::clone::Clone;
-use std::net::{SocketAddr, SocketAddrV4};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::sync::Mutex;
+
+/// The timeout per upstream dns server for forwarded queries
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
 
 /// A DNS server that responds with one IP for all requests
 pub struct CaptiveDnsServer {
     exit_receiver: tokio::sync::oneshot::Receiver<()>,
     server_addr: SocketAddrV4,
+    /// Answer all queries with the server address. True by default.
+    pub hijack: bool,
+    /// Queries are forwarded to these servers if hijacking is disabled
+    pub upstream: Vec<SocketAddr>,
+    /// Per domain policies. Domains without a policy are hijacked or not, depending on [`hijack`].
+    pub policies: DomainPolicies,
+    /// TXT queries for these domains are answered with the given text, eg the captive portal api uri
+    pub txt_records: Vec<(String, String)>,
+    /// Host names, like "api.portal", that are answered with the given address instead of the server address.
+    /// Only exact matches count. Names are expected in lower case and without trailing dot.
+    pub hosts: HashMap<String, Ipv4Addr>,
+    /// Hijacked queries are answered with this address instead of the server address, if set.
+    /// Useful if the portal page is served by another host.
+    pub answer_ip: Option<Ipv4Addr>,
     /// For testing: Quits the receive loop after one received packet
     #[allow(unused)]
     only_once: bool,
@@ -37,6 +67,12 @@ impl CaptiveDnsServer {
             CaptiveDnsServer {
                 server_addr,
                 exit_receiver,
+                hijack: true,
+                upstream: Vec::new(),
+                policies: DomainPolicies::default(),
+                txt_records: Vec::new(),
+                hosts: HashMap::new(),
+                answer_ip: None,
                 only_once: false,
             },
             exit_handler,
@@ -44,10 +80,25 @@ impl CaptiveDnsServer {
     }
 
     pub async fn run(&mut self) -> Result<(), CaptivePortalError> {
-        let mut socket = tokio::net::UdpSocket::bind(SocketAddr::V4(self.server_addr.clone())).await?;
+        let socket = self.bind()?;
+        self.receive_loop(socket).await
+    }
+
+    /// Binds the server socket. The server address is updated with the bound port, eg if port 0 is given.
+    fn bind(&mut self) -> Result<std::net::UdpSocket, CaptivePortalError> {
+        let socket = std::net::UdpSocket::bind(SocketAddr::V4(self.server_addr))?;
         socket.set_broadcast(true).expect("Set broadcast flag on udp socket");
+        socket.set_nonblocking(true)?;
+        self.server_addr.set_port(socket.local_addr()?.port());
 
         info!("Started dns server on {}", &self.server_addr);
+        Ok(socket)
+    }
+
+    async fn receive_loop(&mut self, socket: std::net::UdpSocket) -> Result<(), CaptivePortalError> {
+        // Forwarded queries are answered by their own tasks via a clone of the socket
+        let forward_socket = Arc::new(Mutex::new(UdpSocket::from_std(socket.try_clone()?)?));
+        let mut socket = UdpSocket::from_std(socket)?;
 
         let mut req_buffer = BytePacketBuffer::new();
         loop {
@@ -58,7 +109,25 @@ impl CaptiveDnsServer {
                 Some((size, socket_addr)) => {
                     req_buffer.set_size(size)?;
                     if let Ok(p) = DnsPacket::from_buffer(&mut req_buffer) {
-                        handle_request(&self, p, socket_addr, &mut req_buffer, &mut socket).await?;
+                        let pass_through = match p.questions.first() {
+                            Some(question) => {
+                                self.txt_record(question).is_none()
+                                    && self.host_address(&question.name).is_none()
+                                    && self.policy(&question.name) == DomainPolicy::PassThrough
+                            },
+                            None => false,
+                        };
+                        if pass_through && !self.upstream.is_empty() {
+                            // The upstream servers might take a while. Other queries are answered meanwhile.
+                            let request = req_buffer.buf[..size].to_vec();
+                            let len = write_response(self, p, &mut req_buffer)?;
+                            let fallback = req_buffer.get_range(0, len)?.to_vec();
+                            let (upstream, socket) = (self.upstream.clone(), forward_socket.clone());
+                            tokio::spawn(forward_request(upstream, request, fallback, socket_addr, socket));
+                        } else {
+                            let len = write_response(self, p, &mut req_buffer)?;
+                            socket.send_to(req_buffer.get_range(0, len)?, socket_addr).await?;
+                        }
                     }
                 },
                 // Exit signal received
@@ -76,14 +145,68 @@ impl CaptiveDnsServer {
         info!("Stopped dns server on {}", &self.server_addr);
         Ok(())
     }
+
+    /// The policy of the given domain. Domains without a configured policy are hijacked with
+    /// [`DEFAULT_TTL`] or passed through if hijacking is disabled.
+    fn policy(&self, domain: &str) -> DomainPolicy {
+        self.policies.get(domain).unwrap_or(if self.hijack {
+            DomainPolicy::Hijack { ttl: DEFAULT_TTL }
+        } else {
+            DomainPolicy::PassThrough
+        })
+    }
+
+    /// The configured text of a TXT query, if any
+    fn txt_record(&self, question: &DnsQuery) -> Option<&str> {
+        if question.qtype != QueryType::TXT {
+            return None;
+        }
+        let name = question.name.trim_end_matches('.');
+        self.txt_records
+            .iter()
+            .find(|(domain, _)| domain.trim_end_matches('.').eq_ignore_ascii_case(name))
+            .map(|(_, text)| &text[..])
+    }
+
+    /// The configured address of the given host name, if any
+    fn host_address(&self, domain: &str) -> Option<Ipv4Addr> {
+        self.hosts.get(&domain.trim_end_matches('.').to_lowercase()).cloned()
+    }
+
+    /// The address of hijacked queries
+    fn answer_address(&self) -> Ipv4Addr {
+        self.answer_ip.unwrap_or_else(|| *self.server_addr.ip())
+    }
+}
+
+/// Forwards the raw request to the upstream servers and relays the response to the client.
+/// The client gets the given fallback response if none of the upstream servers responded.
+async fn forward_request(
+    upstream: Vec<SocketAddr>,
+    request: Vec<u8>,
+    fallback: Vec<u8>,
+    src: SocketAddr,
+    socket: Arc<Mutex<UdpSocket>>,
+) {
+    let response = match upstream::query(&upstream, &request, UPSTREAM_TIMEOUT).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Failed to forward dns query: {}", e);
+            fallback
+        },
+    };
+    if let Err(e) = socket.lock().await.send_to(&response, src).await {
+        warn!("Failed to answer forwarded dns query: {}", e);
+    }
 }
 
-async fn handle_request(
+/// Writes the answer to the request into the buffer and returns its length. The request is answered with the
+/// server address, a configured host address or TXT record. If the domain is passed through, the request is
+/// refused (no upstream servers) or answered with a server failure (no upstream server responded).
+fn write_response(
     server: &CaptiveDnsServer,
     request: DnsPacket,
-    src: SocketAddr,
     mut res_buffer: &mut BytePacketBuffer,
-    socket: &mut UdpSocket,
 ) -> Result<usize, CaptivePortalError> {
     res_buffer.reset_for_write();
 
@@ -100,21 +223,40 @@ async fn handle_request(
         info!("Received DNS query: {:?}", question);
 
         packet.questions.push(question.clone());
-        packet.header.rescode = ResultCode::NOERROR;
-
-        let answer = DnsRecord::A {
-            domain: question.name.clone(),
-            addr: server.server_addr.ip().clone(),
-            ttl: 360,
-        };
-        packet.answers.push(answer);
+        if let Some(text) = server.txt_record(question) {
+            packet.header.rescode = ResultCode::NOERROR;
+            packet.answers.push(DnsRecord::TXT {
+                domain: question.name.clone(),
+                text: text.to_owned(),
+                ttl: CHECK_DOMAIN_TTL,
+            });
+        } else if let Some(addr) = server.host_address(&question.name) {
+            info!("Answering {} with configured address {}", question.name, addr);
+            packet.header.rescode = ResultCode::NOERROR;
+            packet.answers.push(DnsRecord::A {
+                domain: question.name.clone(),
+                addr,
+                ttl: DEFAULT_TTL,
+            });
+        } else {
+            match server.policy(&question.name) {
+                DomainPolicy::PassThrough if server.upstream.is_empty() => packet.header.rescode = ResultCode::REFUSED,
+                DomainPolicy::PassThrough => packet.header.rescode = ResultCode::SERVFAIL,
+                DomainPolicy::Hijack { ttl } => {
+                    packet.header.rescode = ResultCode::NOERROR;
+                    let answer = DnsRecord::A {
+                        domain: question.name.clone(),
+                        addr: server.answer_address(),
+                        ttl,
+                    };
+                    packet.answers.push(answer);
+                },
+            }
+        }
     }
 
     packet.write(&mut res_buffer)?;
-
-    let len = res_buffer.pos();
-    let data = res_buffer.get_range(0, len)?;
-    Ok(socket.send_to(data, src).await?)
+    Ok(res_buffer.pos())
 }
 
 #[cfg(test)]
@@ -152,12 +294,23 @@ mod tests {
         Ok(DnsPacket::from_buffer(&mut res_buffer)?)
     }
 
+    /// Runs the given server on its own task. Returns the bound address.
+    fn spawn(
+        mut dns_server: CaptiveDnsServer,
+    ) -> (tokio::task::JoinHandle<Result<(), CaptivePortalError>>, SocketAddrV4) {
+        let socket = dns_server.bind().expect("Bind dns server");
+        let socket_addr = dns_server.server_addr;
+        let server = tokio::spawn(async move { dns_server.receive_loop(socket).await });
+        (server, socket_addr)
+    }
+
     async fn test_domain_async() {
-        let socket_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 43210);
-        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(socket_addr);
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
         dns_server.only_once = true;
+        let socket = dns_server.bind().expect("Bind dns server");
+        let socket_addr = dns_server.server_addr;
 
-        let server = dns_server.run();
+        let server = dns_server.receive_loop(socket);
         let lookup = async move {
             let r = lookup("www.google.com", QueryType::A, SocketAddr::V4(socket_addr)).await?;
             let r = unsafe { r.answers.get_unchecked(0) };
@@ -178,6 +331,270 @@ mod tests {
             .expect("Failed to execute server or lookup");
     }
 
+    #[tokio::test]
+    async fn no_hijack() {
+        // An upstream server that answers every query with 10.1.2.3
+        let mut upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = BytePacketBuffer::new();
+            let (size, src) = upstream.recv_from(&mut buffer.buf).await.unwrap();
+            buffer.set_size(size).unwrap();
+            let mut packet = DnsPacket::from_buffer(&mut buffer).unwrap();
+            packet.header.response = true;
+            packet.answers.push(DnsRecord::A {
+                domain: packet.questions[0].name.clone(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: 60,
+            });
+            buffer.reset_for_write();
+            packet.write(&mut buffer).unwrap();
+            upstream.send_to(&buffer.buf[..buffer.pos()], src).await.unwrap();
+        });
+
+        // Queries are forwarded
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        dns_server.hijack = false;
+        dns_server.upstream = vec![upstream_addr];
+        let (server, socket_addr) = spawn(dns_server);
+        let r = lookup("www.google.com", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, ttl, .. }) => {
+                assert_eq!(addr, &Ipv4Addr::new(10, 1, 2, 3));
+                assert_eq!(*ttl, 60);
+            },
+            r => panic!("Unexpected answer {:?}", r),
+        }
+        let _ = exit_handler.send(());
+        server.await.unwrap().unwrap();
+
+        // Without upstream servers, queries are refused
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        dns_server.hijack = false;
+        let (server, socket_addr) = spawn(dns_server);
+        let r = lookup("www.google.com", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        assert_eq!(r.header.rescode, ResultCode::REFUSED);
+        assert!(r.answers.is_empty());
+        let _ = exit_handler.send(());
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn forward_without_blocking() {
+        // An upstream server that never responds
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        dns_server.hijack = false;
+        dns_server.upstream = vec![silent.local_addr().unwrap()];
+        dns_server
+            .hosts
+            .insert("api.portal".to_owned(), Ipv4Addr::new(192, 168, 42, 2));
+        let (server, socket_addr) = spawn(dns_server);
+
+        // Local answers are not delayed by a forwarded query
+        let forwarded = tokio::spawn(lookup("www.google.com", QueryType::A, SocketAddr::V4(socket_addr)));
+        delay_for(Duration::from_millis(50)).await;
+        let r = tokio::time::timeout(
+            Duration::from_millis(500),
+            lookup("api.portal", QueryType::A, SocketAddr::V4(socket_addr)),
+        )
+        .await
+        .expect("Answered while forwarding")
+        .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, .. }) => assert_eq!(addr, &Ipv4Addr::new(192, 168, 42, 2)),
+            r => panic!("Unexpected answer {:?}", r),
+        }
+
+        // The forwarded query fails after the upstream timeout
+        let r = forwarded.await.unwrap().unwrap();
+        assert_eq!(r.header.rescode, ResultCode::SERVFAIL);
+        let _ = exit_handler.send(());
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn host_map() {
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        dns_server
+            .hosts
+            .insert("api.portal".to_owned(), Ipv4Addr::new(192, 168, 42, 2));
+        let (server, socket_addr) = spawn(dns_server);
+
+        // A mapped host is answered with its address
+        let r = lookup("API.portal.", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, .. }) => assert_eq!(addr, &Ipv4Addr::new(192, 168, 42, 2)),
+            r => panic!("Unexpected answer {:?}", r),
+        }
+
+        // Others, including subdomains of a mapped host, with the server address
+        let r = lookup("cdn.api.portal", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, .. }) => assert_eq!(addr, socket_addr.ip()),
+            r => panic!("Unexpected answer {:?}", r),
+        }
+        let _ = exit_handler.send(());
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn answer_ip() {
+        let (mut dns_server, exit_handler) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0));
+        dns_server.answer_ip = Some(Ipv4Addr::new(192, 168, 42, 5));
+        dns_server
+            .hosts
+            .insert("api.portal".to_owned(), Ipv4Addr::new(192, 168, 42, 2));
+        let (server, socket_addr) = spawn(dns_server);
+
+        // Hijacked queries are answered with the override instead of the bind address
+        let r = lookup("www.google.com", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, .. }) => assert_eq!(addr, &Ipv4Addr::new(192, 168, 42, 5)),
+            r => panic!("Unexpected answer {:?}", r),
+        }
+
+        // Mapped hosts keep their address
+        let r = lookup("api.portal", QueryType::A, SocketAddr::V4(socket_addr))
+            .await
+            .unwrap();
+        match r.answers.get(0) {
+            Some(DnsRecord::A { addr, .. }) => assert_eq!(addr, &Ipv4Addr::new(192, 168, 42, 2)),
+            r => panic!("Unexpected answer {:?}", r),
+        }
+        let _ = exit_handler.send(());
+        server.await.unwrap().unwrap();
+    }
+
+    /// A xorshift pseudo random number generator. Good enough for fuzzing with a fixed seed.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn parse_random_packets() {
+        let mut rng = XorShift(0x5eed_cafe_f00d_d00d);
+
+        // A valid query to derive mutated packets from
+        let mut query = BytePacketBuffer::new();
+        query.reset_for_write();
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuery::new("www.google.com".to_owned(), QueryType::A));
+        packet.write(&mut query).expect("Write query");
+        let query_len = query.pos();
+
+        let mut buffer = BytePacketBuffer::new();
+        for i in 0..10000 {
+            let size = (rng.next() % (buffer.buf.len() as u64 + 1)) as usize;
+            if i % 2 == 0 {
+                // Random bytes
+                for byte in buffer.buf[..size].iter_mut() {
+                    *byte = rng.next() as u8;
+                }
+                buffer.set_size(size).expect("Size within buffer capacity");
+            } else {
+                // A valid query with a few random bytes, to get past the header more often
+                buffer.buf[..query_len].copy_from_slice(&query.buf[..query_len]);
+                for _ in 0..(rng.next() % 4 + 1) {
+                    let pos = (rng.next() % query_len as u64) as usize;
+                    buffer.buf[pos] = rng.next() as u8;
+                }
+                buffer.set_size(query_len).expect("Size within buffer capacity");
+            }
+            // Must never panic. The buffer is reused for the response, as the server does.
+            if let Ok(mut packet) = DnsPacket::from_buffer(&mut buffer) {
+                buffer.reset_for_write();
+                let _ = packet.write(&mut buffer);
+            }
+        }
+
+        // Out of range accesses are errors
+        buffer.set_size(10).expect("Size within buffer capacity");
+        assert!(buffer.set_size(513).is_err());
+        assert!(buffer.seek(11).is_err());
+        assert!(buffer.step(usize::max_value()).is_err());
+        assert!(buffer.get(10).is_err());
+        assert!(buffer.get_range(5, 6).is_err());
+        assert!(buffer.get_range(5, usize::max_value()).is_err());
+        assert!(buffer.set(10, 0).is_err());
+        assert_eq!(buffer.get_range(5, 5).expect("Range within size").len(), 5);
+    }
+
+    #[test]
+    fn txt_record() {
+        // Longer than a single character-string
+        let text = format!("http://192.168.42.1/captive-portal-api?{}", "x".repeat(300));
+        let record = DnsRecord::TXT {
+            domain: "_captive-portal.local".to_owned(),
+            text: text.clone(),
+            ttl: 5,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        buffer.reset_for_write();
+        let len = record.write(&mut buffer).expect("Write TXT record");
+        // Two length prefixed character-strings
+        let data_start = len - (text.len() + 2);
+        assert_eq!(
+            buffer.get_range(data_start - 2, 2).unwrap(),
+            &((text.len() + 2) as u16).to_be_bytes()
+        );
+        assert_eq!(buffer.get(data_start).unwrap(), 255);
+        assert_eq!(buffer.get(data_start + 256).unwrap(), (text.len() - 255) as u8);
+
+        buffer.set_size(len).expect("Size within buffer capacity");
+        buffer.seek(0).unwrap();
+        assert_eq!(DnsRecord::read(&mut buffer).expect("Read TXT record"), record);
+
+        // A character-string that exceeds the record is an error
+        buffer.set(data_start, 255).unwrap();
+        buffer.set(data_start + 256, 255).unwrap();
+        buffer.seek(0).unwrap();
+        assert!(DnsRecord::read(&mut buffer).is_err());
+
+        let (mut dns_server, _) = CaptiveDnsServer::new(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 53));
+        dns_server.txt_records = vec![("_captive-portal.local".to_owned(), text.clone())];
+        let query = |name: &str, qtype| DnsQuery::new(name.to_owned(), qtype);
+        assert_eq!(
+            dns_server.txt_record(&query("_Captive-Portal.local.", QueryType::TXT)),
+            Some(&text[..])
+        );
+        assert_eq!(
+            dns_server.txt_record(&query("_captive-portal.local", QueryType::A)),
+            None
+        );
+        assert_eq!(dns_server.txt_record(&query("www.google.com", QueryType::TXT)), None);
+    }
+
+    #[test]
+    fn parse_qname_jump_loop() {
+        // A qname that points to itself
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[0] = 0xC0;
+        buffer.buf[1] = 0x00;
+        buffer.set_size(2).expect("Size within buffer capacity");
+        let mut qname = String::new();
+        assert!(buffer.read_qname(&mut qname).is_err());
+    }
+
     #[tokio::test]
     async fn test_domain() {
         let timeout = delay_for(Duration::from_secs(2));
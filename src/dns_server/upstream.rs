@@ -0,0 +1,141 @@
+//! Queries upstream dns servers. The servers are tried in the given order, each with its own timeout,
+//! until one of them responds.
+
+use super::byte_buffer::BytePacketBuffer;
+use super::dns_packet::DnsPacket;
+use super::dns_query::{DnsQuery, QueryType};
+use super::dns_record::DnsRecord;
+use crate::CaptivePortalError;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// The default dns port, if an upstream server is given without port
+const DNS_PORT: u16 = 53;
+
+/// Parses an upstream dns server like "1.1.1.1" or "1.1.1.1:5353".
+pub fn parse_dns_server(server: &str) -> Result<SocketAddr, std::net::AddrParseError> {
+    match server.parse::<SocketAddr>() {
+        Ok(addr) => Ok(addr),
+        Err(_) => Ok(SocketAddr::new(server.parse::<IpAddr>()?, DNS_PORT)),
+    }
+}
+
+/// Sends the given dns request to one server and waits for the response with the same id.
+async fn query_server(server: SocketAddr, request: &[u8], buffer: &mut [u8]) -> Result<usize, CaptivePortalError> {
+    let bind_addr: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let mut socket = UdpSocket::bind(bind_addr).await?;
+    socket.send_to(request, server).await?;
+    loop {
+        let (size, src) = socket.recv_from(buffer).await?;
+        // Ignore stray packets and responses to other requests
+        if src == server && size >= 2 && buffer[..2] == request[..2] {
+            return Ok(size);
+        }
+    }
+}
+
+/// Sends the given dns request to the given servers, one after another, until one responds
+/// within `server_timeout`. Returns the raw response.
+pub async fn query(
+    servers: &[SocketAddr],
+    request: &[u8],
+    server_timeout: Duration,
+) -> Result<Vec<u8>, CaptivePortalError> {
+    if request.len() < 2 {
+        return Err(CaptivePortalError::Generic("Invalid dns request".to_owned()));
+    }
+    let mut buffer = [0u8; 512];
+    for server in servers {
+        match timeout(server_timeout, query_server(*server, request, &mut buffer)).await {
+            Ok(Ok(size)) => return Ok(buffer[..size].to_vec()),
+            Ok(Err(e)) => warn!("Upstream dns server {} failed: {}", server, e),
+            Err(_) => warn!("Upstream dns server {} did not respond", server),
+        }
+    }
+    Err(CaptivePortalError::Generic(
+        "No upstream dns server responded".to_owned(),
+    ))
+}
+
+/// Resolves the first ipv4 address of the given host via the given upstream servers.
+/// Returns None if the host has no ipv4 address.
+pub async fn resolve_ipv4(
+    servers: &[SocketAddr],
+    host: &str,
+    server_timeout: Duration,
+) -> Result<Option<Ipv4Addr>, CaptivePortalError> {
+    let mut packet = DnsPacket::new();
+    packet.header.id = std::process::id() as u16;
+    packet.header.questions = 1;
+    packet.header.recursion_desired = true;
+    packet.questions.push(DnsQuery::new(host.to_owned(), QueryType::A));
+
+    let mut buffer = BytePacketBuffer::new();
+    buffer.reset_for_write();
+    packet.write(&mut buffer)?;
+    let len = buffer.pos();
+    let response = query(servers, buffer.get_range(0, len)?, server_timeout).await?;
+
+    let mut buffer = BytePacketBuffer::new();
+    buffer.buf[..response.len()].copy_from_slice(&response);
+    buffer.set_size(response.len())?;
+    let packet = DnsPacket::from_buffer(&mut buffer)?;
+    Ok(packet.answers.iter().find_map(|answer| match answer {
+        DnsRecord::A { addr, .. } => Some(*addr),
+        _ => None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_dns_server, query};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    #[test]
+    fn dns_server() {
+        assert_eq!(
+            parse_dns_server("1.1.1.1").unwrap(),
+            "1.1.1.1:53".parse::<SocketAddr>().unwrap()
+        );
+        assert_eq!(
+            parse_dns_server("1.1.1.1:5353").unwrap(),
+            "1.1.1.1:5353".parse::<SocketAddr>().unwrap()
+        );
+        assert!(parse_dns_server("one.one").is_err());
+    }
+
+    #[tokio::test]
+    async fn failover() {
+        // The first server never answers
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut alive = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let servers = vec![dead.local_addr().unwrap(), alive.local_addr().unwrap()];
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            let (size, src) = alive.recv_from(&mut buffer).await.unwrap();
+            // Answer with the request id
+            let response = [&buffer[..2], b"response"].concat();
+            assert_eq!(size, 9);
+            alive.send_to(&response, src).await.unwrap();
+        });
+
+        let response = query(&servers, b"\x12\x34request", Duration::from_millis(200))
+            .await
+            .unwrap();
+        assert_eq!(&response[2..], b"response");
+
+        assert!(query(&servers[..1], b"\x12\x34request", Duration::from_millis(50))
+            .await
+            .is_err());
+        drop(dead);
+    }
+}
@@ -29,11 +29,37 @@ pub enum CaptivePortalError {
     DhcpError(&'static str),
     HttpRoutingFailed,
     NotInStationMode,
+    /// The network backend is still starting up
+    BackendNotReady,
     NotRequiredConnectivity(NetworkManagerState),
     HotspotFailed,
     NoWifiDeviceFound,
     InvalidSharedKey(String),
-    NoSharedKeyProvided,
+    /// A passphrase has been given for an open network
+    PassphraseNotAllowedForOpen,
+    IdentityRequiredForEnterprise,
+    PassphraseRequired,
+    InvalidSubnetPrefix(u8),
+    InvalidConnectivityCheckUri(String),
+    InvalidCaptivePortalUri(String),
+    AddressConflict(String),
+    InvalidEapSettings(String),
+    /// The named credential profile does not exist or cannot be read
+    InvalidCredentialProfile(String),
+    /// The wifi device is not managed by the network backend
+    DeviceUnmanaged {
+        interface: String,
+    },
+    /// The wifi device cannot operate as access point. A hotspot cannot be started.
+    ApModeUnsupported {
+        interface: String,
+    },
+    /// Wifi is blocked (rfkill) and could not be enabled. A hard block is caused by a hardware switch.
+    WifiBlocked {
+        hard: bool,
+    },
+    /// The network backend does not support the named operation
+    Unsupported(&'static str),
 }
 
 impl Unpin for CaptivePortalError {}
@@ -114,11 +140,64 @@ impl fmt::Display for CaptivePortalError {
             CaptivePortalError::Ser(ref e) => e.fmt(f),
             CaptivePortalError::RecvError(ref e) => e.fmt(f),
             CaptivePortalError::NotInStationMode => write!(f, "Scanning not possible: Not in station mode!"),
+            CaptivePortalError::BackendNotReady => write!(f, "The network backend is not ready yet"),
             CaptivePortalError::NotRequiredConnectivity(_) => write!(f, "Connectivity is limited"),
             CaptivePortalError::HotspotFailed => write!(f, "Failed to initiate a hotspot"),
             CaptivePortalError::NoWifiDeviceFound => write!(f, "No wifi device found on this system"),
             CaptivePortalError::InvalidSharedKey(ref m) => write!(f, "Invalid Passphrase: {}", m),
-            CaptivePortalError::NoSharedKeyProvided => write!(f, "Passphrase required!"),
+            CaptivePortalError::PassphraseNotAllowedForOpen => {
+                write!(f, "The network is open. Please remove the passphrase!")
+            },
+            CaptivePortalError::IdentityRequiredForEnterprise => {
+                write!(f, "Enterprise networks require an identity, eg a user name!")
+            },
+            CaptivePortalError::PassphraseRequired => write!(f, "Passphrase required!"),
+            CaptivePortalError::InvalidSubnetPrefix(prefix) => {
+                write!(
+                    f,
+                    "Invalid subnet prefix /{}. Expected a value between 8 and 30",
+                    prefix
+                )
+            },
+            CaptivePortalError::InvalidConnectivityCheckUri(ref uri) => {
+                write!(
+                    f,
+                    "Invalid connectivity check uri: {}. Expected an absolute http(s) uri",
+                    uri
+                )
+            },
+            CaptivePortalError::DeviceUnmanaged { ref interface } => write!(
+                f,
+                "Wifi device {} is not managed by NetworkManager. \
+                 Use `nmcli device set {} managed yes` or start with --manage-device",
+                interface, interface
+            ),
+            CaptivePortalError::ApModeUnsupported { ref interface } => {
+                write!(f, "Wifi device {} does not support access point mode", interface)
+            },
+            CaptivePortalError::WifiBlocked { hard: true } => {
+                write!(
+                    f,
+                    "Wifi is blocked by a hardware switch. Turn the switch on to continue"
+                )
+            },
+            CaptivePortalError::WifiBlocked { hard: false } => {
+                write!(
+                    f,
+                    "Wifi is blocked in software and could not be enabled. Try `rfkill unblock wifi`"
+                )
+            },
+            CaptivePortalError::Unsupported(what) => write!(f, "Not supported by the network backend: {}", what),
+            CaptivePortalError::InvalidEapSettings(ref m) => write!(f, "Invalid 802.1x settings: {}", m),
+            CaptivePortalError::InvalidCredentialProfile(ref name) => {
+                write!(f, "Unknown or invalid credential profile: {}", name)
+            },
+            CaptivePortalError::AddressConflict(ref m) => write!(f, "Address conflict: {}", m),
+            CaptivePortalError::InvalidCaptivePortalUri(ref uri) => write!(
+                f,
+                "Invalid captive portal uri: {}. Expected an absolute http(s) uri of at most 255 bytes",
+                uri
+            ),
             CaptivePortalError::HttpRoutingFailed => write!(f, "Failed to internally route http data"),
             CaptivePortalError::DhcpError(str) => str.fmt(f),
             CaptivePortalError::IwdError(str) => str.fmt(f),
@@ -1,10 +1,15 @@
 //! Serves the static ui files. If the "includeui" feature is set, the ui files are compiled in
 //! and no system file access is required.
+//!
+//! Precompressed siblings of a file, eg "index.js.br" or "index.js.gz", are served instead of the file
+//! itself if the client supports the encoding. Brotli is preferred over gzip.
 
 use super::CaptivePortalError;
 use crate::http_server::HttpServerStateSync;
+use crate::utils::portal_url;
 use hyper::header::HeaderValue;
 use hyper::{Body, Request, Response, StatusCode};
+use std::net::SocketAddrV4;
 use std::path::{Path, PathBuf};
 
 #[cfg(any(feature = "includeui", not(debug_assertions)))]
@@ -80,6 +85,39 @@ impl<'a> FileWrapper {
     }
 }
 
+/// Precompressed file suffixes and their content encoding, in order of preference.
+const PRECOMPRESSED: [(&str, &str); 2] = [(".br", "br"), (".gz", "gzip")];
+
+/// Returns true if the given Accept-Encoding header value accepts the given encoding, eg "gzip, br;q=0.8".
+fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(|part| part.trim());
+        if parts.next() != Some(encoding) {
+            return false;
+        }
+        // An encoding with a quality of 0 is not acceptable
+        !parts.any(|param| param.starts_with("q=") && param[2..].parse::<f32>().map(|q| q == 0.0).unwrap_or(false))
+    })
+}
+
+/// Finds the file for the given path with the given lookup function. A precompressed sibling is preferred
+/// if the client accepts its encoding. Returns the file and the content encoding, if any.
+fn find_encoded<T, F>(path: &str, accept_encoding: Option<&str>, find: F) -> Option<(T, Option<&'static str>)>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    if let Some(accept_encoding) = accept_encoding {
+        for (suffix, encoding) in PRECOMPRESSED.iter() {
+            if accepts_encoding(accept_encoding, encoding) {
+                if let Some(file) = find(&format!("{}{}", path, suffix)) {
+                    return Some((file, Some(*encoding)));
+                }
+            }
+        }
+    }
+    find(path).map(|file| (file, None))
+}
+
 fn mime_type_from_ext(ext: &str) -> &str {
     match ext {
         "html" => "text/html",
@@ -90,15 +128,23 @@ fn mime_type_from_ext(ext: &str) -> &str {
     }
 }
 
+/// Returns the absolute location of the main page, eg "http://192.168.42.1/portal/index.html".
+pub(crate) fn index_location(server_addr: &SocketAddrV4, host: Option<&str>, base_path: &str) -> String {
+    // The portal is only served via http
+    portal_url(false, host, server_addr, &format!("{}/index.html", base_path))
+}
+
+/// Serves the file of the given request path. The path must not contain the base path of the server.
 pub fn serve_file(
     root: &Path,
     mut response: Response<Body>,
+    path: &str,
     req: &Request<Body>,
     state: &HttpServerStateSync,
 ) -> Result<Response<Body>, CaptivePortalError> {
-    let path = &req.uri().path()[1..];
+    let path = path.trim_start_matches('/');
 
-    let file = match () {
+    let find = |path: &str| match () {
         #[cfg(all(not(feature = "includeui"), debug_assertions))]
         () => FileWrapper::from_filesystem(root, path),
         #[cfg(any(feature = "includeui", not(debug_assertions)))]
@@ -109,17 +155,21 @@ pub fn serve_file(
                 .and_then(|f| Some(FileWrapper::from_included(&f)))
         },
     };
+    let accept_encoding = match req.headers().get("Accept-Encoding") {
+        Some(v) => Some(v.to_str()?),
+        None => None,
+    };
+    let (file, encoding) = match find_encoded(path, accept_encoding, find) {
+        Some((file, encoding)) => (Some(file), encoding),
+        None => (None, None),
+    };
     // A captive portal catches all GET requests (that accept */* or text) and redirects to the main page.
     if file.is_none() {
         if let Some(v) = req.headers().get("Accept") {
             let accept = v.to_str()?;
             if accept.contains("text") || accept.contains("*/*") {
                 let state = state.lock().expect("Lock http_state mutex");
-                let redirect_loc = format!(
-                    "http://{}:{}/index.html",
-                    state.server_addr.ip().to_string(),
-                    state.server_addr.port()
-                );
+                let redirect_loc = index_location(&state.server_addr, state.portal_host.as_deref(), &state.base_path);
                 drop(state); // release mutex
                 *response.status_mut() = StatusCode::FOUND;
                 response.headers_mut().append(
@@ -133,15 +183,24 @@ pub fn serve_file(
 
     // Serve UI
     if let Some(file) = file {
-        let mime = match file.path().extension() {
+        // The mime type of a precompressed file is the one of the requested file
+        let mime = match Path::new(path).extension() {
             Some(ext) => mime_type_from_ext(ext.to_str().expect("file path extension OsStr->str")),
             None => "application/octet-stream",
         };
-        info!("Serve {} for {}", mime, path);
+        info!("Serve {} for {}", mime, file.path().display());
         response.headers_mut().append(
             "Content-Type",
             HeaderValue::from_str(mime).expect("mime to header value"),
         );
+        if let Some(encoding) = encoding {
+            response
+                .headers_mut()
+                .append("Content-Encoding", HeaderValue::from_static(encoding));
+        }
+        response
+            .headers_mut()
+            .append("Vary", HeaderValue::from_static("Accept-Encoding"));
         *response.body_mut() = file.contents();
         return Ok(response);
     }
@@ -149,3 +208,48 @@ pub fn serve_file(
     *response.status_mut() = StatusCode::NOT_FOUND;
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts_encoding, find_encoded};
+    use std::collections::HashMap;
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts_encoding("gzip, deflate, br", "br"));
+        assert!(accepts_encoding("br;q=0.5", "br"));
+        assert!(!accepts_encoding("gzip, br;q=0", "br"));
+        assert!(!accepts_encoding("gzip", "br"));
+    }
+
+    #[test]
+    fn precompressed_selection() {
+        let files: HashMap<&str, &str> = vec![
+            ("index.js", "identity"),
+            ("index.js.gz", "gzip body"),
+            ("index.js.br", "br body"),
+            ("index.html", "html"),
+            ("index.html.gz", "gzip html"),
+        ]
+        .into_iter()
+        .collect();
+        let find = |path: &str| files.get(path).cloned();
+
+        // br is preferred over gzip over identity
+        assert_eq!(
+            find_encoded("index.js", Some("gzip, deflate, br"), &find),
+            Some(("br body", Some("br")))
+        );
+        assert_eq!(
+            find_encoded("index.js", Some("gzip"), &find),
+            Some(("gzip body", Some("gzip")))
+        );
+        assert_eq!(find_encoded("index.js", None, &find), Some(("identity", None)));
+        // Falls back to gzip if there is no br sibling
+        assert_eq!(
+            find_encoded("index.html", Some("br, gzip"), &find),
+            Some(("gzip html", Some("gzip")))
+        );
+        assert_eq!(find_encoded("missing.js", Some("br"), &find), None);
+    }
+}
@@ -1,28 +1,43 @@
 //! A hyper based http server that serves the "ui" directory. It also provides a server-send-event
 //! endpoint at /events for live updates on discovered access points.
+//! The captive portal api (RFC 8908) is served at /captive-portal-api.
+//! The hotspot ssid and network are served at /portal-info.
+//! The version and network backend of this service are served at /version.
+//! Details of a single network, all of its access points merged, are served at /network/{ssid}.
+//! The number of event stream clients and listed access points is capped. Their usage is served at /status.
+//! A connection attempt can be cancelled at /cancel-connect.
 //!
 //! ## Crossmodule usage
 //! This module uses the crates error type and uses the
 //! *NetworkManagerEvent*, *WifiConnections* and *WifiConnectionEvent* structs
 //! of the network manager module.
 
+use bytes::Bytes;
 use hyper::header::HeaderValue;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, body::HttpBody, Method, Request, Response, Server, StatusCode};
-use std::net::{SocketAddr, SocketAddrV4};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::path::PathBuf;
-use std::time::Duration;
-use serde::Deserialize;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
 
 use futures_util::future::Either;
 use futures_util::future::try_select;
 use tokio::time::delay_for;
 
 use super::errors::CaptivePortalError;
+use super::log_buffer::LogBuffer;
 use super::network_backend::NetworkBackend;
-use super::network_interface::{WifiConnectionEvent, WifiConnectionEventType, WifiConnections};
+use super::network_interface::{
+    credentials_from_data, ssid_bytes, unless_cancelled, AccessPointCredentials, ConnectCancel, ConnectProgress,
+    ConnectProgressChannel, CredentialProfile, CredentialStore, RadioState, ScanOutcome, Security, WifiConnection,
+    WifiConnectionEvent, WifiConnectionEventType, WifiConnections, SSID,
+};
+use super::utils::{parse_hex, portal_url};
 
 mod file_serve;
 pub(crate) mod sse;
@@ -32,35 +47,611 @@ pub struct WifiConnectionRequest {
     /// wpa, wep, open, enterprise
     pub mode: String,
     pub ssid: String,
+    /// The raw ssid as listed in "/networks", for networks whose ssid is not valid UTF-8
+    pub ssid_hex: Option<String>,
     pub identity: Option<String>,
     pub passphrase: Option<String>,
+    /// Enterprise only: The eap method, "peap" (default) or "ttls"
+    pub eap: Option<String>,
+    /// Enterprise only: The phase2 authentication, eg "mschapv2" (default), "gtc" or "pap"
+    pub phase2: Option<String>,
+    /// Enterprise only: The authentication server certificate must match this domain suffix, eg "example.com"
+    pub domain_suffix_match: Option<String>,
+    /// Enterprise only: The PEM encoded CA certificate of the authentication server
+    pub ca_cert: Option<String>,
     pub hw: Option<String>,
+    /// Only associate with the access point given by "hw", even if other access points share the ssid
+    #[serde(default)]
+    pub lock_bssid: bool,
+    /// A credential profile that provides the values not given by this request, eg the identity and passphrase
+    pub profile: Option<String>,
+}
+
+impl WifiConnectionRequest {
+    /// The raw bytes of the requested ssid, see [`ssid_bytes`]
+    pub fn ssid_bytes(&self) -> Result<Vec<u8>, CaptivePortalError> {
+        ssid_bytes(&self.ssid, self.ssid_hex.as_deref())
+    }
+
+    /// Validates the given passphrase and identity against the requested security mode.
+    /// Open networks are connected without credentials. A passphrase is rejected for those.
+    /// Without a passphrase, the saved connection of a secured network is used.
+    ///
+    /// A referenced credential profile is resolved with the given store and must be bound to the requested ssid.
+    /// Values of the request take precedence. Unknown profiles and profiles that can not be used for this request
+    /// fail with the same error, so that clients can not probe for profile names.
+    pub fn credentials(
+        &self,
+        store: Option<&dyn CredentialStore>,
+    ) -> Result<AccessPointCredentials, CaptivePortalError> {
+        let mode = Security::try_from(self.mode.clone())?;
+        let name = match self.profile {
+            Some(ref name) => name,
+            None => return self.credentials_with_profile(CredentialProfile::default(), mode),
+        };
+        store
+            .and_then(|store| store.resolve(name).ok())
+            .filter(|profile| profile.ssids.contains(&self.ssid))
+            .and_then(|profile| self.credentials_with_profile(profile, mode).ok())
+            .ok_or_else(|| CaptivePortalError::InvalidCredentialProfile(name.clone()))
+    }
+
+    fn credentials_with_profile(
+        &self,
+        profile: CredentialProfile,
+        mode: Security,
+    ) -> Result<AccessPointCredentials, CaptivePortalError> {
+        let given =
+            |value: &Option<String>, fallback: Option<String>| value.clone().filter(|v| !v.is_empty()).or(fallback);
+        let passphrase = given(&self.passphrase, profile.passphrase);
+
+        let open = match mode {
+            Security::NONE => true,
+            _ => false,
+        };
+        if !open && passphrase.is_none() {
+            return Ok(AccessPointCredentials::Saved);
+        }
+        credentials_from_data(
+            passphrase,
+            given(&self.identity, profile.identity),
+            given(&self.eap, profile.eap),
+            given(&self.phase2, profile.phase2),
+            given(&self.domain_suffix_match, profile.domain_suffix_match),
+            given(&self.ca_cert, profile.ca_cert).map(String::into_bytes),
+            mode,
+        )
+    }
+}
+
+/// Parses and validates the body of a "/connect" request. Malformed requests, an empty ssid and credentials
+/// that do not fit the network security are rejected, before the portal is asked to quit.
+/// Returns the request and its credentials.
+fn parse_connect_request(
+    body: &[u8],
+    store: Option<&dyn CredentialStore>,
+) -> Result<(WifiConnectionRequest, AccessPointCredentials), CaptivePortalError> {
+    let parsed: WifiConnectionRequest = serde_json::from_slice(body)?;
+    if parsed.ssid.is_empty() {
+        return Err(CaptivePortalError::Generic("No ssid given".to_owned()));
+    }
+    parsed.ssid_bytes()?;
+    let credentials = parsed.credentials(store)?;
+    Ok((parsed, credentials))
+}
+
+/// A passphrase may only be omitted for secured networks with a saved connection, given by `saved_ssids`,
+/// or if the referenced credential profile provides one.
+fn verify_saved_connection(ssid: &SSID, saved_ssids: &[SSID]) -> Result<(), CaptivePortalError> {
+    if !saved_ssids.contains(ssid) {
+        return Err(CaptivePortalError::PassphraseRequired);
+    }
+    Ok(())
+}
+
+/// Returns the time until the portal has been up for `min_uptime`, if any.
+fn min_uptime_remaining(started: Instant, min_uptime: Duration, now: Instant) -> Option<Duration> {
+    let remaining = min_uptime.checked_sub(now.duration_since(started))?;
+    if remaining == Duration::from_secs(0) {
+        return None;
+    }
+    Some(remaining)
+}
+
+/// A request of the http server to the state machine. The http server quits after sending it.
+#[derive(Debug)]
+pub enum PortalRequest {
+    /// The user selected a wifi connection in the UI
+    Connect(Box<WifiConnectionRequest>),
+    /// All wifi connections have been forgotten via "/reset". The portal should be restarted.
+    Reset,
+}
+
+/// Receives the request of the http server, or None if the server quit without a request
+pub type PortalRequestReceiver = tokio::sync::oneshot::Receiver<Option<PortalRequest>>;
+
+/// A command of the http server to the portal, which stays open while the command is processed.
+/// The portal answers via the contained sender. A dropped sender means the command has been rejected.
+#[derive(Debug)]
+pub enum PortalCommand {
+    /// Try to connect to one of the known wifi connections once.
+    /// Answers true if a connection could be established.
+    AutoConnect(tokio::sync::oneshot::Sender<bool>),
+}
+
+/// A requested scan that did not complete within this time is reported as failed
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time for access point events to arrive after a scan completed
+const SCAN_SETTLE_DELAY: Duration = Duration::from_secs(1);
+
+/// The response of "/captive-portal-api" (RFC 8908)
+#[derive(Serialize)]
+struct CaptivePortalApiResponse {
+    captive: bool,
+    #[serde(rename = "user-portal-url")]
+    user_portal_url: String,
+}
+
+/// Returns the uri of the captive portal api (RFC 8908) of a http server listening on the given address
+/// with the given host name and normalized base path. This is the uri that is announced via dhcp option 114.
+pub fn captive_portal_api_uri(server_addr: &SocketAddrV4, host: Option<&str>, base_path: &str) -> String {
+    // The portal is only served via http
+    portal_url(false, host, server_addr, &format!("{}/captive-portal-api", base_path))
+}
+
+/// Called by clients that discovered the captive portal api via dhcp option 114.
+/// The client is always captive while the portal is open.
+fn captive_portal_api(state: &HttpServerStateSync) -> Result<Response<Body>, CaptivePortalError> {
+    let state = state.lock().expect("http state mutex lock");
+    let data = serde_json::to_string(&CaptivePortalApiResponse {
+        captive: true,
+        user_portal_url: file_serve::index_location(&state.server_addr, state.portal_host.as_deref(), &state.base_path),
+    })?;
+    drop(state); // release mutex
+
+    let mut response = Response::new(Body::from(data));
+    let headers = response.headers_mut();
+    headers.append("content-type", HeaderValue::from_static("application/captive+json"));
+    headers.append("cache-control", HeaderValue::from_static("private"));
+    Ok(response)
+}
+
+/// The response of "/portal-info"
+#[derive(Serialize)]
+struct PortalInfoResponse {
+    gateway: String,
+    ssid: String,
+    subnet_prefix: u8,
+    portal_url: String,
+}
+
+impl PortalInfoResponse {
+    fn new(state: &HttpServerState) -> PortalInfoResponse {
+        PortalInfoResponse {
+            gateway: state.server_addr.ip().to_string(),
+            ssid: state.ssid.clone(),
+            subnet_prefix: state.subnet_prefix,
+            portal_url: file_serve::index_location(&state.server_addr, state.portal_host.as_deref(), &state.base_path),
+        }
+    }
+}
+
+/// Called by the UI to show connection instructions for the hotspot.
+fn portal_info(state: &HttpServerStateSync) -> Result<Response<Body>, CaptivePortalError> {
+    let state = state.lock().expect("http state mutex lock");
+    let data = serde_json::to_string(&PortalInfoResponse::new(&state))?;
+    drop(state); // release mutex
+
+    let mut response = Response::new(Body::from(data));
+    response
+        .headers_mut()
+        .append("content-type", HeaderValue::from_static("application/json"));
+    Ok(response)
+}
+
+/// Decodes a percent encoded url path segment into raw bytes, which are not necessarily valid UTF-8.
+/// Returns None if the encoding is invalid.
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Some(bytes)
+}
+
+/// Returns the details of all access points of the network "/network/{ssid}", merged into one entry.
+///
+/// The ssid is percent encoded raw bytes. Like for connection requests, a "ssid_hex" query parameter
+/// with the hex encoded raw ssid takes precedence. Networks with a ssid that is not valid UTF-8 can be
+/// requested either way.
+async fn network_details(
+    state: &HttpServerStateSync,
+    ssid: &str,
+    query: Option<&str>,
+) -> Result<Response<Body>, CaptivePortalError> {
+    let mut response = Response::new(Body::empty());
+    let ssid_hex = query.and_then(|query| query.split('&').find(|param| param.starts_with("ssid_hex=")));
+    let ssid = match ssid_hex {
+        Some(param) => parse_hex(&param["ssid_hex=".len()..]),
+        None => percent_decode(ssid),
+    };
+    let ssid = match ssid {
+        Some(ssid) if !ssid.is_empty() && ssid.len() <= 32 => ssid,
+        _ => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(response);
+        },
+    };
+    let nm = state.lock().expect("http state mutex lock").network_manager.clone();
+    match nm.network_details(&ssid).await? {
+        Some(details) => {
+            *response.body_mut() = Body::from(serde_json::to_string(&details)?);
+            response
+                .headers_mut()
+                .append("content-type", HeaderValue::from_static("application/json"));
+        },
+        None => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+    Ok(response)
+}
+
+/// The response of "/autoconnect"
+#[derive(Serialize)]
+struct AutoConnectResponse {
+    connected: bool,
 }
 
 /// The http server.
 pub struct HttpServer {
     exit_handler: tokio::sync::oneshot::Receiver<()>,
-    connection_receiver: tokio::sync::oneshot::Receiver<Option<WifiConnectionRequest>>,
+    connection_receiver: PortalRequestReceiver,
     /// The server state.
     pub state: HttpServerStateSync,
     pub server_addr: SocketAddrV4,
     pub ui_path: PathBuf,
+    /// Serve on this unix domain socket instead of the tcp server address, eg for a fronting web server
+    pub unix_socket: Option<PathBuf>,
 }
 
 /// The http server state including the wifi connection list.
 pub struct HttpServerState {
     /// If the user selected a connection in the UI, this sender will be called
-    connection_sender: Option<tokio::sync::oneshot::Sender<Option<WifiConnectionRequest>>>,
-    pub connections: WifiConnections,
+    connection_sender: Option<tokio::sync::oneshot::Sender<Option<PortalRequest>>>,
+    /// The listed access points. Only changed by the methods below, which keep the networks cache valid.
+    connections: WifiConnections,
+    /// The serialized access point list served at "/networks"
+    networks_cache: NetworksCache,
     pub server_addr: SocketAddrV4,
+    /// The ssid of the hotspot
+    pub ssid: String,
+    /// The subnet prefix length of the hotspot network
+    pub subnet_prefix: u8,
     pub sse: sse::Clients,
+    /// Number of event stream clients served since the server started
+    pub sse_clients_served: usize,
     pub network_manager: NetworkBackend,
+    /// The token that guards "/reset". The endpoint is disabled if this is not set.
+    pub reset_token: Option<String>,
+    /// Access points with a lower signal strength in percent are not listed
+    pub min_strength: u8,
+    /// Commands like "/autoconnect" are send to the portal via this channel
+    pub command_sender: Option<tokio::sync::mpsc::Sender<PortalCommand>>,
+    /// Set while a user requested auto connect attempt runs. Connection requests are rejected meanwhile.
+    auto_connect_running: bool,
+    /// Access points that have not been seen for this duration are removed from the list. Disabled if not set.
+    pub ap_ttl: Option<Duration>,
+    /// Request paths that are answered with "204 No Content". See [`is_noise_path`].
+    pub noise_paths: Vec<String>,
+    /// The in-memory log lines served at "/logs". The endpoint is disabled if this is not set.
+    pub log_buffer: Option<LogBuffer>,
+    /// The token that guards "/logs". The endpoint is disabled if this is not set.
+    pub logs_token: Option<String>,
+    /// The path prefix of all routes, eg "/portal". Empty for no prefix. See [`normalize_base_path`].
+    pub base_path: String,
+    /// The host name in urls that point to the portal. The server address is used if not set.
+    pub portal_host: Option<String>,
+    /// When the http server has been created
+    pub started: Instant,
+    /// A connection request is only passed to the portal after the server has been up for this duration
+    pub min_uptime: Duration,
+    /// The oldest server-send-event client is disconnected if a new one would exceed this number
+    pub max_sse_clients: usize,
+    /// The least recently seen access point is removed from the list if a new one would exceed this number
+    pub max_networks: usize,
+    /// The progress of connection attempts. The last one is reported at "/status".
+    pub connect_progress: ConnectProgressChannel,
+    /// Cancels the running connection attempt or a delayed connection request
+    pub connect_cancel: ConnectCancel,
+    /// Resolves the credential profiles of connection requests
+    pub credential_store: Option<Arc<dyn CredentialStore>>,
 }
 
+impl HttpServerState {
+    /// The listed access points
+    pub fn networks(&self) -> &[WifiConnection] {
+        &self.connections.0
+    }
+
+    /// Lists the given access points. Access points below the minimum signal strength are not listed.
+    pub fn add_networks(&mut self, access_points: impl IntoIterator<Item = WifiConnection>) {
+        let min_strength = self.min_strength;
+        self.connections
+            .0
+            .extend(access_points.into_iter().filter(|ap| ap.strength >= min_strength));
+        self.networks_cache.invalidate();
+    }
+
+    /// The serialized list served at "/networks"
+    fn networks_json(&mut self) -> Result<Bytes, serde_json::Error> {
+        self.networks_cache.get(&self.connections)
+    }
+
+    /// Applies the network event to the list, see [`apply_network_event`].
+    fn update_networks(&mut self, event: &WifiConnectionEvent) -> Option<WifiConnectionEventType> {
+        let event_type = apply_network_event(&mut self.connections.0, event, self.min_strength);
+        if event_type.is_some() {
+            self.networks_cache.invalidate();
+        }
+        event_type
+    }
+
+    /// Removes access points that have not been seen for the given duration and returns them
+    fn prune_networks(&mut self, now: Instant, ttl: Duration) -> Vec<WifiConnection> {
+        let removed = remove_stale_networks(&mut self.connections.0, now, ttl);
+        if !removed.is_empty() {
+            self.networks_cache.invalidate();
+        }
+        removed
+    }
+
+    /// Removes the least recently seen access points above the maximum number and returns them
+    fn evict_networks(&mut self) -> Vec<WifiConnection> {
+        let evicted = evict_oldest_networks(&mut self.connections.0, self.max_networks);
+        if !evicted.is_empty() {
+            self.networks_cache.invalidate();
+        }
+        evicted
+    }
+}
+
+/// The response of "/status": The resource usage of the http server state
+#[derive(Serialize, Debug)]
+struct StatusResponse {
+    sse_clients: usize,
+    max_sse_clients: usize,
+    networks: usize,
+    max_networks: usize,
+    /// The approximate memory usage of the server-send-event clients and the access point list
+    approx_bytes: usize,
+    /// True if the access point list is empty
+    no_networks: bool,
+    /// If the access point list is empty: True if wifi is blocked, which explains the empty list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rfkill: Option<bool>,
+    /// The progress of the last connection attempt, eg the reason why it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_progress: Option<ConnectProgress>,
+}
+
+impl StatusResponse {
+    fn new(state: &HttpServerState) -> StatusResponse {
+        StatusResponse {
+            sse_clients: state.sse.len(),
+            max_sse_clients: state.max_sse_clients,
+            networks: state.networks().len(),
+            max_networks: state.max_networks,
+            approx_bytes: sse::approx_bytes(&state.sse) + approx_networks_bytes(state.networks()),
+            no_networks: state.networks().is_empty(),
+            rfkill: None,
+            connect_progress: state.connect_progress.last(),
+        }
+    }
+}
+
+/// The serialized access point list of "/networks". Polling clients get the same snapshot, until the list
+/// changed and the cache has been invalidated.
+#[derive(Default)]
+struct NetworksCache(Option<Bytes>);
+
+impl NetworksCache {
+    /// Returns the cached json of the list or serializes the given list, if the cache has been invalidated
+    fn get(&mut self, connections: &WifiConnections) -> Result<Bytes, serde_json::Error> {
+        if let Some(json) = self.0.as_ref() {
+            return Ok(json.clone());
+        }
+        let json = Bytes::from(serde_json::to_vec(connections)?);
+        self.0 = Some(json.clone());
+        Ok(json)
+    }
+
+    fn invalidate(&mut self) {
+        self.0 = None;
+    }
+}
+
+/// The approximate memory usage of the given access point list in bytes
+fn approx_networks_bytes(connections: &[WifiConnection]) -> usize {
+    connections
+        .iter()
+        .map(|c| std::mem::size_of::<WifiConnection>() + c.ssid.capacity() + c.hw.capacity())
+        .sum()
+}
+
+/// The default maximum number of server-send-event clients. The oldest client is disconnected if exceeded.
+const DEFAULT_MAX_SSE_CLIENTS: usize = 16;
+/// The default maximum number of listed access points. The least recently seen one is removed if exceeded.
+const DEFAULT_MAX_NETWORKS: usize = 128;
+
 /// The thread safe wrapper around the http server state.
 pub type HttpServerStateSync = Arc<Mutex<HttpServerState>>;
 
+/// Normalizes a configured base path like "portal/" to the form "/portal".
+/// An empty or "/" base path means no prefix and is returned as empty string.
+pub fn normalize_base_path(base_path: &str) -> String {
+    let trimmed = base_path.trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+/// Strips the normalized base path from the given request path.
+/// Returns None if the request path is not below the base path.
+fn strip_base_path<'a>(base_path: &str, path: &'a str) -> Option<&'a str> {
+    if base_path.is_empty() {
+        return Some(path);
+    }
+    if !path.starts_with(base_path) {
+        return None;
+    }
+    match &path[base_path.len()..] {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+/// Returns true if the given request path is one of the noise paths, like "/favicon.ico".
+/// A noise path with a trailing "*" matches all paths with that prefix.
+///
+/// Browsers request those paths on their own. Redirecting them to the portal page
+/// only causes log noise and may confuse the captive portal detection.
+fn is_noise_path(noise_paths: &[String], path: &str) -> bool {
+    noise_paths.iter().any(|noise_path| {
+        if noise_path.ends_with('*') {
+            path.starts_with(&noise_path[..noise_path.len() - 1])
+        } else {
+            noise_path == path
+        }
+    })
+}
+
+/// Returns a "204 No Content" response if the given request path is a noise path and None otherwise.
+fn noise_response(noise_paths: &[String], path: &str) -> Option<Response<Body>> {
+    if !is_noise_path(noise_paths, path) {
+        return None;
+    }
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+    Some(response)
+}
+
+/// The liveness probe path. It is independent of the base path.
+const HEALTHZ_PATH: &str = "/healthz";
+
+/// Returns "200 OK" with body "ok" for GET requests of the liveness probe path and None otherwise.
+/// Does not access the http state, so that the probe never blocks on the state mutex.
+fn healthz_response(method: &Method, path: &str) -> Option<Response<Body>> {
+    if method != Method::GET || path != HEALTHZ_PATH {
+        return None;
+    }
+    Some(Response::new(Body::from("ok")))
+}
+
+/// The version path. Like the liveness probe, it is independent of the base path.
+const VERSION_PATH: &str = "/version";
+
+/// The response of "/version"
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    /// The network backend, "networkmanager" or "iwd"
+    backend: &'static str,
+    /// The enabled cargo features besides the backend
+    features: Vec<&'static str>,
+}
+
+impl VersionResponse {
+    fn new() -> VersionResponse {
+        let mut features = Vec::new();
+        if cfg!(feature = "includeui") {
+            features.push("includeui");
+        }
+        VersionResponse {
+            version: env!("CARGO_PKG_VERSION"),
+            backend: crate::network_backend::BACKEND_NAME,
+            features,
+        }
+    }
+}
+
+/// Returns the version and backend of this service as json for GET requests of the version path
+/// and None otherwise. Does not access the http state.
+fn version_response(method: &Method, path: &str) -> Result<Option<Response<Body>>, CaptivePortalError> {
+    if method != Method::GET || path != VERSION_PATH {
+        return Ok(None);
+    }
+    let mut response = Response::new(Body::from(serde_json::to_string(&VersionResponse::new())?));
+    response
+        .headers_mut()
+        .append("content-type", HeaderValue::from_static("application/json"));
+    Ok(Some(response))
+}
+
+/// Sends the given scan progress to all server-send-event clients.
+fn send_scan_event(state: &HttpServerStateSync, event: sse::ScanEvent) {
+    let mut state = state.lock().expect("http state mutex lock");
+    sse::send_scan_event(&mut state.sse, &event).expect("json encoding failed");
+}
+
+/// The event for a completed scan with `count` listed access points. An empty list is reported as "no_networks",
+/// explained by the radio state if known.
+fn scan_complete_event(count: usize, radio_state: Option<RadioState>) -> sse::ScanEvent {
+    match count {
+        0 => sse::ScanEvent::NoNetworks {
+            rfkill: radio_state.map_or(false, |radio_state| radio_state.rfkill()),
+        },
+        count => sse::ScanEvent::ScanComplete { count },
+    }
+}
+
+/// The event for the currently listed access points, after a scan completed.
+///
+/// ## Crossmodule usage
+/// This method calls into the network manager if no access points are listed
+async fn listed_networks_event(state: &HttpServerStateSync, nm: &NetworkBackend) -> sse::ScanEvent {
+    let count = state.lock().expect("http state mutex lock").networks().len();
+    let radio_state = match count {
+        0 => nm.radio_state().await.ok(),
+        _ => None,
+    };
+    scan_complete_event(count, radio_state)
+}
+
+/// Reports a scan request that did not start a new scan and returns the response status.
+/// Returns None if a scan has been started. Its completion must be awaited.
+async fn report_scan_request(
+    state: &HttpServerStateSync,
+    nm: &NetworkBackend,
+    scan: Result<ScanOutcome, CaptivePortalError>,
+) -> Option<StatusCode> {
+    match scan {
+        Ok(ScanOutcome::Scanned) => None,
+        // The access points of the last scan are listed already
+        Ok(ScanOutcome::RateLimited) => {
+            send_scan_event(state, listed_networks_event(state, nm).await);
+            Some(StatusCode::OK)
+        },
+        // Some network adapters do not allow a scan while a hotspot is running
+        Err(e) => {
+            send_scan_event(state, sse::ScanEvent::ScanFailed { reason: e.to_string() });
+            Some(StatusCode::INTERNAL_SERVER_ERROR)
+        },
+    }
+}
+
 /// Called when the user requests a wifi list refresh via /refresh.
+/// The scan progress is reported via server-send-events: "scan_started" and either
+/// "scan_complete" with the number of listed access points, "no_networks" with the rfkill state
+/// or "scan_failed" with a reason. If the last scan is recent, its access points are reported right away.
 ///
 /// ## Crossmodule usage
 /// This method calls into the network manager
@@ -69,17 +660,224 @@ pub async fn user_requests_wifi_list_refresh(state: HttpServerStateSync) -> Stat
         Ok(state) => state.network_manager.clone(),
         Err(_) => return StatusCode::INTERNAL_SERVER_ERROR,
     };
-    if let Ok(_) = nm.scan_networks().await {
+    let last_scan = nm.last_scan().await.unwrap_or(-1);
+    send_scan_event(&state, sse::ScanEvent::ScanStarted);
+    if let Some(status) = report_scan_request(&state, &nm, nm.scan_networks().await).await {
+        return status;
+    }
+
+    tokio::spawn(async move {
+        let event = match nm.wait_for_scan(last_scan, SCAN_TIMEOUT).await {
+            Ok(true) => {
+                // Found access points are added to the list via access point events. Give them time to arrive.
+                delay_for(SCAN_SETTLE_DELAY).await;
+                listed_networks_event(&state, &nm).await
+            },
+            Ok(false) => sse::ScanEvent::ScanFailed {
+                reason: "Timeout".to_owned(),
+            },
+            Err(e) => sse::ScanEvent::ScanFailed { reason: e.to_string() },
+        };
+        send_scan_event(&state, event);
+    });
+    StatusCode::OK
+}
+
+/// Returns true if the request carries the given token in an "Authorization: Bearer <token>" header.
+/// The token is compared in constant time, so the response time does not reveal a matching prefix.
+fn has_bearer_token(req: &Request<Body>, token: &str) -> bool {
+    const PREFIX: &[u8] = b"Bearer ";
+    let value = match req.headers().get(hyper::header::AUTHORIZATION) {
+        Some(value) => value.as_bytes(),
+        None => return false,
+    };
+    if !value.starts_with(PREFIX) {
+        return false;
+    }
+    let value = &value[PREFIX.len()..];
+    let token = token.as_bytes();
+    // Only the length of the token can be learned from the timing
+    value.len() == token.len() && value.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Renders the given log lines as json array if the query contains "format=json" and as plain text otherwise.
+fn render_logs(lines: Vec<String>, query: Option<&str>) -> Result<Response<Body>, CaptivePortalError> {
+    let json = query
+        .map(|query| query.split('&').any(|param| param == "format=json"))
+        .unwrap_or(false);
+    let (content_type, data) = if json {
+        ("application/json", serde_json::to_string(&lines)?)
+    } else {
+        ("text/plain; charset=utf-8", lines.join("\n"))
+    };
+    let mut response = Response::new(Body::from(data));
+    let headers = response.headers_mut();
+    headers.append("content-type", HeaderValue::from_static(content_type));
+    headers.append("cache-control", HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+/// Called when the user requests the last log lines via /logs.
+fn user_requests_logs(state: &HttpServerStateSync, req: &Request<Body>) -> Result<Response<Body>, CaptivePortalError> {
+    let (log_buffer, logs_token) = {
+        let state = state.lock().expect("http state mutex lock");
+        (state.log_buffer.clone(), state.logs_token.clone())
+    };
+    let mut response = Response::new(Body::empty());
+    // The logs contain ssids, client addresses and dns queries. They are never served without a token.
+    let (log_buffer, logs_token) = match (log_buffer, logs_token) {
+        (Some(log_buffer), Some(logs_token)) => (log_buffer, logs_token),
+        _ => {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        },
+    };
+    if !has_bearer_token(req, &logs_token) {
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        return Ok(response);
+    }
+    // The lines are a copy. The buffer is not locked while the response is rendered.
+    render_logs(log_buffer.lines(), req.uri().query())
+}
+
+/// Called when the user requests to forget all wifi connections via /reset.
+/// Hotspot connections are only forgotten if the query contains "include_hotspots=true".
+///
+/// ## Crossmodule usage
+/// This method calls into the network manager
+async fn user_requests_reset(
+    state: HttpServerStateSync,
+    req: &Request<Body>,
+) -> Result<StatusCode, CaptivePortalError> {
+    let (reset_token, connect_in_progress, nm) = {
+        let state = state.lock().expect("http state mutex lock");
+        (
+            state.reset_token.clone(),
+            state.connection_sender.is_none(),
+            state.network_manager.clone(),
+        )
+    };
+    let reset_token = match reset_token {
+        Some(reset_token) => reset_token,
+        None => return Ok(StatusCode::NOT_FOUND),
+    };
+    if !has_bearer_token(req, &reset_token) {
+        return Ok(StatusCode::UNAUTHORIZED);
+    }
+    if connect_in_progress {
+        return Ok(StatusCode::CONFLICT);
+    }
+
+    let include_hotspots = req
+        .uri()
+        .query()
+        .map(|query| query.split('&').any(|param| param == "include_hotspots=true"))
+        .unwrap_or(false);
+    let deleted = nm.forget_all_connections(include_hotspots).await?;
+    info!("Reset: Forgot {} wifi connections", deleted);
+
+    let sender = state.lock().expect("http state mutex lock").connection_sender.take();
+    match sender {
+        Some(sender) => {
+            sender
+                .send(Some(PortalRequest::Reset))
+                .map_err(|_| CaptivePortalError::HttpRoutingFailed)?;
+            Ok(StatusCode::OK)
+        },
+        None => Ok(StatusCode::CONFLICT),
+    }
+}
+
+/// Marks the running auto connect attempt of "/autoconnect". The mark is removed when the request
+/// completes or is dropped, eg because the client disconnected.
+struct AutoConnectRunning(HttpServerStateSync);
+
+impl Drop for AutoConnectRunning {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.auto_connect_running = false;
+        }
+    }
+}
+
+/// Called when the user requests an immediate connection attempt to known wifi connections via /autoconnect.
+/// Responds with the outcome as json after the portal performed the attempt.
+/// Only one attempt at a time is possible and none if the user already selected a wifi connection.
+/// Connection requests are rejected while the attempt runs.
+async fn user_requests_auto_connect(state: HttpServerStateSync) -> Result<Response<Body>, CaptivePortalError> {
+    let mut response = Response::new(Body::empty());
+    let mut command_sender = {
+        let mut state = state.lock().expect("http state mutex lock");
+        if state.connection_sender.is_none() || state.auto_connect_running {
+            *response.status_mut() = StatusCode::CONFLICT;
+            return Ok(response);
+        }
+        match state.command_sender.clone() {
+            Some(command_sender) => {
+                state.auto_connect_running = true;
+                command_sender
+            },
+            None => {
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                return Ok(response);
+            },
+        }
+    };
+    let _running = AutoConnectRunning(state.clone());
+
+    let (result_sender, result_receiver) = tokio::sync::oneshot::channel::<bool>();
+    command_sender
+        .send(PortalCommand::AutoConnect(result_sender))
+        .await
+        .map_err(|_| CaptivePortalError::HttpRoutingFailed)?;
+
+    match result_receiver.await {
+        Ok(connected) => {
+            let data = serde_json::to_string(&AutoConnectResponse { connected })?;
+            response
+                .headers_mut()
+                .append("content-type", HeaderValue::from_static("application/json"));
+            *response.body_mut() = Body::from(data);
+        },
+        // The portal rejected the attempt, because another one is in progress or the portal is closing
+        Err(_) => *response.status_mut() = StatusCode::CONFLICT,
+    }
+    Ok(response)
+}
+
+/// Takes the sender for the connection request. Only the first of several "/connect" submissions,
+/// eg of a double click or of two devices, gets it. The others are rejected with "409 Conflict".
+fn take_connection_sender<T>(connection_sender: &mut Option<T>) -> Result<T, StatusCode> {
+    connection_sender.take().ok_or(StatusCode::CONFLICT)
+}
+
+/// Sends the published progress of connection attempts to all server-send-event clients as "connect" events.
+pub(crate) async fn forward_connect_progress(
+    state: HttpServerStateSync,
+    mut progress: tokio::sync::mpsc::UnboundedReceiver<ConnectProgress>,
+) {
+    while let Some(progress) = progress.recv().await {
+        let mut state = state.lock().expect("http state mutex lock");
+        sse::send_connect_progress(&mut state.sse, &progress).expect("json encoding failed");
+    }
+}
+
+/// Called when the user requests to cancel a connection attempt via /cancel-connect.
+/// A connection request that is delayed until the minimum portal uptime is dropped and the portal keeps running.
+/// Responds with "409 Conflict" if there is nothing to cancel.
+fn user_requests_cancel_connect(state: &HttpServerStateSync) -> StatusCode {
+    let connect_cancel = state.lock().expect("http state mutex lock").connect_cancel.clone();
+    if connect_cancel.cancel() {
         StatusCode::OK
     } else {
-        // Some network adapters do not allow a scan while a hotspot is running
-        StatusCode::INTERNAL_SERVER_ERROR
+        StatusCode::CONFLICT
     }
 }
 
-/// Routes to one of the dynamic routes "/networks" (list of wifi networks),
-/// "/events" (server send events), "/refresh" (requests a wifi scan) and "/connect".
-/// "/connect" will exit the http server and make the future of the outer state
+/// Routes to "/healthz" (liveness probe) or one of the dynamic routes "/networks" (list of wifi networks),
+/// "/events" (server send events), "/refresh" (requests a wifi scan), "/captive-portal-api",
+/// "/portal-info", "/logs", "/autoconnect", "/connect", "/cancel-connect" and "/reset".
+/// "/connect" and "/reset" will exit the http server and make the future of the outer state
 /// machine to resolve.
 async fn http_router(
     state: HttpServerStateSync,
@@ -87,30 +885,84 @@ async fn http_router(
     req: Request<Body>,
     src: SocketAddr,
 ) -> Result<Response<Body>, CaptivePortalError> {
+    // Answered before anything else, the state mutex is not locked
+    if let Some(response) = healthz_response(req.method(), req.uri().path()) {
+        return Ok(response);
+    }
+    if let Some(response) = version_response(req.method(), req.uri().path())? {
+        return Ok(response);
+    }
+
     let mut response = Response::new(Body::empty());
 
+    // Requests outside of the base path are treated like requests for unknown files
+    let path = {
+        let state = state.lock().expect("http state mutex lock");
+        let path = strip_base_path(&state.base_path, req.uri().path())
+            .unwrap_or("")
+            .to_owned();
+        if let Some(response) = noise_response(&state.noise_paths, &path) {
+            return Ok(response);
+        }
+        path
+    };
+
     if req.method() == Method::GET {
-        if req.uri().path() == "/networks" {
-            let state = state.lock().expect("http state mutex lock");
-            let data = serde_json::to_string(&state.connections)?;
-            drop(state); // release mutex
+        if path == "/networks" {
+            let data = state.lock().expect("http state mutex lock").networks_json()?; // release mutex
             response
                 .headers_mut()
                 .append("content-type", HeaderValue::from_static("application/json"));
             *response.body_mut() = Body::from(data);
             return Ok(response);
-        } else if req.uri().path() == "/events" {
+        } else if path == "/events" {
             let mut state = state.lock().expect("http state mutex lock");
-            let result = sse::create_stream(&mut state.sse, src.ip());
+            let max_clients = state.max_sse_clients;
+            let result = sse::create_stream(&mut state.sse, src.ip(), max_clients);
+            state.sse_clients_served += 1;
             return Ok(result);
-        } else if req.uri().path() == "/refresh" {
+        } else if path == "/status" {
+            let (mut status, nm) = {
+                let state = state.lock().expect("http state mutex lock");
+                (StatusResponse::new(&state), state.network_manager.clone())
+            };
+            if status.no_networks {
+                // The status is reported, even if the radio state is not available
+                status.rfkill = nm.radio_state().await.ok().map(|radio_state| radio_state.rfkill());
+            }
+            let data = serde_json::to_string(&status)?;
+            response
+                .headers_mut()
+                .append("content-type", HeaderValue::from_static("application/json"));
+            *response.body_mut() = Body::from(data);
+            return Ok(response);
+        } else if path.starts_with("/network/") {
+            return network_details(&state, &path["/network/".len()..], req.uri().query()).await;
+        } else if path == "/refresh" {
             *response.status_mut() = user_requests_wifi_list_refresh(state.clone()).await;
             return Ok(response);
+        } else if path == "/captive-portal-api" {
+            return captive_portal_api(&state);
+        } else if path == "/portal-info" {
+            return portal_info(&state);
+        } else if path == "/logs" {
+            return user_requests_logs(&state, &req);
         }
 
-        return file_serve::serve_file(&ui_path, response, &req, &state);
+        return file_serve::serve_file(&ui_path, response, &path, &req, &state);
     }
-    if req.method() == Method::POST && req.uri().path() == "/connect" {
+    if req.method() == Method::POST && path == "/autoconnect" {
+        return user_requests_auto_connect(state).await;
+    }
+    if req.method() == Method::POST && path == "/reset" {
+        *response.status_mut() = user_requests_reset(state, &req).await?;
+        return Ok(response);
+    }
+    if req.method() == Method::POST && path == "/cancel-connect" {
+        *response.status_mut() = user_requests_cancel_connect(&state);
+        return Ok(response);
+    }
+    if req.method() == Method::POST && path == "/connect" {
         // Body is a stream of chunks of bytes.
         let mut body = req.into_body();
         let mut output = Vec::new();
@@ -120,15 +972,79 @@ async fn http_router(
             output.extend(&bytes[..]);
         }
 
-        let parsed: WifiConnectionRequest = serde_json::from_slice(&output[..])?;
-        let mut state = state.lock().expect("http state mutex lock");
-        let sender = state.connection_sender.take().expect("http state mutex lock");
+        let (nm, credential_store) = {
+            let state = state.lock().expect("http state mutex lock");
+            (state.network_manager.clone(), state.credential_store.clone())
+        };
+
+        // Reject malformed requests before the portal quits
+        let parsed = match parse_connect_request(&output[..], credential_store.as_deref()) {
+            // Returning users may connect without retyping the passphrase of a saved connection
+            Ok((parsed, AccessPointCredentials::Saved)) => {
+                let saved_ssids: Vec<SSID> = match nm.connection_history().await {
+                    Ok(history) => history.into_iter().map(|entry| entry.ssid).collect(),
+                    Err(e) => {
+                        warn!("Failed to list saved connections: {}", e);
+                        Vec::new()
+                    },
+                };
+                verify_saved_connection(&parsed.ssid, &saved_ssids).map(|_| parsed)
+            },
+            Ok((parsed, _)) => Ok(parsed),
+            Err(e) => Err(e),
+        };
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                *response.body_mut() = Body::from(e.to_string());
+                return Ok(response);
+            },
+        };
+        let mut state_guard = state.lock().expect("http state mutex lock");
+        if state_guard.auto_connect_running {
+            info!("Connection request rejected. An auto connect attempt is in progress");
+            *response.status_mut() = StatusCode::CONFLICT;
+            *response.body_mut() = Body::from("An auto connect attempt is in progress");
+            return Ok(response);
+        }
+        let sender = match take_connection_sender(&mut state_guard.connection_sender) {
+            Ok(sender) => sender,
+            Err(status) => {
+                info!("Connection request rejected. A connection attempt is already in progress");
+                *response.status_mut() = status;
+                *response.body_mut() = Body::from("A connection attempt is already in progress");
+                return Ok(response);
+            },
+        };
+        let remaining = min_uptime_remaining(state_guard.started, state_guard.min_uptime, Instant::now());
+        let connect_cancel = state_guard.connect_cancel.clone();
         // release mutex as soon as possible
-        drop(state);
+        drop(state_guard);
 
-        sender
-            .send(Some(parsed))
-            .map_err(|_| CaptivePortalError::HttpRoutingFailed)?;
+        match remaining {
+            // The portal stays up until the minimum uptime is reached. The request is passed on afterwards,
+            // unless it is cancelled before.
+            Some(remaining) => {
+                info!("Connection request accepted. Applied in {}s", remaining.as_secs());
+                let mut cancelled = connect_cancel.start();
+                tokio::spawn(async move {
+                    match unless_cancelled(Box::pin(delay_for(remaining)), &mut cancelled).await {
+                        Some(()) => {
+                            connect_cancel.finish();
+                            let _ = sender.send(Some(PortalRequest::Connect(Box::new(parsed))));
+                        },
+                        None => {
+                            info!("Connection request cancelled");
+                            state.lock().expect("http state mutex lock").connection_sender = Some(sender);
+                        },
+                    }
+                });
+            },
+            None => sender
+                .send(Some(PortalRequest::Connect(Box::new(parsed))))
+                .map_err(|_| CaptivePortalError::HttpRoutingFailed)?,
+        }
         *response.status_mut() = StatusCode::OK;
         return Ok(response);
     }
@@ -138,14 +1054,25 @@ async fn http_router(
 }
 
 impl HttpServer {
+    /// Rejects connection requests with "409 Conflict", eg while a connection attempt is running.
+    /// Returns the sender of connection requests. The server quits if it is dropped.
+    pub(crate) fn reject_connection_requests(&self) -> Option<tokio::sync::oneshot::Sender<Option<PortalRequest>>> {
+        self.state
+            .lock()
+            .expect("http state mutex lock")
+            .connection_sender
+            .take()
+    }
+
     pub fn into(
         self,
     ) -> (
         tokio::sync::oneshot::Receiver<()>,
-        tokio::sync::oneshot::Receiver<Option<WifiConnectionRequest>>,
+        PortalRequestReceiver,
         HttpServerStateSync,
         SocketAddrV4,
         PathBuf,
+        Option<PathBuf>,
     ) {
         (
             self.exit_handler,
@@ -153,6 +1080,7 @@ impl HttpServer {
             self.state,
             self.server_addr,
             self.ui_path,
+            self.unix_socket,
         )
     }
 
@@ -166,7 +1094,7 @@ impl HttpServer {
         ui_path: PathBuf,
     ) -> (HttpServer, tokio::sync::oneshot::Sender<()>) {
         let (tx, exit_handler) = tokio::sync::oneshot::channel::<()>();
-        let (connection_sender, connection_receiver) = tokio::sync::oneshot::channel::<Option<WifiConnectionRequest>>();
+        let (connection_sender, connection_receiver) = tokio::sync::oneshot::channel::<Option<PortalRequest>>();
 
         (
             HttpServer {
@@ -177,10 +1105,32 @@ impl HttpServer {
                     connection_sender: Some(connection_sender),
                     network_manager: nm,
                     connections: WifiConnections(Vec::new()),
+                    networks_cache: NetworksCache::default(),
                     server_addr,
+                    ssid: String::new(),
+                    subnet_prefix: 24,
                     sse: sse::new(),
+                    sse_clients_served: 0,
+                    reset_token: None,
+                    min_strength: 0,
+                    command_sender: None,
+                    auto_connect_running: false,
+                    base_path: String::new(),
+                    portal_host: None,
+                    ap_ttl: None,
+                    noise_paths: Vec::new(),
+                    log_buffer: None,
+                    logs_token: None,
+                    started: Instant::now(),
+                    min_uptime: Duration::from_secs(0),
+                    max_sse_clients: DEFAULT_MAX_SSE_CLIENTS,
+                    max_networks: DEFAULT_MAX_NETWORKS,
+                    connect_progress: ConnectProgressChannel::default(),
+                    connect_cancel: ConnectCancel::default(),
+                    credential_store: None,
                 })),
                 ui_path,
+                unix_socket: None,
             },
             tx,
         )
@@ -189,29 +1139,15 @@ impl HttpServer {
     /// Consumes the server object and runs it until it receives an exit signal via
     /// the [`tokio::sync::oneshot::Sender`] returned by [`new`]. Also quits the server
     /// when
-    pub async fn run(self: HttpServer) -> Result<Option<WifiConnectionRequest>, super::CaptivePortalError> {
+    pub async fn run(self: HttpServer) -> Result<Option<PortalRequest>, super::CaptivePortalError> {
         // Consume the HttpServer by destructuring into its parts
-        let (exit_handler, connection_receiver, state, server_addr, ui_path) = self.into();
+        let (exit_handler, connection_receiver, state, server_addr, ui_path, unix_socket) = self.into();
 
         // We need a cloned state for each future in this method
         let state_for_ping = state.clone();
 
-        let make_service = make_service_fn(move |socket: &AddrStream| {
-            let remote_addr = socket.remote_addr();
-            // There is a future constructed in this future. Time to clone again.
-            let state = state.clone();
-            let ui_path = ui_path.clone();
-            async move {
-                let fun = service_fn(move |req| http_router(state.clone(), ui_path.clone(), req, remote_addr));
-                Ok::<_, hyper::Error>(fun)
-            }
-        });
-
-        // Construct server and bind it
-        let server = Server::bind(&SocketAddr::V4(server_addr.clone())).serve(make_service);
-
-        // A graceful shutdown state: This only contains the wifi connection request, if any.
-        type GracefulShutdownRequestState = Option<WifiConnectionRequest>;
+        // A graceful shutdown state: This only contains the portal request, if any.
+        type GracefulShutdownRequestState = Option<PortalRequest>;
         let graceful_shutdown_state = Arc::new(Mutex::new(GracefulShutdownRequestState::None));
 
         // The clone will be consumed by the graceful shutdown future
@@ -236,6 +1172,9 @@ impl HttpServer {
                 }
                 let mut state = state_for_ping.lock().expect("http state mutex lock");
                 sse::ping(&mut state.sse);
+                if let Some(ap_ttl) = state.ap_ttl {
+                    prune_stale_networks(&mut state, Instant::now(), ap_ttl);
+                }
             }
             // After the not-so-endless loop finished: Close all server-send-event connections.
             // Without closing them, the graceful shutdown future would never resolve.
@@ -243,7 +1182,7 @@ impl HttpServer {
             sse::close_all(&mut state.sse);
         });
 
-        let graceful = server.with_graceful_shutdown(async move {
+        let shutdown = async move {
             // We either shutdown when the exit_handler got called OR when we received a connection
             // request by the user.
             let r = try_select(exit_handler, connection_receiver).await;
@@ -275,11 +1214,34 @@ impl HttpServer {
             // Stop server-send-events keep alive and refresh request future
             let _ = keep_alive_exit.send(());
             ()
-        });
+        };
 
-        info!("Started http server on {}", &server_addr);
-        graceful.await?;
-        info!("Stopped http server on {}", &server_addr);
+        match unix_socket {
+            Some(path) => {
+                let router = move |req, remote_addr| http_router(state.clone(), ui_path.clone(), req, remote_addr);
+                info!("Started http server on {}", path.display());
+                serve_unix_socket(&path, router, shutdown).await?;
+                info!("Stopped http server on {}", path.display());
+            },
+            None => {
+                let make_service = make_service_fn(move |socket: &AddrStream| {
+                    let remote_addr = socket.remote_addr();
+                    // There is a future constructed in this future. Time to clone again.
+                    let state = state.clone();
+                    let ui_path = ui_path.clone();
+                    async move {
+                        let fun = service_fn(move |req| http_router(state.clone(), ui_path.clone(), req, remote_addr));
+                        Ok::<_, hyper::Error>(fun)
+                    }
+                });
+
+                // Construct server and bind it
+                let server = Server::try_bind(&SocketAddr::V4(server_addr))?.serve(make_service);
+                info!("Started http server on {}", &server_addr);
+                server.with_graceful_shutdown(shutdown).await?;
+                info!("Stopped http server on {}", &server_addr);
+            },
+        }
 
         // Extract the graceful shutdown state
         let mut state: MutexGuard<GracefulShutdownRequestState> = graceful_shutdown_state
@@ -289,29 +1251,993 @@ impl HttpServer {
     }
 }
 
+/// Serves the given router on a unix domain socket at "path" until "shutdown" resolves.
+/// A stale socket file of a previous run is replaced. The socket file is removed on shutdown.
+///
+/// Requests via the unix socket are treated like local requests. A unix socket connection has no
+/// remote address, each connection is given its own loopback address instead. Event streams
+/// of different connections must not replace each other.
+async fn serve_unix_socket<R, F>(
+    path: &std::path::Path,
+    router: R,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), CaptivePortalError>
+where
+    R: Fn(Request<Body>, SocketAddr) -> F + Clone + Send + 'static,
+    F: Future<Output = Result<Response<Body>, CaptivePortalError>> + Send + 'static,
+{
+    let _ = std::fs::remove_file(path);
+    let mut listener = tokio::net::UnixListener::bind(path)?;
+    let mut connections: u32 = 0;
+    let make_service = make_service_fn(move |_socket: &tokio::net::UnixStream| {
+        connections = connections.wrapping_add(1);
+        let remote_addr = unix_socket_remote_addr(connections);
+        let router = router.clone();
+        async move { Ok::<_, hyper::Error>(service_fn(move |req| router(req, remote_addr))) }
+    });
+    let result = Server::builder(hyper::server::accept::from_stream(listener.incoming()))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await;
+    let _ = std::fs::remove_file(path);
+    Ok(result?)
+}
+
+/// The loopback address of the n-th unix socket connection, within 127.0.0.1 to 127.255.255.254
+fn unix_socket_remote_addr(connection: u32) -> SocketAddr {
+    let host = connection % 0x00ff_fffe + 1;
+    SocketAddr::from((Ipv4Addr::from(0x7f00_0000 | host), 0))
+}
+
+/// Applies a network event to the given list of networks. Access points with a signal strength
+/// below "min_strength" are not listed and considered removed.
+///
+/// Returns the effective event type or None if the list did not change.
+fn apply_network_event(
+    connections: &mut Vec<WifiConnection>,
+    event: &WifiConnectionEvent,
+    min_strength: u8,
+) -> Option<WifiConnectionEventType> {
+    let event_type = match event.event {
+        WifiConnectionEventType::Added | WifiConnectionEventType::Changed
+            if event.access_point.strength < min_strength =>
+        {
+            WifiConnectionEventType::Removed
+        },
+        event_type => event_type,
+    };
+    match (
+        connections.iter().position(|n| n.ssid == event.access_point.ssid),
+        event_type,
+    ) {
+        (Some(pos), WifiConnectionEventType::Added) | (Some(pos), WifiConnectionEventType::Changed) => {
+            use std::mem;
+            let dest = connections
+                .get_mut(pos)
+                .expect("update_network: Vector access on connections");
+            mem::replace(dest, event.access_point.clone());
+        },
+        (Some(pos), WifiConnectionEventType::Removed) => {
+            connections.remove(pos);
+        },
+        (None, WifiConnectionEventType::Added) => {
+            connections.push(event.access_point.clone());
+        },
+        // The access point became strong enough to be listed
+        (None, WifiConnectionEventType::Changed) => {
+            connections.push(event.access_point.clone());
+            return Some(WifiConnectionEventType::Added);
+        },
+        (None, WifiConnectionEventType::Removed) => return None,
+    };
+    Some(event_type)
+}
+
+/// Removes access points that have not been seen within the given ttl from the given list.
+/// Returns the removed access points.
+fn remove_stale_networks(connections: &mut Vec<WifiConnection>, now: Instant, ttl: Duration) -> Vec<WifiConnection> {
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < connections.len() {
+        if now.duration_since(connections[i].last_seen) > ttl {
+            removed.push(connections.remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+/// Removes the least recently seen access points until the list has at most `max` entries.
+/// Returns the removed access points.
+fn evict_oldest_networks(connections: &mut Vec<WifiConnection>, max: usize) -> Vec<WifiConnection> {
+    let mut removed = Vec::new();
+    while connections.len() > max {
+        let oldest = connections
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, c)| c.last_seen)
+            .map(|(i, _)| i)
+            .expect("Non empty list");
+        removed.push(connections.remove(oldest));
+    }
+    removed
+}
+
+/// Removes stale access points from the list and notifies the clients.
+/// Access points might vanish without a removed signal, for example with flaky drivers.
+fn prune_stale_networks(state: &mut HttpServerState, now: Instant, ttl: Duration) {
+    for access_point in state.prune_networks(now, ttl) {
+        info!("Remove stale network {}", &access_point.ssid);
+        let event = WifiConnectionEvent {
+            access_point,
+            event: WifiConnectionEventType::Removed,
+        };
+        sse::send_wifi_connection(&mut state.sse, &event).expect("json encoding failed");
+    }
+}
+
 /// Call this method to update, add, remove a network
-pub async fn update_network(http_state: HttpServerStateSync, event: WifiConnectionEvent) {
+pub async fn update_network(http_state: HttpServerStateSync, mut event: WifiConnectionEvent) {
+    event.access_point.last_seen = Instant::now();
     let mut state = http_state.lock().expect("Mutex lock for http state on update_network");
     info!("Add network {}", &event.access_point.ssid);
-    let ref mut connections = state.connections.0;
-    match connections.iter().position(|n| n.ssid == event.access_point.ssid) {
-        Some(pos) => {
-            match event.event {
-                WifiConnectionEventType::Added => {
-                    use std::mem;
-                    let dest = connections
-                        .get_mut(pos)
-                        .expect("update_network: Vector access on connections");
-                    mem::replace(dest, event.access_point.clone());
+    if let Some(event_type) = state.update_networks(&event) {
+        let event = WifiConnectionEvent {
+            access_point: event.access_point,
+            event: event_type,
+        };
+        sse::send_wifi_connection(&mut state.sse, &event).expect("json encoding failed");
+    }
+    for access_point in state.evict_networks() {
+        info!("Network list full. Remove {}", &access_point.ssid);
+        let event = WifiConnectionEvent {
+            access_point,
+            event: WifiConnectionEventType::Removed,
+        };
+        sse::send_wifi_connection(&mut state.sse, &event).expect("json encoding failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::file_serve::index_location;
+    use super::sse;
+    use super::{
+        apply_network_event, approx_networks_bytes, captive_portal_api_uri, evict_oldest_networks, has_bearer_token,
+        healthz_response, http_router, is_noise_path, listed_networks_event, min_uptime_remaining, noise_response,
+        normalize_base_path, parse_connect_request, percent_decode, remove_stale_networks, render_logs,
+        report_scan_request, scan_complete_event, serve_unix_socket, strip_base_path, unix_socket_remote_addr,
+        verify_saved_connection, version_response, HttpServer, HttpServerStateSync, PortalCommand, PortalInfoResponse,
+        PortalRequest, WifiConnectionRequest,
+    };
+    use crate::log_buffer::LogBuffer;
+    use crate::network_interface::{
+        AccessPointCredentials, CredentialProfile, CredentialStore, RadioState, ScanOutcome, WifiConnection,
+        WifiConnectionEvent, WifiConnectionEventType,
+    };
+    use crate::{CaptivePortalError, NetworkBackend};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+    use tokio::sync::oneshot;
+
+    /// A http server state for router tests and the receiver of its portal requests.
+    /// Requests must not call into the network backend, it never answers.
+    fn test_state() -> (HttpServerStateSync, oneshot::Receiver<Option<PortalRequest>>) {
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 80);
+        let (server, _exit) = HttpServer::new(server_addr, NetworkBackend::unanswered(), PathBuf::new());
+        let (_, connection_receiver, state, ..) = server.into();
+        (state, connection_receiver)
+    }
+
+    /// Routes the request as if it came from a hotspot client
+    async fn route(state: &HttpServerStateSync, method: Method, path: &str, body: &str) -> Response<Body> {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let src = SocketAddr::from(([192, 168, 42, 10], 50000));
+        http_router(state.clone(), PathBuf::new(), req, src)
+            .await
+            .expect("Routed request")
+    }
+
+    fn access_point(ssid: &str, strength: u8) -> WifiConnection {
+        WifiConnection {
+            ssid: ssid.to_owned(),
+            ssid_hex: None,
+            hw: String::new(),
+            security: "wpa",
+            strength,
+            frequency: 2412,
+            is_own: false,
+            last_seen: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn connect_open_network() {
+        let request: WifiConnectionRequest =
+            serde_json::from_str(r#"{"mode":"open","ssid":"cafe","identity":"ignored"}"#).unwrap();
+        match request.credentials(None) {
+            Ok(AccessPointCredentials::None) => {},
+            r => panic!("Expected no credentials. Got {:?}", r),
+        }
+
+        let request: WifiConnectionRequest =
+            serde_json::from_str(r#"{"mode":"open","ssid":"cafe","passphrase":"a_password"}"#).unwrap();
+        match request.credentials(None) {
+            Err(CaptivePortalError::PassphraseNotAllowedForOpen) => {},
+            r => panic!("Expected PassphraseNotAllowedForOpen. Got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connect_saved_network() {
+        // No passphrase: The saved connection is activated, if there is one
+        let saved = vec!["home".to_owned()];
+        let (request, credentials) = parse_connect_request(br#"{"mode":"wpa","ssid":"home"}"#, None).unwrap();
+        match credentials {
+            AccessPointCredentials::Saved => {},
+            r => panic!("Expected saved credentials. Got {:?}", r),
+        }
+        assert!(verify_saved_connection(&request.ssid, &saved).is_ok());
+        let (request, _) = parse_connect_request(br#"{"mode":"wpa","ssid":"office","passphrase":""}"#, None).unwrap();
+        match verify_saved_connection(&request.ssid, &saved) {
+            Err(CaptivePortalError::PassphraseRequired) => {},
+            r => panic!("Expected PassphraseRequired. Got {:?}", r),
+        }
+
+        // A given passphrase replaces the saved one
+        match parse_connect_request(br#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#, None) {
+            Ok((_, AccessPointCredentials::Wpa { .. })) => {},
+            r => panic!("Expected wpa credentials. Got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_without_saved_connection_lookup() {
+        // Only requests without a passphrase look up the saved connections. The backend never answers,
+        // so these requests would not complete otherwise.
+        let (state, mut connection_receiver) = test_state();
+        let response = route(&state, Method::POST, "/connect", "garbage").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = route(&state, Method::POST, "/connect", r#"{"mode":"wpa","ssid":"home"}"#);
+        let response = tokio::time::timeout(Duration::from_millis(100), response).await;
+        assert!(response.is_err(), "Expected a lookup of saved connections");
+
+        let body = r#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#;
+        let response = route(&state, Method::POST, "/connect", body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        match connection_receiver.try_recv() {
+            Ok(Some(PortalRequest::Connect(request))) => assert_eq!(request.ssid, "home"),
+            r => panic!("Expected a connection request. Got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_during_auto_connect() {
+        let (state, mut connection_receiver) = test_state();
+        let (command_sender, mut commands) = tokio::sync::mpsc::channel(1);
+        state.lock().unwrap().command_sender = Some(command_sender);
+        let body = r#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#;
+
+        let auto_connect = tokio::spawn({
+            let state = state.clone();
+            async move { route(&state, Method::POST, "/autoconnect", "").await }
+        });
+        let result_sender = match commands.recv().await {
+            Some(PortalCommand::AutoConnect(result_sender)) => result_sender,
+            None => panic!("Expected an auto connect command"),
+        };
+        let response = route(&state, Method::POST, "/connect", body).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = route(&state, Method::POST, "/autoconnect", "").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(connection_receiver.try_recv().is_err());
+
+        // Accepted again after the attempt failed
+        result_sender.send(false).unwrap();
+        let response = auto_connect.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = route(&state, Method::POST, "/connect", body).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        match connection_receiver.try_recv() {
+            Ok(Some(PortalRequest::Connect(request))) => assert_eq!(request.ssid, "home"),
+            r => panic!("Expected a connection request. Got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_during_connect_attempt() {
+        // The listener of a running connection attempt
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 80);
+        let (server, _exit) = HttpServer::new(server_addr, NetworkBackend::unanswered(), PathBuf::new());
+        let _connection_sender = server.reject_connection_requests();
+        let (_, _, state, ..) = server.into();
+        let mut cancelled = state.lock().unwrap().connect_cancel.start();
+
+        let body = r#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#;
+        let response = route(&state, Method::POST, "/connect", body).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = route(&state, Method::POST, "/cancel-connect", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(cancelled.try_recv().is_ok());
+        let response = route(&state, Method::POST, "/cancel-connect", "").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[derive(Debug)]
+    struct TestStore;
+
+    impl CredentialStore for TestStore {
+        fn resolve(&self, profile: &str) -> Result<CredentialProfile, CaptivePortalError> {
+            match profile {
+                "corp" => Ok(CredentialProfile {
+                    ssids: vec!["office".to_owned()],
+                    identity: Some("device-42".to_owned()),
+                    passphrase: Some("a_password".to_owned()),
+                    eap: Some("ttls".to_owned()),
+                    ..Default::default()
+                }),
+                "incomplete" => Ok(CredentialProfile {
+                    ssids: vec!["office".to_owned()],
+                    passphrase: Some("a_password".to_owned()),
+                    ..Default::default()
+                }),
+                _ => Err(CaptivePortalError::InvalidCredentialProfile(profile.to_owned())),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_with_profile() {
+        let store: Option<&dyn CredentialStore> = Some(&TestStore);
+        match parse_connect_request(br#"{"mode":"enterprise","ssid":"office","profile":"corp"}"#, store) {
+            Ok((
+                _,
+                AccessPointCredentials::Enterprise {
+                    identity,
+                    passphrase,
+                    eap,
+                    phase2,
+                    ..
                 },
-                WifiConnectionEventType::Removed => {
-                    connections.remove(pos);
+            )) => {
+                assert_eq!(identity, "device-42");
+                assert_eq!(passphrase, "a_password");
+                assert_eq!(eap.as_deref(), Some("ttls"));
+                assert_eq!(phase2, None);
+            },
+            r => panic!("Expected enterprise credentials. Got {:?}", r),
+        }
+
+        // Values of the request take precedence
+        let request: WifiConnectionRequest =
+            serde_json::from_str(r#"{"mode":"enterprise","ssid":"office","profile":"corp","identity":"admin"}"#)
+                .unwrap();
+        match request.credentials(store) {
+            Ok(AccessPointCredentials::Enterprise { identity, .. }) => assert_eq!(identity, "admin"),
+            r => panic!("Expected enterprise credentials. Got {:?}", r),
+        }
+
+        // Unknown profile, a profile of another network, an incomplete profile or no store
+        let bodies: [&[u8]; 3] = [
+            br#"{"mode":"enterprise","ssid":"office","profile":"other"}"#,
+            br#"{"mode":"enterprise","ssid":"home","profile":"corp"}"#,
+            br#"{"mode":"enterprise","ssid":"office","profile":"incomplete"}"#,
+        ];
+        for body in bodies.iter() {
+            match parse_connect_request(body, store) {
+                Err(e @ CaptivePortalError::InvalidCredentialProfile(_)) => {
+                    assert!(e.to_string().starts_with("Unknown or invalid credential profile"))
                 },
+                r => panic!("Expected InvalidCredentialProfile. Got {:?}", r),
+            }
+        }
+        let body = br#"{"mode":"enterprise","ssid":"office","profile":"corp"}"#;
+        match parse_connect_request(body, None) {
+            Err(CaptivePortalError::InvalidCredentialProfile(_)) => {},
+            r => panic!("Expected InvalidCredentialProfile. Got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_connect() {
+        let (state, mut connection_receiver) = test_state();
+
+        // Two rapid submissions: Exactly one is passed to the portal, the other one is rejected
+        let home = r#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#;
+        let cafe = r#"{"mode":"open","ssid":"cafe"}"#;
+        let (a, b) = futures_util::future::join(
+            route(&state, Method::POST, "/connect", home),
+            route(&state, Method::POST, "/connect", cafe),
+        )
+        .await;
+        let mut statuses = vec![a.status(), b.status()];
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::OK, StatusCode::CONFLICT]);
+        match connection_receiver.try_recv() {
+            Ok(Some(PortalRequest::Connect(request))) => assert!(request.ssid == "home" || request.ssid == "cafe"),
+            r => panic!("Expected a connection request. Got {:?}", r),
+        }
+
+        // Also later ones
+        let response = route(&state, Method::POST, "/connect", home).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn early_garbage_connect() {
+        // Malformed requests are rejected with a 400 before the connection sender is taken.
+        // The server therefore does not shut down.
+        assert!(parse_connect_request(b"", None).is_err());
+        assert!(parse_connect_request(b"garbage", None).is_err());
+        assert!(parse_connect_request(br#"{"mode":"wpa"}"#, None).is_err());
+        assert!(parse_connect_request(br#"{"mode":"open","ssid":""}"#, None).is_err());
+        assert!(parse_connect_request(br#"{"mode":"open","ssid":"cafe","passphrase":"a_password"}"#, None).is_err());
+        let (request, _) = parse_connect_request(br#"{"mode":"open","ssid":"cafe"}"#, None).unwrap();
+        assert_eq!(request.ssid, "cafe");
+
+        // A ssid that is not valid UTF-8 is given by its raw bytes
+        let body = br#"{"mode":"open","ssid":"Caf\ufffd","ssid_hex":"436166e9"}"#;
+        let (request, _) = parse_connect_request(body, None).unwrap();
+        assert_eq!(request.ssid_bytes().unwrap(), vec![b'C', b'a', b'f', 0xe9]);
+        let body = br#"{"mode":"open","ssid":"Caf\ufffd","ssid_hex":"Caf"}"#;
+        assert!(parse_connect_request(body, None).is_err());
+
+        // A valid early request is delayed until the minimum uptime is reached
+        let started = Instant::now();
+        let min_uptime = Duration::from_secs(30);
+        assert_eq!(
+            min_uptime_remaining(started, min_uptime, started + Duration::from_secs(10)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(min_uptime_remaining(started, min_uptime, started + min_uptime), None);
+        assert_eq!(
+            min_uptime_remaining(started, min_uptime, started + Duration::from_secs(31)),
+            None
+        );
+        assert_eq!(min_uptime_remaining(started, Duration::from_secs(0), started), None);
+    }
+
+    #[test]
+    fn networks_cache() {
+        fn ssids(json: &[u8]) -> Vec<String> {
+            serde_json::from_slice::<Vec<serde_json::Value>>(json)
+                .unwrap()
+                .iter()
+                .map(|n| n["ssid"].as_str().unwrap().to_owned())
+                .collect()
+        }
+        let (state, _) = test_state();
+        let mut state = state.lock().unwrap();
+        state.min_strength = 20;
+        state.max_networks = 3;
+        state.add_networks(vec![access_point("a", 50), access_point("weak", 10)]);
+        let json = state.networks_json().unwrap();
+        assert_eq!(ssids(&json), vec!["a"]);
+
+        // Served from the cache, as long as the list did not change
+        assert_eq!(state.networks_json().unwrap().as_ptr(), json.as_ptr());
+        let event = WifiConnectionEvent {
+            access_point: access_point("weak", 10),
+            event: WifiConnectionEventType::Added,
+        };
+        assert!(state.update_networks(&event).is_none());
+        assert_eq!(state.networks_json().unwrap().as_ptr(), json.as_ptr());
+
+        // Every change invalidates the cache
+        for ssid in &["b", "c", "d"] {
+            let event = WifiConnectionEvent {
+                access_point: access_point(ssid, 50),
+                event: WifiConnectionEventType::Added,
             };
-        },
-        None => {
-            state.connections.0.push(event.access_point.clone());
-        },
-    };
-    sse::send_wifi_connection(&mut state.sse, &event).expect("json encoding failed");
+            assert!(state.update_networks(&event).is_some());
+        }
+        assert_eq!(ssids(&state.networks_json().unwrap()), vec!["a", "b", "c", "d"]);
+        assert_eq!(state.evict_networks().len(), 1);
+        assert_eq!(ssids(&state.networks_json().unwrap()).len(), 3);
+        state.add_networks(vec![access_point("e", 50)]);
+        assert_eq!(ssids(&state.networks_json().unwrap()).len(), 4);
+        let pruned = state.prune_networks(Instant::now() + Duration::from_secs(60), Duration::from_secs(30));
+        assert_eq!(pruned.len(), 4);
+        assert!(ssids(&state.networks_json().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn caps_evict_oldest() {
+        let now = Instant::now();
+        let mut connections = Vec::new();
+        for (i, ssid) in ["a", "b", "c"].iter().enumerate() {
+            let mut ap = access_point(ssid, 50);
+            ap.last_seen = now + Duration::from_secs(i as u64);
+            connections.push(ap);
+        }
+        // "a" has been seen a while ago, but is updated now
+        connections[0].last_seen = now + Duration::from_secs(10);
+        let bytes = approx_networks_bytes(&connections);
+
+        let removed = evict_oldest_networks(&mut connections, 2);
+        let removed: Vec<&str> = removed.iter().map(|c| &c.ssid[..]).collect();
+        assert_eq!(removed, vec!["b"]);
+        let ssids: Vec<&str> = connections.iter().map(|c| &c.ssid[..]).collect();
+        assert_eq!(ssids, vec!["a", "c"]);
+        assert!(approx_networks_bytes(&connections) < bytes);
+        assert!(evict_oldest_networks(&mut connections, 2).is_empty());
+
+        // The oldest server-send-event client is disconnected
+        let mut clients = sse::new();
+        for i in 1..=3 {
+            sse::create_stream(&mut clients, [10, 0, 0, i].into(), 2);
+        }
+        assert_eq!(clients.len(), 2);
+        let bytes = sse::approx_bytes(&clients);
+        // A reconnecting client replaces its old stream
+        sse::create_stream(&mut clients, [10, 0, 0, 3].into(), 2);
+        assert_eq!(clients.len(), 2);
+        assert_eq!(sse::approx_bytes(&clients), bytes);
+    }
+
+    /// Reads the server-send-events of the given stream until one of the given type arrives
+    async fn next_event(events: &mut Body, event_type: &str) -> String {
+        use hyper::body::HttpBody;
+        let pattern = format!(r#""type":"{}""#, event_type);
+        loop {
+            let chunk = tokio::time::timeout(Duration::from_secs(1), events.data())
+                .await
+                .expect("Event within time")
+                .expect("Open event stream")
+                .unwrap();
+            let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+            if chunk.contains(&pattern) {
+                return chunk;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_after_recent_scan() {
+        let (state, _) = test_state();
+        state
+            .lock()
+            .unwrap()
+            .add_networks(vec![access_point("cafe", 60), access_point("home", 80)]);
+        let mut events = route(&state, Method::GET, "/events", "").await.into_body();
+        let nm = state.lock().unwrap().network_manager.clone();
+
+        // The access points of the last scan are reported right away
+        let status = report_scan_request(&state, &nm, Ok(ScanOutcome::RateLimited)).await;
+        assert_eq!(status, Some(StatusCode::OK));
+        let event = next_event(&mut events, "scan_complete").await;
+        assert!(event.contains(r#""count":2"#), "{}", event);
+
+        // A started scan is reported once it completed
+        assert_eq!(report_scan_request(&state, &nm, Ok(ScanOutcome::Scanned)).await, None);
+    }
+
+    #[tokio::test]
+    async fn listed_networks_after_scan() {
+        let (state, _) = test_state();
+        let nm = state.lock().unwrap().network_manager.clone();
+
+        // Listed access points are reported without asking the backend
+        state.lock().unwrap().add_networks(vec![access_point("cafe", 60)]);
+        let event = tokio::time::timeout(Duration::from_millis(100), listed_networks_event(&state, &nm))
+            .await
+            .expect("Event without backend call");
+        let event = serde_json::to_value(&event).unwrap();
+        assert_eq!(event["type"], "scan_complete");
+        assert_eq!(event["count"], 1);
+
+        // An empty scan asks the backend for the radio state, which might explain the empty list
+        let later = Instant::now() + Duration::from_secs(60);
+        assert_eq!(
+            state
+                .lock()
+                .unwrap()
+                .prune_networks(later, Duration::from_secs(30))
+                .len(),
+            1
+        );
+        let event = tokio::time::timeout(Duration::from_millis(100), listed_networks_event(&state, &nm)).await;
+        assert!(event.is_err(), "Expected a radio state query");
+    }
+
+    #[tokio::test]
+    async fn failed_scan() {
+        let (state, _) = test_state();
+        let mut events = route(&state, Method::GET, "/events", "").await.into_body();
+        let nm = state.lock().unwrap().network_manager.clone();
+
+        let scan = Err(CaptivePortalError::Generic("Device busy".to_owned()));
+        let status = report_scan_request(&state, &nm, scan).await;
+        assert_eq!(status, Some(StatusCode::INTERNAL_SERVER_ERROR));
+        let event = next_event(&mut events, "scan_failed").await;
+        assert!(event.contains("Device busy"), "{}", event);
+    }
+
+    #[tokio::test]
+    async fn healthz() {
+        // The probe is answered while another thread holds the state mutex
+        let (state, _) = test_state();
+        let (locked_sender, locked) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                let _guard = state.lock().unwrap();
+                locked_sender.send(()).unwrap();
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        });
+        locked.recv().unwrap();
+        let response = route(&state, Method::GET, "/healthz", "").await;
+        assert!(state.try_lock().is_err(), "The probe waited for the state mutex");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+        holder.join().unwrap();
+
+        assert!(healthz_response(&Method::POST, "/healthz").is_none());
+        assert!(healthz_response(&Method::GET, "/healthz/other").is_none());
+        assert!(healthz_response(&Method::GET, "/index.html").is_none());
+    }
+
+    #[tokio::test]
+    async fn unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let path = std::env::temp_dir().join(format!("wifi-captive-test-{}.sock", std::process::id()));
+        let (state, _connection_receiver) = test_state();
+        let router = {
+            let state = state.clone();
+            move |req, remote_addr| http_router(state.clone(), PathBuf::new(), req, remote_addr)
+        };
+        let (exit, exit_receiver) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                serve_unix_socket(&path, router, async move {
+                    let _ = exit_receiver.await;
+                })
+                .await
+            }
+        });
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK"), "{}", response);
+        assert!(response.ends_with("ok"), "{}", response);
+
+        // Event streams of different connections are kept
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+            stream.write_all(b"GET /events HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = [0u8; 15];
+            stream.read_exact(&mut response).await.unwrap();
+            assert_eq!(&response, b"HTTP/1.1 200 OK");
+            streams.push(stream);
+        }
+        assert_eq!(state.lock().unwrap().sse.len(), 2);
+        sse::close_all(&mut state.lock().unwrap().sse);
+        drop(streams);
+
+        exit.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unix_socket_remote_addresses() {
+        assert_eq!(unix_socket_remote_addr(1), SocketAddr::from(([127, 0, 0, 2], 0)));
+        assert_eq!(
+            unix_socket_remote_addr(0x00ff_fffd),
+            SocketAddr::from(([127, 255, 255, 254], 0))
+        );
+        assert_eq!(
+            unix_socket_remote_addr(0x00ff_fffe),
+            SocketAddr::from(([127, 0, 0, 1], 0))
+        );
+        assert_ne!(unix_socket_remote_addr(1), unix_socket_remote_addr(2));
+    }
+
+    #[tokio::test]
+    async fn version() {
+        let response = version_response(&Method::GET, "/version")
+            .unwrap()
+            .expect("version response");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let version: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(version["backend"], crate::network_backend::BACKEND_NAME);
+        assert!(version["features"].is_array());
+
+        assert!(version_response(&Method::POST, "/version").unwrap().is_none());
+        assert!(version_response(&Method::GET, "/version.html").unwrap().is_none());
+    }
+
+    #[test]
+    fn min_strength_filter() {
+        let mut connections = Vec::new();
+        for (ssid, strength) in &[("strong", 80), ("weak", 10), ("medium", 30)] {
+            let event = WifiConnectionEvent {
+                access_point: access_point(ssid, *strength),
+                event: WifiConnectionEventType::Added,
+            };
+            apply_network_event(&mut connections, &event, 20);
+        }
+        let ssids: Vec<&str> = connections.iter().map(|c| &c.ssid[..]).collect();
+        assert_eq!(ssids, vec!["strong", "medium"]);
+
+        // A listed access point that became too weak is removed
+        let event = WifiConnectionEvent {
+            access_point: access_point("medium", 15),
+            event: WifiConnectionEventType::Added,
+        };
+        match apply_network_event(&mut connections, &event, 20) {
+            Some(WifiConnectionEventType::Removed) => {},
+            _ => panic!("Expected a removed event"),
+        }
+        assert_eq!(connections.len(), 1);
+
+        // Nothing to remove
+        assert!(apply_network_event(&mut connections, &event, 20).is_none());
+    }
+
+    #[test]
+    fn strength_changed() {
+        let mut connections = vec![access_point("listed", 50)];
+        let changed = |ssid: &str, strength: u8| WifiConnectionEvent {
+            access_point: access_point(ssid, strength),
+            event: WifiConnectionEventType::Changed,
+        };
+
+        match apply_network_event(&mut connections, &changed("listed", 60), 20) {
+            Some(WifiConnectionEventType::Changed) => {},
+            _ => panic!("Expected a changed event"),
+        }
+        assert_eq!(connections[0].strength, 60);
+
+        // An unlisted access point became strong enough
+        match apply_network_event(&mut connections, &changed("unlisted", 30), 20) {
+            Some(WifiConnectionEventType::Added) => {},
+            _ => panic!("Expected an added event"),
+        }
+
+        // A listed access point became too weak
+        match apply_network_event(&mut connections, &changed("listed", 10), 20) {
+            Some(WifiConnectionEventType::Removed) => {},
+            _ => panic!("Expected a removed event"),
+        }
+        assert_eq!(connections.len(), 1);
+    }
+
+    #[test]
+    fn stale_networks() {
+        let now = Instant::now();
+        let mut stale = access_point("stale", 50);
+        stale.last_seen = now - Duration::from_secs(60);
+        let mut connections = vec![access_point("fresh", 50), stale];
+
+        let removed = remove_stale_networks(&mut connections, now, Duration::from_secs(30));
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].ssid, "stale");
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].ssid, "fresh");
+
+        assert!(remove_stale_networks(&mut connections, now, Duration::from_secs(30)).is_empty());
+    }
+
+    #[tokio::test]
+    async fn captive_portal_api() {
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 80);
+        assert_eq!(
+            captive_portal_api_uri(&server_addr, None, ""),
+            "http://192.168.42.1/captive-portal-api"
+        );
+
+        let (state, _) = test_state();
+        let response = route(&state, Method::GET, "/captive-portal-api", "").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/captive+json");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(data["captive"], true);
+        assert_eq!(data["user-portal-url"], "http://192.168.42.1/index.html");
+    }
+
+    #[test]
+    fn portal_info() {
+        let (state, _) = test_state();
+        let mut state = state.lock().unwrap();
+        state.ssid = "WiFi Connect".to_owned();
+        state.subnet_prefix = 24;
+        let data = serde_json::to_value(&PortalInfoResponse::new(&state)).unwrap();
+        assert_eq!(data["gateway"], "192.168.42.1");
+        assert_eq!(data["ssid"], "WiFi Connect");
+        assert_eq!(data["subnet_prefix"], 24);
+        assert_eq!(data["portal_url"], "http://192.168.42.1/index.html");
+
+        // Behind a reverse proxy
+        state.portal_host = Some("portal.local".to_owned());
+        state.base_path = "/portal".to_owned();
+        let data = serde_json::to_value(&PortalInfoResponse::new(&state)).unwrap();
+        assert_eq!(data["portal_url"], "http://portal.local/portal/index.html");
+    }
+
+    #[test]
+    fn no_networks() {
+        let blocked = RadioState {
+            enabled: false,
+            hardware_enabled: true,
+        };
+        let event = |count, radio_state| serde_json::to_value(&scan_complete_event(count, radio_state)).unwrap();
+        assert_eq!(event(3, Some(blocked))["type"], "scan_complete");
+        assert_eq!(event(3, Some(blocked))["count"], 3);
+
+        assert_eq!(event(0, Some(blocked))["type"], "no_networks");
+        assert_eq!(event(0, Some(blocked))["rfkill"], true);
+        let hardware_blocked = RadioState {
+            enabled: true,
+            hardware_enabled: false,
+        };
+        assert_eq!(event(0, Some(hardware_blocked))["rfkill"], true);
+        let enabled = RadioState {
+            enabled: true,
+            hardware_enabled: true,
+        };
+        assert_eq!(event(0, Some(enabled))["rfkill"], false);
+        // Unknown radio state
+        assert_eq!(event(0, None)["type"], "no_networks");
+        assert_eq!(event(0, None)["rfkill"], false);
+    }
+
+    #[test]
+    fn network_path_decoding() {
+        assert_eq!(percent_decode("Home").as_deref(), Some(&b"Home"[..]));
+        assert_eq!(percent_decode("My%20Wifi%2F2").as_deref(), Some(&b"My Wifi/2"[..]));
+        assert_eq!(percent_decode("Caf%C3%A9").as_deref(), Some("Café".as_bytes()));
+        // Not valid UTF-8
+        assert_eq!(percent_decode("Caf%E9").as_deref(), Some(&b"Caf\xe9"[..]));
+        assert_eq!(percent_decode("100%"), None);
+        assert_eq!(percent_decode("%zz"), None);
+    }
+
+    #[test]
+    fn base_path() {
+        assert_eq!(normalize_base_path(""), "");
+        assert_eq!(normalize_base_path("/"), "");
+        assert_eq!(normalize_base_path("portal/"), "/portal");
+
+        let base_path = normalize_base_path("/portal/");
+        assert_eq!(strip_base_path(&base_path, "/portal/networks"), Some("/networks"));
+        assert_eq!(strip_base_path(&base_path, "/portal"), Some("/"));
+        assert_eq!(strip_base_path(&base_path, "/portalx/networks"), None);
+        assert_eq!(strip_base_path(&base_path, "/networks"), None);
+        assert_eq!(strip_base_path("", "/networks"), Some("/networks"));
+
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 80);
+        assert_eq!(
+            index_location(&server_addr, None, &base_path),
+            "http://192.168.42.1/portal/index.html"
+        );
+        assert_eq!(
+            captive_portal_api_uri(&server_addr, None, &base_path),
+            "http://192.168.42.1/portal/captive-portal-api"
+        );
+        let server_addr = SocketAddrV4::new(Ipv4Addr::new(192, 168, 42, 1), 8080);
+        assert_eq!(
+            index_location(&server_addr, Some("portal.local"), &base_path),
+            "http://portal.local:8080/portal/index.html"
+        );
+        assert_eq!(
+            captive_portal_api_uri(&server_addr, Some("portal.local"), &base_path),
+            "http://portal.local:8080/portal/captive-portal-api"
+        );
+    }
+
+    #[tokio::test]
+    async fn logs() {
+        use hyper::body::to_bytes;
+
+        let lines = vec!["first".to_owned(), "second".to_owned()];
+        let response = render_logs(lines.clone(), None).unwrap();
+        assert_eq!(response.headers()["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "first\nsecond");
+
+        let response = render_logs(lines, Some("format=json")).unwrap();
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), r#"["first","second"]"#);
+
+        // Never served without a token
+        let (state, _) = test_state();
+        let log_buffer = LogBuffer::new(10);
+        log_buffer.push("first".to_owned());
+        state.lock().unwrap().log_buffer = Some(log_buffer);
+        let response = route(&state, Method::GET, "/logs", "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        state.lock().unwrap().logs_token = Some("a_token".to_owned());
+        let response = route(&state, Method::GET, "/logs", "").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let req = Request::get("/logs")
+            .header("Authorization", "Bearer a_token")
+            .body(Body::empty())
+            .unwrap();
+        let response = http_router(
+            state,
+            PathBuf::new(),
+            req,
+            SocketAddr::from(([192, 168, 42, 10], 50000)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "first");
+    }
+
+    #[test]
+    fn scan_events() {
+        use super::sse::ScanEvent;
+        assert_eq!(
+            serde_json::to_string(&ScanEvent::ScanStarted).unwrap(),
+            r#"{"type":"scan_started"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ScanEvent::ScanComplete { count: 4 }).unwrap(),
+            r#"{"type":"scan_complete","count":4}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ScanEvent::ScanFailed {
+                reason: "Timeout".to_owned()
+            })
+            .unwrap(),
+            r#"{"type":"scan_failed","reason":"Timeout"}"#
+        );
+    }
+
+    #[test]
+    fn noise_paths() {
+        let noise_paths = vec!["/favicon.ico".to_owned(), "/apple-touch-icon*".to_owned()];
+        assert!(is_noise_path(&noise_paths, "/favicon.ico"));
+        assert!(is_noise_path(&noise_paths, "/apple-touch-icon-precomposed.png"));
+        assert!(!is_noise_path(&noise_paths, "/favicon.ico.png"));
+        assert!(!is_noise_path(&noise_paths, "/index.html"));
+        assert!(!is_noise_path(&[], "/favicon.ico"));
+
+        // Noise is not redirected to the portal page
+        let response = noise_response(&noise_paths, "/favicon.ico").expect("No content response");
+        assert_eq!(response.status(), hyper::StatusCode::NO_CONTENT);
+        assert!(response.headers().get("Location").is_none());
+        assert!(noise_response(&noise_paths, "/generate_204").is_none());
+    }
+
+    #[test]
+    fn bearer_token() {
+        let req = Request::post("/reset")
+            .header("Authorization", "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        assert!(has_bearer_token(&req, "secret"));
+        assert!(!has_bearer_token(&req, "other"));
+        assert!(!has_bearer_token(&req, "secreT"));
+        assert!(!has_bearer_token(&req, "secrets"));
+
+        let req = Request::post("/reset")
+            .header("Authorization", "secret")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!has_bearer_token(&req, "secret"));
+
+        let req = Request::post("/reset").body(Body::empty()).unwrap();
+        assert!(!has_bearer_token(&req, "secret"));
+    }
 }
@@ -6,9 +6,10 @@
 
 use hyper::{Body, Response};
 use bytes::Bytes;
+use serde::Serialize;
 use std::net::IpAddr;
 
-use crate::network_interface::WifiConnectionEvent;
+use crate::network_interface::{ConnectProgress, WifiConnectionEvent};
 use std::collections::LinkedList;
 
 pub type Clients = LinkedList<Client>;
@@ -46,6 +47,44 @@ pub fn send_wifi_connection(
     Ok(())
 }
 
+/// The progress of a wifi scan that has been requested via "/refresh".
+/// Send as "scan" event, eg `{"type":"scan_complete","count":4}`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScanEvent {
+    ScanStarted,
+    /// The scan completed. `count` is the number of listed access points.
+    ScanComplete {
+        count: usize,
+    },
+    /// The scan completed without any access point. `rfkill` is true if wifi is blocked.
+    NoNetworks {
+        rfkill: bool,
+    },
+    ScanFailed {
+        reason: String,
+    },
+}
+
+pub fn send_scan_event(clients: &mut Clients, event: &ScanEvent) -> Result<(), serde_json::error::Error> {
+    let message = format!("retry: 3000\nevent: scan\ndata: {}\n\n", serde_json::to_string(event)?);
+    push_to_all_clients(clients, message);
+    Ok(())
+}
+
+/// Sends the progress of a connection attempt as "connect" event, eg `{"type":"authenticating"}`.
+pub fn send_connect_progress(
+    clients: &mut Clients,
+    progress: &ConnectProgress,
+) -> Result<(), serde_json::error::Error> {
+    let message = format!(
+        "retry: 3000\nevent: connect\ndata: {}\n\n",
+        serde_json::to_string(progress)?
+    );
+    push_to_all_clients(clients, message);
+    Ok(())
+}
+
 /// Push a message for the event to all clients registered on the channel.
 ///
 /// The message is first serialized and then send to all registered
@@ -67,16 +106,28 @@ fn push_to_all_clients(clients: &mut Clients, chunk: String) {
     }
 }
 
+/// The approximate memory usage of the given clients in bytes
+pub fn approx_bytes(clients: &Clients) -> usize {
+    clients.len() * std::mem::size_of::<Client>()
+}
+
 /// Initiate a new SSE stream for the given request and request IP.
 /// Each IP can only have one stream. If there is already an existing one,
 /// the old one will be closed and overwritten.
-pub fn create_stream(clients: &mut Clients, src: IpAddr) -> Response<Body> {
+/// If there are already `max_clients` streams, the oldest ones are closed.
+pub fn create_stream(clients: &mut Clients, src: IpAddr, max_clients: usize) -> Response<Body> {
     let (sender, body) = Body::channel();
 
     let drained = clients.drain_filter(|client| client.dest == src);
     for client in drained {
         client.tx.abort();
     }
+    while clients.len() >= max_clients.max(1) {
+        if let Some(client) = clients.pop_front() {
+            info!("SSE Client evicted: {:?}", &client.dest);
+            client.tx.abort();
+        }
+    }
     clients.push_back(Client { tx: sender, dest: src });
 
     info!("SSE Client added: {:?}. Clients: {}", src, clients.len());
@@ -10,6 +10,7 @@ mod network_interface;
 mod utils;
 
 pub mod config;
+pub mod log_buffer;
 pub mod portal;
 pub mod state_machine;
 
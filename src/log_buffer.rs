@@ -0,0 +1,158 @@
+//! # In-memory log ring buffer
+//! A [`log::Log`] implementation that forwards all records to env_logger and additionally keeps
+//! the last lines in a ring buffer. The buffer is served by the http server at "/logs".
+//!
+//! The buffer mutex is private and only held for pushing a preformatted line or copying
+//! all lines out. No code that might log runs while the lock is held.
+
+use log::{Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A thread safe ring buffer of log lines. Clones share the same buffer.
+#[derive(Clone, Debug, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    /// Creates a buffer that keeps the last `capacity` lines.
+    pub fn new(capacity: usize) -> LogBuffer {
+        LogBuffer {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Appends a line and drops the oldest one if the buffer is full.
+    pub fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        // A poisoned lock only means that another thread panicked while pushing. The buffer is still usable.
+        let mut lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// Returns a copy of all buffered lines, the oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        let lines = match self.lines.lock() {
+            Ok(lines) => lines,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        lines.iter().cloned().collect()
+    }
+}
+
+/// Forwards to env_logger and tees every enabled record into a [`LogBuffer`].
+pub struct TeeLogger {
+    inner: env_logger::Logger,
+    buffer: LogBuffer,
+}
+
+impl TeeLogger {
+    pub fn new(inner: env_logger::Logger, buffer: LogBuffer) -> TeeLogger {
+        TeeLogger { inner, buffer }
+    }
+
+    /// Installs this logger as global logger. The max level is taken from the env_logger filter.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.inner.filter());
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+/// Formats a record as "<unix timestamp> <LEVEL> <target>: <message>".
+fn format_record(record: &Record) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!(
+        "{} {:<5} {}: {}",
+        timestamp,
+        record.level(),
+        record.target(),
+        record.args()
+    )
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        // Format before locking the buffer. Formatting may invoke Display impls that log themselves.
+        let line = format_record(record);
+        self.buffer.push(line);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogBuffer, TeeLogger};
+    use log::{Level, Log, Record};
+
+    #[test]
+    fn ring_buffer() {
+        let buffer = LogBuffer::new(2);
+        buffer.push("a".to_owned());
+        buffer.push("b".to_owned());
+        buffer.push("c".to_owned());
+        assert_eq!(buffer.lines(), vec!["b", "c"]);
+
+        // Clones share the lines
+        buffer.clone().push("d".to_owned());
+        assert_eq!(buffer.lines(), vec!["c", "d"]);
+
+        let disabled = LogBuffer::new(0);
+        disabled.push("a".to_owned());
+        assert!(disabled.lines().is_empty());
+    }
+
+    #[test]
+    fn tee_logger() {
+        let buffer = LogBuffer::new(10);
+        let inner = env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Info)
+            .is_test(true)
+            .build();
+        let logger = TeeLogger::new(inner, buffer.clone());
+
+        logger.log(
+            &Record::builder()
+                .level(Level::Info)
+                .target("dhcp")
+                .args(format_args!("lease {}", 1))
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .level(Level::Debug)
+                .target("dhcp")
+                .args(format_args!("filtered"))
+                .build(),
+        );
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("INFO  dhcp: lease 1"));
+    }
+}
@@ -4,8 +4,10 @@
 use dbus::arg::RefArg;
 use dbus::message::SignalArgs;
 use dbus::{nonblock, Path};
+use futures_util::stream::BoxStream;
 use futures_util::StreamExt;
-use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
 use std::net::{Shutdown, SocketAddr};
 use std::str::FromStr;
 use tokio::future::FutureExt;
@@ -34,6 +36,31 @@ impl From<&str> for NetworkManagerState {
     }
 }
 
+/// Returns the changed station state, if the given properties change contains one
+fn station_state(changed: &PropertiesPropertiesChanged) -> Option<NetworkManagerState> {
+    if changed.interface_name != "net.connman.iwd.Station" {
+        return None;
+    }
+    let state = changed.changed_properties.get("State")?.as_str()?;
+    Some(NetworkManagerState::from(state))
+}
+
+/// The station state of a properties changed signal of the wifi device object, if it contains one.
+/// Unlike [`PropertiesPropertiesChanged`] this type is `Send` and can be used with a [`SignalStream`].
+struct StationStateChanged(Option<NetworkManagerState>);
+
+impl dbus::arg::ReadAll for StationStateChanged {
+    fn read(i: &mut dbus::arg::Iter) -> Result<Self, dbus::arg::TypeMismatchError> {
+        let changed: PropertiesPropertiesChanged = dbus::arg::ReadAll::read(i)?;
+        Ok(StationStateChanged(station_state(&changed)))
+    }
+}
+
+impl SignalArgs for StationStateChanged {
+    const NAME: &'static str = PropertiesPropertiesChanged::NAME;
+    const INTERFACE: &'static str = PropertiesPropertiesChanged::INTERFACE;
+}
+
 impl NetworkBackend {
     /// Continuously print connection state changes
     #[allow(dead_code)]
@@ -71,6 +98,39 @@ impl NetworkBackend {
         Ok(())
     }
 
+    /// Use the host and port of the given uri for the internet connectivity check.
+    /// The default target is kept if no uri is given.
+    pub async fn set_connectivity_check_uri(&self, uri: Option<&str>) -> Result<(), CaptivePortalError> {
+        let uri = match uri {
+            Some(uri) => crate::utils::verify_connectivity_check_uri(uri)?,
+            None => return Ok(()),
+        };
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+        let host = uri.host().unwrap_or_default().to_owned();
+        info!("Connectivity check target set to {}:{}", host, port);
+        *self.connectivity_check.lock().expect("Connectivity check mutex") = (host, port);
+        Ok(())
+    }
+
+    /// Resolve the connectivity check host via the given dns servers instead of the system resolver.
+    /// The servers are tried in order.
+    pub async fn set_upstream_dns(&self, servers: Vec<SocketAddr>) -> Result<(), CaptivePortalError> {
+        *self.upstream_dns.lock().expect("Upstream dns mutex") = servers;
+        Ok(())
+    }
+
+    /// Returns a stream of iwd station state changes of the wifi device.
+    ///
+    /// The stream is single-consumer. Each call creates an independent dbus subscription,
+    /// so call this once per consumer. The current state is not part of the stream, use [`NetworkBackend::state`].
+    pub async fn state_stream(&self) -> Result<BoxStream<'static, NetworkManagerState>, CaptivePortalError> {
+        // The station state is a property of the wifi device object
+        let stream = SignalStream::<StationStateChanged>::prop_new(&self.wifi_device_path, self.conn.clone()).await?;
+        Ok(StreamExt::filter_map(stream, |(changed, _path)| futures_util::future::ready(changed.0)).boxed())
+    }
+
     /// The returned future resolves when either the timeout expired or state of the
     /// **active** connection (eg /org/freedesktop/NetworkManager/ActiveConnection/12) is the expected state
     /// or changes into the expected state.
@@ -144,17 +204,35 @@ impl NetworkBackend {
         }
     }
 
-    /// Network Manager implements this internally. Connman / iwd don't. This check will try to resolve via DNS www.google.com
-    /// and also tries to establish a TCP connection.
+    /// Network Manager implements this internally. Connman / iwd don't. This check will try to resolve via DNS
+    /// the configured connectivity check host (www.google.com by default) and also tries to establish a TCP connection.
     ///
     /// This method is assumed to be called when a limited connection is already confirmed and returns
     /// [`NetworkManagerState::ConnectedLimited`] if not successful and [`NetworkManagerState::Connected`] otherwise.
     async fn test_internet_connectivity(&self, timeout: std::time::Duration) -> NetworkManagerState {
+        let (host, port) = self
+            .connectivity_check
+            .lock()
+            .expect("Connectivity check mutex")
+            .clone();
+        let upstream_dns = self.upstream_dns.lock().expect("Upstream dns mutex").clone();
+        if !upstream_dns.is_empty() {
+            let r = crate::dns_server::upstream::resolve_ipv4(&upstream_dns, &host, timeout).await;
+            let r = match r {
+                Ok(Some(v)) => v,
+                _ => return NetworkManagerState::ConnectedLimited,
+            };
+            let r = tokio::time::timeout(timeout, TcpStream::connect(SocketAddr::new(r.into(), port))).await;
+            return match r {
+                Ok(Ok(v)) => {
+                    let _ = v.shutdown(Shutdown::Both);
+                    NetworkManagerState::Connected
+                },
+                _ => NetworkManagerState::ConnectedLimited,
+            };
+        }
         /// Resolve dns: This may be cached however and cannot be used as connectivity indicator
-        let r = GaiResolver::new()
-            .resolve(Name::from_str("www.google.com").unwrap())
-            .timeout(timeout)
-            .await;
+        let r = tokio::time::timeout(timeout, GaiResolver::new().call(Name::from_str(&host).unwrap())).await;
         let mut r = match r {
             Ok(Ok(v)) => v,
             _ => return NetworkManagerState::ConnectedLimited,
@@ -166,7 +244,7 @@ impl NetworkBackend {
             None => return NetworkManagerState::ConnectedLimited,
         };
         /// Try to establish a TCP connection
-        let r = TcpStream::connect(SocketAddr::new(r, 80)).timeout(timeout).await;
+        let r = tokio::time::timeout(timeout, TcpStream::connect(SocketAddr::new(r, port))).await;
         match r {
             Ok(Ok(v)) => {
                 let _ = v.shutdown(Shutdown::Both);
@@ -176,3 +254,43 @@ impl NetworkBackend {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::station_state;
+    use crate::network_interface::NetworkManagerState;
+    use dbus::arg::Variant;
+    use dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+    use std::collections::HashMap;
+
+    fn changed(interface_name: &str, property: &str, value: &str) -> PropertiesPropertiesChanged {
+        let mut changed_properties = HashMap::new();
+        changed_properties.insert(
+            property.to_owned(),
+            Variant(Box::new(value.to_owned()) as Box<dyn dbus::arg::RefArg>),
+        );
+        PropertiesPropertiesChanged {
+            interface_name: interface_name.to_owned(),
+            changed_properties,
+            invalidated_properties: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn station_state_changes() {
+        let state = station_state(&changed("net.connman.iwd.Station", "State", "connecting"));
+        assert_eq!(state, Some(NetworkManagerState::Connecting));
+        let state = station_state(&changed("net.connman.iwd.Station", "State", "roaming"));
+        assert_eq!(state, Some(NetworkManagerState::Connected));
+
+        // Other properties and interfaces of the device object
+        assert_eq!(
+            station_state(&changed("net.connman.iwd.Station", "Scanning", "true")),
+            None
+        );
+        assert_eq!(
+            station_state(&changed("net.connman.iwd.Device", "State", "connected")),
+            None
+        );
+    }
+}
@@ -2,6 +2,7 @@
 //! method to find a wifi device via the network manager dbus API.
 
 use super::NM_BUSNAME;
+use crate::network_interface::WifiDeviceInfo;
 use crate::CaptivePortalError;
 use dbus::nonblock;
 use std::sync::Arc;
@@ -30,24 +31,22 @@ pub(crate) async fn find_wifi_device(
         if let Some(entry) = entry.get("net.connman.iwd.Device") {
             let device_hw = entry
                 .get("Address")
-                .ok_or(CaptivePortalError::Generic(
-                    "net.connman.iwd.Device: Must have an 'Address'",
-                ))?
+                .ok_or_else(|| {
+                    CaptivePortalError::Generic("net.connman.iwd.Device: Must have an 'Address'".to_owned())
+                })?
                 .0
                 .as_str()
-                .ok_or(CaptivePortalError::Generic(
-                    "net.connman.iwd.Device/Address: Expects a string!",
-                ))?;
+                .ok_or_else(|| {
+                    CaptivePortalError::Generic("net.connman.iwd.Device/Address: Expects a string!".to_owned())
+                })?;
             let device_interface = entry
                 .get("Name")
-                .ok_or(CaptivePortalError::Generic(
-                    "net.connman.iwd.Device: Must have a 'Name'",
-                ))?
+                .ok_or_else(|| CaptivePortalError::Generic("net.connman.iwd.Device: Must have a 'Name'".to_owned()))?
                 .0
                 .as_str()
-                .ok_or(CaptivePortalError::Generic(
-                    "net.connman.iwd.Device/Name: Expects a string!",
-                ))?;
+                .ok_or_else(|| {
+                    CaptivePortalError::Generic("net.connman.iwd.Device/Name: Expects a string!".to_owned())
+                })?;
 
             if let Some(interface_name) = preferred_interface {
                 if &interface_name[..] != device_interface {
@@ -69,3 +68,32 @@ pub(crate) async fn find_wifi_device(
 
     Err(CaptivePortalError::NoWifiDeviceFound)
 }
+
+/// Lists all wifi devices, eg for selecting one of multiple radios.
+/// The device state is the iwd device mode, eg "station" or "ap".
+pub(crate) async fn list_wifi_devices(
+    connection: Arc<dbus::nonblock::SyncConnection>,
+) -> Result<Vec<WifiDeviceInfo>, CaptivePortalError> {
+    let p = nonblock::Proxy::new(NM_BUSNAME, "/", connection.clone());
+    use super::generated::iwd::OrgFreedesktopDBusObjectManager;
+
+    let objects = p.get_managed_objects().await?;
+    let mut devices = Vec::new();
+    for (_device_path, entry) in objects {
+        if let Some(entry) = entry.get("net.connman.iwd.Device") {
+            let property = |name: &str| {
+                entry
+                    .get(name)
+                    .and_then(|v| v.0.as_str())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            devices.push(WifiDeviceInfo {
+                interface_name: property("Name"),
+                hw: property("Address"),
+                state: property("Mode"),
+            });
+        }
+    }
+    Ok(devices)
+}
@@ -18,24 +18,49 @@ mod credentials_agent;
 mod find_wifi_device;
 
 use crate::{
-    dbus_tokio, AccessPointCredentials, ActiveConnection, CaptivePortalError, ConnectionState, Connectivity,
-    NetworkManagerState, WifiConnection, SSID,
+    dbus_tokio, AccessPointCredentials, ActiveConnection, CaptivePortalError, ConnectOptions, ConnectProgress,
+    ConnectionHistoryEntry, ConnectionState, Connectivity, NetworkManagerState, RadioState, ScanBudget, WifiBand,
+    WifiConnection, WifiConnectionDetails, WifiDeviceInfo, SSID,
 };
 pub use access_points_changed::AccessPointsChangedStream;
 
 use crate::dbus_tokio::SignalStream;
+use crate::network_interface::{ScanCoordinator, ScanOutcome, MIN_SCAN_INTERVAL};
 use crate::network_backend::NM_PATH;
+use crate::utils::poll_until_ok;
 use dbus::arg::RefArg;
 use dbus::nonblock::SyncConnection;
 use dbus::{nonblock, Path};
+use futures_util::stream::BoxStream;
 use futures_util::StreamExt;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::stream::StreamExt as TokioStreamExt;
 
 pub const NM_BUSNAME: &str = "net.connman.iwd";
 
+/// The signal strength in percent of the given network in the ordered network list of a station.
+/// iwd reports the RSSI in 100 * dBm. -100 dBm and below equals 0%, -50 dBm and above 100%.
+fn signal_strength(network_path: &Path<'_>, ordered_networks: Vec<(Path<'static>, i16)>) -> Option<u8> {
+    ordered_networks
+        .into_iter()
+        .find(|(path, _)| path == network_path)
+        .map(|(_, rssi)| (2 * (rssi as i32 / 100 + 100)).max(0).min(100) as u8)
+}
+
+/// The paths of all known networks of the given iwd objects. The object properties are not `Send`
+/// and are dropped here, before the known networks are queried.
+fn known_network_paths<V>(
+    objects: std::collections::HashMap<Path<'static>, std::collections::HashMap<String, V>>,
+) -> Vec<Path<'static>> {
+    objects
+        .into_iter()
+        .filter(|(_, interfaces)| interfaces.contains_key("net.connman.iwd.KnownNetwork"))
+        .map(|(path, _)| path)
+        .collect()
+}
+
 #[derive(Clone)]
 pub struct NetworkBackend {
     exit_handler: Arc<Mutex<Option<tokio::sync::oneshot::Sender<()>>>>,
@@ -46,12 +71,29 @@ pub struct NetworkBackend {
     hw: String,
     /// Network interface name
     interface_name: String,
+    /// Host and port that are probed by the internet connectivity check
+    pub(crate) connectivity_check: Arc<Mutex<(String, u16)>>,
+    /// Dns servers that resolve the connectivity check host. The system resolver is used if empty.
+    pub(crate) upstream_dns: Arc<Mutex<Vec<SocketAddr>>>,
+    /// Coalesces the scan requests of all callers
+    scans: ScanCoordinator,
 }
 
 impl NetworkBackend {
     /// Create a new connection to the network manager. This will also try to enable networking
     /// and wifi. Returns a network manager instance or an error if no wifi device can be found.
-    pub async fn new(interface_name: &Option<String>) -> Result<NetworkBackend, CaptivePortalError> {
+    ///
+    /// The wifi device might not be registered yet on a cold boot. Finding the device is retried
+    /// until `device_wait` elapsed. A zero duration fails immediately.
+    ///
+    /// iwd reports its state per device. Waiting for the device covers its readiness, `ready_wait` is ignored.
+    /// iwd does not identify connections by uuid. The hotspot uuid is ignored.
+    pub async fn new(
+        interface_name: &Option<String>,
+        device_wait: Duration,
+        _ready_wait: Duration,
+        _hotspot_uuid: Option<String>,
+    ) -> Result<NetworkBackend, CaptivePortalError> {
         // Prepare an exit handler
         let (exit_handler, exit_receiver) = tokio::sync::oneshot::channel::<()>();
 
@@ -73,26 +115,40 @@ impl NetworkBackend {
             }
         });
 
-        let wifi_device = find_wifi_device::find_wifi_device(conn.clone(), interface_name).await?;
+        let wifi_device = poll_until_ok(device_wait, Duration::from_secs(1), || {
+            find_wifi_device::find_wifi_device(conn.clone(), interface_name)
+        })
+        .await?;
         Ok(NetworkBackend {
             exit_handler: Arc::new(Mutex::new(Some(exit_handler))),
             conn,
             interface_name: wifi_device.interface_name,
             hw: wifi_device.hw,
             wifi_device_path: wifi_device.device_path,
+            connectivity_check: Arc::new(Mutex::new(("www.google.com".to_owned(), 80))),
+            upstream_dns: Arc::new(Mutex::new(Vec::new())),
+            scans: ScanCoordinator::new(MIN_SCAN_INTERVAL),
         })
     }
 
     /// Network might be disabled or "unmanaged". This method tries to enable networking and wifi.
-    pub async fn enable_networking_and_wifi(&self) -> Result<(), CaptivePortalError> {
+    /// iwd manages all wifi devices, `manage_device` is ignored.
+    ///
+    /// Returns [`CaptivePortalError::WifiBlocked`] if the adapter cannot be powered, eg because of rfkill.
+    pub async fn enable_networking_and_wifi(&self, _manage_device: bool) -> Result<(), CaptivePortalError> {
         use generated::device::NetConnmanIwdDevice;
         let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
         p.set_powered(true).await?;
-        Ok(())
+        self.radio_state().await?.ensure_unblocked()
     }
 
-    /// Scan for access points if the last scan is older than 10 seconds
-    pub async fn scan_networks(&self) -> Result<(), CaptivePortalError> {
+    /// Scan for access points if the last scan is older than 10 seconds.
+    /// Concurrent calls are coalesced into one request.
+    pub async fn scan_networks(&self) -> Result<ScanOutcome, CaptivePortalError> {
+        self.scans.scan(|| self.request_scan()).await
+    }
+
+    async fn request_scan(&self) -> Result<(), CaptivePortalError> {
         use generated::device::NetConnmanIwdDevice;
         use generated::device::NetConnmanIwdStation;
         let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
@@ -103,6 +159,54 @@ impl NetworkBackend {
         Ok(())
     }
 
+    /// Lists all wifi devices of the system. This backend only manages the one it has been created for.
+    pub async fn list_wifi_devices(&self) -> Result<Vec<WifiDeviceInfo>, CaptivePortalError> {
+        find_wifi_device::list_wifi_devices(self.conn.clone()).await
+    }
+
+    /// Returns [`CaptivePortalError::ApModeUnsupported`] if the adapter of the wifi device
+    /// does not list "ap" in its supported modes.
+    pub async fn check_ap_mode(&self) -> Result<(), CaptivePortalError> {
+        use generated::adapter::NetConnmanIwdAdapter;
+        use generated::device::NetConnmanIwdDevice;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let p = nonblock::Proxy::new(NM_BUSNAME, p.adapter().await?, self.conn.clone());
+        if p.supported_modes().await?.iter().any(|mode| mode == "ap") {
+            return Ok(());
+        }
+        Err(CaptivePortalError::ApModeUnsupported {
+            interface: self.interface_name.clone(),
+        })
+    }
+
+    /// iwd runs the hotspot and station connections in the mode of a single device.
+    /// A connection next to the hotspot is not supported. Always returns None.
+    pub async fn concurrent_station(&self) -> Result<Option<NetworkBackend>, CaptivePortalError> {
+        Ok(None)
+    }
+
+    /// iwd does not report the time of the last scan. Always returns 0.
+    pub async fn last_scan(&self) -> Result<i64, CaptivePortalError> {
+        Ok(0)
+    }
+
+    /// Waits until the station is no longer scanning. The `last_scan` argument is ignored.
+    /// Returns false if the station is still scanning after the timeout.
+    pub async fn wait_for_scan(&self, _last_scan: i64, timeout: Duration) -> Result<bool, CaptivePortalError> {
+        use generated::device::NetConnmanIwdStation;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if !p.scanning().await? {
+                return Ok(true);
+            }
+            if std::time::Instant::now() > deadline {
+                return Ok(false);
+            }
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+        }
+    }
+
     /// Terminates this network manager dbus connection
     pub fn quit(self) {
         let mut exit_handler = self
@@ -126,6 +230,53 @@ impl NetworkBackend {
         Ok(state)
     }
 
+    /// The software and hardware (rfkill) enabled state of wifi. iwd reports an unpowered adapter for both,
+    /// a hardware block is therefore reported as software block.
+    pub async fn radio_state(&self) -> Result<RadioState, CaptivePortalError> {
+        use generated::adapter::NetConnmanIwdAdapter;
+        use generated::device::NetConnmanIwdDevice;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let p = nonblock::Proxy::new(NM_BUSNAME, p.adapter().await?, self.conn.clone());
+        Ok(RadioState {
+            enabled: NetConnmanIwdAdapter::powered(&p).await?,
+            hardware_enabled: true,
+        })
+    }
+
+    /// The signal strength in percent of the network the station is currently connected to.
+    /// Returns None if the station is not connected.
+    pub async fn active_signal_strength(&self) -> Result<Option<u8>, CaptivePortalError> {
+        use generated::device::NetConnmanIwdStation;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        // The connected network property does not exist if the station is not connected
+        if NetworkManagerState::from(&p.state().await?[..]) != NetworkManagerState::Connected {
+            return Ok(None);
+        }
+        let network_path = p.connected_network().await?;
+        Ok(signal_strength(&network_path, p.get_ordered_networks().await?))
+    }
+
+    /// iwd does not report the steps of a connection attempt. The stream is empty.
+    pub async fn connect_progress_stream(&self) -> Result<BoxStream<'static, ConnectProgress>, CaptivePortalError> {
+        Ok(futures_util::stream::empty().boxed())
+    }
+
+    /// The name of the network the station is currently connected to.
+    /// Returns None if the station is not connected.
+    pub async fn connected_ssid(&self) -> Result<Option<SSID>, CaptivePortalError> {
+        use generated::device::NetConnmanIwdStation;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        // The connected network property does not exist if the station is not connected
+        if NetworkManagerState::from(&p.state().await?[..]) != NetworkManagerState::Connected {
+            return Ok(None);
+        }
+        let network_path = p.connected_network().await?;
+
+        use generated::network::NetConnmanIwdNetwork;
+        let p = nonblock::Proxy::new(NM_BUSNAME, network_path, self.conn.clone());
+        Ok(Some(p.name().await?))
+    }
+
     /// Enables auto connect. This enumerates all known connections and sets auto connect to true.
     pub async fn try_auto_connect(&self, timeout: std::time::Duration) -> Result<bool, CaptivePortalError> {
         let p = nonblock::Proxy::new(NM_BUSNAME, "/", self.conn.clone());
@@ -162,6 +313,43 @@ impl NetworkBackend {
         }
     }
 
+    /// Forgets all known networks and returns the number of forgotten networks.
+    /// iwd does not store hotspot/APs as "known network"s, so "include_hotspots" has no effect.
+    pub async fn forget_all_connections(&self, _include_hotspots: bool) -> Result<usize, CaptivePortalError> {
+        let p = nonblock::Proxy::new(NM_BUSNAME, "/", self.conn.clone());
+        use generated::iwd::OrgFreedesktopDBusObjectManager;
+        use generated::known_network::NetConnmanIwdKnownNetwork;
+
+        let network_paths = known_network_paths(p.get_managed_objects().await?);
+        let mut deleted = 0;
+        for network_path in network_paths {
+            let p = nonblock::Proxy::new(NM_BUSNAME, network_path, self.conn.clone());
+            p.forget().await?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    /// Returns all known networks. iwd reports the last connection time as ISO 8601 string,
+    /// which is not parsed, and does not remember the access points of a known network.
+    pub async fn connection_history(&self) -> Result<Vec<ConnectionHistoryEntry>, CaptivePortalError> {
+        let p = nonblock::Proxy::new(NM_BUSNAME, "/", self.conn.clone());
+        use generated::iwd::OrgFreedesktopDBusObjectManager;
+        use generated::known_network::NetConnmanIwdKnownNetwork;
+
+        let network_paths = known_network_paths(p.get_managed_objects().await?);
+        let mut history = Vec::new();
+        for network_path in network_paths {
+            let p = nonblock::Proxy::new(NM_BUSNAME, network_path, self.conn.clone());
+            history.push(ConnectionHistoryEntry {
+                ssid: p.name().await?,
+                last_connected: None,
+                seen_bssids: Vec::new(),
+            });
+        }
+        Ok(history)
+    }
+
     /// Connect to the given SSID with the given credentials.
     /// First tries to find a wifi connection if "hw" is set or "overwrite_same_ssid_connection" is true.
     /// If it finds one, the connection will be altered to use the given credentials and SSID, otherwise a new connection is created.
@@ -172,18 +360,17 @@ impl NetworkBackend {
     /// created connection will be named "My AP 1".
     ///
     /// # Arguments:
-    /// * ssid: The ssid
+    /// * ssid: The raw bytes of the ssid, which is not necessarily valid UTF-8
     /// * credentials: The connection credentials
-    /// * hw: The target access point mac address. If this is set, this method will first try to find
-    ///   a connection that was connected to that access point in the past and update that connection.
-    /// * overwrite_same_ssid_connection: If this is true and a connection can be found that matches the
-    ///   given SSID, that connection will be updated.
+    /// * options: The target access point and how the connection is found, see [`ConnectOptions`].
+    ///   iwd always stores known networks, "persist_connection" is ignored.
+    /// * cancelled: Cancels the attempt, see [`crate::ConnectCancel`]. The attempt is disconnected.
     pub async fn connect_to(
         &self,
-        ssid: SSID,
+        ssid: Vec<u8>,
         credentials: AccessPointCredentials,
-        hw: Option<String>,
-        overwrite_same_ssid_connection: bool,
+        options: ConnectOptions,
+        cancelled: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<Option<ActiveConnection>, CaptivePortalError> {
         unimplemented!()
     }
@@ -203,9 +390,11 @@ impl NetworkBackend {
     ///
     /// ## Arguments
     /// * find_all: Perform a full scan. This may take up to a minute.
+    /// * budget: Limits the overall work. The access points found so far are returned if it is exhausted.
     pub async fn list_access_points(
         &self,
         timeout: std::time::Duration,
+        budget: ScanBudget,
     ) -> Result<Vec<WifiConnection>, CaptivePortalError> {
         if find_all {
             self.scan_networks().await?;
@@ -213,24 +402,66 @@ impl NetworkBackend {
         unimplemented!()
     }
 
+    /// Return detailed data of the network with the given raw ssid. iwd only exposes the security type and signal
+    /// strength of networks, the flags, bitrate and channel width are left empty.
+    pub async fn network_details(&self, ssid: &[u8]) -> Result<Option<WifiConnectionDetails>, CaptivePortalError> {
+        let is_active = self.connected_ssid().await?.as_deref().map(str::as_bytes) == Some(ssid);
+        let details = self
+            .list_access_points(Duration::from_secs(0), ScanBudget::default())
+            .await?
+            .into_iter()
+            .filter(|connection| connection.ssid.as_bytes() == ssid)
+            .map(|connection| WifiConnectionDetails {
+                access_points: vec![connection.hw.clone()],
+                connection,
+                flags: Vec::new(),
+                wpa_flags: Vec::new(),
+                rsn_flags: Vec::new(),
+                max_bitrate: 0,
+                channel_width: None,
+                is_active,
+            })
+            .collect();
+        Ok(WifiConnectionDetails::merge(details))
+    }
+
     /// iwd does not store hotspot/APs as "known network"s, so there is nothing to deactivate.
     /// This method will however change from hotspot/AP mode into station mode if necessary.
-    pub async fn deactivate_hotspots(&self) -> Result<(), CaptivePortalError> {
+    /// Returns true if the mode has been changed.
+    pub async fn deactivate_hotspots(&self) -> Result<bool, CaptivePortalError> {
         use generated::device::NetConnmanIwdDevice;
         use generated::device::NetConnmanIwdStation;
         let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
-        if p.mode().await? != "station" {
-            p.set_mode("station".into()).await?;
+        if p.mode().await? == "station" {
+            return Ok(false);
         }
-        Ok(())
+        p.set_mode("station".into()).await?;
+        Ok(true)
     }
 
-    /// Starts a hotspot
+    /// The hotspot is never kept up during a connection attempt, see [`NetworkBackend::concurrent_station`].
+    /// Always returns None.
+    pub async fn active_hotspot(&self) -> Result<Option<dbus::Path<'static>>, CaptivePortalError> {
+        Ok(None)
+    }
+
+    /// iwd auto connects to known networks in station mode on its own. Nothing to do.
+    pub async fn enable_auto_connect(&self) {}
+
+    /// iwd only manages wifi devices and knows nothing about addresses of other network interfaces.
+    /// An empty list is returned.
+    pub async fn active_ipv4_addresses(&self) -> Result<Vec<(String, Ipv4Addr, u8)>, CaptivePortalError> {
+        Ok(Vec::new())
+    }
+
+    /// Starts a hotspot. iwd does not support to select the wifi band, the band argument is ignored.
     pub async fn hotspot_start(
         &self,
         ssid: SSID,
         password: String,
         address: Option<Ipv4Addr>,
+        prefix: u8,
+        _band: WifiBand,
     ) -> Result<ActiveConnection, CaptivePortalError> {
         use generated::device::NetConnmanIwdAccessPoint;
         use generated::device::NetConnmanIwdDevice;
@@ -276,3 +507,24 @@ impl NetworkBackend {
         unimplemented!()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::signal_strength;
+    use dbus::Path;
+
+    #[test]
+    fn network_signal_strength() {
+        let networks = vec![
+            (Path::from("/net/connman/iwd/0/3/6f6666696365_psk"), -4500),
+            (Path::from("/net/connman/iwd/0/3/686f6d65_psk"), -7000),
+            (Path::from("/net/connman/iwd/0/3/63616665_open"), -10500),
+        ];
+        let strength = |path: &str| signal_strength(&Path::from(path), networks.clone());
+        assert_eq!(strength("/net/connman/iwd/0/3/6f6666696365_psk"), Some(100));
+        assert_eq!(strength("/net/connman/iwd/0/3/686f6d65_psk"), Some(60));
+        assert_eq!(strength("/net/connman/iwd/0/3/63616665_open"), Some(0));
+        // The connected network is not part of the last scan
+        assert_eq!(strength("/net/connman/iwd/0/3/6775657374_open"), None);
+    }
+}
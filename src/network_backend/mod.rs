@@ -11,3 +11,33 @@ mod nm;
 pub use iwd::*;
 #[cfg(feature = "networkmanager")]
 pub use nm::*;
+#[cfg(all(test, feature = "networkmanager"))]
+pub(crate) use nm::test_bus;
+
+/// The name of the compiled in network backend
+#[cfg(feature = "iwd")]
+pub const BACKEND_NAME: &str = "iwd";
+#[cfg(feature = "networkmanager")]
+pub const BACKEND_NAME: &str = "networkmanager";
+
+use crate::network_interface::ConnectionHistoryEntry;
+use crate::CaptivePortalError;
+use std::time::Duration;
+
+impl NetworkBackend {
+    /// Sends the connection history (see `NetworkBackend::connection_history`) every "interval" to the given channel,
+    /// eg for analytics. Resolves when the receiver has been dropped.
+    pub async fn export_connection_history(
+        &self,
+        interval: Duration,
+        mut sender: tokio::sync::mpsc::Sender<Vec<ConnectionHistoryEntry>>,
+    ) -> Result<(), CaptivePortalError> {
+        loop {
+            let history = self.connection_history().await?;
+            if sender.send(history).await.is_err() {
+                return Ok(());
+            }
+            tokio::time::delay_for(interval).await;
+        }
+    }
+}
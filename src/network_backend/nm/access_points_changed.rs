@@ -1,9 +1,18 @@
 //! # Access points change stream
 //! Provides a stream of [`WifiConnectionEvent`]s.
+//!
+//! Access points are added and removed via signals of the wifi device. Signal strength changes
+//! are reported via property change signals of each access point. Those are subscribed to
+//! when an access point is added and unsubscribed when it is removed.
 
+use dbus::arg;
 use dbus::message::SignalArgs;
+use futures_util::future::{ready, FutureExt};
 use futures_util::stream::select;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use super::device;
 use crate::dbus_tokio::SignalStream;
@@ -11,13 +20,121 @@ use crate::network_backend::nm::generated::device::{DeviceWirelessAccessPointAdd
 use crate::network_backend::{NetworkBackend, NM_BUSNAME};
 use crate::network_interface::WifiConnectionEventType;
 use crate::CaptivePortalError;
+use futures_core::future::BoxFuture;
 use futures_core::stream::BoxStream;
 
+/// The maximum number of access points that are watched for signal strength changes.
+/// Each watched access point requires a dbus match rule.
+const MAX_AP_SUBSCRIPTIONS: usize = 64;
+
+const NM_ACCESS_POINT_INTERFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+
 pub struct AccessPointChanged {
     pub path: String,
     pub event: WifiConnectionEventType,
 }
 
+type SubscribeResult = Result<BoxStream<'static, AccessPointChanged>, CaptivePortalError>;
+type SubscribeFn = Box<dyn Fn(String) -> BoxFuture<'static, SubscribeResult> + Send>;
+
+/// Merges the added / removed stream with the property change streams of all added access points.
+struct AccessPointsChangedStream {
+    added_removed: BoxStream<'static, AccessPointChanged>,
+    /// Subscribes to the property changes of the access point with the given path
+    subscribe: SubscribeFn,
+    max_subscriptions: usize,
+    /// Subscriptions that are not yet established
+    pending: Vec<(String, BoxFuture<'static, SubscribeResult>)>,
+    /// Property change streams by access point path
+    subscriptions: HashMap<String, BoxStream<'static, AccessPointChanged>>,
+}
+
+impl AccessPointsChangedStream {
+    fn new(
+        added_removed: BoxStream<'static, AccessPointChanged>,
+        subscribe: SubscribeFn,
+        max_subscriptions: usize,
+    ) -> Self {
+        AccessPointsChangedStream {
+            added_removed,
+            subscribe,
+            max_subscriptions,
+            pending: Vec::new(),
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    fn is_watched(&self, path: &str) -> bool {
+        self.subscriptions.contains_key(path) || self.pending.iter().any(|(p, _)| p == path)
+    }
+
+    fn watch(&mut self, path: &str) {
+        if self.is_watched(path) {
+            return;
+        }
+        if self.subscriptions.len() + self.pending.len() >= self.max_subscriptions {
+            debug!("Not watching access point {} for changes: Too many subscriptions", path);
+            return;
+        }
+        let subscription = (self.subscribe)(path.to_owned());
+        self.pending.push((path.to_owned(), subscription));
+    }
+
+    fn unwatch(&mut self, path: &str) {
+        self.subscriptions.remove(path);
+        self.pending.retain(|(p, _)| p != path);
+    }
+}
+
+impl Stream for AccessPointsChangedStream {
+    type Item = AccessPointChanged;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        // Move established subscriptions over
+        let mut i = 0;
+        while i < this.pending.len() {
+            if let Poll::Ready(r) = this.pending[i].1.poll_unpin(cx) {
+                let (path, _) = this.pending.swap_remove(i);
+                match r {
+                    Ok(stream) => {
+                        this.subscriptions.insert(path, stream);
+                    },
+                    Err(e) => warn!("Failed to watch access point {}: {}", path, e),
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        let mut ended = Vec::new();
+        for (path, stream) in this.subscriptions.iter_mut() {
+            match stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(event)) => return Poll::Ready(Some(event)),
+                Poll::Ready(None) => ended.push(path.clone()),
+                Poll::Pending => {},
+            }
+        }
+        for path in ended {
+            this.subscriptions.remove(&path);
+        }
+
+        match this.added_removed.poll_next_unpin(cx) {
+            Poll::Ready(Some(event)) => {
+                match event.event {
+                    WifiConnectionEventType::Added => this.watch(&event.path),
+                    WifiConnectionEventType::Removed => this.unwatch(&event.path),
+                    WifiConnectionEventType::Changed => {},
+                }
+                Poll::Ready(Some(event))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 fn helper_1(v: (DeviceWirelessAccessPointAdded, String)) -> AccessPointChanged {
     AccessPointChanged {
         event: WifiConnectionEventType::Added,
@@ -32,6 +149,47 @@ fn helper_2(v: (DeviceWirelessAccessPointRemoved, String)) -> AccessPointChanged
     }
 }
 
+/// The org.freedesktop.DBus.Properties.PropertiesChanged signal of an access point.
+/// Only the signal strength is kept, so that the signal can be send between threads.
+#[derive(Debug)]
+struct AccessPointStrengthChanged {
+    interface_name: String,
+    strength: Option<u8>,
+}
+
+impl arg::ReadAll for AccessPointStrengthChanged {
+    fn read(i: &mut arg::Iter) -> Result<Self, arg::TypeMismatchError> {
+        use arg::RefArg;
+        let interface_name: String = i.read()?;
+        let changed_properties: HashMap<String, arg::Variant<Box<dyn RefArg + 'static>>> = i.read()?;
+        let strength = changed_properties
+            .get("Strength")
+            .and_then(|v| v.0.as_u64())
+            .map(|v| v as u8);
+        Ok(AccessPointStrengthChanged {
+            interface_name,
+            strength,
+        })
+    }
+}
+
+impl SignalArgs for AccessPointStrengthChanged {
+    const NAME: &'static str = "PropertiesChanged";
+    const INTERFACE: &'static str = "org.freedesktop.DBus.Properties";
+}
+
+/// Only signal strength changes are of interest
+fn helper_3(v: (AccessPointStrengthChanged, String)) -> Option<AccessPointChanged> {
+    if v.0.interface_name == NM_ACCESS_POINT_INTERFACE && v.0.strength.is_some() {
+        Some(AccessPointChanged {
+            event: WifiConnectionEventType::Changed,
+            path: v.1,
+        })
+    } else {
+        None
+    }
+}
+
 pub async fn ap_changed_stream(
     network_manager: &NetworkBackend,
 ) -> Result<BoxStream<'static, AccessPointChanged>, CaptivePortalError> {
@@ -59,5 +217,84 @@ pub async fn ap_changed_stream(
             .await?
             .map(helper_2);
 
-    Ok(select(inner_stream_added, inner_stream_removed).boxed())
+    let conn = network_manager.conn.clone();
+    let subscribe: SubscribeFn = Box::new(move |path: String| {
+        let conn = conn.clone();
+        async move {
+            let rule = AccessPointStrengthChanged::match_rule(Some(&NM_BUSNAME.to_owned().into()), Some(&path.into()))
+                .static_clone();
+            let stream = SignalStream::<AccessPointStrengthChanged>::new(conn, rule)
+                .await?
+                .filter_map(|v| ready(helper_3(v)));
+            Ok(stream.boxed())
+        }
+        .boxed()
+    });
+
+    Ok(AccessPointsChangedStream::new(
+        select(inner_stream_added, inner_stream_removed).boxed(),
+        subscribe,
+        MAX_AP_SUBSCRIPTIONS,
+    )
+    .boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessPointChanged, AccessPointsChangedStream, SubscribeFn};
+    use crate::network_interface::WifiConnectionEventType;
+    use futures_util::future::FutureExt;
+    use futures_util::stream::{self, StreamExt};
+
+    fn event(path: &str, event: WifiConnectionEventType) -> AccessPointChanged {
+        AccessPointChanged {
+            path: path.to_owned(),
+            event,
+        }
+    }
+
+    #[tokio::test]
+    async fn merged_stream() {
+        let (sender, receiver) = futures_channel::mpsc::unbounded::<AccessPointChanged>();
+        // Each watched access point reports one strength change
+        let subscribe: SubscribeFn = Box::new(|path: String| {
+            async move {
+                let changed = stream::iter(vec![event(&path, WifiConnectionEventType::Changed)]);
+                Ok(changed.chain(stream::pending()).boxed())
+            }
+            .boxed()
+        });
+        let mut stream = AccessPointsChangedStream::new(receiver.boxed(), subscribe, 1);
+
+        sender
+            .unbounded_send(event("/ap/1", WifiConnectionEventType::Added))
+            .unwrap();
+        let e = stream.next().await.unwrap();
+        assert_eq!(e.event, WifiConnectionEventType::Added);
+        assert_eq!(stream.pending.len(), 1);
+
+        let e = stream.next().await.unwrap();
+        assert_eq!(e.event, WifiConnectionEventType::Changed);
+        assert_eq!(e.path, "/ap/1");
+        assert!(stream.subscriptions.contains_key("/ap/1"));
+
+        // The number of subscriptions is bounded
+        sender
+            .unbounded_send(event("/ap/2", WifiConnectionEventType::Added))
+            .unwrap();
+        let e = stream.next().await.unwrap();
+        assert_eq!(e.event, WifiConnectionEventType::Added);
+        assert!(!stream.is_watched("/ap/2"));
+
+        // Removed access points are no longer watched
+        sender
+            .unbounded_send(event("/ap/1", WifiConnectionEventType::Removed))
+            .unwrap();
+        let e = stream.next().await.unwrap();
+        assert_eq!(e.event, WifiConnectionEventType::Removed);
+        assert!(stream.subscriptions.is_empty());
+
+        drop(sender);
+        assert!(stream.next().await.is_none());
+    }
 }
@@ -1,17 +1,19 @@
 //! This module contains connectivity and state related types. This includes
 //! network manager state as well as connection and device state.
 
-use futures_util::stream::StreamExt;
+use futures_util::stream::{BoxStream, StreamExt};
 use tokio::time::timeout;
 
+use super::device_state_type::{connect_progress, DeviceState};
 use super::NetworkBackend;
 use super::NM_BUSNAME;
 use crate::dbus_tokio::SignalStream;
 use crate::network_backend::NM_PATH;
-use crate::network_interface::{ConnectionState, NetworkManagerState};
+use crate::network_interface::{ConnectProgress, ConnectionState, NetworkManagerState};
 use crate::CaptivePortalError;
 use dbus::message::SignalArgs;
 use dbus::nonblock;
+use std::path::Path;
 
 impl From<u32> for NetworkManagerState {
     fn from(state: u32) -> Self {
@@ -35,7 +37,76 @@ impl From<u32> for NetworkManagerState {
     }
 }
 
+/// Network manager reads the connectivity check uri from its configuration only.
+/// This file is written by [`NetworkBackend::set_connectivity_check_uri`] and outlives this program,
+/// until it is removed again by calling that method without an uri.
+const CONNECTIVITY_CONF_PATH: &str = "/etc/NetworkManager/conf.d/99-wifi-captive-connectivity.conf";
+
+/// Reload flag for network manager: Only reload NetworkManager.conf
+const RELOAD_FLAG_CONF: u32 = 0x01;
+
+/// Writes the connectivity configuration for the given uri to the given path, or removes it if no uri is given.
+/// Returns true if the configuration has changed.
+fn update_connectivity_conf(path: &Path, uri: Option<&hyper::Uri>) -> std::io::Result<bool> {
+    let uri = match uri {
+        Some(uri) => uri,
+        None => {
+            return match std::fs::remove_file(path) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            };
+        },
+    };
+    let content = format!("[connectivity]\nenabled=true\nuri={}\n", uri);
+    if let Ok(existing) = std::fs::read_to_string(path) {
+        if existing == content {
+            return Ok(false);
+        }
+    }
+    std::fs::write(path, content)?;
+    Ok(true)
+}
+
 impl NetworkBackend {
+    /// Use the given uri for network managers internet connectivity check. The configured uri
+    /// of network manager applies again if no uri is given.
+    ///
+    /// Network manager does not allow to change the uri via dbus. A configuration snippet
+    /// is written to "/etc/NetworkManager/conf.d" or removed instead and network manager is asked to reload
+    /// its configuration. Nothing happens if the configuration is already up to date.
+    ///
+    /// The snippet changes the system configuration and stays in place after this program quits,
+    /// unless this method is called again without an uri.
+    pub async fn set_connectivity_check_uri(&self, uri: Option<&str>) -> Result<(), CaptivePortalError> {
+        let uri = match uri {
+            Some(uri) => Some(crate::utils::verify_connectivity_check_uri(uri)?),
+            None => None,
+        };
+        let changed = update_connectivity_conf(Path::new(CONNECTIVITY_CONF_PATH), uri.as_ref())
+            .map_err(|e| CaptivePortalError::IO(e, "Failed to update the connectivity check configuration"))?;
+        if !changed {
+            return Ok(());
+        }
+
+        use super::networkmanager::NetworkManager;
+        let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+        p.reload(RELOAD_FLAG_CONF).await?;
+        match uri {
+            Some(uri) => info!("Connectivity check uri set to {}", uri),
+            None => info!("Connectivity check uri reset to the network manager configuration"),
+        }
+        Ok(())
+    }
+
+    /// Network manager performs the connectivity check itself and uses the dns servers
+    /// of the active connection. Returns [`CaptivePortalError::Unsupported`].
+    pub async fn set_upstream_dns(&self, _servers: Vec<std::net::SocketAddr>) -> Result<(), CaptivePortalError> {
+        Err(CaptivePortalError::Unsupported(
+            "upstream dns servers for the connectivity check",
+        ))
+    }
+
     /// Continuously print connection state changes
     #[allow(dead_code)]
     pub async fn print_connection_changes(&self) -> Result<(), CaptivePortalError> {
@@ -72,6 +143,34 @@ impl NetworkBackend {
         Ok(())
     }
 
+    /// Returns a stream of network manager state changes, eg for pushing state transitions to the UI.
+    ///
+    /// The stream is single-consumer. Each call creates an independent dbus subscription,
+    /// so call this once per consumer. The current state is not part of the stream, use [`NetworkBackend::state`].
+    pub async fn state_stream(&self) -> Result<BoxStream<'static, NetworkManagerState>, CaptivePortalError> {
+        use super::networkmanager::NetworkManagerStateChanged as StateChanged;
+
+        let stream = SignalStream::<StateChanged>::prop_new(&NM_PATH.to_owned().into(), self.conn.clone()).await?;
+        Ok(stream
+            .map(|(value, _path)| NetworkManagerState::from(value.state))
+            .boxed())
+    }
+
+    /// Returns a stream of the connection progress of the wifi device, derived from its state changes.
+    ///
+    /// The stream is single-consumer. Subscribe before starting a connection attempt to not miss early steps.
+    pub async fn connect_progress_stream(&self) -> Result<BoxStream<'static, ConnectProgress>, CaptivePortalError> {
+        use super::device::DeviceStateChanged as StateChanged;
+
+        let rule = StateChanged::match_rule(None, Some(&self.wifi_device_path)).static_clone();
+        let stream: SignalStream<StateChanged> = SignalStream::new(self.conn.clone(), rule).await?;
+        Ok(stream
+            .filter_map(
+                |(value, _path)| async move { connect_progress(DeviceState::from(value.new_state), value.reason) },
+            )
+            .boxed())
+    }
+
     /// The returned future resolves when either the timeout expired or state of the
     /// **active** connection (eg /org/freedesktop/NetworkManager/ActiveConnection/12) is the expected state
     /// or changes into the expected state.
@@ -176,3 +275,55 @@ impl NetworkBackend {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::update_connectivity_conf;
+    use crate::network_interface::NetworkManagerState;
+
+    #[test]
+    fn connectivity_conf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("99-wifi-captive-connectivity.conf");
+        let uri: hyper::Uri = "http://example.com/check".parse().unwrap();
+
+        assert!(update_connectivity_conf(&path, Some(&uri)).unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[connectivity]\nenabled=true\nuri=http://example.com/check\n"
+        );
+        // Up to date
+        assert!(!update_connectivity_conf(&path, Some(&uri)).unwrap());
+
+        // A stale configuration is removed once the uri is no longer set
+        assert!(update_connectivity_conf(&path, None).unwrap());
+        assert!(!path.exists());
+        assert!(!update_connectivity_conf(&path, None).unwrap());
+
+        // A read-only configuration directory is an error for the caller to log
+        assert!(update_connectivity_conf(&dir.path().join("missing/file.conf"), Some(&uri)).is_err());
+    }
+
+    #[test]
+    fn state_mapping() {
+        let mapped: Vec<NetworkManagerState> = vec![0, 10, 20, 30, 40, 50, 60, 70, 42]
+            .into_iter()
+            .map(NetworkManagerState::from)
+            .collect();
+        assert_eq!(
+            mapped,
+            vec![
+                NetworkManagerState::Unknown,
+                NetworkManagerState::Asleep,
+                NetworkManagerState::Disconnected,
+                NetworkManagerState::Disconnecting,
+                NetworkManagerState::Connecting,
+                // Local connectivity only is treated as disconnected
+                NetworkManagerState::Disconnected,
+                NetworkManagerState::ConnectedLimited,
+                NetworkManagerState::Connected,
+                NetworkManagerState::Unknown,
+            ]
+        );
+    }
+}
@@ -1,4 +1,6 @@
-//! # The device state type. Only used for debugging messages.
+//! # The device state type. Used for debugging messages and to report the connection progress.
+
+use crate::network_interface::ConnectProgress;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum DeviceState {
@@ -40,3 +42,84 @@ impl From<u32> for DeviceState {
         }
     }
 }
+
+/// Maps a device state change to the connection progress. States that are not part of a connection attempt
+/// are mapped to None. The reason of a change into the failed state is a network manager NMDeviceStateReason.
+pub(crate) fn connect_progress(state: DeviceState, reason: u32) -> Option<ConnectProgress> {
+    match state {
+        DeviceState::Prepare | DeviceState::Config => Some(ConnectProgress::Associating),
+        DeviceState::NeedAuth => Some(ConnectProgress::Authenticating),
+        DeviceState::IpConfig => Some(ConnectProgress::ObtainingIp),
+        DeviceState::IpCheck | DeviceState::Secondaries => Some(ConnectProgress::CheckingInternet),
+        DeviceState::Activated => Some(ConnectProgress::Connected),
+        DeviceState::Failed => Some(ConnectProgress::Failed(failure_reason(reason))),
+        _ => None,
+    }
+}
+
+/// A human readable description of the most common device state reasons of failed connection attempts
+fn failure_reason(reason: u32) -> String {
+    match reason {
+        7 => "Wrong password or missing credentials".to_owned(),
+        8..=11 => "Authentication failed".to_owned(),
+        5 | 6 | 15..=17 => "No ip address received".to_owned(),
+        53 => "Network not found".to_owned(),
+        reason => format!("Device state reason {}", reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect_progress, DeviceState};
+    use crate::network_interface::ConnectProgress;
+
+    #[test]
+    fn progress_mapping() {
+        let mapped: Vec<Option<ConnectProgress>> = vec![30, 40, 50, 60, 70, 80, 90, 100, 110]
+            .into_iter()
+            .map(|state| connect_progress(DeviceState::from(state), 0))
+            .collect();
+        assert_eq!(
+            mapped,
+            vec![
+                None,
+                Some(ConnectProgress::Associating),
+                Some(ConnectProgress::Associating),
+                Some(ConnectProgress::Authenticating),
+                Some(ConnectProgress::ObtainingIp),
+                Some(ConnectProgress::CheckingInternet),
+                Some(ConnectProgress::CheckingInternet),
+                Some(ConnectProgress::Connected),
+                None,
+            ]
+        );
+
+        let failed = |reason| connect_progress(DeviceState::Failed, reason);
+        assert_eq!(
+            failed(7),
+            Some(ConnectProgress::Failed(
+                "Wrong password or missing credentials".to_owned()
+            ))
+        );
+        assert_eq!(
+            failed(17),
+            Some(ConnectProgress::Failed("No ip address received".to_owned()))
+        );
+        assert_eq!(
+            failed(53),
+            Some(ConnectProgress::Failed("Network not found".to_owned()))
+        );
+        assert_eq!(
+            failed(36),
+            Some(ConnectProgress::Failed("Device state reason 36".to_owned()))
+        );
+
+        let json = serde_json::to_value(&failed(53).unwrap()).unwrap();
+        assert_eq!(json["type"], "failed");
+        assert_eq!(json["reason"], "Network not found");
+        assert_eq!(
+            serde_json::to_value(&ConnectProgress::ObtainingIp).unwrap()["type"],
+            "obtaining_ip"
+        );
+    }
+}
@@ -3,9 +3,9 @@
 
 use dbus::nonblock;
 
-use super::wifi_settings::{self, VariantMap, WiFiConnectionSettings};
+use super::wifi_settings::{self, VariantMap, WiFiConnectionSettings, WifiConnectionMode};
 use crate::network_backend::{NetworkBackend, IN_MEMORY_ONLY, NM_BUSNAME, NM_PATH, NM_SETTINGS_PATH};
-use crate::network_interface::{AccessPointCredentials, SSID};
+use crate::network_interface::{AccessPointCredentials, ConnectionHistoryEntry, SSID};
 use crate::CaptivePortalError;
 
 impl NetworkBackend {
@@ -53,19 +53,98 @@ impl NetworkBackend {
         return Ok(None);
     }
 
+    /// Returns the dbus path of the wifi station connection that has been connected most recently.
+    /// None if no station connection has ever been activated.
+    pub(crate) async fn most_recent_connection(&self) -> Result<Option<dbus::Path<'static>>, CaptivePortalError> {
+        let connections = {
+            use super::generated::connections::Settings;
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_SETTINGS_PATH, self.conn.clone());
+            p.connections().await?
+        };
+        let mut candidates = Vec::new();
+        for connection_path in connections {
+            let settings = wifi_settings::get_connection_settings(self.conn.clone(), connection_path.clone()).await?;
+            if let Some(settings) = settings {
+                candidates.push((connection_path, settings));
+            }
+        }
+        Ok(most_recent(candidates))
+    }
+
+    /// Returns all known wifi station connections with the time of their last successful connection
+    /// and the access points they have been connected to. Access point (hotspot) connections are skipped.
+    pub async fn connection_history(&self) -> Result<Vec<ConnectionHistoryEntry>, CaptivePortalError> {
+        let connections = {
+            use super::generated::connections::Settings;
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_SETTINGS_PATH, self.conn.clone());
+            p.connections().await?
+        };
+        let mut history = Vec::new();
+        for connection_path in connections {
+            let settings = wifi_settings::get_connection_settings(self.conn.clone(), connection_path).await?;
+            if let Some(settings) = settings {
+                if settings.mode == WifiConnectionMode::AP {
+                    continue;
+                }
+                history.push(ConnectionHistoryEntry {
+                    ssid: settings.ssid,
+                    last_connected: Some(settings.timestamp).filter(|v| *v > 0),
+                    seen_bssids: settings.seen_bssids,
+                });
+            }
+        }
+        Ok(history)
+    }
+
+    /// Deletes all known wifi connections and returns the number of deleted connections.
+    /// Access point (hotspot) connections are only deleted if "include_hotspots" is true.
+    /// Non-wifi connections, like ethernet, are never touched.
+    pub async fn forget_all_connections(&self, include_hotspots: bool) -> Result<usize, CaptivePortalError> {
+        let connections = {
+            use super::generated::connections::Settings;
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_SETTINGS_PATH, self.conn.clone());
+            p.connections().await?
+        };
+        let mut deleted = 0;
+        for connection_path in connections {
+            let settings = wifi_settings::get_connection_settings(self.conn.clone(), connection_path.clone()).await?;
+            if let Some(settings) = settings {
+                if settings.mode == WifiConnectionMode::AP && !include_hotspots {
+                    continue;
+                }
+                info!("Forget wifi connection {} {}", settings.uuid, settings.ssid);
+                use super::generated::connection_nm::Connection;
+                let p = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
+                p.delete().await?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
     /// Returns a tuple with network manager dbus paths on success: (connection, active_connection)
     pub(crate) async fn update_connection<'a>(
         &self,
         connection_path: dbus::Path<'a>,
-        ssid: &SSID,
+        ssid: &[u8],
         old_connection: WiFiConnectionSettings,
         credentials: AccessPointCredentials,
+        bssid: Option<&str>,
     ) -> Result<(dbus::Path<'a>, dbus::Path<'_>), CaptivePortalError> {
         use super::generated::connection_nm::Connection;
         let p = nonblock::Proxy::new(NM_BUSNAME, connection_path.clone(), self.conn.clone());
-        let settings = wifi_settings::make_arguments_for_ap::<&'static str>(ssid, credentials, Some(old_connection))?;
+        let settings =
+            wifi_settings::make_arguments_for_ap::<&'static str>(ssid, credentials, Some(old_connection), bssid)?;
         p.update2(settings, IN_MEMORY_ONLY, VariantMap::new()).await?;
-        // Activate connection
+        self.activate_connection(connection_path).await
+    }
+
+    /// Activates the given connection on the wifi device.
+    /// Returns a tuple with network manager dbus paths on success: (connection, active_connection)
+    pub(crate) async fn activate_connection<'a>(
+        &self,
+        connection_path: dbus::Path<'a>,
+    ) -> Result<(dbus::Path<'a>, dbus::Path<'_>), CaptivePortalError> {
         let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
         use super::generated::networkmanager::NetworkManager;
         let active_path = p
@@ -74,3 +153,47 @@ impl NetworkBackend {
         Ok((connection_path, active_path))
     }
 }
+
+/// Selects the station connection with the most recent activation timestamp.
+/// Access point (hotspot) connections and never activated connections are skipped.
+fn most_recent<T>(connections: Vec<(T, WiFiConnectionSettings)>) -> Option<T> {
+    connections
+        .into_iter()
+        .filter(|(_, settings)| settings.mode != WifiConnectionMode::AP && settings.timestamp > 0)
+        .max_by_key(|(_, settings)| settings.timestamp)
+        .map(|(connection, _)| connection)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::most_recent;
+    use crate::network_backend::nm::wifi_settings::{WiFiConnectionSettings, WifiConnectionMode};
+
+    fn settings(mode: WifiConnectionMode, timestamp: u64) -> WiFiConnectionSettings {
+        WiFiConnectionSettings {
+            id: String::new(),
+            uuid: String::new(),
+            ssid: String::new(),
+            mode,
+            seen_bssids: Vec::new(),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn most_recent_by_timestamp() {
+        let connections = vec![
+            ("old", settings(WifiConnectionMode::Infrastructure, 1_500_000_000)),
+            ("recent", settings(WifiConnectionMode::Infrastructure, 1_580_000_000)),
+            ("hotspot", settings(WifiConnectionMode::AP, 1_590_000_000)),
+            ("never", settings(WifiConnectionMode::Infrastructure, 0)),
+        ];
+        assert_eq!(most_recent(connections), Some("recent"));
+
+        let connections = vec![
+            ("hotspot", settings(WifiConnectionMode::AP, 1_590_000_000)),
+            ("never", settings(WifiConnectionMode::Infrastructure, 0)),
+        ];
+        assert_eq!(most_recent(connections), None);
+    }
+}
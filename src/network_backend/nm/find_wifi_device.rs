@@ -1,7 +1,9 @@
 //! This crate will immediately quit if no wifi device can be found. This module encapsulates the
 //! method to find a wifi device via the network manager dbus API.
 
+use super::device_state_type::DeviceState;
 use super::{NM_BUSNAME, NM_PATH};
+use crate::network_interface::WifiDeviceInfo;
 use crate::CaptivePortalError;
 use dbus::nonblock;
 use std::sync::Arc;
@@ -26,6 +28,19 @@ impl From<i64> for DeviceType {
     }
 }
 
+/// Returns true if the given network manager device type is a wifi device
+fn is_wifi_device(device_type: u32) -> bool {
+    DeviceType::from(device_type as i64) == DeviceType::WiFi
+}
+
+/// Wireless device capability flag: The device supports access point mode
+const NM_WIFI_DEVICE_CAP_AP: u32 = 0x40;
+
+/// Returns true if the given wireless capabilities include access point mode
+fn supports_ap_mode(capabilities: u32) -> bool {
+    capabilities & NM_WIFI_DEVICE_CAP_AP != 0
+}
+
 pub(crate) struct FindWifiDeviceResult {
     /// The network manager dbus api device path
     pub device_path: dbus::Path<'static>,
@@ -50,7 +65,7 @@ pub(crate) async fn find_wifi_device(
         let device_data = nonblock::Proxy::new(NM_BUSNAME, &device_path, connection.clone());
         use super::generated::device::Device;
         let device_type = device_data.device_type().await?;
-        if device_type == DeviceType::WiFi as u32 {
+        if is_wifi_device(device_type) {
             use super::generated::device::DeviceWireless;
             let hw = device_data.hw_address().await?;
             info!("Wireless device found: {}", interface_name);
@@ -68,7 +83,7 @@ pub(crate) async fn find_wifi_device(
         let device_data = nonblock::Proxy::new(NM_BUSNAME, &device_path, connection.clone());
         use super::generated::device::Device;
         let dtype = device_data.device_type().await?;
-        if dtype == DeviceType::WiFi as u32 {
+        if is_wifi_device(dtype) {
             use super::generated::device::DeviceWireless;
             let hw = device_data.hw_address().await?;
             let interface_name = device_data.interface().await?;
@@ -83,3 +98,82 @@ pub(crate) async fn find_wifi_device(
 
     Err(CaptivePortalError::NoWifiDeviceFound)
 }
+
+/// Lists all wifi devices, eg for selecting one of multiple radios.
+pub(crate) async fn list_wifi_devices(
+    connection: Arc<dbus::nonblock::SyncConnection>,
+) -> Result<Vec<WifiDeviceInfo>, CaptivePortalError> {
+    let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, connection.clone());
+    use super::generated::networkmanager::NetworkManager;
+
+    let mut devices = Vec::new();
+    for device_path in p.get_all_devices().await? {
+        let device_data = nonblock::Proxy::new(NM_BUSNAME, &device_path, connection.clone());
+        use super::generated::device::Device;
+        if !is_wifi_device(device_data.device_type().await?) {
+            continue;
+        }
+        use super::generated::device::DeviceWireless;
+        devices.push(WifiDeviceInfo {
+            interface_name: device_data.interface().await?,
+            hw: device_data.hw_address().await?,
+            state: format!("{:?}", DeviceState::from(Device::state(&device_data).await?)),
+        });
+    }
+    Ok(devices)
+}
+
+/// Returns an error if the given wifi device cannot operate as access point.
+pub(crate) async fn check_ap_mode(
+    connection: Arc<dbus::nonblock::SyncConnection>,
+    device_path: &dbus::Path<'static>,
+    interface_name: &str,
+) -> Result<(), CaptivePortalError> {
+    let device_data = nonblock::Proxy::new(NM_BUSNAME, device_path, connection);
+    use super::generated::device::DeviceWireless;
+    if supports_ap_mode(device_data.wireless_capabilities().await?) {
+        return Ok(());
+    }
+    Err(CaptivePortalError::ApModeUnsupported {
+        interface: interface_name.to_owned(),
+    })
+}
+
+/// Finds a second wifi device for a station connection while the given device runs the hotspot.
+///
+/// Network manager runs one connection per device and does not report AP+STA concurrency of a single radio.
+/// A hotspot and a station connection can only be active at the same time on two devices. The hotspot device
+/// must support access point mode according to its wireless capabilities and the station device must be managed.
+pub(crate) async fn concurrent_station_device(
+    connection: Arc<dbus::nonblock::SyncConnection>,
+    hotspot_device: &dbus::Path<'static>,
+) -> Result<Option<FindWifiDeviceResult>, CaptivePortalError> {
+    use super::generated::device::{Device, DeviceWireless};
+    let device_data = nonblock::Proxy::new(NM_BUSNAME, hotspot_device, connection.clone());
+    if !supports_ap_mode(device_data.wireless_capabilities().await?) {
+        return Ok(None);
+    }
+
+    let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, connection.clone());
+    use super::generated::networkmanager::NetworkManager;
+    for device_path in p.get_all_devices().await? {
+        if &device_path == hotspot_device {
+            continue;
+        }
+        let device_data = nonblock::Proxy::new(NM_BUSNAME, &device_path, connection.clone());
+        if !is_wifi_device(device_data.device_type().await?) || !device_data.managed().await? {
+            continue;
+        }
+        let interface_name = device_data.interface().await?;
+        info!(
+            "Wireless device for connections next to the hotspot: {}",
+            interface_name
+        );
+        return Ok(Some(FindWifiDeviceResult {
+            hw: device_data.hw_address().await?,
+            device_path,
+            interface_name,
+        }));
+    }
+    Ok(None)
+}
@@ -14,6 +14,7 @@ pub trait AccessPoint {
     fn max_bitrate(&self) -> nonblock::MethodReply<u32>;
     fn strength(&self) -> nonblock::MethodReply<u8>;
     fn last_seen(&self) -> nonblock::MethodReply<i32>;
+    fn bandwidth(&self) -> nonblock::MethodReply<u32>;
 }
 
 impl<'a, T: nonblock::NonblockReply, C: ::std::ops::Deref<Target = T>> AccessPoint for nonblock::Proxy<'a, C> {
@@ -96,6 +97,14 @@ impl<'a, T: nonblock::NonblockReply, C: ::std::ops::Deref<Target = T>> AccessPoi
             "LastSeen",
         )
     }
+
+    fn bandwidth(&self) -> nonblock::MethodReply<u32> {
+        <Self as nonblock::stdintf::org_freedesktop_dbus::Properties>::get(
+            &self,
+            "org.freedesktop.NetworkManager.AccessPoint",
+            "Bandwidth",
+        )
+    }
 }
 
 #[derive(Debug)]
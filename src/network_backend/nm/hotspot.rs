@@ -2,16 +2,57 @@
 use dbus::nonblock;
 use std::net::Ipv4Addr;
 
-use super::wifi_settings::{self, VariantMap, VariantMapNested, WifiConnectionMode};
-use super::{NetworkBackend, HOTSPOT_UUID, IN_MEMORY_ONLY, NM_BUSNAME, NM_PATH, NM_SETTINGS_PATH, VOLATILE_FLAG};
+use super::wifi_settings::{self, VariantMap, VariantMapNested, WiFiConnectionSettings, WifiConnectionMode};
+use super::{NetworkBackend, IN_MEMORY_ONLY, NM_BUSNAME, NM_PATH, NM_SETTINGS_PATH, VOLATILE_FLAG};
 use crate::dbus_tokio::SignalStream;
-use crate::network_interface::{ActiveConnection, ConnectionState, SSID};
+use crate::network_interface::{ActiveConnection, ConnectionState, WifiBand, SSID};
 use crate::CaptivePortalError;
 use dbus::message::SignalArgs;
 use futures_util::StreamExt;
 
 impl NetworkBackend {
-    /// The hotspot that is created by this service has a unique id.
+    /// Returns the ipv4 addresses with prefix length of all active connections on other devices than the wifi device,
+    /// together with the connection id. The connection on the wifi device is replaced by the hotspot and not returned.
+    pub async fn active_ipv4_addresses(&self) -> Result<Vec<(String, Ipv4Addr, u8)>, CaptivePortalError> {
+        use super::generated::connection_active::ConnectionActive;
+        use super::generated::networkmanager::NetworkManager;
+        use dbus::arg::{RefArg, Variant};
+        use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+        use std::collections::HashMap;
+
+        let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+        let mut addresses = Vec::new();
+        for connection_path in p.active_connections().await? {
+            let active = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
+            if ConnectionActive::devices(&active)
+                .await?
+                .contains(&self.wifi_device_path)
+            {
+                continue;
+            }
+            let ip4_config = ConnectionActive::ip4_config(&active).await?;
+            if &*ip4_config == "/" {
+                continue;
+            }
+            let id = ConnectionActive::id(&active).await?;
+            let p = nonblock::Proxy::new(NM_BUSNAME, ip4_config, self.conn.clone());
+            let address_data: Vec<HashMap<String, Variant<Box<dyn RefArg + 'static>>>> =
+                Properties::get(&p, "org.freedesktop.NetworkManager.IP4Config", "AddressData").await?;
+            for data in address_data {
+                let address = data
+                    .get("address")
+                    .and_then(|v| v.0.as_str())
+                    .and_then(|v| v.parse().ok());
+                let prefix = data.get("prefix").and_then(|v| v.0.as_u64());
+                if let (Some(address), Some(prefix)) = (address, prefix) {
+                    addresses.push((id.clone(), address, prefix as u8));
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// The hotspot that is created by this service instance has a unique id.
     /// This method will search connections for this id and delete the respective connection.
     ///
     /// This is necessary so that network manager does not try to auto connect to the hotspot
@@ -19,7 +60,7 @@ impl NetworkBackend {
     async fn hotspot_remove_existing(&self) -> Result<(), CaptivePortalError> {
         let p = nonblock::Proxy::new(NM_BUSNAME, NM_SETTINGS_PATH, self.conn.clone());
         use super::generated::connections::Settings;
-        match p.get_connection_by_uuid(HOTSPOT_UUID).await {
+        match p.get_connection_by_uuid(&self.hotspot_uuid).await {
             Ok(connection_path) => {
                 info!("Deleting old hotspot configuration {}", &connection_path);
                 let p = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
@@ -32,22 +73,28 @@ impl NetworkBackend {
         Ok(())
     }
 
-    /// Deactivate all hotspot connections
-    pub async fn deactivate_hotspots(&self) -> Result<(), CaptivePortalError> {
-        self.hotspot_remove_existing().await?;
-
+    /// The active hotspot connections on the wifi device of this instance, with their settings.
+    async fn active_hotspots(&self) -> Result<Vec<(dbus::Path<'static>, WiFiConnectionSettings)>, CaptivePortalError> {
         use super::generated::networkmanager::NetworkManager;
         let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
 
         let connections = p.active_connections().await?;
+        let mut hotspots = Vec::new();
 
         for connection_path in connections {
             let settings = wifi_settings::get_connection_settings(self.conn.clone(), connection_path.clone()).await;
             match settings {
                 Ok(Some(settings)) => {
                     if settings.mode == WifiConnectionMode::AP {
-                        info!("disable hotspot connection {} {}", settings.uuid, settings.ssid);
-                        p.deactivate_connection(connection_path).await?;
+                        use super::generated::connection_active::ConnectionActive;
+                        let active = nonblock::Proxy::new(NM_BUSNAME, connection_path.clone(), self.conn.clone());
+                        if !ConnectionActive::devices(&active)
+                            .await?
+                            .contains(&self.wifi_device_path)
+                        {
+                            continue;
+                        }
+                        hotspots.push((connection_path, settings));
                     }
                 },
                 Err(e) => {
@@ -56,24 +103,56 @@ impl NetworkBackend {
                 _ => {},
             }
         }
+        Ok(hotspots)
+    }
 
-        Ok(())
+    /// Returns the active connection path of a running hotspot on the wifi device of this instance, if any.
+    pub async fn active_hotspot(&self) -> Result<Option<dbus::Path<'static>>, CaptivePortalError> {
+        Ok(self.active_hotspots().await?.into_iter().next().map(|(path, _)| path))
+    }
+
+    /// Deactivate all hotspot connections on the wifi device of this instance.
+    /// Hotspots of other devices are not touched. Returns true if a hotspot has been deactivated.
+    pub async fn deactivate_hotspots(&self) -> Result<bool, CaptivePortalError> {
+        use super::generated::networkmanager::NetworkManager;
+        let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+        let mut deactivated = false;
+
+        for (connection_path, settings) in self.active_hotspots().await? {
+            info!("disable hotspot connection {} {}", settings.uuid, settings.ssid);
+            p.deactivate_connection(connection_path).await?;
+            deactivated = true;
+        }
+
+        self.hotspot_remove_existing().await?;
+        Ok(deactivated)
     }
 
-    /// Starts a hotspot
+    /// Starts a hotspot on the given wifi band. If an address is given, it is assigned with the given
+    /// subnet prefix length to the wifi interface.
+    /// Otherwise network manager chooses an address for a shared connection.
     pub async fn hotspot_start(
         &self,
         ssid: SSID,
         password: String,
         address: Option<Ipv4Addr>,
+        prefix: u8,
+        band: WifiBand,
     ) -> Result<ActiveConnection, CaptivePortalError> {
         self.hotspot_remove_existing().await?;
 
         debug!("Configuring hotspot ...");
         let connection_path = {
             // add connection
-            let settings =
-                wifi_settings::make_arguments_for_sta(ssid, password, address, &self.interface_name, HOTSPOT_UUID)?;
+            let settings = wifi_settings::make_arguments_for_sta(
+                ssid,
+                password,
+                address,
+                prefix,
+                band,
+                &self.interface_name,
+                &self.hotspot_uuid,
+            )?;
             let p = nonblock::Proxy::new(NM_BUSNAME, NM_SETTINGS_PATH, self.conn.clone());
             use super::generated::connections::Settings;
             // We want the dbus nm api AddConnection2 here, but that's not yet available everywhere as of Oct 2019.
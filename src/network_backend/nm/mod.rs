@@ -11,21 +11,28 @@ mod find_wifi_device;
 mod generated;
 mod hotspot;
 mod security;
+#[cfg(test)]
+pub(crate) mod test_bus;
 mod wifi_settings;
 
 use dbus::{nonblock, nonblock::SyncConnection};
 
+use futures_core::future::BoxFuture;
+use futures_util::FutureExt;
 use std::collections::HashMap;
+use std::future::Future;
 use std::sync::{Arc, Mutex};
-use futures_util::StreamExt;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time::delay_for;
 
 // Re-export for easier use in sub-modules
 use crate::dbus_tokio;
 use crate::network_interface::{
-    AccessPointCredentials, ActiveConnection, ConnectionState, NetworkManagerState, WifiConnection, SSID,
+    query_within_budget, ssid_from_raw, ssid_hex, unless_cancelled, AccessPointCredentials, ActiveConnection,
+    ConnectOptions, ConnectionState, NetworkManagerState, RadioState, ScanBudget, ScanCoordinator, ScanOutcome,
+    WifiConnection, WifiConnectionDetails, WifiDeviceInfo, MIN_SCAN_INTERVAL, SSID,
 };
+use crate::utils::poll_until_ok;
 use crate::CaptivePortalError;
 use generated::*;
 use wifi_settings::{VariantMap, VariantMapNested};
@@ -36,6 +43,7 @@ pub use access_points_changed::{ap_changed_stream, AccessPointChanged};
 pub const NM_BUSNAME: &str = "org.freedesktop.NetworkManager";
 pub(crate) const NM_PATH: &str = "/org/freedesktop/NetworkManager";
 pub(crate) const NM_SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+/// The default uuid of the hotspot connection
 pub(crate) const HOTSPOT_UUID: &str = "2b0d0f1d-b79d-43af-bde1-71744625642e";
 
 // Connection flags: optional flags argument.
@@ -45,6 +53,77 @@ pub const SAVE_TO_DISK_FLAG: u32 = 0x01;
 pub const VOLATILE_FLAG: u32 = 0x8 | 0x10;
 pub const IN_MEMORY_ONLY: u32 = 0x8 | 0x20;
 
+/// Attempts of a scan request if the wifi device is temporarily unavailable
+const SCAN_ATTEMPTS: usize = 3;
+/// Delay between scan request attempts
+const SCAN_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Interval for checking if a scan has completed
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Time for the radio to come up after wifi has been enabled
+const RADIO_ENABLE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Interval for checking if network manager is ready
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The outcome of a failed scan request
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ScanError {
+    /// A scan is already in progress. Not an error.
+    AlreadyScanning,
+    /// The device is temporarily unavailable, for example during a switch between access point and station mode
+    Retryable,
+    Fatal,
+}
+
+/// Decodes the "Ssid" byte array of an access point. An empty ssid (hidden network) is returned as None.
+/// See [`ssid_from_raw`] for ssids that are not valid UTF-8.
+fn ssid_from_bytes(ssid: Vec<u8>) -> Option<SSID> {
+    if ssid.is_empty() {
+        return None;
+    }
+    Some(ssid_from_raw(&ssid))
+}
+
+/// The update flags for a successfully established connection
+fn store_flags(persist_connection: bool) -> u32 {
+    if persist_connection {
+        SAVE_TO_DISK_FLAG
+    } else {
+        IN_MEMORY_ONLY
+    }
+}
+
+/// Classifies a failed scan request by its dbus error name.
+fn classify_scan_error(name: Option<&str>) -> ScanError {
+    match name {
+        Some("org.freedesktop.NetworkManager.Device.NotAllowed") => ScanError::AlreadyScanning,
+        Some("org.freedesktop.NetworkManager.Device.NotActive")
+        | Some("org.freedesktop.DBus.Error.UnknownObject")
+        | Some("org.freedesktop.DBus.Error.NoReply") => ScanError::Retryable,
+        _ => ScanError::Fatal,
+    }
+}
+
+/// Network manager reports an unknown state while it is still starting up.
+/// Returns [`CaptivePortalError::BackendNotReady`] in that case.
+fn ensure_ready(state: NetworkManagerState) -> Result<NetworkManagerState, CaptivePortalError> {
+    match state {
+        NetworkManagerState::Unknown => Err(CaptivePortalError::BackendNotReady),
+        state => Ok(state),
+    }
+}
+
+/// Returns true if the wifi device must be set managed.
+/// An unmanaged device is only set managed if `manage_device` is set, see [`CaptivePortalError::DeviceUnmanaged`].
+fn needs_managing(managed: bool, manage_device: bool, interface: &str) -> Result<bool, CaptivePortalError> {
+    match (managed, manage_device) {
+        (true, _) => Ok(false),
+        (false, true) => Ok(true),
+        (false, false) => Err(CaptivePortalError::DeviceUnmanaged {
+            interface: interface.to_owned(),
+        }),
+    }
+}
+
 #[derive(Clone)]
 pub struct NetworkBackend {
     exit_handler: Arc<Mutex<Option<tokio::sync::oneshot::Sender<()>>>>,
@@ -55,18 +134,46 @@ pub struct NetworkBackend {
     hw: String,
     /// Network interface name
     interface_name: String,
+    /// The uuid of the hotspot connection of this instance
+    hotspot_uuid: String,
+    /// Coalesces the scan requests of all callers
+    scans: ScanCoordinator,
 }
 
 impl NetworkBackend {
     /// Create a new connection to the network manager. This will also try to enable networking
     /// and wifi. Returns a network manager instance or an error if no wifi device can be found.
-    pub async fn new(interface_name: &Option<String>) -> Result<NetworkBackend, CaptivePortalError> {
+    ///
+    /// The wifi device might not be registered yet on a cold boot. Finding the device is retried
+    /// until `device_wait` elapsed. A zero duration fails immediately.
+    ///
+    /// Network manager might still be starting up. If `ready_wait` is not zero, this waits up to that time for
+    /// network manager to report a known state before looking for the wifi device.
+    ///
+    /// Multiple instances on the same machine require distinct hotspot uuids.
+    /// A default uuid is used if none is given.
+    pub async fn new(
+        interface_name: &Option<String>,
+        device_wait: Duration,
+        ready_wait: Duration,
+        hotspot_uuid: Option<String>,
+    ) -> Result<NetworkBackend, CaptivePortalError> {
+        // Connect to the D-Bus system bus (this is blocking, unfortunately).
+        let connection = dbus_tokio::new_system_sync()?;
+        NetworkBackend::with_connection(connection, interface_name, device_wait, ready_wait, hotspot_uuid).await
+    }
+
+    /// Like [`NetworkBackend::new`], but uses the given D-Bus connection, eg to a test bus.
+    pub(crate) async fn with_connection(
+        (resource, conn): (dbus_tokio::IOResource<SyncConnection>, Arc<SyncConnection>),
+        interface_name: &Option<String>,
+        device_wait: Duration,
+        ready_wait: Duration,
+        hotspot_uuid: Option<String>,
+    ) -> Result<NetworkBackend, CaptivePortalError> {
         // Prepare an exit handler
         let (exit_handler, exit_receiver) = tokio::sync::oneshot::channel::<()>();
 
-        // Connect to the D-Bus session bus (this is blocking, unfortunately).
-        let (resource, conn) = dbus_tokio::new_system_sync()?;
-
         // The resource is a task that should be spawned onto a tokio compatible
         // reactor ASAP. If the resource ever finishes, you lost connection to D-Bus.
         tokio::spawn(async move {
@@ -82,25 +189,62 @@ impl NetworkBackend {
             }
         });
 
-        let wifi_device = find_wifi_device::find_wifi_device(conn.clone(), interface_name).await?;
+        if ready_wait > Duration::from_secs(0) {
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, conn.clone());
+            let p = &p;
+            let state = poll_until_ok(ready_wait, READY_POLL_INTERVAL, move || async move {
+                use networkmanager::NetworkManager;
+                ensure_ready(NetworkManagerState::from(p.state().await?))
+            })
+            .await?;
+            info!("Network manager is ready. State {:?}", state);
+        }
+
+        let wifi_device = poll_until_ok(device_wait, Duration::from_secs(1), || {
+            find_wifi_device::find_wifi_device(conn.clone(), interface_name)
+        })
+        .await?;
         Ok(NetworkBackend {
             exit_handler: Arc::new(Mutex::new(Some(exit_handler))),
             conn,
             interface_name: wifi_device.interface_name,
             hw: wifi_device.hw,
             wifi_device_path: wifi_device.device_path,
+            hotspot_uuid: hotspot_uuid.unwrap_or_else(|| HOTSPOT_UUID.to_owned()),
+            scans: ScanCoordinator::new(MIN_SCAN_INTERVAL),
         })
     }
 
     /// Network might be disabled or "unmanaged". This method tries to enable networking and wifi.
-    pub async fn enable_networking_and_wifi(&self) -> Result<(), CaptivePortalError> {
+    ///
+    /// An unmanaged wifi device is only set to managed if `manage_device` is set.
+    /// [`CaptivePortalError::DeviceUnmanaged`] is returned otherwise.
+    ///
+    /// Returns [`CaptivePortalError::WifiBlocked`] if wifi is blocked by a hardware switch or stays blocked
+    /// in software (rfkill) after enabling it.
+    pub async fn enable_networking_and_wifi(&self, manage_device: bool) -> Result<(), CaptivePortalError> {
+        use generated::device::Device;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        if needs_managing(p.managed().await?, manage_device, &self.interface_name)? {
+            info!("Set wifi device {} managed", &self.interface_name);
+            p.set_managed(true).await?;
+        }
+
         let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
         use networkmanager::NetworkManager;
         if !p.networking_enabled().await? {
             p.enable(true).await?;
         }
+        if !p.wireless_hardware_enabled().await? {
+            return Err(CaptivePortalError::WifiBlocked { hard: true });
+        }
         if !p.wireless_enabled().await? {
             p.set_wireless_enabled(true).await?;
+            // The radio state follows with a delay. It stays disabled if blocked by rfkill.
+            poll_until_ok(RADIO_ENABLE_TIMEOUT, Duration::from_millis(200), || async {
+                self.radio_state().await?.ensure_unblocked()
+            })
+            .await?;
         }
         if p.connectivity_check_available().await? {
             p.set_connectivity_check_enabled(true).await?;
@@ -108,10 +252,19 @@ impl NetworkBackend {
         Ok(())
     }
 
-    /// Scan for access points if the last scan is older than 10 seconds
-    pub async fn scan_networks(&self) -> Result<(), CaptivePortalError> {
+    /// Scan for access points if the last scan is older than 10 seconds.
+    ///
+    /// The request is retried a few times if the wifi device is temporarily unavailable,
+    /// for example right after the hotspot has been stopped.
+    ///
+    /// Concurrent calls are coalesced into one request. A call within [`MIN_SCAN_INTERVAL`]
+    /// of the last successful request does not request another scan and returns [`ScanOutcome::RateLimited`].
+    pub async fn scan_networks(&self) -> Result<ScanOutcome, CaptivePortalError> {
+        self.scans.scan(|| self.request_scan()).await
+    }
+
+    async fn request_scan(&self) -> Result<(), CaptivePortalError> {
         use generated::device::DeviceWireless;
-        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
 
         // request_scan requires a hashmap of dbus::arg::RefArg parameters as argument.
         // Those are not thread safe, eg implement Send, so cannot be wrapped as intermediate state in the
@@ -120,19 +273,73 @@ impl NetworkBackend {
             p.request_scan(HashMap::new())
         }
 
-        // There is one error that we can expect by calling this method:
-        // org.freedesktop.NetworkManager.Device.NotAllowed - Scanning not allowed while already scanning
-        if let Err(e) = scan_networks(p).await {
-            if let Some(name) = e.name() {
-                // All good
-                if name == "org.freedesktop.NetworkManager.Device.NotAllowed" {
-                    return Ok(());
-                }
+        let mut attempt = 1;
+        loop {
+            let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+            let e = match scan_networks(p).await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            match classify_scan_error(e.name()) {
+                ScanError::AlreadyScanning => return Ok(()),
+                ScanError::Retryable if attempt < SCAN_ATTEMPTS => {
+                    info!(
+                        "Wifi device not ready for scanning: {}. Retrying",
+                        e.message().unwrap_or_default()
+                    );
+                    attempt += 1;
+                    delay_for(SCAN_RETRY_DELAY).await;
+                },
+                _ => return Err(e.into()),
             }
-            return Err(e.into());
         }
+    }
 
-        Ok(())
+    /// Lists all wifi devices of the system. This backend only manages the one it has been created for.
+    pub async fn list_wifi_devices(&self) -> Result<Vec<WifiDeviceInfo>, CaptivePortalError> {
+        find_wifi_device::list_wifi_devices(self.conn.clone()).await
+    }
+
+    /// Returns [`CaptivePortalError::ApModeUnsupported`] if the wifi device cannot operate as access point,
+    /// according to its wireless capability flags.
+    pub async fn check_ap_mode(&self) -> Result<(), CaptivePortalError> {
+        find_wifi_device::check_ap_mode(self.conn.clone(), &self.wifi_device_path, &self.interface_name).await
+    }
+
+    /// Returns a backend for a second wifi device that can connect to a network while the wifi device of this
+    /// backend runs the hotspot. None if there is no such device. Both backends share the dbus connection.
+    pub async fn concurrent_station(&self) -> Result<Option<NetworkBackend>, CaptivePortalError> {
+        let device = find_wifi_device::concurrent_station_device(self.conn.clone(), &self.wifi_device_path).await?;
+        Ok(device.map(|device| NetworkBackend {
+            interface_name: device.interface_name,
+            hw: device.hw,
+            wifi_device_path: device.device_path,
+            scans: ScanCoordinator::new(MIN_SCAN_INTERVAL),
+            ..self.clone()
+        }))
+    }
+
+    /// The time of the last completed scan in milliseconds since boot (CLOCK_BOOTTIME). -1 if no scan happened yet.
+    /// Pass this value to [`wait_for_scan`] to wait for the next scan to complete.
+    pub async fn last_scan(&self) -> Result<i64, CaptivePortalError> {
+        use generated::device::DeviceWireless;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        Ok(p.last_scan().await?)
+    }
+
+    /// Waits until a scan, that completed after the given [`last_scan`] time, is reported.
+    /// Returns false if no scan completed within the timeout.
+    pub async fn wait_for_scan(&self, last_scan: i64, timeout: Duration) -> Result<bool, CaptivePortalError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.last_scan().await? > last_scan {
+                return Ok(true);
+            }
+            if Instant::now() + SCAN_POLL_INTERVAL > deadline {
+                return Ok(false);
+            }
+            delay_for(SCAN_POLL_INTERVAL).await;
+        }
     }
 
     /// Terminates this network manager dbus connection
@@ -153,8 +360,63 @@ impl NetworkBackend {
         Ok(NetworkManagerState::from(p.state().await?))
     }
 
+    /// The software and hardware (rfkill) enabled state of wifi
+    pub async fn radio_state(&self) -> Result<RadioState, CaptivePortalError> {
+        let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+        use networkmanager::NetworkManager;
+        Ok(RadioState {
+            enabled: p.wireless_enabled().await?,
+            hardware_enabled: p.wireless_hardware_enabled().await?,
+        })
+    }
+
+    /// The signal strength in percent of the access point the wifi device is currently connected to.
+    /// Returns None if the device is not connected.
+    pub async fn active_signal_strength(&self) -> Result<Option<u8>, CaptivePortalError> {
+        use device::DeviceWireless;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let ap_path = p.active_access_point().await?;
+        // Network manager returns "/" if there is no active access point
+        if &ap_path[..] == "/" {
+            return Ok(None);
+        }
+
+        use access_point::AccessPoint;
+        let p = nonblock::Proxy::new(NM_BUSNAME, ap_path, self.conn.clone());
+        Ok(Some(p.strength().await?))
+    }
+
+    /// The ssid of the access point the wifi device is currently connected to.
+    /// Returns None if the device is not associated.
+    pub async fn connected_ssid(&self) -> Result<Option<SSID>, CaptivePortalError> {
+        use device::DeviceWireless;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let ap_path = p.active_access_point().await?;
+        // Network manager returns "/" if there is no active access point
+        if &ap_path[..] == "/" {
+            return Ok(None);
+        }
+
+        use access_point::AccessPoint;
+        let p = nonblock::Proxy::new(NM_BUSNAME, ap_path, self.conn.clone());
+        Ok(ssid_from_bytes(p.ssid().await?))
+    }
+
     /// Let network manager try to auto-connect.
+    /// The most recently connected wifi connection is activated first. Network manager falls back to
+    /// any other known connection via auto-connect if that fails.
     pub async fn try_auto_connect(&self, timeout: std::time::Duration) -> Result<bool, CaptivePortalError> {
+        if let Some(connection_path) = self.most_recent_connection().await? {
+            debug!("Activating the most recent connection {}", &connection_path);
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+            use networkmanager::NetworkManager;
+            if let Err(e) = p
+                .activate_connection(connection_path, self.wifi_device_path.clone(), "/".into())
+                .await
+            {
+                warn!("Failed to activate the most recent connection: {}", e);
+            }
+        }
         self.enable_auto_connect().await;
 
         use connections::Settings;
@@ -182,47 +444,62 @@ impl NetworkBackend {
     /// created connection will be named "My AP 1".
     ///
     /// # Arguments:
-    /// * ssid: The ssid
+    /// * ssid: The raw bytes of the ssid, which is not necessarily valid UTF-8
     /// * credentials: The connection credentials
-    /// * hw: The target access point mac address. If this is set, this method will first try to find
-    ///   a connection that was connected to that access point in the past and update that connection.
-    /// * overwrite_same_ssid_connection: If this is true and a connection can be found that matches the
-    ///   given SSID, that connection will be updated.
+    /// * options: The target access point and how the connection is found and stored, see [`ConnectOptions`]
+    /// * cancelled: Cancels the attempt, see [`ConnectCancel`]. The activating connection is deactivated
+    ///   and removed, like a failed one.
+    ///
+    /// [`AccessPointCredentials::Saved`] activates the saved connection of the ssid as it is.
+    /// The saved connection is kept, even if it could not be activated.
     pub async fn connect_to(
         &self,
-        ssid: SSID,
+        ssid: Vec<u8>,
         credentials: AccessPointCredentials,
-        hw: Option<String>,
-        overwrite_same_ssid_connection: bool,
+        options: ConnectOptions,
+        mut cancelled: tokio::sync::oneshot::Receiver<()>,
     ) -> Result<Option<ActiveConnection>, CaptivePortalError> {
+        let ConnectOptions {
+            hw,
+            overwrite_same_ssid_connection,
+            lock_bssid,
+            persist_connection,
+            activation_timeout,
+        } = options;
+        let bssid = if lock_bssid { hw.as_ref().map(|v| &v[..]) } else { None };
+        let saved = match credentials {
+            AccessPointCredentials::Saved => true,
+            _ => false,
+        };
+        // Saved connections are listed by their display name
+        let ssid_name = ssid_from_raw(&ssid);
         // try to find connection, update it, activate it and return the connection path
-        let active_connection = if let Some(hw) = hw {
-            if let Some((connection_path, old_connection)) = self.find_connection_by_mac(&hw).await? {
-                Some(
-                    self.update_connection(connection_path, &ssid, old_connection, credentials.clone())
-                        .await?,
-                )
-            } else {
-                None
-            }
-        } else if overwrite_same_ssid_connection {
-            if let Some((connection_path, old_connection)) = self.find_connection_by_ssid(&ssid).await? {
-                Some(
-                    self.update_connection(connection_path, &ssid, old_connection, credentials.clone())
-                        .await?,
-                )
-            } else {
-                None
-            }
-        } else {
-            None
+        let lookup = connection_lookup(saved, hw.as_deref(), overwrite_same_ssid_connection);
+        let existing = match lookup {
+            ConnectionLookup::Ssid => self.find_connection_by_ssid(&ssid_name).await?,
+            ConnectionLookup::Mac(hw) => self.find_connection_by_mac(&hw.to_owned()).await?,
+            ConnectionLookup::None => None,
+        };
+        let active_connection = match (connect_action(saved, existing.is_some()), existing) {
+            (ConnectAction::ActivateExisting, Some((connection_path, _))) => {
+                Some(self.activate_connection(connection_path).await?)
+            },
+            (ConnectAction::UpdateExisting, Some((connection_path, old_connection))) => Some(
+                self.update_connection(connection_path, &ssid, old_connection, credentials.clone(), bssid)
+                    .await?,
+            ),
+            (ConnectAction::NoSavedConnection, _) => {
+                warn!("No saved connection found for {}", ssid_name);
+                return Ok(None);
+            },
+            (ConnectAction::AddNew, _) | (_, None) => None,
         };
 
         // If not found: Create and activate a new connection
         let (connection_path, active_connection) = if let Some(active_connection) = active_connection {
             active_connection
         } else {
-            let settings = wifi_settings::make_arguments_for_ap(&ssid, credentials, None)?;
+            let settings = wifi_settings::make_arguments_for_ap(&ssid, credentials, None, bssid)?;
             let options = wifi_settings::make_options_for_ap();
 
             // Create connection
@@ -235,51 +512,69 @@ impl NetworkBackend {
         };
 
         // Wait up to 5 seconds while in Deactivated
-        let state = self
-            .wait_for_active_connection_state(
-                ConnectionState::Deactivated,
-                active_connection.clone(),
-                Duration::from_secs(10),
-                true,
-            )
-            .await?;
+        let wait = self.wait_for_active_connection_state(
+            ConnectionState::Deactivated,
+            active_connection.clone(),
+            Duration::from_secs(10),
+            true,
+        );
+        let step =
+            unless_cancelled_or_teardown(wait, &mut cancelled, self, &connection_path, &active_connection, saved);
+        let state = match step.await? {
+            Some(state) => state?,
+            None => return Ok(None),
+        };
         // Not successful
         if state == ConnectionState::Deactivated {
+            if saved {
+                return Ok(None);
+            }
             use connection_nm::Connection;
             let p = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
             p.delete().await?;
             return Ok(None);
         }
 
-        // Wait up to 30 seconds while in Activating
-        let state = self
-            .wait_for_active_connection_state(
-                ConnectionState::Activated,
-                active_connection.clone(),
-                Duration::from_secs(30),
-                false,
-            )
-            .await?;
+        // Wait while in Activating
+        let wait = self.wait_for_active_connection_state(
+            ConnectionState::Activated,
+            active_connection.clone(),
+            activation_timeout,
+            false,
+        );
+        let step =
+            unless_cancelled_or_teardown(wait, &mut cancelled, self, &connection_path, &active_connection, saved);
+        let state = match step.await? {
+            Some(state) => state?,
+            None => return Ok(None),
+        };
 
-        // Remove connection if not successful. Store it permanently if successful
+        // Remove connection if not successful. Store it if successful
         if state == ConnectionState::Activated {
             use connection_nm::Connection;
-            let p = nonblock::Proxy::new(NM_BUSNAME, connection_path.clone(), self.conn.clone());
+            // A saved connection is stored already
+            if !saved {
+                let p = nonblock::Proxy::new(NM_BUSNAME, connection_path.clone(), self.conn.clone());
 
-            // Settings: Provide an empty array, to use the current settings.
-            p.update2(VariantMapNested::new(), SAVE_TO_DISK_FLAG, VariantMap::new())
+                // Settings: Provide an empty array, to use the current settings.
+                p.update2(
+                    VariantMapNested::new(),
+                    store_flags(persist_connection),
+                    VariantMap::new(),
+                )
                 .await?;
+            }
             return Ok(Some(ActiveConnection {
                 connection_path: connection_path.into_static(),
                 active_connection_path: active_connection.into_static(),
                 state,
             }));
-        } else {
+        } else if !saved {
             use connection_nm::Connection;
             let p = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
             p.delete().await?;
-            return Ok(None);
         }
+        Ok(None)
     }
 
     /// Get access point data for the given access point network manager dbus path.
@@ -294,15 +589,17 @@ impl NetworkBackend {
         let access_point_data = nonblock::Proxy::new(NM_BUSNAME, ap_path, self.conn.clone());
         use access_point::AccessPoint;
         let hw = access_point_data.hw_address().await?;
-        let ssid = String::from_utf8(access_point_data.ssid().await?)?;
+        let raw_ssid = access_point_data.ssid().await?;
 
         let wifi_connection = WifiConnection {
             is_own: hw == self.hw,
-            ssid,
+            ssid: ssid_from_raw(&raw_ssid),
+            ssid_hex: ssid_hex(&raw_ssid),
             hw,
             security,
             strength: access_point_data.strength().await?,
             frequency: access_point_data.frequency().await?,
+            last_seen: Instant::now(),
         };
         if !wifi_connection.is_own {
             info!("Found AP {:?}", &wifi_connection.ssid);
@@ -310,16 +607,65 @@ impl NetworkBackend {
         Ok(wifi_connection)
     }
 
+    /// Get detailed access point data for the given access point network manager dbus path.
+    /// The channel width is only known to network manager 1.46 and newer.
+    async fn access_point_details(
+        &self,
+        ap_path: dbus::Path<'static>,
+        is_active: bool,
+    ) -> Result<WifiConnectionDetails, CaptivePortalError> {
+        let connection = self.access_point(ap_path.clone()).await?;
+        let access_point_data = nonblock::Proxy::new(NM_BUSNAME, ap_path, self.conn.clone());
+        use access_point::AccessPoint;
+        Ok(WifiConnectionDetails {
+            access_points: vec![connection.hw.clone()],
+            connection,
+            flags: security::ap_flag_names(access_point_data.flags().await?),
+            wpa_flags: security::security_flag_names(access_point_data.wpa_flags().await?),
+            rsn_flags: security::security_flag_names(access_point_data.rsn_flags().await?),
+            max_bitrate: access_point_data.max_bitrate().await?,
+            channel_width: access_point_data.bandwidth().await.ok(),
+            is_active,
+        })
+    }
+
+    /// Return detailed data of the network with the given raw ssid, which is not necessarily valid UTF-8.
+    /// All access points with that ssid are merged. Returns None if no such network is known.
+    pub async fn network_details(&self, ssid: &[u8]) -> Result<Option<WifiConnectionDetails>, CaptivePortalError> {
+        use device::DeviceWireless;
+        let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let active_ap_path = p.active_access_point().await?;
+
+        let mut details = Vec::new();
+        for ap_path in p.get_access_points().await? {
+            use access_point::AccessPoint;
+            let access_point_data = nonblock::Proxy::new(NM_BUSNAME, ap_path.clone(), self.conn.clone());
+            if access_point_data.ssid().await? != ssid {
+                continue;
+            }
+            let is_active = ap_path == active_ap_path;
+            let ap = self.access_point_details(ap_path, is_active).await?;
+            if !ap.connection.is_own {
+                details.push(ap);
+            }
+        }
+        Ok(WifiConnectionDetails::merge(details))
+    }
+
     /// Return all known access points of the associated wifi device.
     /// The list might not be up to date and can be refreshed with a call to [`scan_networks`].
     ///
     /// ## Arguments
     /// * timeout: If timeout is != 0, performs a full scan. Waits up to timeout for at least one result.
+    /// * budget: Limits the overall work. The access points found so far are returned if it is exhausted.
     pub async fn list_access_points(
         &self,
         mut timeout: std::time::Duration,
+        budget: ScanBudget,
     ) -> Result<Vec<WifiConnection>, CaptivePortalError> {
         let p = nonblock::Proxy::new(NM_BUSNAME, self.wifi_device_path.clone(), self.conn.clone());
+        let started = Instant::now();
+        let mut queries = 0;
 
         let connections = {
             use device::DeviceWireless;
@@ -329,23 +675,32 @@ impl NetworkBackend {
             let interval = Duration::from_millis(500);
             loop {
                 // Get access point list
-                let stream = futures_util::stream::iter(p.get_access_points().await?.into_iter());
-                let access_point_paths: Vec<WifiConnection> = stream
-                    .filter_map(|ap_path| {
-                        async {
-                            self.access_point(ap_path).await.ok().and_then(|ap| match ap.is_own {
-                                true => None,
-                                false => Some(ap),
-                            })
-                        }
-                    })
-                    .collect()
-                    .await;
-                if access_point_paths.len() > 0 {
-                    break access_point_paths;
+                queries += 1;
+                let (access_points, exhausted) = query_within_budget(
+                    p.get_access_points().await?,
+                    &budget,
+                    started,
+                    &mut queries,
+                    |ap_path| async {
+                        self.access_point(ap_path).await.ok().and_then(|ap| match ap.is_own {
+                            true => None,
+                            false => Some(ap),
+                        })
+                    },
+                )
+                .await;
+                if exhausted {
+                    warn!(
+                        "Access point listing budget exhausted. Returning {} access points",
+                        access_points.len()
+                    );
+                    break access_points;
                 }
-                if timeout.as_millis() <= 0 {
-                    break access_point_paths;
+                if !access_points.is_empty() {
+                    break access_points;
+                }
+                if timeout.as_millis() <= 0 || budget.exhausted(queries, started) {
+                    break access_points;
                 }
                 delay_for(interval).await;
                 timeout -= interval;
@@ -355,3 +710,371 @@ impl NetworkBackend {
         Ok(connections)
     }
 }
+
+/// The network manager calls that tear down a cancelled connection attempt
+trait AttemptTeardown {
+    fn deactivate(&self, active_connection: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>>;
+    fn delete(&self, connection_path: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>>;
+}
+
+impl AttemptTeardown for NetworkBackend {
+    fn deactivate(&self, active_connection: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>> {
+        async move {
+            use networkmanager::NetworkManager;
+            let p = nonblock::Proxy::new(NM_BUSNAME, NM_PATH, self.conn.clone());
+            Ok(p.deactivate_connection(active_connection).await?)
+        }
+        .boxed()
+    }
+
+    fn delete(&self, connection_path: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>> {
+        async move {
+            use connection_nm::Connection;
+            let p = nonblock::Proxy::new(NM_BUSNAME, connection_path, self.conn.clone());
+            Ok(p.delete().await?)
+        }
+        .boxed()
+    }
+}
+
+/// Runs the given step of a connection attempt to completion, unless the attempt is cancelled first.
+/// A cancelled attempt is torn down: The connection is deactivated and removed. A saved connection is
+/// only deactivated. Returns None if cancelled.
+async fn unless_cancelled_or_teardown<F: Future>(
+    step: F,
+    cancelled: &mut tokio::sync::oneshot::Receiver<()>,
+    teardown: &impl AttemptTeardown,
+    connection_path: &dbus::Path<'_>,
+    active_connection: &dbus::Path<'_>,
+    saved: bool,
+) -> Result<Option<F::Output>, CaptivePortalError> {
+    if let Some(output) = unless_cancelled(Box::pin(step), cancelled).await {
+        return Ok(Some(output));
+    }
+    info!("Connection attempt cancelled");
+    // The connection might have failed in the meantime and is gone already
+    if let Err(e) = teardown.deactivate(active_connection.clone().into_static()).await {
+        info!("Cancelled connection not deactivated: {}", e);
+    }
+    if !saved {
+        teardown.delete(connection_path.clone().into_static()).await?;
+    }
+    Ok(None)
+}
+
+/// Where to look for an existing connection of a connection request
+#[derive(Debug, PartialEq)]
+enum ConnectionLookup<'a> {
+    /// The connection with the requested ssid
+    Ssid,
+    /// The connection that is bound to the given access point mac address
+    Mac(&'a str),
+    /// Do not look for an existing connection
+    None,
+}
+
+/// How a connection request is applied
+#[derive(Debug, PartialEq)]
+enum ConnectAction {
+    /// Activate the existing connection as is. Its saved credentials are used.
+    ActivateExisting,
+    /// Update the existing connection with the requested credentials and activate it
+    UpdateExisting,
+    /// Add and activate a new connection
+    AddNew,
+    /// Saved credentials were requested, but there is no saved connection
+    NoSavedConnection,
+}
+
+/// Returns where to look for an existing connection. A request with saved credentials looks up the connection
+/// of the ssid. Otherwise the connection of the given access point is updated, or the connection of the ssid
+/// if that is to be overwritten.
+fn connection_lookup(saved: bool, hw: Option<&str>, overwrite_same_ssid_connection: bool) -> ConnectionLookup<'_> {
+    match hw {
+        _ if saved => ConnectionLookup::Ssid,
+        Some(hw) => ConnectionLookup::Mac(hw),
+        None if overwrite_same_ssid_connection => ConnectionLookup::Ssid,
+        None => ConnectionLookup::None,
+    }
+}
+
+/// Returns how a connection request is applied, depending on whether an existing connection has been found.
+fn connect_action(saved: bool, found: bool) -> ConnectAction {
+    match (saved, found) {
+        (true, true) => ConnectAction::ActivateExisting,
+        (true, false) => ConnectAction::NoSavedConnection,
+        (false, true) => ConnectAction::UpdateExisting,
+        (false, false) => ConnectAction::AddNew,
+    }
+}
+
+/// A backend for tests of code that holds a backend but must not call into network manager.
+/// Its D-Bus connection leads to a socket that never answers, so calls do not resolve.
+#[cfg(test)]
+impl NetworkBackend {
+    pub(crate) fn unanswered() -> NetworkBackend {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let socket = dir.path().join("bus");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).expect("Bind unix socket");
+        let channel = dbus::channel::Channel::open_private(&format!("unix:path={}", socket.display()))
+            .expect("Connect to unix socket");
+        let (exit_handler, _) = tokio::sync::oneshot::channel::<()>();
+        NetworkBackend {
+            exit_handler: Arc::new(Mutex::new(Some(exit_handler))),
+            conn: Arc::new(SyncConnection::from(channel)),
+            wifi_device_path: dbus::Path::from("/org/freedesktop/NetworkManager/Devices/1"),
+            hw: String::new(),
+            interface_name: "wlan0".to_owned(),
+            hotspot_uuid: HOTSPOT_UUID.to_owned(),
+            scans: ScanCoordinator::new(MIN_SCAN_INTERVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_bus::{bytes, TestBus, ETHERNET_DEVICE, WIFI_DEVICE};
+    use super::{
+        connect_action, connection_lookup, unless_cancelled_or_teardown, AttemptTeardown, ConnectAction,
+        ConnectionLookup, NetworkBackend, SCAN_ATTEMPTS,
+    };
+    use crate::{CaptivePortalError, ConnectCancel, NetworkManagerState, ScanOutcome};
+    use dbus::arg::messageitem::MessageItem;
+    use futures_core::future::BoxFuture;
+    use futures_util::FutureExt;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::time::delay_for;
+
+    /// The device and readiness wait of backends that are created on the test bus
+    const WAIT: Duration = Duration::from_secs(1);
+
+    /// Records the teardown calls. Deactivating fails if set, like for a connection that failed in the meantime.
+    #[derive(Default)]
+    struct TestTeardown {
+        calls: Mutex<Vec<String>>,
+        deactivate_fails: bool,
+    }
+
+    impl AttemptTeardown for TestTeardown {
+        fn deactivate(&self, active_connection: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("deactivate {}", active_connection));
+            let result = match self.deactivate_fails {
+                true => Err(CaptivePortalError::Generic("No active connection".to_owned())),
+                false => Ok(()),
+            };
+            async move { result }.boxed()
+        }
+
+        fn delete(&self, connection_path: dbus::Path<'static>) -> BoxFuture<'_, Result<(), CaptivePortalError>> {
+            self.calls.lock().unwrap().push(format!("delete {}", connection_path));
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_connection_attempt() {
+        let connection = dbus::Path::from("/org/freedesktop/NetworkManager/Settings/1");
+        let active = dbus::Path::from("/org/freedesktop/NetworkManager/ActiveConnection/1");
+        let cancel_mid_attempt = |cancel: ConnectCancel| async move {
+            delay_for(Duration::from_millis(20)).await;
+            assert!(cancel.cancel());
+        };
+
+        // Cancelled while activating: The attempt is deactivated and deleted
+        for deactivate_fails in [false, true].iter() {
+            let teardown = TestTeardown {
+                deactivate_fails: *deactivate_fails,
+                ..Default::default()
+            };
+            let cancel = ConnectCancel::default();
+            let mut cancelled = cancel.start();
+            let activating = futures_util::future::pending::<()>();
+            let (result, _) = futures_util::future::join(
+                unless_cancelled_or_teardown(activating, &mut cancelled, &teardown, &connection, &active, false),
+                cancel_mid_attempt(cancel.clone()),
+            )
+            .await;
+            match result {
+                Ok(None) => {}
+                r => panic!("Expected a cancelled attempt. Got {:?}", r),
+            }
+            assert_eq!(
+                *teardown.calls.lock().unwrap(),
+                vec![format!("deactivate {}", active), format!("delete {}", connection)]
+            );
+        }
+
+        // A saved connection is only deactivated
+        let teardown = TestTeardown::default();
+        let cancel = ConnectCancel::default();
+        let mut cancelled = cancel.start();
+        let activating = futures_util::future::pending::<()>();
+        let (result, _) = futures_util::future::join(
+            unless_cancelled_or_teardown(activating, &mut cancelled, &teardown, &connection, &active, true),
+            cancel_mid_attempt(cancel.clone()),
+        )
+        .await;
+        match result {
+            Ok(None) => {}
+            r => panic!("Expected a cancelled attempt. Got {:?}", r),
+        }
+        assert_eq!(*teardown.calls.lock().unwrap(), vec![format!("deactivate {}", active)]);
+
+        // A finished step is not torn down
+        let teardown = TestTeardown::default();
+        let cancel = ConnectCancel::default();
+        let mut cancelled = cancel.start();
+        let result =
+            unless_cancelled_or_teardown(async { 42 }, &mut cancelled, &teardown, &connection, &active, false).await;
+        match result {
+            Ok(Some(42)) => {}
+            r => panic!("Expected the finished step. Got {:?}", r),
+        }
+        assert!(teardown.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connect_decision() {
+        // Saved credentials: The saved connection of the ssid is activated as is
+        assert_eq!(connection_lookup(true, Some("AA:BB"), false), ConnectionLookup::Ssid);
+        assert_eq!(connect_action(true, true), ConnectAction::ActivateExisting);
+        assert_eq!(connect_action(true, false), ConnectAction::NoSavedConnection);
+
+        // New credentials: The connection of the access point or the ssid is updated, if there is one
+        assert_eq!(
+            connection_lookup(false, Some("AA:BB"), false),
+            ConnectionLookup::Mac("AA:BB")
+        );
+        assert_eq!(
+            connection_lookup(false, Some("AA:BB"), true),
+            ConnectionLookup::Mac("AA:BB")
+        );
+        assert_eq!(connection_lookup(false, None, true), ConnectionLookup::Ssid);
+        assert_eq!(connect_action(false, true), ConnectAction::UpdateExisting);
+        assert_eq!(connection_lookup(false, None, false), ConnectionLookup::None);
+        assert_eq!(connect_action(false, false), ConnectAction::AddNew);
+    }
+
+    #[tokio::test]
+    async fn readiness() {
+        let bus = TestBus::start();
+        // Network manager is still starting up
+        bus.set_state(0);
+        let r = NetworkBackend::with_connection(bus.connection(), &None, Duration::from_secs(0), WAIT, None).await;
+        match r {
+            Err(CaptivePortalError::BackendNotReady) => {}
+            r => panic!("Expected BackendNotReady. Got {:?}", r.map(|nm| nm.interface_name)),
+        }
+
+        // Polled until a known state is reported
+        let starting = NetworkBackend::with_connection(bus.connection(), &None, Duration::from_secs(0), WAIT, None);
+        let started = async {
+            delay_for(Duration::from_millis(100)).await;
+            bus.set_state(70);
+        };
+        let (r, _) = futures_util::future::join(starting, started).await;
+        match r {
+            Ok(nm) => assert_eq!(nm.state().await.ok(), Some(NetworkManagerState::Connected)),
+            r => panic!("Expected a backend. Got {:?}", r.map(|nm| nm.interface_name)),
+        }
+    }
+
+    #[tokio::test]
+    async fn wifi_device() {
+        let bus = TestBus::start();
+        // The ethernet device is skipped
+        let nm = bus.backend().await;
+        assert_eq!(nm.interface_name, "wlan0");
+        assert_eq!(&*nm.wifi_device_path, WIFI_DEVICE);
+        assert_eq!(nm.hw, "00:11:22:33:44:55");
+
+        // A configured interface that is not a wifi device is skipped as well
+        bus.set_reply("GetDeviceByIpIface", vec![MessageItem::ObjectPath(ETHERNET_DEVICE.into())]);
+        let r = NetworkBackend::with_connection(bus.connection(), &Some("eth0".to_owned()), WAIT, WAIT, None).await;
+        match r {
+            Ok(nm) => assert_eq!(&*nm.wifi_device_path, WIFI_DEVICE),
+            r => panic!("Expected a backend. Got {:?}", r.map(|nm| nm.interface_name)),
+        }
+
+        bus.set_property(WIFI_DEVICE, "DeviceType", 14u32);
+        let r = NetworkBackend::with_connection(bus.connection(), &None, Duration::from_secs(0), WAIT, None).await;
+        match r {
+            Err(CaptivePortalError::NoWifiDeviceFound) => {}
+            r => panic!("Expected NoWifiDeviceFound. Got {:?}", r.map(|nm| nm.interface_name)),
+        }
+    }
+
+    #[tokio::test]
+    async fn connected_ssid() {
+        let bus = TestBus::start();
+        let nm = bus.backend().await;
+        // Not associated
+        assert_eq!(nm.connected_ssid().await.unwrap(), None);
+
+        let access_point = "/org/freedesktop/NetworkManager/AccessPoint/1";
+        bus.set_property(WIFI_DEVICE, "ActiveAccessPoint", MessageItem::ObjectPath(access_point.into()));
+        let ssids: [(&[u8], Option<&str>); 3] = [
+            (b"home", Some("home")),
+            // Hidden network
+            (b"", None),
+            // Not valid UTF-8: Still listed with a display-safe name
+            (&[b'a', 0xff, 0xfe], Some("a\u{fffd}\u{fffd}")),
+        ];
+        for (ssid, expected) in ssids.iter() {
+            bus.set_property(access_point, "Ssid", bytes(ssid));
+            assert_eq!(nm.connected_ssid().await.unwrap().as_deref(), *expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn enable_unmanaged_device() {
+        let bus = TestBus::start();
+        bus.set_property(WIFI_DEVICE, "Managed", false);
+        let nm = bus.backend().await;
+        match nm.enable_networking_and_wifi(false).await {
+            Err(e @ CaptivePortalError::DeviceUnmanaged { .. }) => {
+                assert!(e.to_string().contains("nmcli device set wlan0 managed yes"))
+            },
+            r => panic!("Expected DeviceUnmanaged. Got {:?}", r),
+        }
+        assert!(bus.calls("Set").is_empty());
+
+        nm.enable_networking_and_wifi(true).await.unwrap();
+        assert_eq!(bus.property(WIFI_DEVICE, "Managed"), Some(MessageItem::Bool(true)));
+    }
+
+    #[tokio::test]
+    async fn scan_errors() {
+        let bus = TestBus::start();
+        // A scan in progress is not an error
+        bus.set_error("RequestScan", "org.freedesktop.NetworkManager.Device.NotAllowed");
+        match bus.backend().await.scan_networks().await {
+            Ok(ScanOutcome::Scanned) => {}
+            r => panic!("Expected a scan. Got {:?}", r),
+        }
+        assert_eq!(bus.calls("RequestScan").len(), 1);
+
+        // A device that is not ready yet is asked again
+        bus.set_error("RequestScan", "org.freedesktop.NetworkManager.Device.NotActive");
+        match bus.backend().await.scan_networks().await {
+            Err(CaptivePortalError::DBus(name, _)) => {
+                assert_eq!(name, "org.freedesktop.NetworkManager.Device.NotActive")
+            },
+            r => panic!("Expected a dbus error. Got {:?}", r),
+        }
+        assert_eq!(bus.calls("RequestScan").len(), 1 + SCAN_ATTEMPTS);
+
+        bus.set_error("RequestScan", "org.freedesktop.NetworkManager.PermissionDenied");
+        match bus.backend().await.scan_networks().await {
+            Err(CaptivePortalError::DBus(name, _)) => {
+                assert_eq!(name, "org.freedesktop.NetworkManager.PermissionDenied")
+            },
+            r => panic!("Expected a dbus error. Got {:?}", r),
+        }
+        assert_eq!(bus.calls("RequestScan").len(), 2 + SCAN_ATTEMPTS);
+    }
+}
@@ -54,6 +54,42 @@ pub(crate) enum NM80211ApSecurityFlags {
     AP_SEC_KEY_MGMT_802_1X = 0x0000_0200,
 }
 
+/// Returns the names of the set capability flags, eg "privacy"
+pub(crate) fn ap_flag_names(bits: u32) -> Vec<&'static str> {
+    let flags: BitFlags<NM80211ApFlags> = BitFlags::from_bits_truncate(bits);
+    [
+        (NM80211ApFlags::AP_FLAGS_PRIVACY, "privacy"),
+        (NM80211ApFlags::AP_FLAGS_WPS, "wps"),
+        (NM80211ApFlags::AP_FLAGS_WPS_PBC, "wps_pbc"),
+        (NM80211ApFlags::AP_FLAGS_WPS_PIN, "wps_pin"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| *name)
+    .collect()
+}
+
+/// Returns the names of the set WPA or RSN security flags, eg "pair_ccmp" or "key_mgmt_psk"
+pub(crate) fn security_flag_names(bits: u32) -> Vec<&'static str> {
+    let flags: BitFlags<NM80211ApSecurityFlags> = BitFlags::from_bits_truncate(bits);
+    [
+        (NM80211ApSecurityFlags::AP_SEC_PAIR_WEP40, "pair_wep40"),
+        (NM80211ApSecurityFlags::AP_SEC_PAIR_WEP104, "pair_wep104"),
+        (NM80211ApSecurityFlags::AP_SEC_PAIR_TKIP, "pair_tkip"),
+        (NM80211ApSecurityFlags::AP_SEC_PAIR_CCMP, "pair_ccmp"),
+        (NM80211ApSecurityFlags::AP_SEC_GROUP_WEP40, "group_wep40"),
+        (NM80211ApSecurityFlags::AP_SEC_GROUP_WEP104, "group_wep104"),
+        (NM80211ApSecurityFlags::AP_SEC_GROUP_TKIP, "group_tkip"),
+        (NM80211ApSecurityFlags::AP_SEC_GROUP_CCMP, "group_ccmp"),
+        (NM80211ApSecurityFlags::AP_SEC_KEY_MGMT_PSK, "key_mgmt_psk"),
+        (NM80211ApSecurityFlags::AP_SEC_KEY_MGMT_802_1X, "key_mgmt_802_1x"),
+    ]
+    .iter()
+    .filter(|(flag, _)| flags.contains(*flag))
+    .map(|(_, name)| *name)
+    .collect()
+}
+
 // Returns the strongest supported encryption mode of an dbus access point path. The encryption mode depends on
 // quite a few flags and that's why it is encapsulated into its own method.
 pub(crate) async fn get_access_point_security(
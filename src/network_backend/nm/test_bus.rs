@@ -0,0 +1,279 @@
+//! A network manager stand-in on a private D-Bus daemon, for tests of the state machine and the backend.
+//!
+//! Properties are served from a table that tests can change at any time. Method calls are recorded and
+//! answered with the configured reply, or with an empty reply if none is configured.
+//! Signals are not emitted. The "dbus-daemon" executable must be in the PATH.
+
+use super::{NetworkBackend, NM_BUSNAME, NM_PATH, NM_SETTINGS_PATH};
+use crate::dbus_tokio;
+use dbus::arg::messageitem::{MessageItem, MessageItemArray, MessageItemDict};
+use dbus::channel::Channel;
+use dbus::message::MessageType;
+use dbus::Message;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The wifi device of the default setup
+pub(crate) const WIFI_DEVICE: &str = "/org/freedesktop/NetworkManager/Devices/2";
+/// An ethernet device of the default setup. It is listed before the wifi device.
+pub(crate) const ETHERNET_DEVICE: &str = "/org/freedesktop/NetworkManager/Devices/1";
+
+/// Replies of the stand-in: Property values by object path and property name and method replies by method name.
+#[derive(Default)]
+struct Replies {
+    properties: HashMap<(String, String), MessageItem>,
+    methods: HashMap<String, Result<Vec<MessageItem>, String>>,
+    /// The method name and arguments of each call
+    calls: Vec<(String, Vec<MessageItem>)>,
+}
+
+impl Replies {
+    fn answer(&mut self, msg: &Message) -> Message {
+        let path = msg.path().map(|p| p.to_string()).unwrap_or_default();
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let is_properties = msg
+            .interface()
+            .map_or(false, |i| &*i == "org.freedesktop.DBus.Properties");
+        let args = msg.get_items();
+        match (is_properties, &member[..], &args[..]) {
+            (true, "Get", [_, MessageItem::Str(name)]) => match self.properties.get(&(path, name.clone())) {
+                Some(value) => {
+                    let mut reply = msg.method_return();
+                    reply.append_items(&[MessageItem::Variant(Box::new(value.clone()))]);
+                    reply
+                },
+                None => error(msg, "org.freedesktop.DBus.Error.UnknownProperty"),
+            },
+            (true, "Set", [_, MessageItem::Str(name), MessageItem::Variant(value)]) => {
+                self.properties.insert((path, name.clone()), *value.clone());
+                self.calls.push((member, args));
+                msg.method_return()
+            },
+            _ => {
+                let reply = self.methods.get(&member).cloned();
+                self.calls.push((member, args));
+                match reply {
+                    Some(Ok(items)) => {
+                        let mut reply = msg.method_return();
+                        reply.append_items(&items);
+                        reply
+                    },
+                    Some(Err(name)) => error(msg, &name),
+                    None => msg.method_return(),
+                }
+            },
+        }
+    }
+}
+
+fn error(msg: &Message, name: &str) -> Message {
+    let name = dbus::strings::ErrorName::new(name).expect("Error name");
+    msg.error(&name, &std::ffi::CString::new("Test bus error").expect("Error message"))
+}
+
+/// An array of object paths
+pub(crate) fn paths(paths: &[&str]) -> MessageItem {
+    let paths = paths
+        .iter()
+        .map(|p| MessageItem::ObjectPath(p.to_string().into()))
+        .collect();
+    MessageItem::Array(MessageItemArray::new(paths, "ao".into()).expect("Object path array"))
+}
+
+/// A byte array, eg an ssid
+pub(crate) fn bytes(bytes: &[u8]) -> MessageItem {
+    let bytes = bytes.iter().map(|b| MessageItem::Byte(*b)).collect();
+    MessageItem::Array(MessageItemArray::new(bytes, "ay".into()).expect("Byte array"))
+}
+
+/// An empty dictionary of variants, eg for empty settings
+pub(crate) fn empty_dict() -> MessageItem {
+    MessageItem::Dict(MessageItemDict::new(Vec::new(), "s".into(), "v".into()).expect("Dictionary"))
+}
+
+/// A private D-Bus daemon with a network manager stand-in. Both quit when this is dropped.
+pub(crate) struct TestBus {
+    daemon: Child,
+    address: String,
+    replies: Arc<Mutex<Replies>>,
+    quit: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>,
+    _dir: tempfile::TempDir,
+}
+
+impl TestBus {
+    /// Starts the daemon and the stand-in of a network manager with an ethernet and a managed,
+    /// enabled wifi device "wlan0" that supports access point mode. Network manager is disconnected.
+    pub(crate) fn start() -> TestBus {
+        let dir = tempfile::tempdir().expect("Temporary directory");
+        let config = dir.path().join("bus.conf");
+        std::fs::write(
+            &config,
+            format!(
+                r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <listen>unix:path={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>"#,
+                dir.path().join("bus").display()
+            ),
+        )
+        .expect("Write bus configuration");
+        let mut daemon = Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(&["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Start dbus-daemon");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().expect("Daemon output"))
+            .read_line(&mut address)
+            .expect("Bus address");
+        let address = address.trim().to_owned();
+
+        let replies = Arc::new(Mutex::new(Replies::default()));
+        let quit = Arc::new(AtomicBool::new(false));
+        let mut channel = Channel::open_private(&address).expect("Connect to the test bus");
+        channel.register().expect("Register on the test bus");
+        let request_name = Message::new_method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+        )
+        .expect("RequestName call")
+        .append2(NM_BUSNAME, 4u32);
+        channel
+            .send_with_reply_and_block(request_name, Duration::from_secs(5))
+            .expect("Own the network manager bus name");
+        let server = {
+            let (replies, quit) = (replies.clone(), quit.clone());
+            std::thread::spawn(move || {
+                while !quit.load(Ordering::SeqCst) && channel.read_write(Some(Duration::from_millis(20))).is_ok() {
+                    while let Some(msg) = channel.pop_message() {
+                        if msg.msg_type() == MessageType::MethodCall {
+                            let reply = replies.lock().expect("Test bus replies").answer(&msg);
+                            let _ = channel.send(reply);
+                        }
+                    }
+                    channel.flush();
+                }
+            })
+        };
+
+        let bus = TestBus {
+            daemon,
+            address,
+            replies,
+            quit,
+            server: Some(server),
+            _dir: dir,
+        };
+        bus.set_state(20);
+        bus.set_property(NM_PATH, "NetworkingEnabled", true);
+        bus.set_property(NM_PATH, "WirelessHardwareEnabled", true);
+        bus.set_property(NM_PATH, "WirelessEnabled", true);
+        bus.set_property(NM_PATH, "ConnectivityCheckAvailable", false);
+        bus.set_reply("GetAllDevices", vec![paths(&[ETHERNET_DEVICE, WIFI_DEVICE])]);
+        bus.set_reply("ListConnections", vec![paths(&[])]);
+        bus.set_property(NM_SETTINGS_PATH, "Connections", paths(&[]));
+        bus.set_property(ETHERNET_DEVICE, "DeviceType", 1u32);
+        bus.set_property(ETHERNET_DEVICE, "Interface", "eth0");
+        bus.set_property(WIFI_DEVICE, "DeviceType", 2u32);
+        bus.set_property(WIFI_DEVICE, "Interface", "wlan0");
+        bus.set_property(WIFI_DEVICE, "HwAddress", "00:11:22:33:44:55");
+        bus.set_property(WIFI_DEVICE, "Managed", true);
+        bus.set_property(WIFI_DEVICE, "WirelessCapabilities", 0x7fu32);
+        bus.set_property(WIFI_DEVICE, "ActiveAccessPoint", MessageItem::ObjectPath("/".into()));
+        bus
+    }
+
+    /// Sets the value of a property of the given object
+    pub(crate) fn set_property<V: Into<MessageItem>>(&self, path: &str, name: &str, value: V) {
+        self.replies
+            .lock()
+            .expect("Test bus replies")
+            .properties
+            .insert((path.to_owned(), name.to_owned()), value.into());
+    }
+
+    /// Sets the network manager state, as reported by the "State" property and the "state" method
+    pub(crate) fn set_state(&self, state: u32) {
+        self.set_property(NM_PATH, "State", state);
+        self.set_reply("state", vec![MessageItem::UInt32(state)]);
+    }
+
+    /// Returns the value of a property of the given object
+    pub(crate) fn property(&self, path: &str, name: &str) -> Option<MessageItem> {
+        let replies = self.replies.lock().expect("Test bus replies");
+        replies.properties.get(&(path.to_owned(), name.to_owned())).cloned()
+    }
+
+    /// Answers calls of the given method with the given return values
+    pub(crate) fn set_reply(&self, method: &str, items: Vec<MessageItem>) {
+        let mut replies = self.replies.lock().expect("Test bus replies");
+        replies.methods.insert(method.to_owned(), Ok(items));
+    }
+
+    /// Answers calls of the given method with a D-Bus error of the given name
+    pub(crate) fn set_error(&self, method: &str, error_name: &str) {
+        let mut replies = self.replies.lock().expect("Test bus replies");
+        replies.methods.insert(method.to_owned(), Err(error_name.to_owned()));
+    }
+
+    /// Returns the arguments of all calls of the given method so far, including "Set" calls of properties
+    pub(crate) fn calls(&self, method: &str) -> Vec<Vec<MessageItem>> {
+        let replies = self.replies.lock().expect("Test bus replies");
+        replies
+            .calls
+            .iter()
+            .filter(|(member, _)| member == method)
+            .map(|(_, args)| args.clone())
+            .collect()
+    }
+
+    /// A connection to this bus. The contained resource must be spawned, see [`NetworkBackend::with_connection`].
+    pub(crate) fn connection(
+        &self,
+    ) -> (
+        dbus_tokio::IOResource<dbus::nonblock::SyncConnection>,
+        Arc<dbus::nonblock::SyncConnection>,
+    ) {
+        dbus_tokio::new_with_address(&self.address).expect("Connect to the test bus")
+    }
+
+    /// A backend for the wifi device of this bus. Must be called within a tokio runtime.
+    pub(crate) async fn backend(&self) -> NetworkBackend {
+        NetworkBackend::with_connection(
+            self.connection(),
+            &None,
+            Duration::from_secs(0),
+            Duration::from_secs(0),
+            None,
+        )
+        .await
+        .expect("Backend on the test bus")
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        self.quit.store(true, Ordering::SeqCst);
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
@@ -6,8 +6,10 @@
 //! This is an internal implementation detail of the network manager implementation.
 
 use super::NM_BUSNAME;
-use crate::network_interface::{AccessPointCredentials, SSID};
-use crate::utils::verify_password;
+use crate::network_interface::{ssid_from_raw, AccessPointCredentials, WifiBand, SSID};
+use crate::utils::{
+    verify_eap_settings, verify_password, verify_subnet_prefix, verify_wpa_key, DEFAULT_EAP_METHOD, DEFAULT_PHASE2_AUTH,
+};
 use crate::CaptivePortalError;
 
 use std::collections::HashMap;
@@ -33,6 +35,8 @@ pub(crate) struct WiFiConnectionSettings {
     pub ssid: SSID,
     pub mode: WifiConnectionMode,
     pub seen_bssids: Vec<String>,
+    /// The time of the last successful activation in seconds since the unix epoch. 0 if never activated.
+    pub timestamp: u64,
 }
 
 /**
@@ -83,6 +87,8 @@ pub(crate) fn make_arguments_for_sta(
     ssid: SSID,
     password: String,
     address: Option<Ipv4Addr>,
+    prefix: u8,
+    band: WifiBand,
     interface: &str,
     uuid: &str,
 ) -> Result<HashMap<&'static str, VariantMap>, CaptivePortalError> {
@@ -90,7 +96,7 @@ pub(crate) fn make_arguments_for_sta(
 
     let mut wireless: VariantMap = HashMap::new();
     add_val(&mut wireless, "ssid", ssid.as_bytes().to_owned());
-    add_str(&mut wireless, "band", "bg");
+    add_str(&mut wireless, "band", band.as_str());
     add_val(&mut wireless, "hidden", false);
     add_str(&mut wireless, "mode", "ap");
     if password.len() > 0 {
@@ -116,11 +122,12 @@ pub(crate) fn make_arguments_for_sta(
 
     let mut ipv4: VariantMap = HashMap::new();
     if let Some(address) = address {
+        verify_subnet_prefix(prefix)?;
         add_str(&mut ipv4, "method", "manual");
 
         let mut addr_map: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
         addr_map.insert("address".to_owned(), Variant(Box::new(format!("{}", address))));
-        addr_map.insert("prefix".to_owned(), Variant(Box::new(24_u32)));
+        addr_map.insert("prefix".to_owned(), Variant(Box::new(prefix as u32)));
         add_val(&mut ipv4, "address-data", vec![addr_map]);
     } else {
         add_str(&mut ipv4, "method", "shared");
@@ -140,15 +147,34 @@ pub(crate) fn make_options_for_ap() -> HashMap<&'static str, Variant<Box<dyn Ref
     options
 }
 
+/// Parses a mac address like "30:52:CB:84:B5:B5" into its 6 bytes.
+fn parse_bssid(bssid: &str) -> Result<Vec<u8>, CaptivePortalError> {
+    let bytes = bssid
+        .split(':')
+        .map(|v| u8::from_str_radix(v, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| CaptivePortalError::Generic(format!("Invalid bssid: {}", bssid)))?;
+    if bytes.len() != 6 {
+        return Err(CaptivePortalError::Generic(format!("Invalid bssid: {}", bssid)));
+    }
+    Ok(bytes)
+}
+
+/// Creates the settings of a station connection. If a bssid is given, the connection only
+/// associates with the access point of that mac address, even if other access points share the ssid.
 pub(crate) fn make_arguments_for_ap<T: Eq + std::hash::Hash + std::convert::From<&'static str>>(
-    ssid: &SSID,
+    ssid: &[u8],
     credentials: AccessPointCredentials,
     old_connection: Option<WiFiConnectionSettings>,
+    bssid: Option<&str>,
 ) -> Result<HashMap<T, VariantMap>, CaptivePortalError> {
     let mut settings: HashMap<T, VariantMap> = HashMap::new();
 
     let mut wireless: VariantMap = HashMap::new();
-    add_val(&mut wireless, "ssid", ssid.as_bytes().to_owned());
+    add_val(&mut wireless, "ssid", ssid.to_owned());
+    if let Some(bssid) = bssid {
+        add_val(&mut wireless, "bssid", parse_bssid(bssid)?);
+    }
     settings.insert("802-11-wireless".into(), wireless);
 
     let mut connection: VariantMap = HashMap::new();
@@ -182,9 +208,10 @@ pub(crate) fn prepare_wifi_security_settings<T: Eq + std::hash::Hash + std::conv
             settings.insert("802-11-wireless-security".into(), security_settings);
         },
         AccessPointCredentials::Wpa { ref passphrase } => {
-            verify_password(&passphrase)?;
+            verify_wpa_key(passphrase)?;
             let mut security_settings: VariantMap = HashMap::new();
 
+            // Network manager takes either a passphrase or a raw key of 64 hex digits as "psk"
             add_str(&mut security_settings, "key-mgmt", "wpa-psk");
             add_val(&mut security_settings, "psk", passphrase.clone());
 
@@ -193,26 +220,56 @@ pub(crate) fn prepare_wifi_security_settings<T: Eq + std::hash::Hash + std::conv
         AccessPointCredentials::Enterprise {
             ref identity,
             ref passphrase,
+            ref eap,
+            ref phase2,
+            ref domain_suffix_match,
+            ref ca_cert,
         } => {
             verify_password(&passphrase)?;
+            let eap_method = eap.as_ref().map(|v| &v[..]).unwrap_or(DEFAULT_EAP_METHOD);
+            let phase2 = phase2.as_ref().map(|v| &v[..]).unwrap_or(DEFAULT_PHASE2_AUTH);
+            verify_eap_settings(eap_method, phase2)?;
             let mut security_settings: VariantMap = HashMap::new();
 
             add_str(&mut security_settings, "key-mgmt", "wpa-eap");
 
             let mut eap: VariantMap = HashMap::new();
-            add_val(&mut eap, "eap", vec!["peap".to_string()]);
+            add_val(&mut eap, "eap", vec![eap_method.to_string()]);
             add_str(&mut eap, "identity", identity as &str);
             add_str(&mut eap, "password", passphrase as &str);
-            add_str(&mut eap, "phase2-auth", "mschapv2");
+            add_str(&mut eap, "phase2-auth", phase2);
+            if let Some(domain_suffix_match) = domain_suffix_match {
+                add_str(&mut eap, "domain-suffix-match", domain_suffix_match as &str);
+            }
+            if let Some(ca_cert) = ca_cert {
+                add_val(&mut eap, "ca-cert", certificate_blob(ca_cert)?);
+            }
 
             settings.insert("802-11-wireless-security".into(), security_settings);
             settings.insert("802-1x".into(), eap);
         },
         AccessPointCredentials::None => {},
+        // Saved connections are activated as they are
+        AccessPointCredentials::Saved => return Err(CaptivePortalError::PassphraseRequired),
     };
     Ok(())
 }
 
+/// Returns the given certificate data as network manager blob. Network manager takes a byte array for
+/// certificates and interprets it as path if it starts with "file://" or as PKCS#11 uri if it starts with
+/// "pkcs11:". All other data is passed to the supplicant as is, which accepts PEM and DER encoded certificates.
+fn certificate_blob(cert: &[u8]) -> Result<Vec<u8>, CaptivePortalError> {
+    let pem = cert.starts_with(b"-----BEGIN CERTIFICATE-----");
+    // A DER encoded certificate is an ASN.1 sequence
+    let der = cert.first() == Some(&0x30);
+    if !pem && !der {
+        return Err(CaptivePortalError::InvalidEapSettings(
+            "The CA certificate must be PEM or DER encoded".to_owned(),
+        ));
+    }
+    Ok(cert.to_vec())
+}
+
 pub(crate) fn extract(key: &str, map: &HashMap<String, Variant<Box<dyn RefArg>>>) -> String {
     map.get(key)
         .and_then(|v| v.0.as_str().and_then(|v| Some(v.to_owned())))
@@ -266,7 +323,14 @@ pub(crate) async fn get_connection_settings(
     use super::generated::connection_nm::Connection;
 
     let dict = p.get_settings().await?;
+    parse_connection_settings(&dict)
+}
 
+/// Return a wifi connection settings object if the given settings dictionary belongs to a wifi connection
+/// and None otherwise.
+pub(crate) fn parse_connection_settings(
+    dict: &ConnectionSettingsMap,
+) -> Result<Option<WiFiConnectionSettings>, CaptivePortalError> {
     let wireless_settings = if let Some(v) = dict.get("802-11-wireless") {
         v
     } else {
@@ -296,15 +360,21 @@ pub(crate) async fn get_connection_settings(
     Ok(Some(WiFiConnectionSettings {
         id: extract("id", &connection_settings),
         uuid: extract("uuid", &connection_settings),
-        ssid: String::from_utf8(d)?,
+        ssid: ssid_from_raw(&d),
         mode,
         seen_bssids: extract_vector("seen-bssids", &wireless_settings),
+        timestamp: connection_settings
+            .get("timestamp")
+            .and_then(|v| v.0.as_u64())
+            .unwrap_or_default(),
     }))
 }
 
 /// Dbus library helper type
 pub(crate) type VariantMap = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
 pub(crate) type VariantMapNested = HashMap<&'static str, HashMap<&'static str, Variant<Box<dyn RefArg>>>>;
+/// The settings of a connection as returned by network manager
+pub(crate) type ConnectionSettingsMap = HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>;
 
 pub(crate) fn add_val<V>(map: &mut VariantMap, key: &'static str, value: V)
 where
@@ -319,3 +389,244 @@ where
 {
     map.insert(key, Variant(Box::new(value.into())));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        make_arguments_for_ap, make_arguments_for_sta, parse_connection_settings, prepare_wifi_security_settings,
+        VariantMap, WifiConnectionMode,
+    };
+    use crate::network_interface::{AccessPointCredentials, WifiBand};
+    use dbus::arg::{RefArg, Variant};
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn hotspot_band() {
+        for band in &[WifiBand::Bg, WifiBand::A] {
+            let settings = make_arguments_for_sta(
+                "hotspot".to_owned(),
+                "a_password".to_owned(),
+                Some(Ipv4Addr::new(192, 168, 42, 1)),
+                24,
+                *band,
+                "wlan0",
+                "uuid",
+            )
+            .expect("Hotspot settings");
+            let wireless = settings.get("802-11-wireless").expect("Wireless settings");
+            assert_eq!(wireless.get("band").and_then(|v| v.0.as_str()), Some(band.as_str()));
+        }
+        assert_eq!("a".parse::<WifiBand>().ok(), Some(WifiBand::A));
+        assert!("ac".parse::<WifiBand>().is_err());
+    }
+
+    #[test]
+    fn connection_timestamp() {
+        let mut wireless: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        wireless.insert("mode".to_owned(), Variant(Box::new("infrastructure".to_owned())));
+        wireless.insert("ssid".to_owned(), Variant(Box::new(b"home".to_vec())));
+        wireless.insert(
+            "seen-bssids".to_owned(),
+            Variant(Box::new(vec!["30:52:CB:84:B5:B5".to_owned()])),
+        );
+        let mut connection: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        connection.insert("id".to_owned(), Variant(Box::new("home".to_owned())));
+        connection.insert("uuid".to_owned(), Variant(Box::new("uuid".to_owned())));
+        connection.insert("timestamp".to_owned(), Variant(Box::new(1_580_000_000u64)));
+        let mut dict = HashMap::new();
+        dict.insert("802-11-wireless".to_owned(), wireless);
+        dict.insert("connection".to_owned(), connection);
+
+        let settings = parse_connection_settings(&dict)
+            .expect("Parse")
+            .expect("Wifi connection");
+        assert_eq!(settings.ssid, "home");
+        assert_eq!(settings.mode, WifiConnectionMode::Infrastructure);
+        assert_eq!(settings.timestamp, 1_580_000_000);
+        assert_eq!(settings.seen_bssids, vec!["30:52:CB:84:B5:B5"]);
+
+        // Never activated
+        dict.get_mut("connection").expect("Connection").remove("timestamp");
+        let settings = parse_connection_settings(&dict)
+            .expect("Parse")
+            .expect("Wifi connection");
+        assert_eq!(settings.timestamp, 0);
+
+        // Not a wifi connection
+        dict.remove("802-11-wireless");
+        assert!(parse_connection_settings(&dict).expect("Parse").is_none());
+    }
+
+    #[test]
+    fn bssid_lock() {
+        let credentials = AccessPointCredentials::Wpa {
+            passphrase: "a_password".to_owned(),
+        };
+        let ssid = b"mesh";
+        let settings: HashMap<&str, VariantMap> =
+            make_arguments_for_ap(ssid, credentials.clone(), None, Some("30:52:CB:84:B5:B5")).expect("Settings");
+        let wireless = settings.get("802-11-wireless").expect("Wireless settings");
+        let bssid: Vec<u8> = wireless
+            .get("bssid")
+            .and_then(|v| v.0.as_iter())
+            .expect("bssid")
+            .filter_map(|v| v.as_u64().map(|v| v as u8))
+            .collect();
+        assert_eq!(bssid, vec![0x30, 0x52, 0xCB, 0x84, 0xB5, 0xB5]);
+
+        let settings: HashMap<&str, VariantMap> =
+            make_arguments_for_ap(ssid, credentials.clone(), None, None).expect("Settings");
+        assert!(settings
+            .get("802-11-wireless")
+            .expect("Wireless settings")
+            .get("bssid")
+            .is_none());
+
+        assert!(make_arguments_for_ap::<&str>(ssid, credentials, None, Some("30:52:CB")).is_err());
+    }
+
+    fn wpa_psk(passphrase: &str) -> Option<String> {
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        let credentials = AccessPointCredentials::Wpa {
+            passphrase: passphrase.to_owned(),
+        };
+        prepare_wifi_security_settings(&credentials, &mut settings).ok()?;
+        let security = settings.get("802-11-wireless-security").expect("Security settings");
+        security.get("psk").and_then(|v| v.0.as_str()).map(|v| v.to_owned())
+    }
+
+    #[test]
+    fn wpa_passphrase() {
+        assert_eq!(wpa_psk("a_password").as_deref(), Some("a_password"));
+        assert_eq!(wpa_psk("short"), None);
+    }
+
+    #[test]
+    fn wpa_hex_psk() {
+        let hex_psk = "0123456789abcdefABCDEF0123456789abcdef0123456789abcdef0123456789";
+        assert_eq!(hex_psk.len(), 64);
+        assert_eq!(wpa_psk(hex_psk).as_deref(), Some(hex_psk));
+        // 64 characters, but not all of them are hex digits
+        let not_hex = hex_psk.replace("0", "x");
+        assert_eq!(wpa_psk(&not_hex), None);
+    }
+
+    fn enterprise(eap: Option<&str>, phase2: Option<&str>) -> AccessPointCredentials {
+        AccessPointCredentials::Enterprise {
+            identity: "user".to_owned(),
+            passphrase: "a_password".to_owned(),
+            eap: eap.map(|v| v.to_owned()),
+            phase2: phase2.map(|v| v.to_owned()),
+            domain_suffix_match: None,
+            ca_cert: None,
+        }
+    }
+
+    #[test]
+    fn enterprise_phase2() {
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&enterprise(Some("ttls"), Some("pap")), &mut settings).expect("TTLS settings");
+        let eap = settings.get("802-1x").expect("802.1x settings");
+        let methods: Vec<&str> = eap
+            .get("eap")
+            .and_then(|v| v.0.as_iter())
+            .expect("eap methods")
+            .filter_map(|v| v.as_str())
+            .collect();
+        assert_eq!(methods, vec!["ttls"]);
+        assert_eq!(eap.get("phase2-auth").and_then(|v| v.0.as_str()), Some("pap"));
+        assert_eq!(eap.get("identity").and_then(|v| v.0.as_str()), Some("user"));
+
+        // PEAP with MSCHAPv2 is the default
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&enterprise(None, None), &mut settings).expect("PEAP settings");
+        let eap = settings.get("802-1x").expect("802.1x settings");
+        assert_eq!(eap.get("phase2-auth").and_then(|v| v.0.as_str()), Some("mschapv2"));
+
+        // PAP is not supported with PEAP
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        assert!(prepare_wifi_security_settings(&enterprise(None, Some("pap")), &mut settings).is_err());
+        assert!(prepare_wifi_security_settings(&enterprise(Some("tls"), None), &mut settings).is_err());
+    }
+
+    #[test]
+    fn enterprise_domain_suffix_match() {
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&enterprise(None, None), &mut settings).expect("PEAP settings");
+        let eap = settings.get("802-1x").expect("802.1x settings");
+        assert!(eap.get("domain-suffix-match").is_none());
+
+        let mut credentials = enterprise(None, None);
+        if let AccessPointCredentials::Enterprise {
+            ref mut domain_suffix_match,
+            ..
+        } = credentials
+        {
+            *domain_suffix_match = Some("radius.example.com".to_owned());
+        }
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&credentials, &mut settings).expect("PEAP settings");
+        let eap = settings.get("802-1x").expect("802.1x settings");
+        assert_eq!(
+            eap.get("domain-suffix-match").and_then(|v| v.0.as_str()),
+            Some("radius.example.com")
+        );
+    }
+
+    fn bytes(value: &Variant<Box<dyn RefArg>>) -> Vec<u8> {
+        value
+            .0
+            .as_iter()
+            .expect("byte array")
+            .filter_map(|v| v.as_u64())
+            .map(|v| v as u8)
+            .collect()
+    }
+
+    fn with_ca_cert(ca_cert: &[u8]) -> AccessPointCredentials {
+        let mut credentials = enterprise(None, None);
+        if let AccessPointCredentials::Enterprise {
+            ca_cert: ref mut cert, ..
+        } = credentials
+        {
+            *cert = Some(ca_cert.to_vec());
+        }
+        credentials
+    }
+
+    #[test]
+    fn enterprise_inline_ca_cert() {
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&enterprise(None, None), &mut settings).expect("PEAP settings");
+        assert!(settings
+            .get("802-1x")
+            .expect("802.1x settings")
+            .get("ca-cert")
+            .is_none());
+
+        // The PEM data is passed as byte array (blob scheme), not as "file://" path
+        let pem = b"-----BEGIN CERTIFICATE-----\nMIIBszCCAVmgAwIBAgIU\n-----END CERTIFICATE-----\n";
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&with_ca_cert(pem), &mut settings).expect("PEAP settings");
+        let eap = settings.get("802-1x").expect("802.1x settings");
+        let ca_cert = eap.get("ca-cert").expect("ca-cert");
+        assert_eq!(&*ca_cert.0.signature(), "ay");
+        assert_eq!(bytes(ca_cert), pem.to_vec());
+
+        // DER data
+        let der = [0x30, 0x82, 0x01, 0xb3];
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        prepare_wifi_security_settings(&with_ca_cert(&der), &mut settings).expect("PEAP settings");
+        let ca_cert = settings
+            .get("802-1x")
+            .and_then(|eap| eap.get("ca-cert"))
+            .expect("ca-cert");
+        assert_eq!(bytes(ca_cert), der.to_vec());
+
+        // Paths and PKCS#11 uris would be interpreted by network manager
+        let mut settings: HashMap<String, VariantMap> = HashMap::new();
+        assert!(prepare_wifi_security_settings(&with_ca_cert(b"file:///etc/ca.pem\0"), &mut settings).is_err());
+        assert!(prepare_wifi_security_settings(&with_ca_cert(b"pkcs11:token=ca"), &mut settings).is_err());
+    }
+}
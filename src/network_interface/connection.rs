@@ -55,7 +55,17 @@ impl<C: AsRef<Channel> + Process> future::Future for IOResource<C> {
 
 /// Generic connection creator, you might want to use e g `new_session_local`, `new_system_sync` etc for convenience.
 pub fn new<C: From<Channel>>(b: BusType) -> Result<(IOResource<C>, Arc<C>), CaptivePortalError> {
-    let mut channel = Channel::get_private(b)?;
+    from_channel(Channel::get_private(b)?)
+}
+
+/// Connects to the bus with the given address, eg "unix:path=/run/dbus/system_bus_socket".
+pub fn new_with_address<C: From<Channel>>(address: &str) -> Result<(IOResource<C>, Arc<C>), CaptivePortalError> {
+    let mut channel = Channel::open_private(address)?;
+    channel.register()?;
+    from_channel(channel)
+}
+
+fn from_channel<C: From<Channel>>(mut channel: Channel) -> Result<(IOResource<C>, Arc<C>), CaptivePortalError> {
     channel.set_watch_enabled(true);
 
     let w = channel.watch();
@@ -0,0 +1,114 @@
+//! # Named credential profiles
+//! A connection request may reference a profile instead of carrying the secrets itself.
+//! The profile is resolved by a [`CredentialStore`], by default a directory of profile files.
+
+use crate::{CaptivePortalError, SSID};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The credentials of a named profile. Values that are not set must be given by the connection request.
+#[derive(Deserialize, Default, Clone, PartialEq)]
+pub struct CredentialProfile {
+    /// The networks this profile may be used for. A profile without ssids can not be used.
+    #[serde(default)]
+    pub ssids: Vec<SSID>,
+    pub identity: Option<String>,
+    pub passphrase: Option<String>,
+    /// Enterprise only: The eap method, "peap" or "ttls"
+    pub eap: Option<String>,
+    /// Enterprise only: The phase2 authentication, eg "mschapv2", "gtc" or "pap"
+    pub phase2: Option<String>,
+    /// Enterprise only: The authentication server certificate must match this domain suffix
+    pub domain_suffix_match: Option<String>,
+    /// Enterprise only: The PEM encoded CA certificate of the authentication server
+    pub ca_cert: Option<String>,
+}
+
+// Do not leak the passphrase into logs
+impl fmt::Debug for CredentialProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CredentialProfile")
+            .field("ssids", &self.ssids)
+            .field("identity", &self.identity)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| "***"))
+            .field("eap", &self.eap)
+            .field("phase2", &self.phase2)
+            .field("domain_suffix_match", &self.domain_suffix_match)
+            .field("ca_cert", &self.ca_cert)
+            .finish()
+    }
+}
+
+/// Resolves named credential profiles, eg for a fleet of devices that share enterprise credentials.
+pub trait CredentialStore: fmt::Debug + Send + Sync {
+    /// Returns the profile with the given name.
+    /// Fails with [`CaptivePortalError::InvalidCredentialProfile`] if there is no such profile.
+    fn resolve(&self, profile: &str) -> Result<CredentialProfile, CaptivePortalError>;
+}
+
+/// Reads a profile from "<directory>/<name>.json", eg
+/// `{"ssids":["office"],"identity":"device-42","passphrase":"secret","eap":"peap"}`.
+#[derive(Debug, Clone)]
+pub struct FileCredentialStore {
+    pub directory: PathBuf,
+}
+
+impl FileCredentialStore {
+    pub fn new(directory: PathBuf) -> Self {
+        FileCredentialStore { directory }
+    }
+}
+
+impl CredentialStore for FileCredentialStore {
+    fn resolve(&self, profile: &str) -> Result<CredentialProfile, CaptivePortalError> {
+        // Profile names come from http requests. They must not point outside of the directory.
+        let valid = !profile.is_empty()
+            && profile
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            && !profile.starts_with('.');
+        if !valid {
+            return Err(CaptivePortalError::InvalidCredentialProfile(profile.to_owned()));
+        }
+        let path = self.directory.join(format!("{}.json", profile));
+        let content = std::fs::read(&path).map_err(|e| {
+            warn!("Failed to read credential profile {}: {}", path.display(), e);
+            CaptivePortalError::InvalidCredentialProfile(profile.to_owned())
+        })?;
+        serde_json::from_slice(&content).map_err(|e| {
+            warn!("Failed to parse credential profile {}: {}", path.display(), e);
+            CaptivePortalError::InvalidCredentialProfile(profile.to_owned())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("corp.json"),
+            r#"{"ssids":["office"],"identity":"device-42","passphrase":"a_password","eap":"ttls","phase2":"pap"}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("broken.json"), "garbage").unwrap();
+        let store = FileCredentialStore::new(dir.path().to_path_buf());
+
+        let profile = store.resolve("corp").unwrap();
+        assert_eq!(profile.ssids, vec!["office".to_owned()]);
+        assert_eq!(profile.identity.as_deref(), Some("device-42"));
+        assert_eq!(profile.passphrase.as_deref(), Some("a_password"));
+        assert_eq!(profile.eap.as_deref(), Some("ttls"));
+        assert_eq!(profile.domain_suffix_match, None);
+        assert!(!format!("{:?}", profile).contains("a_password"));
+
+        assert!(store.resolve("unknown").is_err());
+        assert!(store.resolve("broken").is_err());
+        assert!(store.resolve("../corp").is_err());
+        assert!(store.resolve("").is_err());
+    }
+}
@@ -1,26 +1,73 @@
 //! # Generic types, traits and methods for network interfaces
 //! Find implementations in [`network_backend`]
 mod connection;
+mod credential_store;
 mod signal_stream;
 
+pub use credential_store::{CredentialProfile, CredentialStore, FileCredentialStore};
+
 pub mod dbus_tokio {
     pub use super::connection::*;
     pub use super::signal_stream::SignalStream;
 }
 
-use crate::CaptivePortalError;
+use crate::{parse_hex, CaptivePortalError};
 use core::fmt;
 use serde::Serialize;
 use std::convert::TryFrom;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
 
 /// A wifi SSID
 /// According to last standard 802.11-2012 (Section 6.3.11.2.2),
 /// a SSID  can be 0-32 octets with an unspecified or UTF8 encoding.
 pub type SSID = String;
 
+/// Converts the raw bytes of a SSID into a display-safe [`SSID`].
+/// Bytes that are not valid UTF-8 are replaced by U+FFFD, so that such networks are still listed.
+/// Such networks are connected by their raw bytes, see [`ssid_hex`].
+pub fn ssid_from_raw(bytes: &[u8]) -> SSID {
+    let ssid = String::from_utf8_lossy(bytes);
+    if let std::borrow::Cow::Owned(_) = ssid {
+        warn!("SSID is not valid UTF-8: {:02x?}", bytes);
+    }
+    ssid.into_owned()
+}
+
+/// Returns the raw bytes of a SSID as hex string, if they are not valid UTF-8.
+/// The display name of such a SSID is lossy and cannot be used for connecting.
+pub fn ssid_hex(bytes: &[u8]) -> Option<String> {
+    if std::str::from_utf8(bytes).is_ok() {
+        return None;
+    }
+    Some(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Returns the raw bytes of a SSID. The hex encoded raw bytes take precedence over the display name, if given.
+/// A SSID has up to 32 bytes.
+pub fn ssid_bytes(ssid: &str, ssid_hex: Option<&str>) -> Result<Vec<u8>, CaptivePortalError> {
+    let bytes = match ssid_hex {
+        Some(hex) => parse_hex(hex),
+        None => Some(ssid.as_bytes().to_vec()),
+    };
+    match bytes {
+        Some(bytes) if !bytes.is_empty() && bytes.len() <= 32 => Ok(bytes),
+        _ => Err(CaptivePortalError::Generic(format!(
+            "Invalid ssid: {}",
+            ssid_hex.unwrap_or(ssid)
+        ))),
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct WifiConnection {
     pub ssid: SSID,
+    /// The raw ssid as hex string, if it is not valid UTF-8. See [`ssid_hex`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid_hex: Option<String>,
     /// The unique hw address of the access point
     pub hw: String,
     // The wifi mode
@@ -31,12 +78,264 @@ pub struct WifiConnection {
     pub frequency: u32,
     // True if this is spawned by the current device
     pub is_own: bool,
+    /// When the access point has been reported the last time, eg by an Added or Changed event
+    #[serde(skip)]
+    pub last_seen: Instant,
+}
+
+/// Detailed information of a network. See `NetworkBackend::network_details`.
+#[derive(Serialize, Clone, Debug)]
+pub struct WifiConnectionDetails {
+    /// The access point with the strongest signal
+    #[serde(flatten)]
+    pub connection: WifiConnection,
+    /// The hw addresses of all access points of the network
+    pub access_points: Vec<String>,
+    /// The capability flags, eg "privacy" or "wps"
+    pub flags: Vec<&'static str>,
+    /// The WPA security flags, eg "pair_tkip" or "key_mgmt_psk"
+    pub wpa_flags: Vec<&'static str>,
+    /// The RSN (WPA2) security flags, eg "pair_ccmp" or "key_mgmt_802_1x"
+    pub rsn_flags: Vec<&'static str>,
+    /// The maximum bitrate in kbit/s. 0 if unknown.
+    pub max_bitrate: u32,
+    /// The channel width in MHz if known
+    pub channel_width: Option<u32>,
+    /// True if the wifi device is currently associated with one of the access points
+    pub is_active: bool,
+}
+
+impl WifiConnectionDetails {
+    /// Merges access points with the same ssid into one entry. The flags are combined and the highest
+    /// bitrate and channel width are reported. Returns None for an empty list.
+    pub fn merge(details: Vec<WifiConnectionDetails>) -> Option<WifiConnectionDetails> {
+        let mut details = details.into_iter();
+        let mut merged = details.next()?;
+        for other in details {
+            if other.connection.strength > merged.connection.strength {
+                merged.connection = other.connection;
+            }
+            merged.access_points.extend(other.access_points);
+            merge_flags(&mut merged.flags, other.flags);
+            merge_flags(&mut merged.wpa_flags, other.wpa_flags);
+            merge_flags(&mut merged.rsn_flags, other.rsn_flags);
+            merged.max_bitrate = merged.max_bitrate.max(other.max_bitrate);
+            merged.channel_width = merged.channel_width.max(other.channel_width);
+            merged.is_active |= other.is_active;
+        }
+        Some(merged)
+    }
 }
 
-#[derive(Serialize, Debug, Copy, Clone)]
+fn merge_flags(flags: &mut Vec<&'static str>, other: Vec<&'static str>) {
+    for flag in other {
+        if !flags.contains(&flag) {
+            flags.push(flag);
+        }
+    }
+}
+
+/// A wifi device of the system. See `NetworkBackend::list_wifi_devices`.
+#[derive(Serialize, Clone, Debug)]
+pub struct WifiDeviceInfo {
+    /// The interface name, eg "wlan0"
+    pub interface_name: String,
+    /// The mac address
+    pub hw: String,
+    /// The backend specific device state, eg "Activated" or "Unmanaged"
+    pub state: String,
+}
+
+/// The radio state of the wifi device. See `NetworkBackend::radio_state`.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
+pub struct RadioState {
+    /// Wifi is enabled in software, it is not blocked by eg "rfkill block wifi" or "nmcli radio wifi off"
+    pub enabled: bool,
+    /// Wifi is not blocked by a hardware switch
+    pub hardware_enabled: bool,
+}
+
+impl RadioState {
+    /// Returns true if the radio is blocked in software or hardware
+    pub fn rfkill(&self) -> bool {
+        !self.enabled || !self.hardware_enabled
+    }
+
+    /// Returns [`CaptivePortalError::WifiBlocked`] if the radio is blocked. A hardware block takes precedence.
+    pub fn ensure_unblocked(&self) -> Result<(), CaptivePortalError> {
+        if !self.hardware_enabled {
+            Err(CaptivePortalError::WifiBlocked { hard: true })
+        } else if !self.enabled {
+            Err(CaptivePortalError::WifiBlocked { hard: false })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Limits the work of a single access point listing, so that a churning environment cannot keep the
+/// backend busy. Partial results are returned if the budget is exhausted. The default is unlimited.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ScanBudget {
+    /// Maximum number of access point queries. Each access point query consists of a few dbus calls.
+    pub max_queries: Option<usize>,
+    /// Maximum wall time
+    pub max_time: Option<Duration>,
+}
+
+impl ScanBudget {
+    /// A budget that is only limited by wall time
+    pub fn with_max_time(max_time: Duration) -> Self {
+        ScanBudget {
+            max_queries: None,
+            max_time: Some(max_time),
+        }
+    }
+
+    /// Returns true if the given number of queries or the elapsed time since "started" exceed this budget.
+    pub fn exhausted(&self, queries: usize, started: Instant) -> bool {
+        self.max_queries.map_or(false, |max| queries >= max)
+            || self.max_time.map_or(false, |max| started.elapsed() >= max)
+    }
+}
+
+/// Queries the given items one after another until all are queried or the budget is exhausted.
+/// Failed queries (None) are skipped. Returns the results and true if the budget has been exhausted.
+pub(crate) async fn query_within_budget<I, T, F, Fut>(
+    items: I,
+    budget: &ScanBudget,
+    started: Instant,
+    queries: &mut usize,
+    query: F,
+) -> (Vec<T>, bool)
+where
+    I: IntoIterator,
+    F: Fn(I::Item) -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let mut results = Vec::new();
+    for item in items {
+        if budget.exhausted(*queries, started) {
+            return (results, true);
+        }
+        *queries += 1;
+        if let Some(result) = query(item).await {
+            results.push(result);
+        }
+    }
+    (results, false)
+}
+
+/// Scans are not requested more often than this. Callers within this interval share the last scan.
+pub(crate) const MIN_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The outcome of a successful scan request, see `NetworkBackend::scan_networks`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScanOutcome {
+    /// A scan has been requested or another caller's request has completed
+    Scanned,
+    /// The last scan is more recent than the minimum interval. No scan has been requested, its results apply.
+    RateLimited,
+}
+
+/// The outcome of a scan request that is shared with all coalesced callers
+type SharedScanOutcome = Option<Result<(), String>>;
+
+#[derive(Default)]
+struct ScanCoordinatorState {
+    /// The time of the last successful scan request
+    last_scan: Option<Instant>,
+    /// Set while a scan request is in flight. Resolves to the outcome of that request.
+    in_flight: Option<watch::Receiver<SharedScanOutcome>>,
+}
+
+/// Coordinates the scan requests of all callers (the "/refresh" endpoint, periodic scans, access point listings).
+/// Concurrent requests are coalesced into one underlying request and requests are rate limited.
+/// Clones share the same state.
+#[derive(Clone)]
+pub(crate) struct ScanCoordinator {
+    min_interval: Duration,
+    state: Arc<Mutex<ScanCoordinatorState>>,
+}
+
+/// Clears the in-flight flag, also if the scanning caller is cancelled
+struct InFlight(Arc<Mutex<ScanCoordinatorState>>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.lock().expect("scan state mutex").in_flight = None;
+    }
+}
+
+impl ScanCoordinator {
+    pub(crate) fn new(min_interval: Duration) -> Self {
+        ScanCoordinator {
+            min_interval,
+            state: Arc::new(Mutex::new(ScanCoordinatorState::default())),
+        }
+    }
+
+    /// Performs the given scan request, unless another caller is already scanning or the last scan
+    /// is more recent than the minimum interval. Callers that joined an in-flight request get its outcome.
+    pub(crate) async fn scan<F, Fut>(&self, request: F) -> Result<ScanOutcome, CaptivePortalError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(), CaptivePortalError>>,
+    {
+        let (sender, in_flight) = loop {
+            let mut receiver = {
+                let mut state = self.state.lock().expect("scan state mutex");
+                if let Some(last_scan) = state.last_scan {
+                    if last_scan.elapsed() < self.min_interval {
+                        return Ok(ScanOutcome::RateLimited);
+                    }
+                }
+                match state.in_flight {
+                    Some(ref receiver) => receiver.clone(),
+                    None => {
+                        let (sender, receiver) = watch::channel(None);
+                        state.in_flight = Some(receiver);
+                        break (sender, InFlight(self.state.clone()));
+                    },
+                }
+            };
+            // Another caller is scanning. Wait for its outcome.
+            while let Some(outcome) = receiver.recv().await {
+                if let Some(outcome) = outcome {
+                    return outcome
+                        .map(|_| ScanOutcome::Scanned)
+                        .map_err(CaptivePortalError::Generic);
+                }
+            }
+            // The scanning caller has been cancelled. Try again.
+        };
+
+        let result = request().await;
+        if result.is_ok() {
+            self.state.lock().expect("scan state mutex").last_scan = Some(Instant::now());
+        }
+        drop(in_flight);
+        let _ = sender.broadcast(Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string())));
+        result.map(|_| ScanOutcome::Scanned)
+    }
+}
+
+/// A configured wifi connection and when it has been used. See `NetworkBackend::connection_history`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ConnectionHistoryEntry {
+    pub ssid: SSID,
+    /// The time of the last successful connection in seconds since the unix epoch. None if never connected.
+    pub last_connected: Option<u64>,
+    /// The mac addresses of the access points that this connection has been connected to
+    pub seen_bssids: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
 pub enum WifiConnectionEventType {
     Added,
     Removed,
+    /// The signal strength of an access point changed
+    Changed,
 }
 
 impl fmt::Display for WifiConnectionEventType {
@@ -160,28 +459,546 @@ impl TryFrom<String> for Security {
     }
 }
 
+/// The progress of a connection attempt to a wifi network. Serialized as eg `{"type":"obtaining_ip"}` or
+/// `{"type":"failed","reason":"Network not found"}`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", content = "reason", rename_all = "snake_case")]
+pub enum ConnectProgress {
+    /// The device is associating with the access point
+    Associating,
+    /// Credentials are exchanged with the access point
+    Authenticating,
+    /// The device requests an ip address, eg via dhcp
+    ObtainingIp,
+    /// The connection is established. Connectivity is checked.
+    CheckingInternet,
+    Connected,
+    /// The connection attempt failed for the given reason
+    Failed(String),
+}
+
+/// Publishes the progress of connection attempts. The last published progress is kept, eg for reporting
+/// a failure reason after the portal has been reopened. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectProgressChannel {
+    last: Arc<Mutex<Option<ConnectProgress>>>,
+    subscribers: Arc<Mutex<Vec<tokio::sync::mpsc::UnboundedSender<ConnectProgress>>>>,
+}
+
+impl ConnectProgressChannel {
+    /// Publishes the given progress. A repeated progress, eg of consecutive association steps, is ignored.
+    pub fn publish(&self, progress: ConnectProgress) {
+        let mut last = self.last.lock().expect("connect progress mutex lock");
+        if last.as_ref() == Some(&progress) {
+            return;
+        }
+        info!("Connection progress: {:?}", progress);
+        let mut subscribers = self.subscribers.lock().expect("connect progress mutex lock");
+        subscribers.retain(|subscriber| subscriber.send(progress.clone()).is_ok());
+        *last = Some(progress);
+    }
+
+    /// Forgets the progress of the previous attempt. Call this when a new attempt starts.
+    pub fn reset(&self) {
+        self.last.lock().expect("connect progress mutex lock").take();
+    }
+
+    /// Returns a receiver for the progress that is published from now on
+    pub fn subscribe(&self) -> tokio::sync::mpsc::UnboundedReceiver<ConnectProgress> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        self.subscribers
+            .lock()
+            .expect("connect progress mutex lock")
+            .push(sender);
+        receiver
+    }
+
+    /// Returns the last published progress, if any
+    pub fn last(&self) -> Option<ConnectProgress> {
+        self.last.lock().expect("connect progress mutex lock").clone()
+    }
+}
+
+/// Options of a connection attempt, see `NetworkBackend::connect_to`
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// The target access point mac address. A connection that was connected to that access point
+    /// in the past is updated, if there is one.
+    pub hw: Option<String>,
+    /// Update a connection that matches the ssid, if there is one.
+    pub overwrite_same_ssid_connection: bool,
+    /// Only associate with the access point given by "hw", even if other access points share the ssid,
+    /// eg in mesh networks.
+    pub lock_bssid: bool,
+    /// Save the connection to disk on success. Otherwise it is only kept in memory and vanishes on a reboot.
+    pub persist_connection: bool,
+    /// The time to wait while the connection is activating, eg during authentication.
+    pub activation_timeout: Duration,
+}
+
+/// Cancels the running connection attempt, eg on user request. Clones share the same attempt.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectCancel {
+    sender: Arc<Mutex<Option<tokio::sync::oneshot::Sender<()>>>>,
+}
+
+impl ConnectCancel {
+    /// Starts a new attempt. The returned receiver resolves with Ok once the attempt is cancelled.
+    /// See [`unless_cancelled`].
+    pub fn start(&self) -> tokio::sync::oneshot::Receiver<()> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        *self.sender.lock().expect("connect cancel mutex lock") = Some(sender);
+        receiver
+    }
+
+    /// Cancels the running attempt. Returns false if there is none.
+    pub fn cancel(&self) -> bool {
+        match self.sender.lock().expect("connect cancel mutex lock").take() {
+            Some(sender) => sender.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Ends the running attempt. It cannot be cancelled anymore.
+    pub fn finish(&self) {
+        self.sender.lock().expect("connect cancel mutex lock").take();
+    }
+}
+
+/// Runs the given step of a connection attempt to completion, unless the attempt is cancelled first.
+/// Returns None if cancelled. A finished [`ConnectCancel`] does not count as cancelled.
+pub async fn unless_cancelled<F: Future + Unpin>(
+    step: F,
+    cancelled: &mut tokio::sync::oneshot::Receiver<()>,
+) -> Option<F::Output> {
+    use futures_util::future::{select, Either};
+    match select(step, cancelled).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right((Ok(()), _)) => None,
+        Either::Right((Err(_), step)) => Some(step.await),
+    }
+}
+
+/// The wifi band of a hotspot.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WifiBand {
+    /// 2.4 GHz
+    Bg,
+    /// 5 GHz
+    A,
+}
+
+impl WifiBand {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WifiBand::Bg => "bg",
+            WifiBand::A => "a",
+        }
+    }
+
+    /// The band of the given frequency in MHz. None for other bands, eg 6 GHz.
+    pub fn from_frequency(frequency: u32) -> Option<WifiBand> {
+        match frequency {
+            2400..=2500 => Some(WifiBand::Bg),
+            4900..=5900 => Some(WifiBand::A),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the hw address of the strongest access point of the given ssid on the given band, if any.
+/// Used to pin a connection to the faster band, if a network is broadcast on several bands.
+pub fn strongest_access_point_on_band<'a>(
+    ssid: &str,
+    access_points: &'a [WifiConnection],
+    band: WifiBand,
+) -> Option<&'a str> {
+    access_points
+        .iter()
+        .filter(|ap| ap.ssid == ssid && WifiBand::from_frequency(ap.frequency) == Some(band))
+        .max_by_key(|ap| ap.strength)
+        .map(|ap| &ap.hw[..])
+}
+
+impl FromStr for WifiBand {
+    type Err = CaptivePortalError;
+
+    fn from_str(band: &str) -> Result<Self, Self::Err> {
+        match band {
+            "bg" => Ok(WifiBand::Bg),
+            "a" => Ok(WifiBand::A),
+            _ => Err(CaptivePortalError::Generic(format!(
+                "Expected a wifi band (bg, a). Got: {}",
+                band
+            ))),
+        }
+    }
+}
+
 /// Different encryption mechanisms require different sets of credentials.
 #[derive(Debug, Clone)]
 pub enum AccessPointCredentials {
     None,
     Wep { passphrase: String },
     Wpa { passphrase: String },
-    Enterprise { identity: String, passphrase: String },
+    /// The eap method ("peap", "ttls") and phase2 authentication ("mschapv2", "gtc", "pap", ...) default to
+    /// PEAP with MSCHAPv2 if not set.
+    /// The authentication server certificate must match the domain suffix (subject or SAN), if one is set.
+    /// It must also be signed by the given CA certificate (PEM or DER data), if one is set. The certificate
+    /// is passed to the backend inline, nothing is written to disk.
+    Enterprise {
+        identity: String,
+        passphrase: String,
+        eap: Option<String>,
+        phase2: Option<String>,
+        domain_suffix_match: Option<String>,
+        ca_cert: Option<Vec<u8>>,
+    },
+    /// Activate the saved connection of the network without changing its credentials
+    Saved,
 }
 
 /// Converts a set of credentials into the [`AccessPointCredentials`] type.
+/// The eap method, phase2 authentication, domain suffix match and CA certificate are only used for
+/// enterprise networks.
+///
+/// Empty values count as not given. Open networks must not have a passphrase, all others require one.
+/// Enterprise networks also require an identity.
 pub fn credentials_from_data(
-    passphrase: String,
+    passphrase: Option<String>,
     identity: Option<String>,
+    eap: Option<String>,
+    phase2: Option<String>,
+    domain_suffix_match: Option<String>,
+    ca_cert: Option<Vec<u8>>,
     mode: Security,
 ) -> Result<AccessPointCredentials, CaptivePortalError> {
+    let passphrase = passphrase.filter(|v| !v.is_empty());
+    let identity = identity.filter(|v| !v.is_empty());
     match mode {
+        Security::NONE => match passphrase {
+            Some(_) => Err(CaptivePortalError::PassphraseNotAllowedForOpen),
+            None => Ok(AccessPointCredentials::None),
+        },
         Security::ENTERPRISE => Ok(AccessPointCredentials::Enterprise {
-            identity: identity.ok_or(CaptivePortalError::NoSharedKeyProvided)?,
-            passphrase,
+            identity: identity.ok_or(CaptivePortalError::IdentityRequiredForEnterprise)?,
+            passphrase: passphrase.ok_or(CaptivePortalError::PassphraseRequired)?,
+            eap,
+            phase2,
+            domain_suffix_match: domain_suffix_match.filter(|v| !v.is_empty()),
+            ca_cert: ca_cert.filter(|v| !v.is_empty()),
         }),
-        Security::WPA | Security::WPA2 => Ok(AccessPointCredentials::Wpa { passphrase }),
-        Security::WEP => Ok(AccessPointCredentials::Wep { passphrase }),
-        Security::NONE => Ok(AccessPointCredentials::None),
+        Security::WPA | Security::WPA2 => Ok(AccessPointCredentials::Wpa {
+            passphrase: passphrase.ok_or(CaptivePortalError::PassphraseRequired)?,
+        }),
+        Security::WEP => Ok(AccessPointCredentials::Wep {
+            passphrase: passphrase.ok_or(CaptivePortalError::PassphraseRequired)?,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        credentials_from_data, query_within_budget, ssid_bytes, ssid_from_raw, ssid_hex,
+        strongest_access_point_on_band, unless_cancelled, AccessPointCredentials, ConnectCancel, ConnectProgress,
+        ConnectProgressChannel, RadioState, ScanBudget, ScanCoordinator, ScanOutcome, Security, WifiBand,
+        WifiConnection, WifiConnectionDetails,
+    };
+    use crate::CaptivePortalError;
+    use futures_util::future::join;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::time::delay_for;
+
+    #[tokio::test]
+    async fn cancel_connect() {
+        let cancel = ConnectCancel::default();
+        // No attempt running
+        assert!(!cancel.cancel());
+
+        // Cancelling mid-step aborts the step
+        let mut cancelled = cancel.start();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            delay_for(Duration::from_millis(10)).await;
+            assert!(canceller.cancel());
+        });
+        let started = Instant::now();
+        let step = unless_cancelled(Box::pin(delay_for(Duration::from_secs(5))), &mut cancelled).await;
+        assert!(step.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // The attempt can only be cancelled once
+        assert!(!cancel.cancel());
+
+        // A finished attempt runs its steps to completion
+        let mut cancelled = cancel.start();
+        cancel.finish();
+        assert!(!cancel.cancel());
+        let step = unless_cancelled(Box::pin(async { 42 }), &mut cancelled).await;
+        assert_eq!(step, Some(42));
+    }
+
+    #[test]
+    fn prefer_band() {
+        let ap = |ssid: &str, hw: &str, strength, frequency| WifiConnection {
+            ssid: ssid.to_owned(),
+            ssid_hex: None,
+            hw: hw.to_owned(),
+            security: "wpa2",
+            strength,
+            frequency,
+            is_own: false,
+            last_seen: Instant::now(),
+        };
+        let access_points = vec![
+            ap("Home", "aa", 90, 2412),
+            ap("Home", "bb", 40, 5180),
+            ap("Home", "cc", 60, 5745),
+            ap("Office", "dd", 95, 5200),
+            ap("Home", "ee", 99, 5955),
+        ];
+        // The strongest 5 GHz access point of the ssid, even if a 2.4 GHz one is stronger
+        assert_eq!(
+            strongest_access_point_on_band("Home", &access_points, WifiBand::A),
+            Some("cc")
+        );
+        assert_eq!(
+            strongest_access_point_on_band("Home", &access_points, WifiBand::Bg),
+            Some("aa")
+        );
+        // Only broadcast on 2.4 GHz
+        assert_eq!(
+            strongest_access_point_on_band("Cafe", &access_points, WifiBand::A),
+            None
+        );
+        assert_eq!(WifiBand::from_frequency(5955), None);
+    }
+
+    #[test]
+    fn radio_blocked() {
+        let radio = |enabled, hardware_enabled| RadioState {
+            enabled,
+            hardware_enabled,
+        };
+        assert!(radio(true, true).ensure_unblocked().is_ok());
+        let blocked = [(false, true, false), (true, false, true), (false, false, true)];
+        for &(enabled, hardware_enabled, expected) in blocked.iter() {
+            match radio(enabled, hardware_enabled).ensure_unblocked() {
+                Err(CaptivePortalError::WifiBlocked { hard }) if hard == expected => {}
+                r => panic!("Expected WifiBlocked {{ hard: {} }}. Got {:?}", expected, r),
+            }
+        }
+    }
+
+    #[test]
+    fn non_utf8_ssid() {
+        assert_eq!(ssid_from_raw(b"home"), "home");
+        assert_eq!(ssid_from_raw("Café".as_bytes()), "Café");
+        // Latin-1 encoded "Café" and a truncated multi byte sequence
+        assert_eq!(ssid_from_raw(&[b'C', b'a', b'f', 0xe9]), "Caf\u{fffd}");
+        assert_eq!(ssid_from_raw(&[b'a', 0xe2, 0x82]), "a\u{fffd}");
+
+        // Such networks are connected by their raw bytes
+        assert_eq!(ssid_hex(b"home"), None);
+        let hex = ssid_hex(&[b'C', b'a', b'f', 0xe9]).expect("hex encoded ssid");
+        assert_eq!(hex, "436166e9");
+        assert_eq!(
+            ssid_bytes("Caf\u{fffd}", Some(&hex)).unwrap(),
+            vec![b'C', b'a', b'f', 0xe9]
+        );
+        assert_eq!(ssid_bytes("home", None).unwrap(), b"home".to_vec());
+        assert!(ssid_bytes("Caf\u{fffd}", Some("43616")).is_err());
+        assert!(ssid_bytes("", None).is_err());
+        assert!(ssid_bytes(&"a".repeat(33), None).is_err());
+    }
+
+    #[test]
+    fn merge_details() {
+        let details = |hw: &str, strength, flags: Vec<&'static str>, max_bitrate, channel_width, is_active| {
+            WifiConnectionDetails {
+                connection: WifiConnection {
+                    ssid: "Home".to_owned(),
+                    ssid_hex: None,
+                    hw: hw.to_owned(),
+                    security: "wpa2",
+                    strength,
+                    frequency: 2412,
+                    is_own: false,
+                    last_seen: Instant::now(),
+                },
+                access_points: vec![hw.to_owned()],
+                flags: Vec::new(),
+                wpa_flags: Vec::new(),
+                rsn_flags: flags,
+                max_bitrate,
+                channel_width,
+                is_active,
+            }
+        };
+        assert!(WifiConnectionDetails::merge(Vec::new()).is_none());
+
+        let merged = WifiConnectionDetails::merge(vec![
+            details("aa", 40, vec!["pair_ccmp", "key_mgmt_psk"], 54000, None, false),
+            details("bb", 70, vec!["pair_ccmp"], 130000, Some(20), false),
+            details("cc", 60, vec!["group_ccmp"], 65000, Some(40), true),
+        ])
+        .unwrap();
+        assert_eq!(merged.connection.hw, "bb");
+        assert_eq!(merged.access_points, vec!["aa", "bb", "cc"]);
+        assert_eq!(merged.rsn_flags, vec!["pair_ccmp", "key_mgmt_psk", "group_ccmp"]);
+        assert_eq!(merged.max_bitrate, 130000);
+        assert_eq!(merged.channel_width, Some(40));
+        assert!(merged.is_active);
+    }
+
+    #[tokio::test]
+    async fn scan_budget() {
+        let query = |v: u32| async move {
+            if v % 2 == 0 {
+                Some(v)
+            } else {
+                None
+            }
+        };
+
+        let unlimited = ScanBudget::default();
+        let mut queries = 0;
+        let (results, exhausted) = query_within_budget(0..6, &unlimited, Instant::now(), &mut queries, query).await;
+        assert_eq!(results, vec![0, 2, 4]);
+        assert!(!exhausted);
+        assert_eq!(queries, 6);
+
+        // Returns the partial result early. Failed queries count as well.
+        let budget = ScanBudget {
+            max_queries: Some(4),
+            max_time: None,
+        };
+        let mut queries = 1;
+        let (results, exhausted) = query_within_budget(0..6, &budget, Instant::now(), &mut queries, query).await;
+        assert_eq!(results, vec![0, 2]);
+        assert!(exhausted);
+        assert_eq!(queries, 4);
+
+        let budget = ScanBudget::with_max_time(Duration::from_millis(0));
+        let mut queries = 0;
+        let (results, exhausted) = query_within_budget(0..6, &budget, Instant::now(), &mut queries, query).await;
+        assert!(results.is_empty());
+        assert!(exhausted);
+    }
+
+    #[tokio::test]
+    async fn scan_coalescing() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let request = |result: Result<(), CaptivePortalError>| {
+            let requests = requests.clone();
+            move || async move {
+                requests.fetch_add(1, Ordering::SeqCst);
+                delay_for(Duration::from_millis(50)).await;
+                result
+            }
+        };
+
+        // Two concurrent scans result in one request
+        let coordinator = ScanCoordinator::new(Duration::from_secs(60));
+        let (a, b) = join(coordinator.scan(request(Ok(()))), coordinator.scan(request(Ok(())))).await;
+        assert_eq!(a.unwrap(), ScanOutcome::Scanned);
+        assert_eq!(b.unwrap(), ScanOutcome::Scanned);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Within the minimum interval, no request is made and the last scan applies
+        let outcome = coordinator.clone().scan(request(Ok(()))).await.unwrap();
+        assert_eq!(outcome, ScanOutcome::RateLimited);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // A failure is shared with the coalesced callers and does not count as last scan
+        let requests_before = requests.load(Ordering::SeqCst);
+        let coordinator = ScanCoordinator::new(Duration::from_secs(60));
+        let failure = || Err(CaptivePortalError::Generic("busy".to_owned()));
+        let (a, b) = join(
+            coordinator.scan(request(failure())),
+            coordinator.scan(request(failure())),
+        )
+        .await;
+        assert!(a.is_err() && b.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), requests_before + 1);
+        coordinator.scan(request(Ok(()))).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), requests_before + 2);
+    }
+
+    fn credentials(
+        passphrase: Option<&str>,
+        identity: Option<&str>,
+        mode: Security,
+    ) -> Result<AccessPointCredentials, CaptivePortalError> {
+        credentials_from_data(
+            passphrase.map(|v| v.to_owned()),
+            identity.map(|v| v.to_owned()),
+            None,
+            None,
+            None,
+            None,
+            mode,
+        )
+    }
+
+    #[test]
+    fn passphrase_for_open_network() {
+        match credentials(Some("a_password"), None, Security::NONE) {
+            Err(CaptivePortalError::PassphraseNotAllowedForOpen) => {},
+            r => panic!("Expected PassphraseNotAllowedForOpen. Got {:?}", r),
+        }
+        match credentials(Some(""), None, Security::NONE) {
+            Ok(AccessPointCredentials::None) => {},
+            r => panic!("Expected open network credentials. Got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn identity_for_enterprise_network() {
+        match credentials(Some("a_password"), None, Security::ENTERPRISE) {
+            Err(CaptivePortalError::IdentityRequiredForEnterprise) => {},
+            r => panic!("Expected IdentityRequiredForEnterprise. Got {:?}", r),
+        }
+        match credentials(Some("a_password"), Some("user"), Security::ENTERPRISE) {
+            Ok(AccessPointCredentials::Enterprise { .. }) => {},
+            r => panic!("Expected enterprise credentials. Got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn passphrase_required() {
+        for mode in vec![Security::WPA, Security::WPA2, Security::WEP] {
+            match credentials(None, None, mode) {
+                Err(CaptivePortalError::PassphraseRequired) => {},
+                r => panic!("Expected PassphraseRequired. Got {:?}", r),
+            }
+        }
+        match credentials(Some(""), Some("user"), Security::ENTERPRISE) {
+            Err(CaptivePortalError::PassphraseRequired) => {},
+            r => panic!("Expected PassphraseRequired. Got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn connect_progress_subscription() {
+        let channel = ConnectProgressChannel::default();
+        channel.publish(ConnectProgress::Failed("Wrong passphrase".to_owned()));
+        let mut receiver = channel.subscribe();
+        channel.reset();
+        assert_eq!(channel.last(), None);
+
+        channel.publish(ConnectProgress::Associating);
+        channel.publish(ConnectProgress::Associating);
+        channel.publish(ConnectProgress::Connected);
+        assert_eq!(receiver.try_recv(), Ok(ConnectProgress::Associating));
+        assert_eq!(receiver.try_recv(), Ok(ConnectProgress::Connected));
+        assert!(receiver.try_recv().is_err());
+
+        // A closed receiver is dropped on the next publish
+        drop(receiver);
+        channel.publish(ConnectProgress::Associating);
+        assert!(channel.subscribers.lock().unwrap().is_empty());
     }
 }
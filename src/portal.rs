@@ -1,46 +1,264 @@
 //! # This module contains the portal implementation, spawned by the state machine.
 
-use super::http_server::WifiConnectionRequest;
+use super::http_server::{HttpServerStateSync, PortalCommand, PortalRequest};
 use super::network_backend::{ap_changed_stream, NetworkBackend};
 use super::network_interface::WifiConnection;
 use super::utils::take_optional;
 use super::{dhcp_server, dns_server, http_server, CaptivePortalError};
 
-use crate::{NetworkManagerState, WifiConnectionEvent};
+use crate::{NetworkManagerState, ScanOutcome, WifiConnectionEvent, SSID};
+use dhcp_server::{DHCPServer, LeaseEvent};
+use dns_server::domain_policy::DomainPolicies;
+use dns_server::CaptiveDnsServer;
 use futures_core::future::BoxFuture;
+use futures_util::future::{select, Either};
 use futures_util::{FutureExt, StreamExt};
+use pin_utils::pin_mut;
+use serde::Serialize;
+use std::collections::HashSet;
 use std::future::Future;
 use std::net::SocketAddrV4;
 use std::pin::Pin;
 use std::task;
 use std::task::Poll;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{delay_for, interval_at, Instant, Interval};
+
+/// The dns and dhcp server tasks of a portal.
+pub(crate) struct ServerTasks {
+    dns_exit: tokio::sync::oneshot::Sender<()>,
+    dhcp_exit: tokio::sync::oneshot::Sender<()>,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+
+impl ServerTasks {
+    /// Spawns the given dns and dhcp server. The exit handlers must belong to the respective server.
+    pub(crate) fn spawn(
+        mut dns_server: CaptiveDnsServer,
+        dns_exit: tokio::sync::oneshot::Sender<()>,
+        mut dhcp_server: DHCPServer,
+        dhcp_exit: tokio::sync::oneshot::Sender<()>,
+    ) -> Self {
+        let dns_task = tokio::spawn(async move {
+            if let Err(e) = dns_server.run().await {
+                error!("{}", e);
+            }
+        });
+        let dhcp_task = tokio::spawn(async move {
+            if let Err(e) = dhcp_server.run().await {
+                error!("{}", e);
+            }
+        });
+        ServerTasks {
+            dns_exit,
+            dhcp_exit,
+            tasks: vec![dns_task, dhcp_task],
+        }
+    }
+
+    /// Creates and spawns the dns and dhcp server of the portal. Leases are sent to `lease_events`, if given.
+    pub(crate) fn for_portal(
+        config: &crate::config::Config,
+        lease_events: Option<UnboundedSender<LeaseEvent>>,
+    ) -> Result<Self, CaptivePortalError> {
+        let captive_portal_uri = announced_captive_portal_uri(config);
+
+        let (mut dns_server, dns_exit) =
+            dns_server::CaptiveDnsServer::new(SocketAddrV4::new(config.gateway, config.dns_port));
+        dns_server.hijack = !config.no_dns_hijack;
+        dns_server.upstream = config.upstream_dns.clone();
+        dns_server.policies = if config.no_dns_hijack {
+            DomainPolicies::new(config.dns_domain_policies.clone())
+        } else {
+            DomainPolicies::with_defaults(config.dns_domain_policies.clone())
+        };
+        dns_server.hosts = config.dns_hosts.iter().cloned().collect();
+        dns_server.answer_ip = config.dns_answer_ip;
+        dns_server.txt_records = config
+            .dns_txt_records
+            .iter()
+            .flat_map(|domain| captive_portal_uri.clone().map(|uri| (domain.clone(), uri)))
+            .collect();
+        let (mut dhcp_server, dhcp_exit) = dhcp_server::DHCPServer::new(
+            SocketAddrV4::new(config.gateway, config.dhcp_port),
+            config.subnet_prefix,
+        );
+        dhcp_server.lease_grace = Duration::from_secs(config.dhcp_lease_grace);
+        dhcp_server.captive_portal_uri = captive_portal_uri;
+        dhcp_server.set_vendor_options(&config.dhcp_vendor_options, config.dhcp_vendor_enterprise_number)?;
+        dhcp_server.set_dns_servers(&config.dhcp_dns_servers)?;
+        dhcp_server.lease_events = lease_events;
+        Ok(ServerTasks::spawn(dns_server, dns_exit, dhcp_server, dhcp_exit))
+    }
+
+    /// Fires the exit handlers of both servers. Resolves after both server tasks finished
+    /// and released their sockets.
+    pub(crate) async fn shutdown(self) {
+        // A server that already quit, for example because of a bind error, dropped its receiver
+        let _ = self.dns_exit.send(());
+        let _ = self.dhcp_exit.send(());
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Why a portal session closed. See [`PortalSessionSummary`].
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PortalCloseReason {
+    /// Quit from outside, eg by the state machine
+    Exit,
+    /// The hotspot has been stopped
+    HotspotStopped,
+    /// The portal timeout elapsed or network manager reported connectivity meanwhile
+    Timeout,
+    /// The maximum portal lifetime has been reached
+    MaxLifetime,
+    /// A user requested auto connect attempt succeeded
+    AutoConnected,
+    /// The user selected a wifi network
+    Connect,
+    /// The user requested to forget all wifi connections
+    Reset,
+    /// The http server failed
+    Failed,
+    /// The portal has been dropped before it resolved, eg on ctrl+c
+    Dropped,
+}
+
+/// A record of a portal session. It is logged once when the portal is torn down and sent to
+/// [`crate::config::Config::portal_summaries`], if set.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PortalSessionSummary {
+    /// Unix time in seconds when the portal opened
+    pub opened: u64,
+    /// Time in seconds the portal was open
+    pub duration: u64,
+    /// Number of event stream clients served
+    pub sse_clients: usize,
+    /// Number of distinct clients that got a dhcp lease
+    pub dhcp_clients: usize,
+    /// The ssid the user selected, if any
+    pub selected_ssid: Option<SSID>,
+    pub close_reason: PortalCloseReason,
+}
+
+/// Accumulates the statistics of a portal session
+struct PortalSession {
+    opened: SystemTime,
+    started: Instant,
+    dhcp_clients: HashSet<[u8; 6]>,
+    selected_ssid: Option<SSID>,
+    close_reason: Option<PortalCloseReason>,
+}
+
+impl PortalSession {
+    fn new() -> Self {
+        PortalSession {
+            opened: SystemTime::now(),
+            started: Instant::now(),
+            dhcp_clients: HashSet::new(),
+            selected_ssid: None,
+            close_reason: None,
+        }
+    }
+
+    /// Records an exit condition. Only the first one counts, later ones are consequences of it.
+    fn close(&mut self, reason: PortalCloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+    }
+
+    fn lease(&mut self, event: &LeaseEvent) {
+        self.dhcp_clients.insert(event.chaddr);
+    }
+
+    /// Records the outcome of the http server. A user request takes precedence over other exit conditions.
+    fn result(&mut self, result: &Result<Option<PortalRequest>, CaptivePortalError>) {
+        match result {
+            Ok(Some(PortalRequest::Connect(request))) => {
+                self.selected_ssid = Some(request.ssid.clone());
+                self.close_reason = Some(PortalCloseReason::Connect);
+            },
+            Ok(Some(PortalRequest::Reset)) => self.close_reason = Some(PortalCloseReason::Reset),
+            Ok(None) => {},
+            Err(_) => self.close(PortalCloseReason::Failed),
+        }
+    }
+
+    fn summary(&self, sse_clients: usize, now: Instant) -> PortalSessionSummary {
+        PortalSessionSummary {
+            opened: self
+                .opened
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            duration: now.duration_since(self.started).as_secs(),
+            sse_clients,
+            dhcp_clients: self.dhcp_clients.len(),
+            selected_ssid: self.selected_ssid.clone(),
+            close_reason: self.close_reason.unwrap_or(PortalCloseReason::Dropped),
+        }
+    }
+}
+
+/// A user requested auto connect attempt. Resolves to true if a connection could be established.
+type AutoConnectFuture<'a> = BoxFuture<'a, Result<bool, CaptivePortalError>>;
 
 /// The portal type offers a web-ui and redirection services ("Captive Portal"). It stays online
 /// for a certain configurable time and returns when the user has selected a wifi SSID and entered
-/// credentials.
+/// credentials or requested a reset.
 ///
 /// # Implementation details
 /// The portal spawns several background tasks for dns, dhcp, access point changes.
+/// The dns and dhcp server are shut down before the portal future resolves, so that their sockets are
+/// released on every exit path. If the portal is dropped early, the servers quit in the background.
 /// It is itself a future that polls the timeout, connection-changed and webserver inner futures.
-/// It also resolves when the user has selected a wifi connection from the UI.
+/// It also resolves when the user has selected a wifi connection from the UI or when a user requested
+/// auto connect attempt succeeded.
+///
+/// A [`PortalSessionSummary`] is emitted when the portal is dropped.
 pub struct Portal<'a> {
+    nm: &'a NetworkBackend,
     /// Used to quit the server by the timeout or user wifi selection
     http_exit: Option<tokio::sync::oneshot::Sender<()>>,
-    /// As soon as Portal is dropped, the dns server will stop
-    #[allow(dead_code)]
-    dns_exit: tokio::sync::oneshot::Sender<()>,
-    /// As soon as Portal is dropped, the dhcp server will stop
-    #[allow(dead_code)]
-    dhcp_exit: tokio::sync::oneshot::Sender<()>,
+    /// The dns and dhcp server. As soon as Portal is dropped, the servers will stop.
+    servers: Option<ServerTasks>,
+    /// The shutdown future of the servers. Will be polled by this wrapping future after the http server resolved.
+    servers_shutdown: Option<BoxFuture<'static, ()>>,
+    /// The http server result. Returned after the servers have been shut down.
+    result: Option<Result<Option<PortalRequest>, CaptivePortalError>>,
     /// Internal: This future is polled by this wrapping future to determine if outside wants us to quit.
     exit_receiver: Option<tokio::sync::oneshot::Receiver<()>>,
     /// The timeout future. Will be polled by this wrapping future.
     timeout: Option<BoxFuture<'a, Result<NetworkManagerState, CaptivePortalError>>>,
+    /// The maximum portal lifetime. Unlike the timeout it is not reset by portal activity.
+    max_lifetime: Option<BoxFuture<'static, ()>>,
+    /// Periodic wifi scans, if enabled. Found access points reach the UI via the access point changed stream.
+    scan_interval: Option<Interval>,
+    /// The current periodic scan. Will be polled by this wrapping future.
+    scan: Option<BoxFuture<'a, Result<ScanOutcome, CaptivePortalError>>>,
     /// The connection changed future. Will be polled by this wrapping future.
     hotspot_stopped_fut: Option<BoxFuture<'a, Result<(), CaptivePortalError>>>,
+    /// Commands of the http server. Will be polled by this wrapping future.
+    command_receiver: tokio::sync::mpsc::Receiver<PortalCommand>,
+    /// A user requested auto connect attempt and the sender for its outcome. Will be polled by this wrapping future.
+    auto_connect: Option<(AutoConnectFuture<'a>, tokio::sync::oneshot::Sender<bool>)>,
+    /// The time to wait for a connection of a user requested auto connect attempt
+    auto_connect_timeout: Duration,
     /// The http server future. Will be polled by this wrapping future.
-    http_server: Pin<Box<dyn Future<Output = Result<Option<WifiConnectionRequest>, CaptivePortalError>> + Send>>,
+    http_server: Pin<Box<dyn Future<Output = Result<Option<PortalRequest>, CaptivePortalError>> + Send>>,
+    /// The http server state, for the session summary
+    http_state: HttpServerStateSync,
+    /// Leases of the dhcp server. Will be polled by this wrapping future.
+    lease_events: UnboundedReceiver<LeaseEvent>,
+    /// The statistics of this portal session
+    session: PortalSession,
+    /// Receives the session summary, if set
+    session_summaries: Option<UnboundedSender<PortalSessionSummary>>,
 }
 
 impl<'a> Portal<'a> {
@@ -53,33 +271,41 @@ impl<'a> Portal<'a> {
         wifi_access_points: Vec<WifiConnection>,
         timeout: Duration,
     ) -> Result<(Portal<'a>, tokio::sync::oneshot::Sender<()>), CaptivePortalError> {
-        let (http_server, http_exit) = http_server::HttpServer::new(
+        let (mut http_server, http_exit) = http_server::HttpServer::new(
             SocketAddrV4::new(config.gateway.clone(), config.listening_port),
             nm.clone(),
             config.get_ui_directory(),
         );
+        http_server.unix_socket = config.unix_socket.clone();
 
         let mut state = http_server.state.lock().expect("Lock http_state mutex for portal");
-        state.connections.0.extend(wifi_access_points);
+        state.min_strength = config.min_strength;
+        if config.ap_ttl > 0 {
+            state.ap_ttl = Some(Duration::from_secs(config.ap_ttl));
+        }
+        // The config already contains the ssid of the passphrase file, if any
+        state.ssid = config.ssid.clone();
+        state.subnet_prefix = config.subnet_prefix;
+        state.add_networks(wifi_access_points);
+        state.reset_token = config.reset_token.clone();
+        state.min_uptime = Duration::from_secs(config.min_portal_secs);
+        state.noise_paths = config.noise_paths.clone();
+        state.log_buffer = config.log_buffer.clone();
+        state.connect_progress = config.connect_progress.clone();
+        state.connect_cancel = config.connect_cancel.clone();
+        state.credential_store = config.credential_store();
+        state.logs_token = config.logs_token.clone();
+        state.base_path = http_server::normalize_base_path(&config.base_path);
+        state.portal_host = config.portal_host.clone();
+        let (command_sender, command_receiver) = tokio::sync::mpsc::channel::<PortalCommand>(1);
+        state.command_sender = Some(command_sender);
         drop(state);
 
         let http_state = http_server.state.clone();
+        let summary_http_state = http_server.state.clone();
 
-        let (mut dns_server, dns_exit) =
-            dns_server::CaptiveDnsServer::new(SocketAddrV4::new(config.gateway.clone(), config.dns_port));
-        let (mut dhcp_server, dhcp_exit) =
-            dhcp_server::DHCPServer::new(SocketAddrV4::new(config.gateway.clone(), config.dhcp_port));
-
-        tokio::spawn(async move {
-            if let Err(e) = dns_server.run().await {
-                error!("{}", e);
-            }
-        });
-        tokio::spawn(async move {
-            if let Err(e) = dhcp_server.run().await {
-                error!("{}", e);
-            }
-        });
+        let (lease_sender, lease_events) = tokio::sync::mpsc::unbounded_channel();
+        let servers = ServerTasks::for_portal(config, Some(lease_sender))?;
 
         let nm_clone = nm.clone();
         tokio::spawn(async move {
@@ -91,7 +317,7 @@ impl<'a> Portal<'a> {
                 },
                 Ok(stream) => stream,
             };
-            for event in stream.next().await {
+            while let Some(event) = stream.next().await {
                 let access_point = nm_clone.access_point(event.path).await;
                 if let Ok(access_point) = access_point {
                     if access_point.is_own {
@@ -109,12 +335,30 @@ impl<'a> Portal<'a> {
         let (exit_handler, exit_receiver) = tokio::sync::oneshot::channel::<()>();
 
         let portal = Portal {
+            nm,
             http_server: Box::pin(http_server.run()),
-            dns_exit,
-            dhcp_exit,
+            http_state: summary_http_state,
+            lease_events,
+            session: PortalSession::new(),
+            session_summaries: config.portal_summaries.clone(),
+            command_receiver,
+            auto_connect: None,
+            auto_connect_timeout: Duration::from_secs(config.wait_before_reconfigure),
+            servers: Some(servers),
+            servers_shutdown: None,
+            result: None,
             exit_receiver: Some(exit_receiver),
             http_exit: Some(http_exit),
             timeout: Some(nm.wait_for_connectivity(config.internet_connectivity, timeout).boxed()),
+            max_lifetime: portal_deadline(config.max_portal_secs).map(|deadline| delay_for(deadline).boxed()),
+            scan_interval: match config.scan_interval {
+                0 => None,
+                secs => Some(interval_at(
+                    Instant::now() + Duration::from_secs(secs),
+                    Duration::from_secs(secs),
+                )),
+            },
+            scan: None,
             hotspot_stopped_fut: Some(nm.on_hotspot_stopped(wifi_sta_active_connection).boxed()),
         };
 
@@ -122,6 +366,69 @@ impl<'a> Portal<'a> {
     }
 }
 
+/// The captive portal uri that is announced via dhcp option 114 and dns TXT records, if any.
+/// The own captive portal api is only announced on request, because it is not served via https.
+fn announced_captive_portal_uri(config: &crate::config::Config) -> Option<String> {
+    if config.captive_portal_uri.is_some() || !config.announce_captive_portal_api {
+        return config.captive_portal_uri.clone();
+    }
+    Some(http_server::captive_portal_api_uri(
+        &SocketAddrV4::new(config.gateway, config.listening_port),
+        config.portal_host.as_deref(),
+        &http_server::normalize_base_path(&config.base_path),
+    ))
+}
+
+/// Serves "/status" and "/cancel-connect" while a connection attempt runs. The portal is down meanwhile.
+/// Only its dns and dhcp server might be kept up, see [`crate::config::Config::keep_hotspot_during_connect`].
+/// The progress of the attempt is also sent to event stream clients.
+/// Further connection requests are rejected. Call the returned exit handler to stop the listener.
+pub(crate) fn connect_listener(
+    nm: &NetworkBackend,
+    config: &crate::config::Config,
+) -> (impl Future<Output = ()>, tokio::sync::oneshot::Sender<()>) {
+    let (mut http_server, http_exit) = http_server::HttpServer::new(
+        SocketAddrV4::new(config.gateway, config.listening_port),
+        nm.clone(),
+        config.get_ui_directory(),
+    );
+    http_server.unix_socket = config.unix_socket.clone();
+
+    let mut state = http_server
+        .state
+        .lock()
+        .expect("Lock http_state mutex for connect listener");
+    state.ssid = config.ssid.clone();
+    state.connect_progress = config.connect_progress.clone();
+    state.connect_cancel = config.connect_cancel.clone();
+    state.base_path = http_server::normalize_base_path(&config.base_path);
+    state.portal_host = config.portal_host.clone();
+    drop(state);
+    let connection_sender = http_server.reject_connection_requests();
+    let progress_receiver = config.connect_progress.subscribe();
+    let progress = http_server::forward_connect_progress(http_server.state.clone(), progress_receiver);
+
+    let listener = async move {
+        let _connection_sender = connection_sender;
+        // The progress is forwarded until the server quits
+        let server = http_server.run();
+        pin_mut!(server);
+        pin_mut!(progress);
+        if let Either::Left((Err(e), _)) = select(server, progress).await {
+            warn!("Status of the connection attempt not available: {}", e);
+        }
+    };
+    (listener, http_exit)
+}
+
+/// Returns the maximum portal lifetime for the given configuration value. 0 means unlimited.
+fn portal_deadline(max_portal_secs: u64) -> Option<Duration> {
+    match max_portal_secs {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
 /// The portal is also a future. It polls on various exit conditions like the timeout,
 /// a user selected wifi, or when the active connection changes its state. And it
 /// also polls on the webserver of course.
@@ -129,7 +436,7 @@ impl<'a> Portal<'a> {
 /// All polled futures are wrapped in Optional in the portal structure, because we do not
 /// want to call a resolved future again.
 impl<'a> Future for Portal<'a> {
-    type Output = Result<Option<WifiConnectionRequest>, CaptivePortalError>;
+    type Output = Result<Option<PortalRequest>, CaptivePortalError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
         let mut exit_soon = false;
@@ -138,6 +445,7 @@ impl<'a> Future for Portal<'a> {
         if let Some(exit_receiver) = self.exit_receiver.as_mut() {
             if let Poll::Ready(_) = exit_receiver.poll_unpin(cx) {
                 exit_soon = true;
+                self.session.close(PortalCloseReason::Exit);
                 take_optional(self.as_mut(), |me| &mut me.exit_receiver);
             }
         }
@@ -145,6 +453,7 @@ impl<'a> Future for Portal<'a> {
         if let Some(connection_state_change_fut) = self.hotspot_stopped_fut.as_mut() {
             if let Poll::Ready(_) = connection_state_change_fut.as_mut().poll(cx) {
                 exit_soon = true;
+                self.session.close(PortalCloseReason::HotspotStopped);
                 take_optional(self.as_mut(), |me| &mut me.hotspot_stopped_fut);
             }
         }
@@ -152,20 +461,235 @@ impl<'a> Future for Portal<'a> {
         if let Some(timeout) = self.timeout.as_mut() {
             if let Poll::Ready(_) = timeout.poll_unpin(cx) {
                 exit_soon = true;
+                self.session.close(PortalCloseReason::Timeout);
                 take_optional(self.as_mut(), |me| &mut me.timeout);
             }
         }
 
+        if let Some(max_lifetime) = self.max_lifetime.as_mut() {
+            if max_lifetime.poll_unpin(cx).is_ready() {
+                info!("Maximum portal lifetime reached");
+                exit_soon = true;
+                self.session.close(PortalCloseReason::MaxLifetime);
+                take_optional(self.as_mut(), |me| &mut me.max_lifetime);
+            }
+        }
+
+        // A scan that is still running when the next one is due is not interrupted
+        let mut scan_due = false;
+        if let Some(scan_interval) = self.scan_interval.as_mut() {
+            while scan_interval.poll_tick(cx).is_ready() {
+                scan_due = true;
+            }
+        }
+        if scan_due && self.scan.is_none() {
+            self.scan = Some(self.nm.scan_networks().boxed());
+        }
+        if let Some(scan) = self.scan.as_mut() {
+            if let Poll::Ready(result) = scan.poll_unpin(cx) {
+                // Single radio devices usually refuse to scan in access point mode
+                if let Err(e) = result {
+                    info!("Periodic scan skipped: {}", e);
+                }
+                self.scan = None;
+            }
+        }
+
+        // Only one auto connect attempt at a time. Dropping the outcome sender rejects additional requests.
+        while let Poll::Ready(Some(command)) = self.command_receiver.poll_recv(cx) {
+            match command {
+                PortalCommand::AutoConnect(result_sender) => {
+                    if self.auto_connect.is_none() {
+                        info!("User requested auto connect attempt");
+                        let auto_connect = self.nm.try_auto_connect(self.auto_connect_timeout).boxed();
+                        self.auto_connect = Some((auto_connect, result_sender));
+                    }
+                },
+            }
+        }
+
+        if let Some((auto_connect, _)) = self.auto_connect.as_mut() {
+            if let Poll::Ready(result) = auto_connect.poll_unpin(cx) {
+                let connected = match result {
+                    Ok(connected) => connected,
+                    Err(e) => {
+                        warn!("Auto connect attempt failed: {}", e);
+                        false
+                    },
+                };
+                if let Some((_, result_sender)) = self.auto_connect.take() {
+                    let _ = result_sender.send(connected);
+                }
+                // A connection has been established. Leave the portal.
+                if connected {
+                    exit_soon = true;
+                    self.session.close(PortalCloseReason::AutoConnected);
+                }
+            }
+        }
+
+        while let Poll::Ready(Some(event)) = self.lease_events.poll_recv(cx) {
+            self.session.lease(&event);
+        }
+
         if exit_soon && self.http_exit.is_some() {
             take_optional(self.as_mut(), |me| &mut me.http_exit);
         }
 
-        // Safety: we never move `self.value`
-        let http_server = unsafe { self.as_mut().map_unchecked_mut(|me| &mut me.http_server) };
-        if let Poll::Ready(v) = http_server.poll(cx) {
-            return Poll::Ready(v);
+        if self.result.is_none() {
+            // Safety: we never move `self.value`
+            let http_server = unsafe { self.as_mut().map_unchecked_mut(|me| &mut me.http_server) };
+            if let Poll::Ready(v) = http_server.poll(cx) {
+                self.session.result(&v);
+                self.result = Some(v);
+                if let Some(servers) = self.servers.take() {
+                    self.servers_shutdown = Some(servers.shutdown().boxed());
+                }
+            }
+        }
+
+        // Resolve after the dns and dhcp server released their sockets
+        if self.result.is_some() {
+            if let Some(servers_shutdown) = self.servers_shutdown.as_mut() {
+                if servers_shutdown.poll_unpin(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                take_optional(self.as_mut(), |me| &mut me.servers_shutdown);
+            }
+            if let Some(result) = self.result.take() {
+                return Poll::Ready(result);
+            }
         }
 
         Poll::Pending
     }
 }
+
+/// Emits the session summary on every exit path, including an early drop.
+impl<'a> Drop for Portal<'a> {
+    fn drop(&mut self) {
+        let sse_clients = match self.http_state.lock() {
+            Ok(state) => state.sse_clients_served,
+            Err(_) => 0,
+        };
+        let summary = self.session.summary(sse_clients, Instant::now());
+        match serde_json::to_string(&summary) {
+            Ok(json) => info!("Portal session summary: {}", json),
+            Err(e) => warn!("Failed to encode portal session summary: {}", e),
+        }
+        if let Some(session_summaries) = self.session_summaries.as_ref() {
+            let _ = session_summaries.send(summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{announced_captive_portal_uri, portal_deadline, PortalCloseReason, PortalSession, ServerTasks};
+    use crate::http_server::PortalRequest;
+    use crate::{dhcp_server, dns_server, CaptivePortalError};
+    use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+    use std::time::Duration;
+    use tokio::time::{delay_for, Instant};
+
+    fn lease(chaddr: [u8; 6]) -> dhcp_server::LeaseEvent {
+        dhcp_server::LeaseEvent {
+            chaddr,
+            ip: Ipv4Addr::new(192, 168, 42, 2),
+            user_classes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn session_summary() {
+        let mut session = PortalSession::new();
+        // A renewed lease is counted once
+        session.lease(&lease([1, 2, 3, 4, 5, 6]));
+        session.lease(&lease([1, 2, 3, 4, 5, 6]));
+        session.lease(&lease([1, 2, 3, 4, 5, 7]));
+        // Not closed yet
+        let summary = session.summary(3, Instant::now());
+        assert_eq!(summary.dhcp_clients, 2);
+        assert_eq!(summary.sse_clients, 3);
+        assert_eq!(summary.selected_ssid, None);
+        assert_eq!(summary.close_reason, PortalCloseReason::Dropped);
+
+        // The first exit condition counts
+        session.close(PortalCloseReason::Timeout);
+        session.close(PortalCloseReason::Exit);
+        session.result(&Ok(None));
+        let summary = session.summary(3, session.started + Duration::from_secs(90));
+        assert_eq!(summary.close_reason, PortalCloseReason::Timeout);
+        assert_eq!(summary.duration, 90);
+        assert!(summary.opened > 0);
+
+        // Unless the user selected a network
+        let request = serde_json::from_str(r#"{"mode":"open","ssid":"cafe"}"#).unwrap();
+        session.result(&Ok(Some(PortalRequest::Connect(Box::new(request)))));
+        let summary = session.summary(3, Instant::now());
+        assert_eq!(summary.close_reason, PortalCloseReason::Connect);
+        assert_eq!(summary.selected_ssid.as_deref(), Some("cafe"));
+
+        let mut session = PortalSession::new();
+        session.result(&Err(CaptivePortalError::HttpRoutingFailed));
+        assert_eq!(
+            session.summary(0, Instant::now()).close_reason,
+            PortalCloseReason::Failed
+        );
+
+        let json = serde_json::to_string(&session.summary(0, Instant::now())).unwrap();
+        assert!(json.contains(r#""close_reason":"failed""#));
+    }
+
+    #[test]
+    fn deadline() {
+        assert_eq!(portal_deadline(0), None);
+        assert_eq!(portal_deadline(600), Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn captive_portal_uri() {
+        let mut config = crate::config::Config::new();
+        assert_eq!(announced_captive_portal_uri(&config), None);
+
+        config.announce_captive_portal_api = true;
+        let api_uri = announced_captive_portal_uri(&config).expect("captive portal api");
+        assert!(api_uri.ends_with("/captive-portal-api"), "{}", api_uri);
+
+        // A configured uri takes precedence
+        config.captive_portal_uri = Some("https://portal.example/api".to_owned());
+        assert_eq!(announced_captive_portal_uri(&config), config.captive_portal_uri);
+    }
+
+    /// Returns a currently unused udp port on localhost
+    fn free_port() -> u16 {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("Bind udp socket");
+        socket.local_addr().expect("Local addr").port()
+    }
+
+    #[tokio::test]
+    async fn servers_release_sockets_on_shutdown() {
+        let dns_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), free_port());
+        let dhcp_addr = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), free_port());
+
+        let (dns_server, dns_exit) = dns_server::CaptiveDnsServer::new(dns_addr);
+        let (dhcp_server, dhcp_exit) = dhcp_server::DHCPServer::new(dhcp_addr, 24);
+        let servers = ServerTasks::spawn(dns_server, dns_exit, dhcp_server, dhcp_exit);
+
+        // Wait for the servers to bind
+        let mut bound = false;
+        for _ in 0..50 {
+            if UdpSocket::bind(dns_addr).is_err() && UdpSocket::bind(dhcp_addr).is_err() {
+                bound = true;
+                break;
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+        assert!(bound, "Servers did not bind");
+
+        servers.shutdown().await;
+
+        assert!(UdpSocket::bind(dns_addr).is_ok(), "DNS socket not released");
+        assert!(UdpSocket::bind(dhcp_addr).is_ok(), "DHCP socket not released");
+    }
+}
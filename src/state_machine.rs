@@ -1,45 +1,80 @@
 //! # The programs state machine. Each state carries its required data, no side-effects.
 
 use crate::config::Config;
-use crate::http_server::WifiConnectionRequest;
+use crate::http_server::{PortalRequest, WifiConnectionRequest};
 use crate::network_backend::NetworkBackend;
-use crate::network_interface::credentials_from_data;
+use crate::portal::{connect_listener, ServerTasks};
 use crate::utils::ctrl_c_or_future;
-use crate::{CaptivePortalError, verify_password, ctrl_c_with_exit_handler};
-use crate::ConnectionState;
 use crate::NetworkManagerState;
+use crate::{classify_bind_error, test_tcp, test_udp, BindError, BindErrorClass};
+use crate::{ctrl_c_with_exit_handler, subnets_overlap, verify_password, CaptivePortalError};
+use crate::{strongest_access_point_on_band, AccessPointCredentials, ScanBudget, WifiBand};
+use crate::{ConnectOptions, ConnectProgress, ConnectProgressChannel, ConnectionState};
+use futures_util::future::{select, Either};
+use futures_util::StreamExt;
 use log::info;
-use std::convert::TryInto;
+use pin_utils::pin_mut;
+use std::net::SocketAddrV4;
 use std::time::Duration;
-use tokio::time::timeout;
+use tokio::time::{delay_for, timeout};
+
+/// The delay before the second reconnect attempt if the portal must never open. Doubled for each further attempt.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(5);
+/// The maximum delay between two reconnect attempts if the portal must never open
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+/// The delay before the second attempt to bind the server ports. Doubled for each further attempt.
+const BIND_RETRY_DELAY_MIN: Duration = Duration::from_secs(1);
+/// The maximum delay between two attempts to bind the server ports
+const BIND_RETRY_DELAY_MAX: Duration = Duration::from_secs(30);
 
 /// The programs state machine. Each state carries its required data, no side-effects.
 /// The configuration and network manager connection are moved between states.
 ///
 /// All states transition into StartUp if the dbus connection to the network manager got lost.
 pub enum StateMachine {
+    /// Tests if the DNS, DHCP and HTTP ports can be bound. Carries the number of failed attempts so far.
+    ///
+    /// # Transitions:
+    /// **StartUp** -> If all ports can be bound
+    /// **Failed** -> If the gateway address is not assigned yet or a port is held by another service
+    ///
+    /// # Errors:
+    /// Error out on missing permissions and other bind failures.
+    CheckPorts(Box<Config>, u32),
+
+    /// A port could not be bound for the given number of attempts. Waits with an increasing delay.
+    ///
+    /// # Transitions:
+    /// **CheckPorts** -> After the delay
+    ///
+    /// # Errors:
+    /// Error out with the bind failure if the configured number of attempts is reached.
+    Failed(Box<Config>, u32, CaptivePortalError),
+
     /// Starts a dbus connection to the system bus.
     /// Connects to network manager, starts the service if necessary.
     ///
     /// # Transitions:
     /// **Connected** -> If network manager reports active connections and a "connected" state.
     /// **TryReconnect** -> If no connection is active
+    /// **ActivatePortal** -> If network manager reports a disconnected state, unless the portal must never open
     ///
     /// # Errors:
     /// Error out if network manager cannot be reached.
-    StartUp(Config),
+    StartUp(Box<Config>),
 
     /// Scans for access points and tries to connect to already known ones.
     ///
     /// # Transitions:
     /// **Connected** -> If network manager transitioned into a connected state.
-    /// **ActivatePortal** -> If no connection can be established
+    /// **ActivatePortal** -> If no connection can be established. If the portal must never open,
+    /// connecting is retried with an increasing delay instead.
     /// **Exit** ->  On ctrl+c
     ///
     /// # Errors:
     /// Fails if network manager permissions do not allow to issue wifi scans or connect to
     /// access points. Error out if network manager cannot be reached.
-    TryReconnect(Config, NetworkBackend),
+    TryReconnect(Box<Config>, NetworkBackend),
 
     /// The device is connected, as reported by network manager
     ///
@@ -49,10 +84,11 @@ pub enum StateMachine {
     /// # Transitions:
     /// **TryReconnect** -> On connection lost
     /// **Exit** ->  On ctrl+c
-    Connected(Config, NetworkBackend),
+    Connected(Box<Config>, NetworkBackend),
 
     /// Activates a wifi hotspot and portal page.
     /// Starts up an http server, a dns server and a dhcp server.
+    /// A hotspot that has been kept up during a failed connection attempt is reused.
     ///
     /// **IF** network manager reported connections:
     /// Starts a timer to periodically (5 min) check if a connection to an already configured wifi
@@ -60,33 +96,77 @@ pub enum StateMachine {
     ///
     /// # Transitions:
     /// **Connect** -> When the user requests to connect to a wifi access point via the http server.
+    /// **ActivatePortal** -> When the user requests to forget all wifi connections via the http server.
+    /// **TryReconnect** -> When a user requested auto connect attempt succeeded, on timeout,
+    /// when the maximum portal lifetime is reached or if the wifi device does not support access point mode
     /// **Connected** -> When a connection could be established
     /// **Exit** ->  On ctrl+c
-    ActivatePortal(Config, NetworkBackend),
+    ActivatePortal(Box<Config>, NetworkBackend),
 
     /// Tries to connect to the given access point.
     ///
+    /// The connection progress is published to the connect progress channel of the Config.
+    /// The progress is served at "/status" and as "connect" events. The attempt can be cancelled at "/cancel-connect".
+    ///
+    /// With [`Config::keep_hotspot_during_connect`] and a second wifi device, the attempt runs on that device.
+    /// The hotspot and the dns and dhcp server stay up meanwhile. The hotspot is only stopped on success.
+    ///
     /// # Transitions:
     /// **Connected** First stores the ssid+passphrase+identity in Config then transition in the connected state.
     /// **ActivatePortal** If the connection fails after a few attempts
-    Connect(Config, NetworkBackend, WifiConnectionRequest),
+    Connect(Box<Config>, NetworkBackend, Box<WifiConnectionRequest>),
 
     /// Quits the program
     ///
-    /// Shuts down the network manager connection.
-    Exit(NetworkBackend),
+    /// Restores networking to a clean state, as configured, and shuts down the network manager connection.
+    Exit(Box<Config>, NetworkBackend),
 }
 
 impl StateMachine {
     pub async fn progress(self) -> Result<Option<StateMachine>, CaptivePortalError> {
         match self {
+            StateMachine::CheckPorts(config, failures) => match test_server_ports(&config).await {
+                Ok(()) => Ok(Some(StateMachine::StartUp(config))),
+                Err((kind, e)) => match classify_bind_error(kind) {
+                    BindErrorClass::Retryable => Ok(Some(StateMachine::Failed(config, failures + 1, e))),
+                    BindErrorClass::Fatal => Err(e),
+                },
+            },
+            StateMachine::Failed(config, failures, e) => match bind_retry_delay(failures, config.bind_attempts) {
+                Some(delay) => {
+                    warn!("{}. Retrying in {} seconds", e, delay.as_secs());
+                    delay_for(delay).await;
+                    Ok(Some(StateMachine::CheckPorts(config, failures)))
+                },
+                None => Err(e),
+            },
             StateMachine::StartUp(config) => {
-                let nm = NetworkBackend::new(&config.interface).await?;
-                nm.enable_networking_and_wifi().await?;
+                let nm = NetworkBackend::new(
+                    &config.interface,
+                    Duration::from_secs(config.wait_for_device),
+                    Duration::from_secs(config.wait_for_backend),
+                    config.hotspot_uuid.clone(),
+                )
+                .await?;
+                nm.enable_networking_and_wifi(config.manage_device).await?;
+                nm.set_connectivity_check_uri(config.connectivity_check_uri.as_deref())
+                    .await?;
+                if !config.upstream_dns.is_empty() {
+                    // The upstream dns servers are still used by the dns server of the portal
+                    match nm.set_upstream_dns(config.upstream_dns.clone()).await {
+                        Err(e @ CaptivePortalError::Unsupported(_)) => info!("{}", e),
+                        r => r?,
+                    }
+                }
 
                 let state = nm.state().await?;
                 info!("Starting up. Network manager reports state {:?}", state);
                 Ok(match state {
+                    NetworkManagerState::Unknown | NetworkManagerState::Asleep | NetworkManagerState::Disconnected
+                        if config.never_open_portal =>
+                    {
+                        Some(StateMachine::TryReconnect(config, nm))
+                    },
                     NetworkManagerState::Unknown | NetworkManagerState::Asleep | NetworkManagerState::Disconnected => {
                         Some(StateMachine::ActivatePortal(config, nm))
                     }
@@ -100,40 +180,48 @@ impl StateMachine {
             }
             StateMachine::TryReconnect(config, nm) => {
                 info!("No connection found. Trying to reestablish");
-                nm.enable_networking_and_wifi().await?;
+                nm.enable_networking_and_wifi(config.manage_device).await?;
 
-                // Try to connect to an existing connection
-                let r =
-                    ctrl_c_or_future(nm.try_auto_connect(Duration::from_secs(config.wait_before_reconfigure))).await?;
-                match r {
-                    // Ctrl+C
-                    None => return Ok(Some(StateMachine::Exit(nm))),
-                    Some(state) => {
-                        if state {
-                            return Ok(Some(StateMachine::Connected(config, nm)));
+                let mut attempt = 0;
+                loop {
+                    attempt += 1;
+                    // Try to connect to an existing connection
+                    let r = ctrl_c_or_future(nm.try_auto_connect(Duration::from_secs(config.wait_before_reconfigure)))
+                        .await?;
+                    match r {
+                        // Ctrl+C
+                        None => return Ok(Some(StateMachine::Exit(config, nm))),
+                        Some(state) => {
+                            if state {
+                                return Ok(Some(StateMachine::Connected(config, nm)));
+                            }
                         }
                     }
+                    let delay = match reconnect_backoff(attempt, config.never_open_portal) {
+                        Some(delay) => delay,
+                        None => return Ok(Some(StateMachine::ActivatePortal(config, nm))),
+                    };
+                    info!("No known network reachable. Retrying in {}s", delay.as_secs());
+                    let r = ctrl_c_or_future(async {
+                        delay_for(delay).await;
+                        Ok(())
+                    })
+                    .await?;
+                    if r.is_none() {
+                        return Ok(Some(StateMachine::Exit(config, nm)));
+                    }
                 }
-                return Ok(Some(StateMachine::ActivatePortal(config, nm)));
             }
             StateMachine::Connected(config, nm) => {
-                nm.deactivate_hotspots().await?;
-
-                let c_state = nm
-                    .wait_for_connectivity(config.internet_connectivity, Duration::from_secs(5))
-                    .await;
-                info!("Current connectivity: {:?}", c_state);
+                deactivate_hotspots(&config, &nm).await?;
 
-                match c_state {
-                    Ok(_) => {}
-                    Err(CaptivePortalError::NotRequiredConnectivity(_)) => {
-                        return Ok(Some(StateMachine::TryReconnect(config, nm)));
-                    }
-                    Err(e) => return Err(e),
+                let failures = ConnectivityFailures::new(config.connectivity_failures);
+                if connectivity_lost(&config, &nm, failures).await? {
+                    return Ok(Some(StateMachine::TryReconnect(config, nm)));
                 }
 
                 if config.quit_after_connected {
-                    return Ok(Some(StateMachine::Exit(nm)));
+                    return Ok(Some(StateMachine::Exit(config, nm)));
                 }
 
                 // Await a connectivity change, ctrl+c or the timeout
@@ -143,36 +231,62 @@ impl StateMachine {
 
                 match r {
                     // Ctrl+C
-                    None => Ok(Some(StateMachine::Exit(nm))),
-                    Some(_) => Ok(Some(StateMachine::TryReconnect(config, nm))),
+                    None => Ok(Some(StateMachine::Exit(config, nm))),
+                    // Connectivity lost. Confirm with further checks, it might only be a short dip.
+                    Some(_) => {
+                        let mut failures = ConnectivityFailures::new(config.connectivity_failures);
+                        if failures.record(false) || connectivity_lost(&config, &nm, failures).await? {
+                            Ok(Some(StateMachine::TryReconnect(config, nm)))
+                        } else {
+                            Ok(Some(StateMachine::Connected(config, nm)))
+                        }
+                    },
                 }
             }
             StateMachine::ActivatePortal(mut config, nm) => {
-                nm.enable_networking_and_wifi().await?;
-                nm.deactivate_hotspots().await?;
+                nm.enable_networking_and_wifi(config.manage_device).await?;
+                // Without access point mode, the only option is to reconnect to a known network
+                match nm.check_ap_mode().await {
+                    Err(e @ CaptivePortalError::ApModeUnsupported { .. }) => {
+                        warn!("{}. Trying to establish a connection instead.", e);
+                        return Ok(Some(StateMachine::TryReconnect(config, nm)));
+                    },
+                    r => r?,
+                }
+                // A hotspot that has been kept up during a failed connection attempt is reused,
+                // so that clients stay connected to it
+                let kept_hotspot = match config.keep_hotspot_during_connect {
+                    true => nm.active_hotspot().await?,
+                    false => None,
+                };
+                if kept_hotspot.is_none() {
+                    deactivate_hotspots(&config, &nm).await?;
+                }
 
                 update_portal_info_via_file(&mut config);
 
                 info!("Acquire wifi access point list. This may take a minute ...");
-                let wifi_access_points = nm.list_access_points(Duration::from_secs(7)).await?;
+                let wifi_access_points = nm
+                    .list_access_points(Duration::from_secs(7), scan_budget(&config))
+                    .await?;
+                if wifi_access_points.is_empty() {
+                    match nm.radio_state().await {
+                        Ok(radio_state) if radio_state.rfkill() => warn!("No access points found: Wifi is blocked"),
+                        _ => warn!("No access points found"),
+                    }
+                }
 
-                let r = timeout(Duration::from_secs(25),nm
-                    .hotspot_start(config.ssid.clone(), config.passphrase.clone(), Some(config.gateway)))
-                    .await;
+                check_address_conflicts(&config, &nm).await?;
 
-                let active_connection = match r {
-                    Ok(Ok(r)) => r.active_connection_path,
-                    Err(_) => {
-                        warn!("Failed to create hotspot: Timeout. Trying to establish a connection instead.");
-                        return Ok(Some(StateMachine::TryReconnect(config, nm)));
-                    }
-                    Ok(Err(e)) => {
-                        warn!(
-                            "Failed to create hotspot: {}. Trying to establish a connection instead.",
-                            e
-                        );
-                        return Ok(Some(StateMachine::TryReconnect(config, nm)));
-                    }
+                let active_connection = match kept_hotspot {
+                    Some(active_connection) => {
+                        info!("Reusing the hotspot that has been kept up during the connection attempt");
+                        active_connection
+                    },
+                    None => match start_hotspot(&config, &nm).await? {
+                        Some(active_connection) => active_connection,
+                        None => return Ok(Some(StateMachine::TryReconnect(config, nm))),
+                    },
                 };
 
                 info!("Activating portal services");
@@ -189,12 +303,16 @@ impl StateMachine {
                 info!("Portal closed");
                 match r {
                     // Ctrl+C
-                    None => Ok(Some(StateMachine::Exit(nm))),
-                    // Either the user has entered a wifi connection or a timeout happened
-                    Some(wifi_connection) => {
-                        match wifi_connection {
+                    None => Ok(Some(StateMachine::Exit(config, nm))),
+                    // Either the user has entered a wifi connection, requested a reset or a timeout happened
+                    Some(portal_request) => {
+                        match portal_request {
                             // The user has entered a wifi connection
-                            Some(wifi_connection) => Ok(Some(StateMachine::Connect(config, nm, wifi_connection))),
+                            Some(PortalRequest::Connect(wifi_connection)) => {
+                                Ok(Some(StateMachine::Connect(config, nm, wifi_connection)))
+                            },
+                            // All wifi connections have been forgotten. Reopen the portal
+                            Some(PortalRequest::Reset) => Ok(Some(StateMachine::ActivatePortal(config, nm))),
                             // Timeout
                             None => Ok(Some(StateMachine::TryReconnect(config, nm))),
                         }
@@ -204,29 +322,63 @@ impl StateMachine {
             StateMachine::Connect(config, nm, network) => {
                 info!("Connecting ...");
 
-                let connection = nm
-                    .connect_to(
-                        network.ssid,
-                        credentials_from_data(
-                            network.passphrase.unwrap_or_default(),
-                            network.identity,
-                            network.mode.try_into()?,
-                        )?,
-                        network.hw,
-                        true,
-                    )
-                    .await?;
-                if let Some(connection) = connection {
-                    match connection.state {
-                        ConnectionState::Activated => Ok(Some(StateMachine::Connected(config, nm))),
-                        _ => Ok(Some(StateMachine::ActivatePortal(config, nm))),
+                let credentials = network.credentials(config.credential_store().as_deref())?;
+                let ssid = network.ssid_bytes()?;
+                // With a second wifi device, the hotspot and the portal services stay up during the attempt.
+                // The hotspot is stopped by the Connected state, ie only on success.
+                let station = match config.keep_hotspot_during_connect {
+                    true => nm.concurrent_station().await?,
+                    false => None,
+                };
+                let station_nm = station.as_ref().unwrap_or(&nm);
+                let (hw, lock_bssid) = preferred_access_point(&config, station_nm, &network).await;
+                let activation_timeout = activation_timeout(&config, &credentials);
+                let cancelled = config.connect_cancel.start();
+                config.connect_progress.reset();
+                // The portal is down. Keep the progress and cancelling of the attempt reachable.
+                let (listener, listener_exit) = connect_listener(&nm, &config);
+                let listener = tokio::spawn(listener);
+                let servers = match station {
+                    Some(_) => Some(ServerTasks::for_portal(&config, None)?),
+                    None => None,
+                };
+                // Subscribe before connecting to not miss the first steps
+                let mut steps = station_nm.connect_progress_stream().await?;
+                let connection = {
+                    let options = ConnectOptions {
+                        hw,
+                        overwrite_same_ssid_connection: true,
+                        lock_bssid,
+                        persist_connection: config.persist_connection,
+                        activation_timeout,
+                    };
+                    let connect = station_nm.connect_to(ssid, credentials, options, cancelled);
+                    pin_mut!(connect);
+                    loop {
+                        match select(&mut connect, steps.next()).await {
+                            Either::Left((connection, _)) => break connection?,
+                            Either::Right((Some(step), _)) => config.connect_progress.publish(step),
+                            Either::Right((None, _)) => break connect.await?,
+                        }
                     }
-                } else {
-                    Ok(Some(StateMachine::ActivatePortal(config, nm)))
+                };
+                config.connect_cancel.finish();
+                let connected = connection.map_or(false, |c| c.state == ConnectionState::Activated);
+                publish_outcome(&config.connect_progress, connected);
+                let _ = listener_exit.send(());
+                let _ = listener.await;
+                if let Some(servers) = servers {
+                    servers.shutdown().await;
+                }
+                match connected {
+                    true => Ok(Some(StateMachine::Connected(config, nm))),
+                    false => Ok(Some(StateMachine::ActivatePortal(config, nm))),
                 }
             }
-            StateMachine::Exit(nm) => {
+            StateMachine::Exit(config, nm) => {
                 info!("Exiting");
+                restore_networking(&config, &nm).await;
+                // Closes the dbus connection. Must be last.
                 nm.quit();
                 Ok(None)
             }
@@ -234,6 +386,246 @@ impl StateMachine {
     }
 }
 
+/// Publishes the outcome of a connection attempt. A failure is only published if the attempt
+/// did not report a more specific reason already, eg by a device state change.
+fn publish_outcome(progress: &ConnectProgressChannel, connected: bool) {
+    match progress.last() {
+        _ if connected => progress.publish(ConnectProgress::Connected),
+        Some(ConnectProgress::Failed(_)) => {},
+        _ => progress.publish(ConnectProgress::Failed(
+            "Connection could not be established".to_owned(),
+        )),
+    }
+}
+
+/// The budget for listing access points, see [`Config::scan_budget`]
+fn scan_budget(config: &Config) -> ScanBudget {
+    match config.scan_budget {
+        0 => ScanBudget::default(),
+        secs => ScanBudget::with_max_time(Duration::from_secs(secs)),
+    }
+}
+
+/// Deactivates the hotspots and waits for the configured settle delay, if a hotspot was running.
+/// Some radios need a moment to switch from access point to station mode. A scan right after
+/// the switch returns no access points on those.
+async fn deactivate_hotspots(config: &Config, nm: &NetworkBackend) -> Result<(), CaptivePortalError> {
+    if nm.deactivate_hotspots().await? && config.hotspot_settle_delay > 0 {
+        info!(
+            "Waiting {} ms for the wifi device to settle after stopping the hotspot",
+            config.hotspot_settle_delay
+        );
+        tokio::time::delay_for(Duration::from_millis(config.hotspot_settle_delay)).await;
+    }
+    Ok(())
+}
+
+/// Starts the hotspot, with one retry if configured. Returns the active connection path of the hotspot
+/// or None if it could not be started.
+async fn start_hotspot(
+    config: &Config,
+    nm: &NetworkBackend,
+) -> Result<Option<dbus::Path<'static>>, CaptivePortalError> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let r = timeout(
+            Duration::from_secs(config.hotspot_timeout),
+            nm.hotspot_start(
+                config.ssid.clone(),
+                config.passphrase.clone(),
+                Some(config.gateway),
+                config.subnet_prefix,
+                config.hotspot_band,
+            ),
+        )
+        .await;
+
+        let error = match r {
+            Ok(Ok(r)) => return Ok(Some(r.active_connection_path)),
+            Err(_) => "Timeout".to_owned(),
+            Ok(Err(e)) => e.to_string(),
+        };
+        if retry_hotspot(attempt, config.hotspot_retry) {
+            warn!("Failed to create hotspot: {}. Retrying once.", error);
+            // Remove a half-created hotspot before the next attempt
+            deactivate_hotspots(config, nm).await?;
+            continue;
+        }
+        warn!(
+            "Failed to create hotspot: {}. Trying to establish a connection instead.",
+            error
+        );
+        return Ok(None);
+    }
+}
+
+/// Restores networking to a clean state for whatever runs next. Each step can be disabled in the [`Config`].
+/// Failed steps are logged only, the program exits regardless.
+async fn restore_networking(config: &Config, nm: &NetworkBackend) {
+    if config.exit_stop_hotspot {
+        if let Err(e) = nm.deactivate_hotspots().await {
+            warn!("Failed to stop the hotspot on exit: {}", e);
+        }
+    }
+    if config.exit_enable_wifi {
+        if let Err(e) = nm.enable_networking_and_wifi(config.manage_device).await {
+            warn!("Failed to enable wifi on exit: {}", e);
+        }
+    }
+    if config.exit_enable_autoconnect {
+        nm.enable_auto_connect().await;
+    }
+    if config.connectivity_check_uri.is_some() {
+        if let Err(e) = nm.set_connectivity_check_uri(None).await {
+            warn!("Failed to reset the connectivity check uri on exit: {}", e);
+        }
+    }
+}
+
+/// Returns the access point to connect to and whether the connection should be pinned to it.
+/// With the 5 GHz preference, the strongest 5 GHz access point of the network is pinned, if there is one.
+async fn preferred_access_point(
+    config: &Config,
+    nm: &NetworkBackend,
+    network: &WifiConnectionRequest,
+) -> (Option<String>, bool) {
+    if !config.prefer_5ghz || network.lock_bssid {
+        return (network.hw.clone(), network.lock_bssid);
+    }
+    // The access points of the last scan are sufficient
+    let access_points = match nm.list_access_points(Duration::from_secs(0), scan_budget(config)).await {
+        Ok(access_points) => access_points,
+        Err(e) => {
+            warn!("Failed to list access points: {}. No band preference applied.", e);
+            return (network.hw.clone(), network.lock_bssid);
+        },
+    };
+    match strongest_access_point_on_band(&network.ssid, &access_points, WifiBand::A) {
+        Some(hw) => {
+            info!("Preferring 5 GHz access point {} of {}", hw, network.ssid);
+            (Some(hw.to_owned()), true)
+        },
+        None => (network.hw.clone(), network.lock_bssid),
+    }
+}
+
+/// The time to wait for a connection with the given credentials to be established.
+/// Enterprise authentication takes longer. The security of a saved connection is not known, so the longer
+/// of both timeouts applies.
+fn activation_timeout(config: &Config, credentials: &AccessPointCredentials) -> Duration {
+    let secs = match credentials {
+        AccessPointCredentials::Enterprise { .. } => config.enterprise_connect_timeout,
+        AccessPointCredentials::Saved => config.enterprise_connect_timeout.max(config.connect_timeout),
+        _ => config.connect_timeout,
+    };
+    Duration::from_secs(secs)
+}
+
+/// Decides what to do after the given (1-based) hotspot creation attempt failed.
+/// Returns true if the hotspot should be created once more instead of falling back to reconnecting.
+fn retry_hotspot(attempt: u32, retry: bool) -> bool {
+    retry && attempt < 2
+}
+
+/// Decides what to do after the given (1-based) reconnect attempt failed.
+/// Returns the delay until the next attempt if the portal must never open, or None to activate the portal.
+fn reconnect_backoff(attempt: u32, never_open_portal: bool) -> Option<Duration> {
+    if !never_open_portal {
+        return None;
+    }
+    let factor = 1u32 << attempt.saturating_sub(1).min(16);
+    Some((RECONNECT_BACKOFF_MIN * factor).min(RECONNECT_BACKOFF_MAX))
+}
+
+/// Tests if the DNS, DHCP and HTTP ports can be bound.
+async fn test_server_ports(config: &Config) -> Result<(), BindError> {
+    test_udp(SocketAddrV4::new(config.gateway, config.dns_port), "DNS Server").await?;
+    test_udp(SocketAddrV4::new(config.gateway, config.dhcp_port), "DHCP Server").await?;
+    test_tcp(SocketAddrV4::new(config.gateway, config.listening_port)).await
+}
+
+/// Returns the delay before the next attempt to bind the server ports, after the given number of failed attempts.
+/// The delay is doubled for each attempt up to a maximum. None is returned if the maximum number of attempts
+/// is reached. An unlimited number of attempts is made if the maximum is 0.
+fn bind_retry_delay(failures: u32, max_attempts: u32) -> Option<Duration> {
+    if max_attempts > 0 && failures >= max_attempts {
+        return None;
+    }
+    let factor = 1u32 << failures.saturating_sub(1).min(16);
+    Some((BIND_RETRY_DELAY_MIN * factor).min(BIND_RETRY_DELAY_MAX))
+}
+
+/// Counts consecutive failed connectivity checks.
+/// Connectivity is deemed lost if the threshold of failed checks in a row is reached.
+struct ConnectivityFailures {
+    threshold: u32,
+    count: u32,
+}
+
+impl ConnectivityFailures {
+    fn new(threshold: u32) -> Self {
+        ConnectivityFailures {
+            threshold: threshold.max(1),
+            count: 0,
+        }
+    }
+
+    /// Records the result of a connectivity check. Returns true if connectivity is deemed lost.
+    fn record(&mut self, connected: bool) -> bool {
+        if connected {
+            self.count = 0;
+            return false;
+        }
+        self.count += 1;
+        self.count >= self.threshold
+    }
+}
+
+/// Checks connectivity until it is confirmed or the configured number of checks failed in a row.
+/// `failures` may already contain failed checks, eg a reported connectivity loss.
+async fn connectivity_lost(
+    config: &Config,
+    nm: &NetworkBackend,
+    mut failures: ConnectivityFailures,
+) -> Result<bool, CaptivePortalError> {
+    loop {
+        let c_state = nm
+            .wait_for_connectivity(config.internet_connectivity, Duration::from_secs(5))
+            .await;
+        info!("Current connectivity: {:?}", c_state);
+
+        match c_state {
+            Ok(_) => return Ok(failures.record(true)),
+            Err(CaptivePortalError::NotRequiredConnectivity(_)) => {
+                if failures.record(false) {
+                    return Ok(true);
+                }
+            },
+            Err(e) => return Err(e),
+        }
+        tokio::time::delay_for(Duration::from_secs(config.connectivity_check_delay)).await;
+    }
+}
+
+/// Checks the gateway subnet against the addresses of other active connections.
+/// Overlapping subnets are logged, or are an error in strict mode.
+async fn check_address_conflicts(config: &Config, nm: &NetworkBackend) -> Result<(), CaptivePortalError> {
+    for (id, address, prefix) in nm.active_ipv4_addresses().await? {
+        if subnets_overlap(config.gateway, config.subnet_prefix, address, prefix) {
+            let message = format!(
+                "Hotspot subnet {}/{} overlaps with {}/{} of connection {}",
+                config.gateway, config.subnet_prefix, address, prefix, id
+            );
+            if config.strict_address_check {
+                return Err(CaptivePortalError::AddressConflict(message));
+            }
+            warn!("{}", message);
+        }
+    }
+    Ok(())
+}
+
 /// Updates SSID/Password via passphrase file, if option is set in config.
 ///
 /// This is not async, because current async file io implementations use the sync API with a background thread.
@@ -272,8 +664,138 @@ fn update_portal_info_via_file(config: &mut Config) {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use super::{
+        activation_timeout, bind_retry_delay, publish_outcome, reconnect_backoff, retry_hotspot, scan_budget,
+        ConnectivityFailures,
+    };
+    #[cfg(feature = "networkmanager")]
+    use super::{Config, StateMachine};
+    #[cfg(feature = "networkmanager")]
+    use crate::network_backend::test_bus::{empty_dict, paths, TestBus, ETHERNET_DEVICE, WIFI_DEVICE};
+    #[cfg(feature = "networkmanager")]
+    use crate::CaptivePortalError;
+    use crate::{AccessPointCredentials, ConnectProgress, ConnectProgressChannel};
+    #[cfg(feature = "networkmanager")]
+    use dbus::arg::messageitem::MessageItem;
     use std::io::BufWriter;
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn failure_of_next_attempt() {
+        let progress = ConnectProgressChannel::default();
+        progress.publish(ConnectProgress::Failed("Wrong passphrase".to_owned()));
+        publish_outcome(&progress, false);
+        assert_eq!(
+            progress.last(),
+            Some(ConnectProgress::Failed("Wrong passphrase".to_owned()))
+        );
+
+        // The reason of the previous attempt must not be reported for the next one
+        progress.reset();
+        publish_outcome(&progress, false);
+        assert_eq!(
+            progress.last(),
+            Some(ConnectProgress::Failed(
+                "Connection could not be established".to_owned()
+            ))
+        );
+
+        progress.reset();
+        publish_outcome(&progress, true);
+        assert_eq!(progress.last(), Some(ConnectProgress::Connected));
+    }
+
+    #[test]
+    fn activation_timeout_by_security() {
+        let mut config = super::Config::new();
+        config.connect_timeout = 30;
+        config.enterprise_connect_timeout = 90;
+        let enterprise = AccessPointCredentials::Enterprise {
+            identity: "user".to_owned(),
+            passphrase: "a_password".to_owned(),
+            eap: None,
+            phase2: None,
+            domain_suffix_match: None,
+            ca_cert: None,
+        };
+        assert_eq!(activation_timeout(&config, &enterprise), Duration::from_secs(90));
+        let wpa = AccessPointCredentials::Wpa {
+            passphrase: "a_password".to_owned(),
+        };
+        assert_eq!(activation_timeout(&config, &wpa), Duration::from_secs(30));
+        assert_eq!(
+            activation_timeout(&config, &AccessPointCredentials::None),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            activation_timeout(&config, &AccessPointCredentials::Saved),
+            Duration::from_secs(90)
+        );
+    }
+
+    #[test]
+    fn hotspot_retry_decision() {
+        // Without the retry option, the first failure falls back to reconnecting
+        assert!(!retry_hotspot(1, false));
+        // With the retry option, exactly one more attempt is made
+        assert!(retry_hotspot(1, true));
+        assert!(!retry_hotspot(2, true));
+    }
+
+    #[test]
+    fn reconnect_decision() {
+        // The portal is activated after a failed reconnect attempt by default
+        assert_eq!(reconnect_backoff(1, false), None);
+        assert_eq!(reconnect_backoff(10, false), None);
+        // Otherwise reconnecting is retried endlessly, with a doubled delay up to the maximum
+        assert_eq!(reconnect_backoff(1, true), Some(Duration::from_secs(5)));
+        assert_eq!(reconnect_backoff(2, true), Some(Duration::from_secs(10)));
+        assert_eq!(reconnect_backoff(3, true), Some(Duration::from_secs(20)));
+        assert_eq!(reconnect_backoff(7, true), Some(Duration::from_secs(300)));
+        assert_eq!(reconnect_backoff(std::u32::MAX, true), Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn configured_scan_budget() {
+        let mut config = super::Config::new();
+        config.scan_budget = 15;
+        assert_eq!(scan_budget(&config).max_time, Some(Duration::from_secs(15)));
+        assert_eq!(scan_budget(&config).max_queries, None);
+        config.scan_budget = 0;
+        assert_eq!(scan_budget(&config).max_time, None);
+    }
+
+    #[test]
+    fn bind_retry_decision() {
+        assert_eq!(bind_retry_delay(1, 3), Some(Duration::from_secs(1)));
+        assert_eq!(bind_retry_delay(2, 3), Some(Duration::from_secs(2)));
+        // The last failed attempt is returned as error
+        assert_eq!(bind_retry_delay(3, 3), None);
+        // Unlimited attempts, with a doubled delay up to the maximum
+        assert_eq!(bind_retry_delay(5, 0), Some(Duration::from_secs(16)));
+        assert_eq!(bind_retry_delay(6, 0), Some(Duration::from_secs(30)));
+        assert_eq!(bind_retry_delay(std::u32::MAX, 0), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn connectivity_failures() {
+        // A single failed check is enough by default
+        let mut failures = ConnectivityFailures::new(1);
+        assert!(failures.record(false));
+
+        // Short dips are suppressed
+        let mut failures = ConnectivityFailures::new(3);
+        assert!(!failures.record(false));
+        assert!(!failures.record(false));
+        assert!(!failures.record(true));
+        assert!(!failures.record(false));
+        assert!(!failures.record(false));
+        assert!(failures.record(false));
+
+        // A threshold of 0 behaves like 1
+        assert!(ConnectivityFailures::new(0).record(false));
+    }
 
     #[test]
     fn update_portal_info_via_file() {
@@ -300,4 +822,131 @@ mod tests {
         assert_eq!(&config.passphrase, "a_password");
         assert_eq!(&config.ssid, "a_ssid");
     }
-}
\ No newline at end of file
+
+    /// Station only: WEP, TKIP, CCMP, WPA and RSN, but not access point mode
+    #[cfg(feature = "networkmanager")]
+    const STATION_ONLY: u32 = 0x3f;
+
+    #[cfg(feature = "networkmanager")]
+    #[tokio::test]
+    async fn portal_without_ap_mode() {
+        let bus = TestBus::start();
+        bus.set_property(WIFI_DEVICE, "WirelessCapabilities", STATION_ONLY);
+        let state = StateMachine::ActivatePortal(Box::new(Config::new()), bus.backend().await);
+        match state.progress().await {
+            Ok(Some(StateMachine::TryReconnect(..))) => {}
+            Ok(_) => panic!("Expected TryReconnect"),
+            Err(e) => panic!("Expected TryReconnect. Got {:?}", e),
+        }
+        assert!(bus.calls("ActivateConnection").is_empty());
+        assert!(bus.calls("AddAndActivateConnection").is_empty());
+    }
+
+    #[cfg(feature = "networkmanager")]
+    #[tokio::test]
+    async fn portal_on_unmanaged_device() {
+        let bus = TestBus::start();
+        bus.set_property(WIFI_DEVICE, "Managed", false);
+        bus.set_property(WIFI_DEVICE, "WirelessCapabilities", STATION_ONLY);
+        let mut config = Config::new();
+        let state = StateMachine::ActivatePortal(Box::new(config.clone()), bus.backend().await);
+        match state.progress().await {
+            Err(e @ CaptivePortalError::DeviceUnmanaged { .. }) => {
+                assert!(e.to_string().contains("nmcli device set wlan0 managed yes"))
+            },
+            Ok(_) => panic!("Expected DeviceUnmanaged"),
+            Err(e) => panic!("Expected DeviceUnmanaged. Got {:?}", e),
+        }
+        assert_eq!(bus.property(WIFI_DEVICE, "Managed"), Some(MessageItem::Bool(false)));
+
+        config.manage_device = true;
+        let state = StateMachine::ActivatePortal(Box::new(config), bus.backend().await);
+        match state.progress().await {
+            Ok(Some(StateMachine::TryReconnect(..))) => {}
+            Ok(_) => panic!("Expected TryReconnect"),
+            Err(e) => panic!("Expected TryReconnect. Got {:?}", e),
+        }
+        assert_eq!(bus.property(WIFI_DEVICE, "Managed"), Some(MessageItem::Bool(true)));
+    }
+
+    /// Connects to "home" on the test bus. The attempt succeeds.
+    #[cfg(feature = "networkmanager")]
+    async fn connect(bus: &TestBus, mut config: Config) -> Result<Option<StateMachine>, CaptivePortalError> {
+        let active_connection = "/org/freedesktop/NetworkManager/ActiveConnection/1";
+        bus.set_reply(
+            "AddAndActivateConnection2",
+            vec![
+                MessageItem::ObjectPath("/org/freedesktop/NetworkManager/Settings/1".into()),
+                MessageItem::ObjectPath(active_connection.into()),
+                empty_dict(),
+            ],
+        );
+        // Activated
+        bus.set_property(active_connection, "State", 2u32);
+        bus.set_reply("Update2", vec![empty_dict()]);
+
+        config.gateway = std::net::Ipv4Addr::LOCALHOST;
+        config.listening_port = 0;
+        let request = serde_json::from_str(r#"{"mode":"wpa","ssid":"home","passphrase":"a_password"}"#).unwrap();
+        StateMachine::Connect(Box::new(config), bus.backend().await, Box::new(request))
+            .progress()
+            .await
+    }
+
+    #[cfg(feature = "networkmanager")]
+    #[tokio::test]
+    async fn connect_and_store() {
+        use crate::network_backend::{IN_MEMORY_ONLY, SAVE_TO_DISK_FLAG};
+        for (persist_connection, flags) in [(true, SAVE_TO_DISK_FLAG), (false, IN_MEMORY_ONLY)].iter() {
+            let bus = TestBus::start();
+            let mut config = Config::new();
+            config.persist_connection = *persist_connection;
+            match connect(&bus, config).await {
+                Ok(Some(StateMachine::Connected(config, _))) => {
+                    assert_eq!(config.connect_progress.last(), Some(ConnectProgress::Connected))
+                },
+                Ok(_) => panic!("Expected Connected"),
+                Err(e) => panic!("Expected Connected. Got {:?}", e),
+            }
+            let updates = bus.calls("Update2");
+            assert_eq!(updates.len(), 1);
+            assert_eq!(updates[0][1], MessageItem::UInt32(*flags));
+        }
+    }
+
+    #[cfg(feature = "networkmanager")]
+    #[tokio::test]
+    async fn keep_hotspot_during_connect() {
+        let second_device = "/org/freedesktop/NetworkManager/Devices/3";
+        // The option, the wireless capabilities of the hotspot device and the device of the connection attempt
+        let attempts = [
+            (false, 0x7f, WIFI_DEVICE),
+            (true, 0x7f, second_device),
+            (true, STATION_ONLY, WIFI_DEVICE),
+        ];
+        for (keep_hotspot, capabilities, device) in attempts.iter() {
+            let bus = TestBus::start();
+            bus.set_reply(
+                "GetAllDevices",
+                vec![paths(&[ETHERNET_DEVICE, WIFI_DEVICE, second_device])],
+            );
+            bus.set_property(second_device, "DeviceType", 2u32);
+            bus.set_property(second_device, "Interface", "wlan1");
+            bus.set_property(second_device, "HwAddress", "00:11:22:33:44:66");
+            bus.set_property(second_device, "Managed", true);
+            bus.set_property(WIFI_DEVICE, "WirelessCapabilities", *capabilities);
+            let mut config = Config::new();
+            config.keep_hotspot_during_connect = *keep_hotspot;
+            match connect(&bus, config).await {
+                Ok(Some(StateMachine::Connected(..))) => {}
+                Ok(_) => panic!("Expected Connected"),
+                Err(e) => panic!("Expected Connected. Got {:?}", e),
+            }
+            let attempts = bus.calls("AddAndActivateConnection2");
+            assert_eq!(attempts.len(), 1);
+            assert_eq!(attempts[0][1], MessageItem::ObjectPath((*device).into()));
+            // The hotspot is stopped by the Connected state
+            assert!(bus.calls("DeactivateConnection").is_empty());
+        }
+    }
+}
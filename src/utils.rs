@@ -1,11 +1,12 @@
 //! # Utility methods and types
 use super::CaptivePortalError;
+use crate::network_interface::AccessPointCredentials;
 
 use futures_util::future::Either;
 use futures_util::future::try_select;
 use pin_utils::pin_mut;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::pin::Pin;
 use std::task::{self, Poll};
 use std::time::Duration;
@@ -30,6 +31,234 @@ pub fn verify_password(password: &str) -> Result<(), CaptivePortalError> {
     }
 }
 
+/// A raw WPA pre-shared key consists of exactly 64 hex digits
+pub fn is_hex_psk(password: &str) -> bool {
+    password.len() == 64 && password.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Decodes pairs of hex digits, eg "0a0b". None if the string is not hex encoded.
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// A WPA key is either a raw pre-shared key of 64 hex digits or a passphrase (see [`verify_password`]).
+/// A raw key is used as is and not hashed again.
+pub fn verify_wpa_key(password: &str) -> Result<(), CaptivePortalError> {
+    if is_hex_psk(password) {
+        Ok(())
+    } else {
+        verify_password(password)
+    }
+}
+
+/// A subnet prefix must leave room for the gateway and at least one client (/30),
+/// but should not exceed a class A network (/8).
+pub fn verify_subnet_prefix(prefix: u8) -> Result<(), CaptivePortalError> {
+    if !(8..=30).contains(&prefix) {
+        Err(CaptivePortalError::InvalidSubnetPrefix(prefix))
+    } else {
+        Ok(())
+    }
+}
+
+/// The default eap method for enterprise networks
+pub const DEFAULT_EAP_METHOD: &str = "peap";
+/// The default phase2 authentication for enterprise networks
+pub const DEFAULT_PHASE2_AUTH: &str = "mschapv2";
+
+/// The eap method must be "peap" or "ttls". PEAP only supports the phase2 authentications
+/// "mschapv2", "gtc" and "md5". TTLS additionally supports "pap", "chap" and "mschap".
+pub fn verify_eap_settings(eap: &str, phase2: &str) -> Result<(), CaptivePortalError> {
+    let valid = match eap {
+        "peap" => ["mschapv2", "gtc", "md5"].contains(&phase2),
+        "ttls" => ["mschapv2", "mschap", "pap", "chap", "gtc", "md5"].contains(&phase2),
+        _ => {
+            return Err(CaptivePortalError::InvalidEapSettings(format!(
+                "Expected an eap method (peap, ttls). Got: {}",
+                eap
+            )))
+        },
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(CaptivePortalError::InvalidEapSettings(format!(
+            "Phase2 authentication {} is not supported with {}",
+            phase2, eap
+        )))
+    }
+}
+
+/// How to handle a failure to bind a server socket
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BindErrorClass {
+    /// The gateway address is not yet assigned or the port is temporarily held by another service
+    Retryable,
+    /// Missing permissions and everything else
+    Fatal,
+}
+
+/// Classifies the io error kind of a failed bind.
+pub fn classify_bind_error(kind: std::io::ErrorKind) -> BindErrorClass {
+    use std::io::ErrorKind;
+    match kind {
+        ErrorKind::AddrNotAvailable | ErrorKind::AddrInUse => BindErrorClass::Retryable,
+        _ => BindErrorClass::Fatal,
+    }
+}
+
+fn map_to_err(
+    err_kind: std::io::ErrorKind,
+    server_addr: SocketAddrV4,
+    service_name: &'static str,
+) -> CaptivePortalError {
+    use std::io::ErrorKind;
+    match err_kind {
+        ErrorKind::AddrNotAvailable => CaptivePortalError::Generic(format!(
+            "Could not bind to {:?} for {}\nThe gateway address is not assigned to any interface!",
+            server_addr, service_name,
+        )),
+        ErrorKind::PermissionDenied => CaptivePortalError::Generic(format!(
+            "You require elevated permissions to bind to port {} for {}.\n\
+             You may use `sudo setcap CAP_NET_BIND_SERVICE=+eip {}`",
+            server_addr.port(),
+            service_name,
+            std::env::args().next().unwrap_or_default()
+        )),
+        ErrorKind::AddrInUse => CaptivePortalError::Generic(format!(
+            "Could not bind to port {} for {}\nThe port is in use.",
+            server_addr.port(),
+            service_name,
+        )),
+        _ => CaptivePortalError::Generic(format!(
+            "Could not bind to {:?} for {}\nThis error happened: {:?}",
+            server_addr, service_name, err_kind
+        )),
+    }
+}
+
+/// A failed bind attempt: The io error kind and the user facing error
+pub type BindError = (std::io::ErrorKind, CaptivePortalError);
+
+// Test if binding to the given address and port works
+pub async fn test_udp(server_addr: SocketAddrV4, service_name: &'static str) -> Result<(), BindError> {
+    let socket = UdpSocket::bind(SocketAddr::V4(server_addr))
+        .await
+        .map_err(|e| (e.kind(), map_to_err(e.kind(), server_addr, service_name)))?;
+    socket.set_broadcast(true).map_err(|e| (e.kind(), e.into()))?;
+    Ok(())
+}
+
+pub async fn test_tcp(server_addr: SocketAddrV4) -> Result<(), BindError> {
+    let socket = tokio::net::TcpListener::bind(SocketAddr::V4(server_addr))
+        .await
+        .map_err(|e| (e.kind(), map_to_err(e.kind(), server_addr, "HTTP Web Interface")))?;
+    drop(socket);
+    Ok(())
+}
+
+/// Returns true if the two given subnets overlap, eg 192.168.1.1/24 and 192.168.0.0/16.
+pub fn subnets_overlap(a: Ipv4Addr, a_prefix: u8, b: Ipv4Addr, b_prefix: u8) -> bool {
+    // Both subnets overlap if they are equal for the shorter prefix
+    let prefix = a_prefix.min(b_prefix).min(32) as u32;
+    if prefix == 0 {
+        return true;
+    }
+    let mask = !0u32 << (32 - prefix);
+    u32::from(a) & mask == u32::from(b) & mask
+}
+
+/// A connectivity check uri must be an absolute http or https uri, eg "http://example.com/check".
+pub fn verify_connectivity_check_uri(uri: &str) -> Result<hyper::Uri, CaptivePortalError> {
+    let parsed: hyper::Uri = uri
+        .parse()
+        .map_err(|_| CaptivePortalError::InvalidConnectivityCheckUri(uri.to_owned()))?;
+    match (parsed.scheme_str(), parsed.host()) {
+        (Some("http"), Some(_)) | (Some("https"), Some(_)) => Ok(parsed),
+        _ => Err(CaptivePortalError::InvalidConnectivityCheckUri(uri.to_owned())),
+    }
+}
+
+/// A captive portal uri must be an absolute http or https uri that fits into a dhcp option (255 bytes).
+pub fn verify_captive_portal_uri(uri: &str) -> Result<hyper::Uri, CaptivePortalError> {
+    let parsed: hyper::Uri = uri
+        .parse()
+        .map_err(|_| CaptivePortalError::InvalidCaptivePortalUri(uri.to_owned()))?;
+    match (parsed.scheme_str(), parsed.host()) {
+        (Some("http"), Some(_)) | (Some("https"), Some(_)) if uri.len() <= 255 => Ok(parsed),
+        _ => Err(CaptivePortalError::InvalidCaptivePortalUri(uri.to_owned())),
+    }
+}
+
+/// Returns the absolute url of the given portal path, eg "http://192.168.42.1/portal/index.html".
+/// The host name is used instead of the server address if given. The port is omitted if it is the default
+/// port of the scheme. All urls that point to the portal, like redirects, dhcp option 114 and the portal info,
+/// must be created with this method.
+///
+/// ## Arguments
+/// * https: Use the https instead of the http scheme
+/// * host: A host name that resolves to the portal, eg "portal.local"
+/// * server_addr: The listening address of the portal web server
+/// * path: The absolute path including the base path, eg "/portal/index.html"
+pub fn portal_url(https: bool, host: Option<&str>, server_addr: &SocketAddrV4, path: &str) -> String {
+    let (scheme, default_port) = match https {
+        true => ("https", 443),
+        false => ("http", 80),
+    };
+    let host = match host {
+        Some(host) => host.to_owned(),
+        None => server_addr.ip().to_string(),
+    };
+    match server_addr.port() {
+        port if port == default_port => format!("{}://{}{}", scheme, host, path),
+        port => format!("{}://{}:{}{}", scheme, host, port, path),
+    }
+}
+
+/// Escapes the special characters of a wifi qr code payload field with a backslash.
+fn escape_qr_field(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' | ';' | ',' | '"' => escaped.push('\\'),
+            _ => {},
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns the wifi qr code payload, eg "WIFI:S:My Wifi;T:WPA;P:password;;", for the given connection.
+/// Other devices can join the network by scanning a qr code of this payload.
+pub fn wifi_qr_payload(ssid: &str, credentials: &AccessPointCredentials) -> String {
+    let ssid = escape_qr_field(ssid);
+    match credentials {
+        AccessPointCredentials::None => format!("WIFI:S:{};T:nopass;;", ssid),
+        AccessPointCredentials::Wep { passphrase } => {
+            format!("WIFI:S:{};T:WEP;P:{};;", ssid, escape_qr_field(passphrase))
+        },
+        AccessPointCredentials::Wpa { passphrase } => {
+            format!("WIFI:S:{};T:WPA;P:{};;", ssid, escape_qr_field(passphrase))
+        },
+        AccessPointCredentials::Enterprise {
+            identity, passphrase, ..
+        } => format!(
+            "WIFI:S:{};T:WPA2-EAP;I:{};P:{};;",
+            ssid,
+            escape_qr_field(identity),
+            escape_qr_field(passphrase)
+        ),
+        // The credentials are not known. Scanning devices ask for them.
+        AccessPointCredentials::Saved => format!("WIFI:S:{};;", ssid),
+    }
+}
+
 /// Takes an optional field member of the portal and sets the optional to None.
 ///
 /// Safety: Because the optional fields are never moved, this is considered safe, albeit the pinning.
@@ -208,3 +437,189 @@ pub trait FutureWithTimeout: Future {
 }
 
 impl<T: ?Sized> FutureWithTimeout for T where T: Future {}
+
+/// Calls the given fallible async function until it succeeds or the timeout elapsed.
+/// The function is called at least once and then every `interval`. The last error is returned on timeout.
+pub(crate) async fn poll_until_ok<F, Fut, R>(
+    timeout: Duration,
+    interval: Duration,
+    mut fun: F,
+) -> Result<R, CaptivePortalError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, CaptivePortalError>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        match fun().await {
+            Ok(r) => return Ok(r),
+            Err(e) => {
+                if tokio::time::Instant::now() + interval > deadline {
+                    return Err(e);
+                }
+                info!("{}. Retrying in {} ms", e, interval.as_millis());
+                tokio::time::delay_for(interval).await;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        classify_bind_error, poll_until_ok, portal_url, subnets_overlap, verify_connectivity_check_uri,
+        wifi_qr_payload, BindErrorClass,
+    };
+    use crate::network_interface::AccessPointCredentials;
+    use crate::CaptivePortalError;
+    use std::cell::Cell;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    #[test]
+    fn connectivity_check_uri() {
+        let uri = verify_connectivity_check_uri("http://example.com/check").unwrap();
+        assert_eq!(uri.host(), Some("example.com"));
+        assert!(verify_connectivity_check_uri("https://example.com:8443/check").is_ok());
+
+        for invalid in &["example.com/check", "/check", "ftp://example.com/check", "http://", ""] {
+            match verify_connectivity_check_uri(invalid) {
+                Err(CaptivePortalError::InvalidConnectivityCheckUri(uri)) => assert_eq!(&uri, invalid),
+                r => panic!("Expected {} to be rejected. Got {:?}", invalid, r),
+            }
+        }
+    }
+
+    #[test]
+    fn portal_urls() {
+        let gateway = Ipv4Addr::new(192, 168, 42, 1);
+        let http = SocketAddrV4::new(gateway, 80);
+        assert_eq!(
+            portal_url(false, None, &http, "/index.html"),
+            "http://192.168.42.1/index.html"
+        );
+        assert_eq!(
+            portal_url(false, Some("portal.local"), &http, "/portal/index.html"),
+            "http://portal.local/portal/index.html"
+        );
+
+        let https = SocketAddrV4::new(gateway, 443);
+        assert_eq!(
+            portal_url(true, None, &https, "/index.html"),
+            "https://192.168.42.1/index.html"
+        );
+        assert_eq!(
+            portal_url(true, Some("portal.local"), &https, "/index.html"),
+            "https://portal.local/index.html"
+        );
+
+        // Non default ports
+        assert_eq!(
+            portal_url(false, None, &https, "/index.html"),
+            "http://192.168.42.1:443/index.html"
+        );
+        assert_eq!(
+            portal_url(true, None, &http, "/index.html"),
+            "https://192.168.42.1:80/index.html"
+        );
+        let custom = SocketAddrV4::new(gateway, 8080);
+        assert_eq!(
+            portal_url(false, Some("portal.local"), &custom, "/captive-portal-api"),
+            "http://portal.local:8080/captive-portal-api"
+        );
+    }
+
+    #[test]
+    fn bind_error_class() {
+        use std::io::ErrorKind;
+        assert_eq!(
+            classify_bind_error(ErrorKind::AddrNotAvailable),
+            BindErrorClass::Retryable
+        );
+        assert_eq!(classify_bind_error(ErrorKind::AddrInUse), BindErrorClass::Retryable);
+        assert_eq!(classify_bind_error(ErrorKind::PermissionDenied), BindErrorClass::Fatal);
+        assert_eq!(classify_bind_error(ErrorKind::InvalidInput), BindErrorClass::Fatal);
+    }
+
+    #[test]
+    fn subnet_overlap() {
+        let gateway = Ipv4Addr::new(192, 168, 42, 1);
+        assert!(subnets_overlap(gateway, 24, Ipv4Addr::new(192, 168, 42, 17), 24));
+        assert!(subnets_overlap(gateway, 24, Ipv4Addr::new(192, 168, 0, 1), 16));
+        assert!(subnets_overlap(
+            Ipv4Addr::new(10, 0, 0, 1),
+            8,
+            Ipv4Addr::new(10, 1, 2, 3),
+            24
+        ));
+        assert!(!subnets_overlap(gateway, 24, Ipv4Addr::new(192, 168, 43, 1), 24));
+        assert!(!subnets_overlap(gateway, 24, Ipv4Addr::new(10, 0, 0, 1), 8));
+        assert!(subnets_overlap(gateway, 32, gateway, 32));
+    }
+
+    #[test]
+    fn qr_payload() {
+        let credentials = AccessPointCredentials::Wpa {
+            passphrase: "pass;word".to_owned(),
+        };
+        assert_eq!(
+            wifi_qr_payload("My Wifi", &credentials),
+            "WIFI:S:My Wifi;T:WPA;P:pass\\;word;;"
+        );
+
+        let credentials = AccessPointCredentials::Wpa {
+            passphrase: r#"a,b"c\d"#.to_owned(),
+        };
+        assert_eq!(
+            wifi_qr_payload(r#"Caf\é;"#, &credentials),
+            r#"WIFI:S:Caf\\é\;;T:WPA;P:a\,b\"c\\d;;"#
+        );
+    }
+
+    #[test]
+    fn qr_payload_open_network() {
+        assert_eq!(
+            wifi_qr_payload("Guest", &AccessPointCredentials::None),
+            "WIFI:S:Guest;T:nopass;;"
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_until_ok_retries() {
+        // The device appears on the third enumeration
+        let attempts = Cell::new(0);
+        let enumerate = || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < 3 {
+                    Err(CaptivePortalError::NoWifiDeviceFound)
+                } else {
+                    Ok(attempt)
+                }
+            }
+        };
+        let r = poll_until_ok(Duration::from_secs(1), Duration::from_millis(10), enumerate).await;
+        assert_eq!(r.ok(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn poll_until_ok_timeout() {
+        let attempts = Cell::new(0);
+        let enumerate = || {
+            attempts.set(attempts.get() + 1);
+            async { Err::<(), _>(CaptivePortalError::NoWifiDeviceFound) }
+        };
+        // No timeout: exactly one attempt
+        let r = poll_until_ok(Duration::from_secs(0), Duration::from_millis(10), enumerate).await;
+        match r {
+            Err(CaptivePortalError::NoWifiDeviceFound) => {},
+            r => panic!("Expected NoWifiDeviceFound. Got {:?}", r),
+        }
+        assert_eq!(attempts.get(), 1);
+
+        let r = poll_until_ok(Duration::from_millis(50), Duration::from_millis(10), enumerate).await;
+        assert!(r.is_err());
+        assert!(attempts.get() > 2);
+    }
+}
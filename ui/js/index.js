@@ -5,6 +5,7 @@ const selectBox = document.getElementById('ssid-select');
 const ssid_input = document.getElementById("ssid");
 const passphrase_input = document.getElementById("passphrase");
 const hw_input = document.getElementById("hw"); // wifi hw -> used as unique id
+const ssid_hex_input = document.getElementById("ssid_hex"); // raw ssid, if it is not valid UTF-8
 const submit_button = document.getElementById('submit_btn');
 const refresh_button = document.getElementById("refresh_button");
 const refresh_text = document.getElementById("refresh_text");
@@ -26,12 +27,13 @@ passphrase_input.addEventListener("input", ev => {
 });
 
 /**
- * Unselect wifi list entry. This also resets the hidden "hw" input,
+ * Unselect wifi list entry. This also resets the hidden "hw" and "ssid_hex" inputs,
  * which would otherwise uniquely identify the list entry to the backend.
  */
 function unselect_entry() {
     document.querySelectorAll(".target_link").forEach(e => delete e.dataset.selected);
     hw_input.value = "";
+    ssid_hex_input.value = "";
     input_mode.value = "open";
 }
 
@@ -46,28 +48,45 @@ function entrySelected(selected, network) {
         submit_button.disabled = ssid_input.value.length === 0 || passphrase_input.value.length === 0;
         document.querySelector('#identity-group').classList.remove("hide");
         document.querySelector('#identity').classList.remove("hide");
+        document.querySelector('#domain-suffix-match-group').classList.remove("hide");
+        document.querySelector('#domain_suffix_match').classList.remove("hide");
     } else if (network.security === 'wpa' || network.security === 'wep') {
         submit_button.disabled = ssid_input.value.length === 0 || passphrase_input.value.length === 0;
         document.querySelector('#passphrase-group').classList.remove("hide");
         document.querySelector('#passphrase').classList.remove("hide");
     } else {
+        // Open networks are connected without credentials
+        passphrase_input.value = "";
+        identity_input.value = "";
         submit_button.disabled = ssid_input.value.length === 0;
         document.querySelector('#identity-group').classList.add("hide");
         document.querySelector('#identity').classList.add("hide");
+        document.querySelector('#domain-suffix-match-group').classList.add("hide");
+        document.querySelector('#domain_suffix_match').classList.add("hide");
         document.querySelector('#passphrase-group').classList.add("hide");
         document.querySelector('#passphrase').classList.add("hide");
     }
 
     input_mode.value = network.security;
     ssid_input.value = network.ssid;
+    ssid_hex_input.value = network.ssid_hex || "";
     hw_input.value = network.hw;
     passphrase_input.focus();
 }
 
 /**
- * Creates a wifi entry and adds it to `selectBox`.
+ * The element id of the wifi entry of the given access point mac address.
+ *
+ * @param hw {string} The unique address (mac) of the wifi network
+ * @returns {string}
+ */
+function option_id(hw) {
+    return "ssid_" + hw.replace(/:/g, "_");
+}
+
+/**
+ * Creates a wifi entry and adds it to `selectBox`. An existing entry of the same network is updated instead.
  *
- * @param id
  * @param network The network struct
  * @param network.strength {int} The strength of the network in percent
  * @param network.frequency {int} The frequency of the network in Mhz
@@ -75,29 +94,32 @@ function entrySelected(selected, network) {
  * @param network.hw {string} The unique address (mac) of the wifi network
  * @param network.security {string} The security. May be "professional", "wpa", "wep", "open"
  */
-function createOption(id, network) {
+function createOption(network) {
+    const id = option_id(network.hw);
     let option = document.getElementById(id);
     let is_new = false;
     if (!option) {
         is_new = true;
         option = document.querySelector("#wifi_item").content.cloneNode(true).firstElementChild;
+        option.id = id;
     }
 
-    option.id = "ssid_" + id;
-
     const link = option.querySelector(".target_link");
-    delete link.dataset.selected;
-    link.addEventListener("click", ev => {
+    // Replaces the handler of a previous update, which refers to outdated network data
+    link.onclick = ev => {
         ev.stopPropagation();
         ev.preventDefault();
 
         unselect_entry();
         link.dataset.selected = "true";
         entrySelected(option, network);
-    });
+    };
 
     const strength = option.querySelector(".target_strength");
     strength.title = "Signal: " + network.strength + "%";
+    for (const c of Array.from(strength.classList)) {
+        if (c.startsWith("waveStrength-")) strength.classList.remove(c);
+    }
     strength.classList.add("waveStrength-" + Math.floor((network.strength + 10) * 4 / 100));
 
     const freq = network.frequency > 5000 ? "5 Ghz" : "2 Ghz";
@@ -108,8 +130,8 @@ function createOption(id, network) {
     subtitle.innerHTML = "Signal: " + network.strength + "% - " + freq;
 
     const encrypted = option.querySelector(".encrypted");
-    if (network.security !== "wpa" && network.security !== "enterprise" && network.security !== "wep")
-        encrypted.classList.add("hide");
+    encrypted.classList.toggle("hide",
+        network.security !== "wpa" && network.security !== "enterprise" && network.security !== "wep");
 
     if (is_new) selectBox.appendChild(option);
 }
@@ -151,7 +173,7 @@ function receive_list_of_networks(networks) {
         return 0;
     });
     for (let network of networks) {
-        createOption("ssid_" + network.hw.replace(":", "_"), network);
+        createOption(network);
     }
 }
 
@@ -167,7 +189,7 @@ async function get_networks() {
     refresh_button.disabled = false;
     refresh_text.innerText = "Automatic refresh enabled";
 
-    fetch("/refresh").then(v => {
+    fetch("refresh").then(v => {
         if (!v.ok) throw Error("Server error " + v.status)
     }).catch(err => {
         refresh_button.disabled = true;
@@ -176,18 +198,26 @@ async function get_networks() {
     });
 
 
-    let response = await fetch("/networks");
+    let response = await fetch("networks");
     if (!response.ok) {
         document.querySelector('#connect-error').classList.remove("hide");
         return;
     }
     receive_list_of_networks(await response.json());
+
+    // Explain why the previous connection attempt failed, if any
+    fetch("status").then(v => v.json()).then(status => {
+        if (status.connect_progress && status.connect_progress.type === "failed") {
+            document.getElementById("connect-error-text").innerText = "Connection failed: " + status.connect_progress.reason;
+            document.querySelector('#connect-error').classList.remove("hide");
+        }
+    }).catch(err => console.log("Failed to fetch status", err));
 }
 
 get_networks()
     .then(() => {
-        // There are three types of events coming form the backend: Added, Removed, List
-        const evtSource = new EventSource("/events");
+        // There are four types of events coming form the backend: Added, Changed, Removed, List
+        const evtSource = new EventSource("events");
 
         evtSource.addEventListener("List", async event => {
             receive_list_of_networks(JSON.parse(event.data));
@@ -195,14 +225,37 @@ get_networks()
 
         evtSource.addEventListener("Added", async event => {
             let event_data = JSON.parse(event.data);
-            let id = "ssid_" + event_data.hw.replace(":", "_");
             console.log("Wifi added/updated", event_data);
-            createOption(id, event_data)
+            createOption(event_data)
+        });
+
+        evtSource.addEventListener("Changed", async event => {
+            let event_data = JSON.parse(event.data);
+            createOption(event_data)
+        });
+
+        // Progress of a scan requested via the refresh button
+        evtSource.addEventListener("scan", async event => {
+            let event_data = JSON.parse(event.data);
+            if (event_data.type === "scan_started") {
+                refresh_button.disabled = true;
+                refresh_text.innerText = "Scanning ...";
+            } else if (event_data.type === "scan_complete") {
+                refresh_button.disabled = false;
+                refresh_text.innerText = "Found " + event_data.count + " networks";
+            } else if (event_data.type === "no_networks") {
+                refresh_button.disabled = false;
+                refresh_text.innerText = event_data.rfkill ? "No networks found: Wifi is disabled" : "No networks found";
+            } else if (event_data.type === "scan_failed") {
+                refresh_button.disabled = false;
+                refresh_text.innerText = "Scan failed";
+                console.log("Scan failed", event_data.reason);
+            }
         });
 
         evtSource.addEventListener("Removed", async event => {
             let event_data = JSON.parse(event.data);
-            let el = document.querySelector("#ssid_" + event_data.hw.replace(":", "_"));
+            let el = document.getElementById(option_id(event_data.hw));
             if (el) el.remove();
             console.log("Wifi removed", event_data);
         });
@@ -239,7 +292,7 @@ function handle_refresh_button(ev) {
         selectBox.removeChild(selectBox.lastChild);
     }
 
-    fetch("/refresh").catch(err => {
+    fetch("refresh").catch(err => {
         document.querySelector('#connect-error').classList.remove('hide');
         console.log("Failed to submit", err);
     });
@@ -261,13 +314,23 @@ form.addEventListener("submit", ev => {
     formData.forEach((value, key) => {
         if (value && value.length) object[key] = value
     });
+    if (object.mode === "open") {
+        delete object.passphrase;
+        delete object.identity;
+        delete object.domain_suffix_match;
+    }
     const json = JSON.stringify(object);
 
-    fetch("/connect", {
+    fetch("connect", {
         method: 'POST', headers: {
             'Content-Type': 'application/json'
         }, body: json
-    }).then(v => {
+    }).then(async v => {
+        // Credentials that do not fit the network security are rejected with a message for the user
+        if (v.status === 400) {
+            document.getElementById("connect-error-text").innerText = await v.text();
+            throw Error("Invalid credentials");
+        }
         if (!v.ok) throw Error("Server error " + v.status);
     }).catch(err => {
         document.querySelector('#applying').classList.add('hide');